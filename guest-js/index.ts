@@ -0,0 +1,1260 @@
+// Typed bindings for the mongo plugin's commands.
+//
+// Each function takes the same arguments as the command's Rust argument
+// struct, with filters, documents and pipelines passed as objects rather
+// than JSON text: they are serialized here, so Extended JSON values such as
+// `{ $oid: "..." }` or `{ $date: "..." }` go through unchanged. Responses the
+// plugin compressed as `{ $zstd, bytes }` are decompressed before they are
+// returned. Commands without a binding of their own can be sent with
+// `command`.
+
+import { invoke } from "@tauri-apps/api/tauri";
+import { listen, type UnlistenFn } from "@tauri-apps/api/event";
+import { decompress } from "fzstd";
+
+export type Document = Record<string, unknown>;
+export type Filter<T = Document> = { [K in keyof T]?: unknown } & Document;
+export type Pipeline = Document[];
+
+export type ReadPreferenceMode = "primary" | "primaryPreferred" | "secondary" | "secondaryPreferred" | "nearest";
+
+export type ReadPreference =
+  | ReadPreferenceMode
+  | { mode: ReadPreferenceMode; tags?: Record<string, string>[]; maxStalenessSeconds?: number };
+
+export type ReadConcern = "local" | "available" | "majority" | "linearizable" | "snapshot";
+
+export interface WriteConcern {
+  /** A number of members, `"majority"` or a tag set name. */
+  w?: number | string;
+  j?: boolean;
+  wtimeoutMS?: number;
+}
+
+/** Read and write concerns over the connection's defaults, for commands not on a session. */
+export interface Concerns {
+  readPreference?: ReadPreference;
+  readConcern?: ReadConcern;
+  writeConcern?: WriteConcern;
+}
+
+/** Arguments every command accepts. */
+export interface CommandOptions extends Concerns {
+  /** The connection to run on, the default connection when left out. */
+  connectionId?: string;
+  /** Canonical Extended JSON keeps every BSON type; relaxed is the default. */
+  extendedJson?: "relaxed" | "canonical";
+  /** Fail with a `timeout` error after this long, also sent as the read's `maxTimeMS`. */
+  timeoutMs?: number;
+  /** Lets `cancelOperation` stop the command while it runs. */
+  operationId?: string;
+}
+
+/** Arguments of the commands that can run on a session. */
+export interface SessionOptions extends CommandOptions {
+  sessionId?: string;
+}
+
+/** Arguments of the writes that can be previewed. */
+export interface WriteOptions extends SessionOptions {
+  /**
+   * Reply with what the write would do, adding `dryRun: true` and whether
+   * the counts are `exact`, without changing any document.
+   */
+  dryRun?: boolean;
+}
+
+/**
+ * What a destructive command replies with while the `confirmations` config
+ * holds it back: send it again with `confirmationToken` to run it.
+ */
+export interface ConfirmationRequired {
+  confirmationRequired: true;
+  confirmationToken: string;
+  expiresInMs: number;
+  impact: { database: string; collection?: string; collections?: number; documents: number; indexes?: number };
+}
+
+/** How a command failed, as the plugin reports it. */
+export interface MongoPluginError {
+  kind:
+    | "connection"
+    | "timeout"
+    | "auth"
+    | "permissionDenied"
+    | "duplicateKey"
+    | "writeConflict"
+    | "validation"
+    | "notFound"
+    | "invalidArgument"
+    | "bson"
+    | "versionConflict"
+    | "cancelled"
+    | "io"
+    | string;
+  message: string;
+  [detail: string]: unknown;
+}
+
+interface Compressed {
+  $zstd: string;
+  bytes: number;
+}
+
+function isCompressed(value: unknown): value is Compressed {
+  return typeof value === "object" && value !== null && typeof (value as Compressed).$zstd === "string";
+}
+
+function unwrap<T>(value: unknown): T {
+  if (!isCompressed(value)) {
+    return value as T;
+  }
+  const packed = Uint8Array.from(atob(value.$zstd), (c) => c.charCodeAt(0));
+  return JSON.parse(new TextDecoder().decode(decompress(packed))) as T;
+}
+
+/** Sends any plugin command by name. */
+export async function command<T = unknown>(name: string, args: Record<string, unknown> = {}): Promise<T> {
+  return unwrap<T>(await invoke(`plugin:mongo|${name}`, args));
+}
+
+const text = (value: unknown) => JSON.stringify(value);
+
+/** Splits the arguments every command takes off a command's own options. */
+function split<O extends CommandOptions>(options: O): [CommandOptions, Omit<O, keyof CommandOptions>] {
+  const { connectionId, extendedJson, readPreference, readConcern, writeConcern, timeoutMs, operationId, ...rest } = options;
+  return [{ connectionId, extendedJson, readPreference, readConcern, writeConcern, timeoutMs, operationId }, rest];
+}
+
+// Connections
+
+export interface DriverInfo {
+  name: string;
+  version?: string;
+  platform?: string;
+}
+
+export interface AuthOptions {
+  mechanism?: "SCRAM-SHA-1" | "SCRAM-SHA-256" | "MONGODB-X509" | "MONGODB-AWS" | "PLAIN";
+  username?: string;
+  password?: string;
+  source?: string;
+  mechanismProperties?: Document;
+}
+
+/** Settings over those in the connection string. Timeouts are in milliseconds. */
+export interface ConnectOptions extends Concerns {
+  connectionId?: string;
+  appName?: string;
+  driverInfo?: DriverInfo;
+  tls?: boolean;
+  tlsCAFile?: string;
+  tlsCertificateKeyFile?: string;
+  tlsAllowInvalidCertificates?: boolean;
+  auth?: AuthOptions;
+  srvMaxHosts?: number;
+  connectTimeoutMS?: number;
+  serverSelectionTimeoutMS?: number;
+  heartbeatFrequencyMS?: number;
+  minPoolSize?: number;
+  maxPoolSize?: number;
+  maxIdleTimeMS?: number;
+  replicaSet?: string;
+  directConnection?: boolean;
+}
+
+/** Opens a connection and returns its id. */
+export function connect(server: string, database: string, options: ConnectOptions = {}): Promise<string> {
+  return command("connectDBServer", { server, database, ...options });
+}
+
+export function closeConnection(connectionId?: string): Promise<unknown> {
+  return command("closeConnection", { connectionId });
+}
+
+export function listConnections(): Promise<unknown[]> {
+  return command("listConnections");
+}
+
+// Reads
+
+export interface Collation {
+  locale: string;
+  [option: string]: unknown;
+}
+
+export interface FindOptions {
+  projection?: Document;
+  sort?: Document;
+  limit?: number;
+  skip?: number;
+  batchSize?: number;
+  collation?: Collation;
+  maxTimeMS?: number;
+  hint?: string | Document;
+}
+
+export interface AggregateOptions {
+  allowDiskUse?: boolean;
+  batchSize?: number;
+  collation?: Collation;
+  maxTimeMS?: number;
+  hint?: string | Document;
+  let?: Document;
+}
+
+export function find<T = Document>(
+  collection: string,
+  query: Filter<T> = {},
+  options: FindOptions & CommandOptions = {},
+): Promise<T[]> {
+  const [common, rest] = split(options);
+  return command("find", { collection, query: text(query), options: rest, ...common });
+}
+
+export function findOne<T = Document>(
+  collection: string,
+  query: Filter<T> = {},
+  options: FindOptions & SessionOptions = {},
+): Promise<T | null> {
+  const [common, { sessionId, ...rest }] = split(options);
+  return command("findOne", { collection, query: text(query), options: rest, ...common, sessionId });
+}
+
+export function findById<T = Document>(collection: string, id: unknown, options: SessionOptions = {}): Promise<T | null> {
+  return command("findById", { collection, id, ...options });
+}
+
+/** The documents with these ids in the order given, `null` where no document has the id. */
+export function findByIds<T = Document>(
+  collection: string,
+  ids: unknown[],
+  options: CommandOptions & { projection?: Document; includeDeleted?: boolean } = {},
+): Promise<(T | null)[]> {
+  return command("findByIds", { collection, ids, ...options });
+}
+
+export function exists(collection: string, filter: Filter, options: CommandOptions & { maxTimeMS?: number } = {}): Promise<boolean> {
+  return command("exists", { collection, filter: text(filter), ...options });
+}
+
+export interface CountResult {
+  count: number;
+  estimated: boolean;
+  cached: boolean;
+  cachedAt?: string;
+}
+
+export function count(
+  collection: string,
+  filter?: Filter,
+  options: CommandOptions & { mode?: string; refresh?: boolean; maxTimeMS?: number } = {},
+): Promise<CountResult> {
+  return command("count", { collection, filter: filter && text(filter), ...options });
+}
+
+export interface CountDocumentsOptions extends CommandOptions {
+  limit?: number;
+  skip?: number;
+  hint?: string | Document;
+  collation?: Collation;
+  maxTimeMS?: number;
+}
+
+export function countDocuments(collection: string, filter?: Filter, options: CountDocumentsOptions = {}): Promise<number> {
+  const [common, rest] = split(options);
+  return command("countDocuments", { collection, filter: filter && text(filter), options: rest, ...common });
+}
+
+export function estimatedDocumentCount(
+  collection: string,
+  options: CommandOptions & { maxTimeMS?: number; includeDeleted?: boolean } = {},
+): Promise<number> {
+  return command("estimatedDocumentCount", { collection, ...options });
+}
+
+export function distinct<V = unknown>(
+  collection: string,
+  field: string,
+  filter?: Filter,
+  options: CommandOptions & { collation?: Collation; maxTimeMS?: number } = {},
+): Promise<V[]> {
+  const [common, rest] = split(options);
+  return command("distinct", { collection, field, filter: filter && text(filter), options: rest, ...common });
+}
+
+export function aggregate<T = Document>(
+  collection: string,
+  pipeline: Pipeline,
+  options: AggregateOptions & CommandOptions = {},
+): Promise<T[]> {
+  const [common, rest] = split(options);
+  return command("aggregate", { collection, pipeline: text(pipeline), options: rest, ...common });
+}
+
+export type ExplainVerbosity = "queryPlanner" | "executionStats" | "allPlansExecution";
+
+/** The server's explain output for `find(collection, query, options)`, which isn't run. */
+export function explainFind(
+  collection: string,
+  query: Filter = {},
+  options: FindOptions & CommandOptions = {},
+  verbosity: ExplainVerbosity = "executionStats",
+): Promise<Document> {
+  const [common, rest] = split(options);
+  return command("find", { collection, query: text(query), options: rest, ...common, explain: verbosity });
+}
+
+/** The server's explain output for `aggregate(collection, pipeline, options)`, which isn't run. */
+export function explainAggregate(
+  collection: string,
+  pipeline: Pipeline,
+  options: AggregateOptions & CommandOptions = {},
+  verbosity: ExplainVerbosity = "executionStats",
+): Promise<Document> {
+  const [common, rest] = split(options);
+  return command("aggregate", { collection, pipeline: text(pipeline), options: rest, ...common, explain: verbosity });
+}
+
+// Cursors
+
+export interface Batch<T> {
+  documents: T[];
+  done: boolean;
+  /** The collection was written to since the cursor opened (`staleCursors: "annotate"`). */
+  stale: boolean;
+}
+
+/** A server cursor read a batch at a time. */
+export class MongoCursor<T = Document> {
+  constructor(readonly id: string, private readonly connectionId?: string) {}
+
+  next(batchSize?: number): Promise<Batch<T>> {
+    return command("cursorNext", { id: this.id, batchSize, connectionId: this.connectionId });
+  }
+
+  close(): Promise<unknown> {
+    return command("cursorClose", { id: this.id, connectionId: this.connectionId });
+  }
+
+  async *[Symbol.asyncIterator](): AsyncGenerator<T> {
+    for (;;) {
+      const batch = await this.next();
+      yield* batch.documents;
+      if (batch.done) {
+        return;
+      }
+    }
+  }
+}
+
+export async function findCursor<T = Document>(
+  collection: string,
+  query: Filter<T> = {},
+  options: FindOptions & CommandOptions = {},
+): Promise<MongoCursor<T>> {
+  const [common, rest] = split(options);
+  const { cursorId } = await command<{ cursorId: string }>("findCursor", {
+    collection,
+    query: text(query),
+    options: rest,
+    ...common,
+  });
+  return new MongoCursor<T>(cursorId, common.connectionId);
+}
+
+export async function aggregateCursor<T = Document>(
+  collection: string,
+  pipeline: Pipeline,
+  options: AggregateOptions & CommandOptions = {},
+): Promise<MongoCursor<T>> {
+  const [common, rest] = split(options);
+  const { cursorId } = await command<{ cursorId: string }>("aggregateCursor", {
+    collection,
+    pipeline: text(pipeline),
+    options: rest,
+    ...common,
+  });
+  return new MongoCursor<T>(cursorId, common.connectionId);
+}
+
+export interface StaleCursors {
+  namespace: string;
+  cursorIds: string[];
+  closed: boolean;
+}
+
+/** Calls `handler` when a write marks or closes open cursors, with `staleCursors` set in the plugin config. */
+export function onStaleCursors(handler: (stale: StaleCursors) => void): Promise<UnlistenFn> {
+  return listen<StaleCursors>("mongo://cursors-stale", (event) => handler(event.payload));
+}
+
+// Writes
+
+export interface UpdateResult {
+  matchedCount: number;
+  modifiedCount: number;
+  upsertedId?: unknown;
+}
+
+export interface DeleteResult {
+  deletedCount: number;
+}
+
+/** `"success"`, or `{ insertedId }` when the collection has an id strategy. */
+export function insertOne<T = Document>(collection: string, data: T, options: SessionOptions = {}): Promise<"success" | { insertedId: unknown }> {
+  return command("insertOne", { collection, data: text(data), ...options });
+}
+
+/** `"success"`, or `{ insertedIds }` when the collection has an id strategy. */
+export function insertMany<T = Document>(
+  collection: string,
+  data: T[],
+  options: SessionOptions = {},
+): Promise<"success" | { insertedIds: unknown[] }> {
+  return command("insertMany", { collection, data: text(data), ...options });
+}
+
+export function updateOne(
+  collection: string,
+  filter: Filter,
+  update: Document | Pipeline,
+  options: WriteOptions & { upsert?: boolean } = {},
+): Promise<UpdateResult> {
+  return command("updateOne", { collection, filter: text(filter), update: text(update), ...options });
+}
+
+export function updateMany(
+  collection: string,
+  filter: Filter,
+  update: Document | Pipeline,
+  options: WriteOptions & { upsert?: boolean } = {},
+): Promise<UpdateResult> {
+  return command("updateMany", { collection, filter: text(filter), update: text(update), ...options });
+}
+
+export function updateById(
+  collection: string,
+  id: unknown,
+  update: Document | Pipeline,
+  options: WriteOptions & { upsert?: boolean } = {},
+): Promise<UpdateResult> {
+  return command("updateById", { collection, id, update: text(update), ...options });
+}
+
+export function replaceOne<T = Document>(
+  collection: string,
+  filter: Filter<T>,
+  replacement: T,
+  options: WriteOptions & { upsert?: boolean } = {},
+): Promise<UpdateResult> {
+  return command("replaceOne", { collection, filter: text(filter), replacement: text(replacement), ...options });
+}
+
+export function findOneAndUpdate<T = Document>(
+  collection: string,
+  filter: Filter<T>,
+  update: Document | Pipeline,
+  options: CommandOptions & { upsert?: boolean; returnDocument?: "before" | "after" } = {},
+): Promise<T | null> {
+  return command("findOneAndUpdate", { collection, filter: text(filter), update: text(update), ...options });
+}
+
+export function deleteOne(collection: string, filter: Filter, options: WriteOptions = {}): Promise<DeleteResult> {
+  return command("deleteOne", { collection, filter: text(filter), ...options });
+}
+
+export function deleteMany(
+  collection: string,
+  filter: Filter,
+  options: WriteOptions & { confirmationToken?: string } = {},
+): Promise<DeleteResult | ConfirmationRequired> {
+  return command("deleteMany", { collection, filter: text(filter), ...options });
+}
+
+export function deleteById(collection: string, id: unknown, options: WriteOptions = {}): Promise<DeleteResult> {
+  return command("deleteById", { collection, id, ...options });
+}
+
+/** Deletes the documents with these ids in one go, listing the ids no document had. */
+export function deleteByIds(
+  collection: string,
+  ids: unknown[],
+  options: CommandOptions & { dryRun?: boolean } = {},
+): Promise<DeleteResult & { notFound: unknown[] }> {
+  return command("deleteByIds", { collection, ids, ...options });
+}
+
+export function findOneAndDelete<T = Document>(collection: string, filter: Filter<T>, options: CommandOptions = {}): Promise<T | null> {
+  return command("findOneAndDelete", { collection, filter: text(filter), ...options });
+}
+
+export function upsertMany<T = Document>(
+  collection: string,
+  documents: T[],
+  keyFields: string[],
+  options: CommandOptions & { ordered?: boolean } = {},
+): Promise<unknown> {
+  return command("upsertMany", { collection, documents: text(documents), keyFields, ...options });
+}
+
+export type WriteModel =
+  | { insertOne: { document: Document } }
+  | { updateOne: { filter: Filter; update: Document | Pipeline; upsert?: boolean } }
+  | { updateMany: { filter: Filter; update: Document | Pipeline; upsert?: boolean } }
+  | { replaceOne: { filter: Filter; replacement: Document; upsert?: boolean } }
+  | { deleteOne: { filter: Filter } }
+  | { deleteMany: { filter: Filter } };
+
+export interface BulkWriteResult {
+  insertedCount: number;
+  matchedCount: number;
+  modifiedCount: number;
+  deletedCount: number;
+  upsertedCount: number;
+  results: { status: "ok" | "failed" | "skipped"; insertedId?: unknown; upsertedId?: unknown }[];
+  writeErrors: { index: number; code: number; message: string }[];
+  writeConcernErrors: { code: number; message: string }[];
+}
+
+export function bulkWrite(
+  collection: string,
+  operations: WriteModel[],
+  options: CommandOptions & { ordered?: boolean; dryRun?: boolean } = {},
+): Promise<BulkWriteResult> {
+  return command("bulkWrite", { collection, operations: text(operations), ...options });
+}
+
+// Sessions and transactions
+
+/** A server session whose `id` is passed as `sessionId` to the commands run on it. */
+export class Session {
+  constructor(readonly id: string, private readonly connectionId?: string) {}
+
+  startTransaction(): Promise<unknown> {
+    return command("startTransaction", { sessionId: this.id, connectionId: this.connectionId });
+  }
+
+  commitTransaction(): Promise<unknown> {
+    return command("commitTransaction", { sessionId: this.id, connectionId: this.connectionId });
+  }
+
+  abortTransaction(): Promise<unknown> {
+    return command("abortTransaction", { sessionId: this.id, connectionId: this.connectionId });
+  }
+
+  end(): Promise<{ closed: boolean }> {
+    return command("endSession", { sessionId: this.id, connectionId: this.connectionId });
+  }
+}
+
+export async function startSession(options: CommandOptions = {}): Promise<Session> {
+  const { sessionId } = await command<{ sessionId: string }>("startSession", { ...options });
+  return new Session(sessionId, options.connectionId);
+}
+
+// Change streams
+
+export interface WatchOptions extends CommandOptions {
+  /** The whole database when left out. */
+  collection?: string;
+  pipeline?: Pipeline;
+  fullDocument?: "default" | "updateLookup" | "whenAvailable" | "required";
+  maxAwaitTimeMS?: number;
+  batchSize?: number;
+  /** An Extended JSON `$timestamp` or whole seconds since the epoch. */
+  startAtOperationTime?: { $timestamp: { t: number; i: number } } | number;
+}
+
+export interface ChangeStreamError {
+  error: string;
+}
+
+/** Stops listening and closes the change stream. */
+export type Unwatch = () => Promise<void>;
+
+/** Opens a change stream and calls `onChange` with each change document. */
+export async function watch<T = Document>(onChange: (change: T | ChangeStreamError) => void, options: WatchOptions = {}): Promise<Unwatch> {
+  const { pipeline, ...rest } = options;
+  const { watchId } = await command<{ watchId: string }>("watch", { ...rest, pipeline: pipeline && text(pipeline) });
+  let unlisten: UnlistenFn;
+  try {
+    unlisten = await listen<T | ChangeStreamError>(`mongo://change/${watchId}`, (event) => onChange(event.payload));
+  } catch (e) {
+    await command("unwatch", { watchId, connectionId: options.connectionId });
+    throw e;
+  }
+  return async () => {
+    unlisten();
+    await command("unwatch", { watchId, connectionId: options.connectionId });
+  };
+}
+
+/** A change document as the server sends it. */
+export interface ChangeEvent<T = Document> {
+  operationType: "insert" | "update" | "replace" | "delete" | "drop" | "rename" | "dropDatabase" | "invalidate" | string;
+  documentKey?: { _id: unknown };
+  fullDocument?: T | null;
+  updateDescription?: {
+    updatedFields?: Document;
+    removedFields?: string[];
+    truncatedArrays?: { field: string; newSize: number }[];
+  };
+}
+
+/** How a `LiveCollection` changed: a document came in, changed, or left. */
+export interface LiveChange<T> {
+  type: "insert" | "update" | "replace" | "delete";
+  id: unknown;
+  /** Where the document is, or was for `delete`, in `documents`. */
+  index: number;
+  document?: T;
+  previous?: T;
+}
+
+export interface LiveCollectionOptions<T> extends FindOptions, CommandOptions {
+  /**
+   * Whether a changed document still belongs, as `query` would decide.
+   * Without it, or with a `projection`, the document is read again with
+   * `query` and the projection to tell.
+   */
+  matches?: (document: T) => boolean;
+}
+
+const idKey = (id: unknown) => JSON.stringify(id);
+
+/** Sets or, with `undefined`, removes a dotted `path` in `document`. */
+function setPath(document: Document, path: string, value: unknown) {
+  const keys = path.split(".");
+  let target = document as Record<string, unknown>;
+  for (const key of keys.slice(0, -1)) {
+    if (typeof target[key] !== "object" || target[key] === null) {
+      target[key] = {};
+    }
+    target = target[key] as Record<string, unknown>;
+  }
+  const last = keys[keys.length - 1];
+  if (value === undefined) {
+    delete target[last];
+  } else {
+    target[last] = value;
+  }
+}
+
+/** `document` with an update's `updateDescription` applied. */
+function patched<T>(document: T, update: NonNullable<ChangeEvent<T>["updateDescription"]>): T {
+  const copy = JSON.parse(JSON.stringify(document)) as Document;
+  for (const { field, newSize } of update.truncatedArrays ?? []) {
+    const array = field.split(".").reduce<unknown>((value, key) => (value as Record<string, unknown> | undefined)?.[key], copy);
+    if (Array.isArray(array)) {
+      array.length = newSize;
+    }
+  }
+  for (const [path, value] of Object.entries(update.updatedFields ?? {})) {
+    setPath(copy, path, value);
+  }
+  for (const path of update.removedFields ?? []) {
+    setPath(copy, path, undefined);
+  }
+  return copy as T;
+}
+
+/**
+ * `query` as a `$match` on change events' `fullDocument`, or `undefined`
+ * when it uses an operator that only applies to a whole document.
+ */
+function onFullDocument(query: Document): Document | undefined {
+  const moved: Document = {};
+  for (const [key, value] of Object.entries(query)) {
+    if (key === "$and" || key === "$or" || key === "$nor") {
+      const clauses = (value as Document[]).map(onFullDocument);
+      if (clauses.some((clause) => clause === undefined)) {
+        return undefined;
+      }
+      moved[key] = clauses;
+    } else if (key.startsWith("$")) {
+      return undefined;
+    } else {
+      moved[`fullDocument.${key}`] = value;
+    }
+  }
+  return moved;
+}
+
+/**
+ * The documents of a `find`, kept up to date from a change stream on the
+ * collection so a UI can bind to `documents`. Only documents matching
+ * `query` are kept, in the shape `projection` gives them. Changes made
+ * while the initial `find` runs are applied once it returns. Documents that
+ * come in are appended, so `sort` holds only for the initial ones, and with
+ * `limit` those that come in once it is reached are left out.
+ */
+export class LiveCollection<T = Document> {
+  readonly documents: T[] = [];
+  private readonly listeners = new Set<(change: LiveChange<T>) => void>();
+  private readonly errorListeners = new Set<(error: ChangeStreamError) => void>();
+  private unwatch?: Unwatch;
+  /** Changes are applied one at a time, in the order they came. */
+  private applied: Promise<void> = Promise.resolve();
+
+  private constructor(
+    private readonly collection: string,
+    private readonly query: Filter<T>,
+    private readonly options: LiveCollectionOptions<T>,
+  ) {}
+
+  static async open<T = Document>(
+    collection: string,
+    query: Filter<T> = {},
+    options: LiveCollectionOptions<T> = {},
+  ): Promise<LiveCollection<T>> {
+    const { matches, ...findOptions } = options;
+    const [common] = split(findOptions);
+    const live = new LiveCollection<T>(collection, query, options);
+    // Inserts that don't match are dropped by the server; other changes may
+    // move a document out of the query, so those all come through.
+    const inserts = onFullDocument(query);
+    const pipeline = inserts && [{ $match: { $or: [{ operationType: { $ne: "insert" } }, inserts] } }];
+    let held: (ChangeEvent<T> | ChangeStreamError)[] | null = [];
+    live.unwatch = await watch<ChangeEvent<T>>((change) => (held ? held.push(change) : live.enqueue(change)), {
+      ...common,
+      collection,
+      pipeline,
+      fullDocument: "updateLookup",
+    });
+    try {
+      live.documents.push(...(await find<T>(collection, query, findOptions)));
+    } catch (e) {
+      await live.close();
+      throw e;
+    }
+    const pending = held;
+    held = null;
+    pending.forEach((change) => live.enqueue(change));
+    await live.applied;
+    return live;
+  }
+
+  /** Calls `listener` with each change to `documents`; returns a function that stops it. */
+  onChange(listener: (change: LiveChange<T>) => void): () => void {
+    this.listeners.add(listener);
+    return () => this.listeners.delete(listener);
+  }
+
+  /** Calls `listener` if the change stream fails or is invalidated, after which `documents` no longer changes. */
+  onError(listener: (error: ChangeStreamError) => void): () => void {
+    this.errorListeners.add(listener);
+    return () => this.errorListeners.delete(listener);
+  }
+
+  /** Stops following the collection and closes the change stream. */
+  async close(): Promise<void> {
+    const unwatch = this.unwatch;
+    this.unwatch = undefined;
+    this.listeners.clear();
+    this.errorListeners.clear();
+    await unwatch?.();
+  }
+
+  private emit(change: LiveChange<T>) {
+    this.listeners.forEach((listener) => listener(change));
+  }
+
+  private fail(error: ChangeStreamError) {
+    this.errorListeners.forEach((listener) => listener(error));
+  }
+
+  private remove(index: number) {
+    const [previous] = this.documents.splice(index, 1);
+    this.emit({ type: "delete", id: (previous as Document)._id, index, previous });
+  }
+
+  private enqueue(change: ChangeEvent<T> | ChangeStreamError) {
+    this.applied = this.applied.then(() => this.apply(change)).catch((e) => this.fail({ error: String(e) }));
+  }
+
+  /** The document with `id` as the collection would now return it, if it still matches. */
+  private async current(id: unknown, document: T | undefined, previous: T | undefined): Promise<T | undefined> {
+    const { matches, projection, ...findOptions } = this.options;
+    if (matches && !projection) {
+      return document !== undefined && matches(document) ? document : undefined;
+    }
+    if (document === undefined && previous === undefined) {
+      return undefined;
+    }
+    const [common] = split(findOptions);
+    const query = { $and: [this.query, { _id: id }] } as Filter<T>;
+    const [found] = await find<T>(this.collection, query, { ...common, projection, limit: 1 });
+    return found;
+  }
+
+  private async apply(change: ChangeEvent<T> | ChangeStreamError) {
+    if ("error" in change) {
+      this.fail(change);
+      return;
+    }
+    switch (change.operationType) {
+      case "insert":
+      case "update":
+      case "replace":
+      case "delete":
+        break;
+      case "drop":
+      case "rename":
+      case "dropDatabase":
+        while (this.documents.length > 0) {
+          this.remove(this.documents.length - 1);
+        }
+        return;
+      case "invalidate":
+        this.fail({ error: "The change stream was invalidated" });
+        return;
+      default:
+        return;
+    }
+    const id = change.documentKey?._id;
+    const index = this.documents.findIndex((document) => idKey((document as Document)._id) === idKey(id));
+    const previous = index >= 0 ? this.documents[index] : undefined;
+    if (change.operationType === "delete") {
+      if (index >= 0) {
+        this.remove(index);
+      }
+      return;
+    }
+    // An update's document is looked up after the fact, and is gone if it was deleted since.
+    let document = change.fullDocument ?? undefined;
+    if (document === undefined && change.operationType === "update" && previous !== undefined && change.updateDescription) {
+      document = patched(previous, change.updateDescription);
+    }
+    // No other change is applied while this one is looked up, so `index` still holds.
+    const current = await this.current(id, document, previous);
+    if (current !== undefined && index >= 0) {
+      this.documents[index] = current;
+      const type = change.operationType === "insert" ? "replace" : (change.operationType as "update" | "replace");
+      this.emit({ type, id, index, document: current, previous });
+    } else if (current !== undefined) {
+      if (this.options.limit && this.documents.length >= this.options.limit) {
+        return;
+      }
+      this.documents.push(current);
+      this.emit({ type: "insert", id, index: this.documents.length - 1, document: current });
+    } else if (index >= 0) {
+      this.remove(index);
+    }
+  }
+}
+
+// Indexes
+
+export interface IndexOptions {
+  name?: string;
+  unique?: boolean;
+  sparse?: boolean;
+  expireAfterSeconds?: number;
+  partialFilterExpression?: Document;
+  collation?: Collation;
+  hidden?: boolean;
+  weights?: Document;
+  defaultLanguage?: string;
+  languageOverride?: string;
+}
+
+export function createIndex(collection: string, keys: Document, options: IndexOptions = {}, commandOptions: CommandOptions = {}): Promise<string> {
+  return command("createIndex", { collection, keys: text(keys), options, ...commandOptions });
+}
+
+export function createIndexes(
+  collection: string,
+  indexes: { keys: Document; options?: IndexOptions }[],
+  options: CommandOptions = {},
+): Promise<string[]> {
+  return command("createIndexes", {
+    collection,
+    indexes: indexes.map((index) => ({ keys: text(index.keys), options: index.options })),
+    ...options,
+  });
+}
+
+/** Drops the named index, or every index but `_id`'s with `*`. */
+export function dropIndex(collection: string, name: string, options: CommandOptions = {}): Promise<unknown> {
+  return command("dropIndex", { collection, name, ...options });
+}
+
+export function listIndexes(collection: string, options: CommandOptions = {}): Promise<Document[]> {
+  return command("listIndexes", { collection, ...options });
+}
+
+// Administration
+
+export function listDatabases(filter?: Filter, options: CommandOptions = {}): Promise<{ name: string; sizeOnDisk: number; empty: boolean }[]> {
+  return command("listDatabases", { filter: filter && text(filter), ...options });
+}
+
+export function listCollections(filter?: Filter, options: CommandOptions & { nameOnly?: boolean } = {}): Promise<unknown[]> {
+  return command("listCollections", { filter: filter && text(filter), ...options });
+}
+
+export interface CreateCollectionOptions {
+  capped?: boolean;
+  size?: number;
+  max?: number;
+  validator?: Document;
+  validationLevel?: "off" | "strict" | "moderate";
+  validationAction?: "error" | "warn";
+}
+
+export function createCollection(collection: string, options: CreateCollectionOptions = {}, commandOptions: CommandOptions = {}): Promise<unknown> {
+  return command("createCollection", { collection, options, ...commandOptions });
+}
+
+export interface ViewDefinition {
+  name: string;
+  viewOn: string;
+  pipeline: Pipeline;
+  collation: Collation | null;
+  /** The views between this one and its source collection, nearest first. */
+  through: string[];
+  sourceCollection: string;
+  sourceExists: boolean;
+}
+
+export function getViewDefinition(view: string, options: CommandOptions = {}): Promise<ViewDefinition> {
+  return command("getViewDefinition", { collection: view, ...options });
+}
+
+export function createView(
+  view: string,
+  viewOn: string,
+  pipeline: Pipeline,
+  options: CommandOptions & { collation?: Collation } = {},
+): Promise<"success"> {
+  return command("createView", { collection: view, viewOn, pipeline: text(pipeline), ...options });
+}
+
+export function modifyView(view: string, viewOn: string, pipeline: Pipeline, options: CommandOptions = {}): Promise<"success"> {
+  return command("modifyView", { collection: view, viewOn, pipeline: text(pipeline), ...options });
+}
+
+export function dropCollection(
+  collection: string,
+  options: CommandOptions & { confirmationToken?: string } = {},
+): Promise<"success" | ConfirmationRequired> {
+  return command("dropCollection", { collection, ...options });
+}
+
+export function renameCollection(collection: string, to: string, options: CommandOptions & { dropTarget?: boolean } = {}): Promise<unknown> {
+  return command("renameCollection", { collection, to, ...options });
+}
+
+export interface CloneCollectionOptions extends CommandOptions {
+  /** Build the source's indexes on the copy, `true` by default. */
+  withIndexes?: boolean;
+  batchSize?: number;
+  operationId?: string;
+}
+
+export interface CloneCollectionResult {
+  operationId: string;
+  copied: number;
+  batches: number;
+  indexes: string[];
+  cancelled: boolean;
+  durationMs: number;
+}
+
+/** Progress arrives as `mongo://operation-progress` events of kind `cloneCollection`. */
+export function cloneCollection(
+  source: string,
+  target: string,
+  filter?: Filter,
+  options: CloneCollectionOptions = {},
+): Promise<CloneCollectionResult> {
+  return command("cloneCollection", { collection: source, to: target, filter: filter && text(filter), ...options });
+}
+
+export type DumpFormat = "ndjson" | "bson";
+
+export interface ExportCollectionResult {
+  operationId: string;
+  path: string;
+  count: number;
+  bytes: number;
+}
+
+/** Writes the collection to `path`, inside a `dump.directories` entry; progress arrives as `exportCollection` operation events. */
+export function exportCollection(
+  collection: string,
+  path: string,
+  options: CommandOptions & { filter?: Filter; format?: DumpFormat } = {},
+): Promise<ExportCollectionResult> {
+  const { filter, ...rest } = options;
+  return command("exportCollection", { collection, path, filter: filter && text(filter), ...rest });
+}
+
+export interface ImportCollectionOptions extends CommandOptions {
+  format?: DumpFormat;
+  dropBeforeImport?: boolean;
+  batchSize?: number;
+  confirmationToken?: string;
+}
+
+export interface ImportCollectionResult {
+  operationId: string;
+  count: number;
+  skipped: number;
+  dropped: boolean;
+}
+
+/** Reads a dump back into the collection; progress arrives as `importCollection` operation events. */
+export function importCollection(
+  collection: string,
+  path: string,
+  options: ImportCollectionOptions = {},
+): Promise<ImportCollectionResult | ConfirmationRequired> {
+  return command("importCollection", { collection, path, ...options });
+}
+
+export function dropDatabase(options: CommandOptions & { confirmationToken?: string } = {}): Promise<"success" | ConfirmationRequired> {
+  return command("dropDatabase", { ...options });
+}
+
+export function runCommand(cmd: Document, options: CommandOptions = {}): Promise<Document> {
+  return command("runCommand", { command: text(cmd), ...options });
+}
+
+// Schema sync
+
+export interface CollectionSpec {
+  name: string;
+  type: "collection" | "view" | string;
+  options: Document;
+  indexes: Document[];
+}
+
+export interface SchemaSpec {
+  database: string;
+  collections: CollectionSpec[];
+}
+
+export interface SchemaChange {
+  collection: string;
+  action:
+    | "createCollection"
+    | "createView"
+    | "modifyCollection"
+    | "optionMismatch"
+    | "createIndex"
+    | "replaceIndex"
+    | "dropIndex"
+    | "extraIndex"
+    | "extraCollection";
+  index?: string;
+  options?: string[];
+}
+
+/** Export with `extendedJson: "canonical"` to keep validators' number types exact. */
+export function exportSchema(options: CommandOptions & { collections?: string[] } = {}): Promise<SchemaSpec> {
+  return command("exportSchema", { ...options });
+}
+
+/** With `dryRun`, reports the changes without making them. */
+export function applySchema(
+  spec: SchemaSpec,
+  options: CommandOptions & { dryRun?: boolean; dropExtraIndexes?: boolean } = {},
+): Promise<{ database: string; applied: boolean; changes: SchemaChange[] }> {
+  return command("applySchema", { spec: text(spec), ...options });
+}
+
+// Diagnostics
+
+/** Rejects with a `timeout` error when the server doesn't answer within `timeoutMS`. */
+export function ping(options: CommandOptions & { timeoutMS?: number } = {}): Promise<{ ok: true; roundTripMs: number }> {
+  return command("ping", { ...options });
+}
+
+export function serverStatus(sections: Record<string, 0 | 1> = {}, options: CommandOptions = {}): Promise<Document> {
+  return command("serverStatus", { sections, ...options });
+}
+
+export interface ConnectionStateEvent {
+  connectionId: string;
+  type: string;
+  at: number;
+}
+
+/** Calls `onChange` with `true` on `mongo://connected` and `false` on `mongo://disconnected`. */
+export async function onConnectionState(onChange: (connected: boolean, event: ConnectionStateEvent) => void): Promise<UnlistenFn> {
+  const connected = await listen<ConnectionStateEvent>("mongo://connected", (event) => onChange(true, event.payload));
+  const disconnected = await listen<ConnectionStateEvent>("mongo://disconnected", (event) => onChange(false, event.payload));
+  return () => {
+    connected();
+    disconnected();
+  };
+}
+
+// Offline queue
+
+export interface QueuedWrite {
+  queueId: string;
+  connectionId: string;
+  database: string;
+  command: string;
+  collection?: string;
+  queuedAt: number;
+  /** Why the write was queued. */
+  error: MongoPluginError;
+}
+
+/** What a write replies with when it was queued rather than sent. */
+export interface Queued {
+  queued: true;
+  queueId: string;
+  error: MongoPluginError;
+}
+
+export function listQueuedWrites(options: CommandOptions = {}): Promise<QueuedWrite[]> {
+  return command("listQueuedWrites", { ...options });
+}
+
+export function discardQueuedWrite(queueId: string, options: CommandOptions = {}): Promise<{ discarded: true }> {
+  return command("discardQueuedWrite", { queueId, ...options });
+}
+
+/** Calls `onFlushed` for each queued write the server took, and `onConflict` for each it rejected. */
+export async function onQueuedWrites(
+  onFlushed: (write: QueuedWrite & { result: unknown }) => void,
+  onConflict: (write: QueuedWrite) => void,
+): Promise<UnlistenFn> {
+  const flushed = await listen<QueuedWrite & { result: unknown }>("mongo://queue-flushed", (event) => onFlushed(event.payload));
+  const conflict = await listen<QueuedWrite>("mongo://queue-conflict", (event) => onConflict(event.payload));
+  return () => {
+    flushed();
+    conflict();
+  };
+}
+
+// Encryption keys (builds with the `csfle` feature)
+
+/** A UUID string, or `{ $binary: { base64, subType: "04" } }` as returned by `createDataKey`. */
+export type KeyId = string | { $binary: { base64: string; subType: string } };
+
+export type MasterKey =
+  | { kmsProvider: "local" }
+  | { kmsProvider: "aws"; masterKey: { region: string; key: string; endpoint?: string } }
+  | { kmsProvider: "azure"; masterKey: { keyVaultEndpoint: string; keyName: string; keyVersion?: string } };
+
+export interface DataKey {
+  _id: KeyId;
+  keyAltNames?: string[];
+  creationDate: unknown;
+  updateDate: unknown;
+  status: number;
+  masterKey: Document;
+}
+
+export function createDataKey(master: MasterKey, keyAltNames: string[] = [], options: CommandOptions = {}): Promise<{ keyId: KeyId }> {
+  return command("createDataKey", { ...master, keyAltNames, ...options });
+}
+
+export function listDataKeys(options: CommandOptions = {}): Promise<DataKey[]> {
+  return command("listDataKeys", { ...options });
+}
+
+export function deleteDataKey(keyId: KeyId, options: CommandOptions = {}): Promise<{ deleted: true }> {
+  return command("deleteDataKey", { keyId, ...options });
+}
+
+export function addKeyAltName(keyId: KeyId, keyAltName: string, options: CommandOptions = {}): Promise<{ modified: boolean }> {
+  return command("addKeyAltName", { keyId, keyAltName, ...options });
+}
+
+export function removeKeyAltName(keyId: KeyId, keyAltName: string, options: CommandOptions = {}): Promise<{ modified: boolean }> {
+  return command("removeKeyAltName", { keyId, keyAltName, ...options });
+}
+
+// Query stats
+
+export interface QueryStat {
+  collection: string;
+  command: string;
+  shape: unknown;
+  count: number;
+  errors: number;
+  totalMs: number;
+  avgMs: number;
+  maxMs: number;
+}
+
+export function getQueryStats(
+  options: CommandOptions & { collection?: string; sortBy?: "count" | "totalMs"; limit?: number } = {},
+): Promise<QueryStat[]> {
+  return command("getQueryStats", { ...options });
+}
+
+export function resetQueryStats(): Promise<unknown> {
+  return command("resetQueryStats");
+}
+
+export interface SlowQuery {
+  database: string;
+  collection?: string;
+  command: string;
+  shape: unknown;
+  durationMs: number;
+  ok: boolean;
+}
+
+/** Calls `handler` for each command over the `slowQueryMs` set in the plugin config. */
+export function onSlowQuery(handler: (query: SlowQuery) => void): Promise<UnlistenFn> {
+  return listen<SlowQuery>("mongo://slow-query", (event) => handler(event.payload));
+}
+
+// Operations
+
+export interface OperationStatus {
+  operationId: string;
+  kind: string;
+  status: "running" | "paused" | "cancelling" | "completed" | "failed" | "cancelled";
+  progress: unknown;
+  startedAt?: number;
+  finishedAt?: number | null;
+  result?: unknown;
+  error?: string | null;
+}
+
+/** Stops a command given an `operationId`, or a long-running operation at its next batch. */
+export function cancelOperation(operationId: string): Promise<OperationStatus> {
+  return command("cancelOperation", { operationId });
+}
+
+// Migrations
+
+export interface MigrationInfo {
+  version: number;
+  name: string;
+  applied: boolean;
+  appliedAt: string | null;
+  reversible: boolean;
+}
+
+export interface MigrationStatus {
+  current: number | null;
+  pending: number;
+  migrations: MigrationInfo[];
+  /** Applied versions the app no longer registers. */
+  unknown: number[];
+}
+
+/** Applies pending migrations up to `to`; progress arrives as `migration` operation events. */
+export function migrateUp(options: CommandOptions & { to?: number } = {}): Promise<{ operationId: string; applied: number[]; current: number | null }> {
+  return command("migrateUp", { ...options });
+}
+
+/** Reverts applied migrations above `to`, or only the newest one without it. */
+export function migrateDown(options: CommandOptions & { to?: number } = {}): Promise<{ operationId: string; reverted: number[]; current: number | null }> {
+  return command("migrateDown", { ...options });
+}
+
+export function migrationStatus(options: CommandOptions = {}): Promise<MigrationStatus> {
+  return command("migrationStatus", { ...options });
+}
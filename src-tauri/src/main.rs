@@ -4,6 +4,7 @@
 
 // main.rs
 
+#[allow(non_snake_case)]
 mod mongodbApi;
 
 #[tauri::command]
@@ -13,7 +14,7 @@ fn greet(name: &str) -> String {
 
 fn main() {
     tauri::Builder::default()
-        .plugin(mongodbApi::MongoPlugin)
+        .plugin(mongodbApi::MongoPlugin::new())
         .invoke_handler(tauri::generate_handler![greet])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
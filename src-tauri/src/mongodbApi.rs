@@ -1,18 +1,286 @@
-use mongodb::{Client, Collection};
+mod admin;
+mod advisor;
+mod archive;
+mod audit;
+mod batch;
+mod bulk;
+mod bulk_write;
+mod changes;
+mod client_options;
+mod clone;
+mod compression;
+mod concerns;
+mod confirmations;
+mod connections;
+mod connectivity;
+mod convert;
+mod counts;
+mod csfle;
+mod cursors;
+mod defaults;
+mod diff;
+mod dry_run;
+mod dump;
+mod duplicates;
+mod encryption;
+pub mod errors;
+mod events;
+mod explain;
+mod export;
+mod gridfs;
+mod guards;
+mod health;
+mod history;
+pub mod ids;
+mod indexes;
+pub mod interceptors;
+pub mod jobs;
+pub mod leader;
+mod locks;
+mod merge;
+pub mod migrations;
+mod namespaces;
+mod offline;
+mod operations;
+pub mod pipelines;
+pub mod queries;
+mod query_stats;
+pub mod policy;
+mod read_options;
+mod references;
+mod replset;
+mod responses;
+pub mod results;
+pub mod retry;
+mod runtime;
+mod saved;
+mod schema;
+mod schema_sync;
+mod search;
+mod series;
+mod sessions;
+mod shutdown;
+mod signing;
+mod softdelete;
+mod stream;
+mod tenancy;
+mod timeouts;
+mod timestamps;
+mod topology;
+mod trash;
+pub mod typed;
+mod versioning;
+mod watch;
+mod xlsx;
+
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
+use mongodb::options::{
+    AggregateOptions, ClientOptions, DriverInfo, FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReplaceOptions, ReturnDocument,
+    UpdateOptions,
+};
+use mongodb::results::{DeleteResult, UpdateResult};
+use mongodb::{Client, ClientSession, Database};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tauri::plugin::Plugin;
-use tauri::Result;
+use tauri::{AppHandle, Invoke, InvokeError, InvokeResolver, Manager, RunEvent, Runtime, WindowEvent};
+
+use errors::MongoPluginError;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 struct DBInfo {
     server: String,
     database: String,
+    #[serde(rename = "connectionId", skip_serializing_if = "Option::is_none")]
+    connection_id: Option<String>,
+    /// How the server logs and profiler name this client. An `appName`
+    /// in the URI is used when this isn't set, and the app's own name and
+    /// version when neither is.
+    #[serde(rename = "appName", skip_serializing_if = "Option::is_none")]
+    app_name: Option<String>,
+    /// Added to the driver's handshake metadata, `tauri` and its version by default.
+    #[serde(rename = "driverInfo", skip_serializing_if = "Option::is_none")]
+    driver_info: Option<DriverMetadata>,
+    /// TLS, authentication, timeout and pool settings over the URI's.
+    #[serde(flatten)]
+    options: client_options::ConnectionOptions,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct DriverMetadata {
+    name: String,
+    version: Option<String>,
+    platform: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct FindArgs {
     collection: String,
     query: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::FindCommandOptions,
+}
+
+#[derive(Deserialize)]
+struct ExistsArgs {
+    collection: String,
+    filter: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FindFieldValueArgs {
+    collection: String,
+    filter: String,
+    field_path: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct DistinctArgs {
+    collection: String,
+    /// Dotted path of the field whose values are listed.
+    field: String,
+    filter: Option<String>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::DistinctCommandOptions,
+}
+
+#[derive(Deserialize)]
+struct FindByIdArgs {
+    collection: String,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FindByIdsArgs {
+    collection: String,
+    ids: Vec<JsonValue>,
+    projection: Option<Document>,
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[derive(Deserialize)]
+struct UpdateByIdArgs {
+    collection: String,
+    id: JsonValue,
+    update: String,
+    upsert: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DeleteByIdArgs {
+    collection: String,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeleteByIdsArgs {
+    collection: String,
+    ids: Vec<JsonValue>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+struct UpdateArgs {
+    collection: String,
+    filter: String,
+    update: String,
+    upsert: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ReplaceOneArgs {
+    collection: String,
+    filter: String,
+    replacement: String,
+    upsert: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DeleteArgs {
+    collection: String,
+    filter: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+enum Returned {
+    #[default]
+    Before,
+    After,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FindOneAndUpdateArgs {
+    collection: String,
+    filter: String,
+    update: String,
+    upsert: Option<bool>,
+    /// The document as it was before the update, the default, or after it.
+    #[serde(default)]
+    return_document: Returned,
+}
+
+#[derive(Deserialize)]
+struct IncrementFieldArgs {
+    collection: String,
+    filter: String,
+    field: String,
+    amount: Option<JsonValue>,
+    upsert: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct PushToArrayArgs {
+    collection: String,
+    filter: String,
+    field: String,
+    value: String,
+    each: Option<bool>,
+    unique: Option<bool>,
+    many: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct PullFromArrayArgs {
+    collection: String,
+    filter: String,
+    field: String,
+    value: String,
+    each: Option<bool>,
+    many: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpsertManyArgs {
+    collection: String,
+    documents: String,
+    key_fields: Vec<String>,
+    ordered: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -31,104 +299,1915 @@ struct InsertManyArgs {
 struct AggregateArgs {
     collection: String,
     pipeline: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::AggregateCommandOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GridFsCleanupArgs {
+    bucket: Option<String>,
+    /// Only orphans older than this count, so uploads still running are
+    /// left alone. Ten minutes by default.
+    older_than_ms: Option<i64>,
 }
 
-pub struct MongoPlugin;
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateWithVersionArgs {
+    collection: String,
+    filter: String,
+    update: String,
+    version_field: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NoArgs {}
+
+/// Plugin settings, read from the `plugins.mongo` section of `tauri.conf.json`.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MongoConfig {
+    /// Longest aggregation pipeline a command may send.
+    pub max_pipeline_stages: Option<usize>,
+    /// Deepest nesting of objects/arrays allowed in a filter.
+    pub max_filter_depth: Option<usize>,
+    /// Server-side time limit applied to every read, capping any the caller sets.
+    #[serde(rename = "maxTimeMS")]
+    pub max_time_ms: Option<u64>,
+    /// Explain reads first and reject those that would scan a whole collection.
+    pub strict_queries: bool,
+    /// Largest serialized response a command may return over IPC.
+    pub max_response_bytes: Option<usize>,
+    /// What happens to a response over `max_response_bytes`.
+    pub oversized_responses: OversizedResponses,
+    /// Fields encrypted by the plugin itself before they are stored.
+    pub field_encryption: Option<encryption::FieldEncryptionConfig>,
+    /// Fields the driver encrypts automatically, in builds with the `csfle` feature.
+    pub csfle: Option<csfle::CsfleConfig>,
+    /// Collections whose documents carry an HMAC checked on every read.
+    pub document_signing: Option<signing::DocumentSigningConfig>,
+    /// Forward server discovery and monitoring events to the frontend.
+    pub topology_events: bool,
+    /// Keep a persisted, parameterized history of the reads run.
+    pub query_history: Option<history::QueryHistoryConfig>,
+    /// Where `saveQuery` keeps definitions.
+    pub saved_queries: saved::SavedQueriesConfig,
+    /// Collections where `deleteById` only marks documents as deleted.
+    pub soft_delete: Option<softdelete::SoftDeleteConfig>,
+    /// Collections whose writes are recorded in a `<collection>_history` shadow.
+    pub audit_trail: Option<audit::AuditTrailConfig>,
+    /// Collections whose documents keep their prior versions for reverting.
+    pub versioning: Option<versioning::VersioningConfig>,
+    /// Collections whose deleted documents go to the `_trash` recycle bin.
+    pub trash: Option<trash::TrashConfig>,
+    /// How long exiting waits for running commands to finish, 3000 by default.
+    pub exit_grace_ms: Option<u64>,
+    /// Collections `count` may watch to keep its counts cached, 4 by default.
+    pub count_cache_watches: Option<usize>,
+    /// Where `archiveDocuments` keeps archive files.
+    pub archive: archive::ArchiveConfig,
+    /// References `mergeDocuments` points at the merged document.
+    pub merge: merge::MergeConfig,
+    /// Collections referring to documents in others, for `checkReferences`
+    /// and cascading deletes.
+    pub relations: Vec<references::Relation>,
+    /// Where GridFS transfers may read and write local files.
+    pub gridfs: gridfs::GridFsConfig,
+    /// Where collection dumps may be written and restored from.
+    pub dump: dump::DumpConfig,
+    /// Where `exportXlsx` may write workbooks.
+    pub xlsx: xlsx::XlsxConfig,
+    /// Collections whose documents get `createdAt` and `updatedAt` set.
+    pub timestamps: Option<timestamps::TimestampsConfig>,
+    /// How `_id`s are generated for documents inserted without one, per collection.
+    pub id_strategies: HashMap<String, ids::IdStrategy>,
+    /// JSON Schemas per collection whose `default`s fill in inserted documents.
+    pub schemas: HashMap<String, JsonValue>,
+    /// Compress database command responses over a size threshold.
+    pub compression: Option<compression::CompressionConfig>,
+    /// Emit `mongo://slow-query` for commands taking at least this many milliseconds.
+    pub slow_query_ms: Option<u64>,
+    /// Mark or close the cursors open on a collection when it is written to.
+    pub stale_cursors: Option<cursors::StaleCursors>,
+    /// Explain hot query shapes now and then so index suggestions go by the plans the server picks.
+    pub explain_sampling: Option<advisor::ExplainSamplingConfig>,
+    /// Make dropping and emptying collections and databases take a confirmation token.
+    pub confirmations: Option<confirmations::ConfirmationsConfig>,
+    /// Keep writes that fail for want of a connection and send them again later.
+    pub offline_queue: Option<offline::OfflineQueueConfig>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum OversizedResponses {
+    /// Hold array results in the plugin and page them out with `readResultPage`.
+    #[default]
+    Buffer,
+    /// Fail the command with a message suggesting how to narrow it.
+    Error,
+}
+
+/// A client opened by `connectDBServer` and the database it selected.
+struct Connection {
+    info: DBInfo,
+    client: Client,
+    db: Database,
+    topology: Arc<topology::TopologyMonitor>,
+    counts: Arc<counts::CountCache>,
+}
+
+impl Connection {
+    /// The `tenant` database on this connection, or the one it was opened with.
+    fn database(&self, tenant: Option<&str>) -> Database {
+        match tenant {
+            Some(tenant) => self.client.database(tenant),
+            None => self.db.clone(),
+        }
+    }
+}
+
+/// Rewrites applied to documents on their way to and from the server:
+/// encryption of configured fields, signing of configured collections and
+/// the app's own result transformers.
+#[derive(Clone, Default)]
+struct DocumentTransforms {
+    encryption: Option<Arc<encryption::FieldEncryption>>,
+    signing: Option<Arc<signing::DocumentSigning>>,
+    results: Option<Arc<results::ResultTransforms>>,
+}
+
+impl DocumentTransforms {
+    /// Seals, then signs, the documents in a command's arguments, so the
+    /// signature covers what is actually stored.
+    fn prepare(&self, command: &str, payload: &mut JsonValue) -> Result<(), MongoPluginError> {
+        if let Some(encryption) = &self.encryption {
+            encryption.seal_payload(command, payload)?;
+        }
+        if let Some(signing) = &self.signing {
+            signing.sign_payload(command, payload).map_err(|e| MongoPluginError::new(errors::ErrorKind::InvalidArgument, e))?;
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.encryption.is_none() && self.signing.is_none() && self.results.is_none()
+    }
+
+    /// Verifies signatures on a command's result, opens sealed fields, then
+    /// runs the app's transformers on what is left.
+    fn finish(&self, command: &str, collection: Option<&str>, result: &mut JsonValue) {
+        if let (Some(signing), Some(collection)) = (&self.signing, collection) {
+            signing.verify_result(command, collection, result);
+        }
+        if let Some(encryption) = &self.encryption {
+            encryption.open_result(result);
+        }
+        if let (Some(results), Some(collection)) = (&self.results, collection) {
+            results.apply_result(command, collection, result);
+        }
+    }
+}
+
+/// What is kept of the writes to configured collections: audit trail
+/// entries and the prior versions of the documents changed.
+#[derive(Clone, Default)]
+struct WriteTracking {
+    audit: Option<Arc<audit::AuditTrail>>,
+    versioning: Option<Arc<versioning::Versioning>>,
+}
+
+impl WriteTracking {
+    fn prepare(&self, command: &str, payload: &mut JsonValue) {
+        if let Some(audit) = &self.audit {
+            audit.assign_ids(command, payload);
+        }
+    }
+
+    /// The documents a command will touch, if anything is kept of its
+    /// writes to the collection it targets.
+    fn target(&self, command: &str, payload: &JsonValue, soft_field: Option<&str>) -> Option<changes::Target> {
+        let collection = payload.get("collection").and_then(JsonValue::as_str)?;
+        let audited = self.audit.as_ref().is_some_and(|audit| audit.audits(collection));
+        let versioned = self.versioning.as_ref().is_some_and(|versioning| versioning.versions(command, collection));
+        if !audited && !versioned {
+            return None;
+        }
+        changes::target(command, payload, soft_field)
+    }
+
+    async fn record(
+        &self,
+        db: &Database,
+        collection: &str,
+        command: &str,
+        actor: &JsonValue,
+        changes: &[changes::Change],
+        mut session: Option<&mut ClientSession>,
+    ) -> mongodb::error::Result<()> {
+        if self.audit.as_ref().is_some_and(|audit| audit.audits(collection)) {
+            audit::record(db, collection, command, actor, changes, session.as_deref_mut()).await?;
+        }
+        match &self.versioning {
+            Some(versioning) if versioning.versions(command, collection) => versioning.record(db, collection, changes, session).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+struct MongoState {
+    connections: connections::ConnectionManager,
+    config: Arc<MongoConfig>,
+    transforms: DocumentTransforms,
+    events: events::EventSink,
+    query_shapes: Arc<advisor::QueryShapes>,
+    query_stats: Arc<query_stats::QueryStats>,
+    history: Option<Arc<history::QueryHistory>>,
+    soft_delete: Option<Arc<softdelete::SoftDelete>>,
+    tracking: WriteTracking,
+    trash: Option<Arc<trash::Trash>>,
+    confirmations: Option<Arc<confirmations::Confirmations>>,
+    offline_queue: Option<Arc<offline::OfflineQueue>>,
+    csfle: Option<Arc<csfle::Csfle>>,
+    ids: Arc<ids::IdStrategies>,
+    streams: Arc<stream::Streams>,
+    cursors: Arc<cursors::Cursors>,
+    sessions: Arc<sessions::Sessions>,
+    operations: Arc<operations::Operations>,
+    migrations: Arc<migrations::Migrations>,
+    retry: Option<Arc<retry::RetryPolicy>>,
+    runtime: Arc<runtime::DbRuntime>,
+    archive_dir: Option<Arc<PathBuf>>,
+}
+
+/// What a database command runs against.
+#[derive(Clone)]
+struct CommandContext {
+    client: Client,
+    db: Database,
+    config: Arc<MongoConfig>,
+    transforms: DocumentTransforms,
+    events: events::EventSink,
+    query_shapes: Arc<advisor::QueryShapes>,
+    query_stats: Arc<query_stats::QueryStats>,
+    history: Option<Arc<history::QueryHistory>>,
+    soft_delete: Option<Arc<softdelete::SoftDelete>>,
+    tracking: WriteTracking,
+    trash: Option<Arc<trash::Trash>>,
+    confirmations: Option<Arc<confirmations::Confirmations>>,
+    offline_queue: Option<Arc<offline::OfflineQueue>>,
+    csfle: Option<Arc<csfle::Csfle>>,
+    ids: Arc<ids::IdStrategies>,
+    streams: Arc<stream::Streams>,
+    cursors: Arc<cursors::Cursors>,
+    sessions: Arc<sessions::Sessions>,
+    operations: Arc<operations::Operations>,
+    migrations: Arc<migrations::Migrations>,
+    retry: Option<Arc<retry::RetryPolicy>>,
+    archive_dir: Option<Arc<PathBuf>>,
+    topology: Arc<topology::TopologyMonitor>,
+    counts: Arc<counts::CountCache>,
+    /// The connection's history profile, see [`history::profile_id`].
+    profile: String,
+    /// Who the command runs for, see [`audit::actor`].
+    actor: JsonValue,
+    /// The tenant whose database `db` is, if any.
+    tenant: Option<String>,
+}
+
+/// The window and tenant that opened a cursor, stream, buffered result or
+/// operation. Only they can reach it afterwards.
+#[derive(Clone, Default, PartialEq)]
+struct Owner {
+    window: String,
+    tenant: Option<String>,
+}
+
+impl CommandContext {
+    /// Who the things this command opens belong to.
+    fn owner(&self) -> Owner {
+        Owner { window: self.actor["window"].as_str().unwrap_or_default().to_string(), tenant: self.tenant.clone() }
+    }
+}
+
+impl MongoState {
+    /// The context for commands on connection `id`, or the default one, in
+    /// the `tenant` database if there is one.
+    fn context(&self, id: Option<&str>, tenant: Option<&str>) -> Result<CommandContext, MongoPluginError> {
+        let connection = self.connections.get(id)?;
+        Ok(CommandContext {
+            client: connection.client.clone(),
+            db: connection.database(tenant),
+            config: self.config.clone(),
+            transforms: self.transforms.clone(),
+            events: self.events.clone(),
+            query_shapes: self.query_shapes.clone(),
+            query_stats: self.query_stats.clone(),
+            history: self.history.clone(),
+            soft_delete: self.soft_delete.clone(),
+            tracking: self.tracking.clone(),
+            trash: self.trash.clone(),
+            confirmations: self.confirmations.clone(),
+            offline_queue: self.offline_queue.clone(),
+            csfle: self.csfle.clone(),
+            ids: self.ids.clone(),
+            streams: self.streams.clone(),
+            cursors: self.cursors.clone(),
+            sessions: self.sessions.clone(),
+            operations: self.operations.clone(),
+            migrations: self.migrations.clone(),
+            retry: self.retry.clone(),
+            archive_dir: self.archive_dir.clone(),
+            topology: connection.topology.clone(),
+            counts: connection.counts.clone(),
+            profile: history::profile_id(&connection.info),
+            actor: JsonValue::Null,
+            tenant: tenant.map(str::to_string),
+        })
+    }
+
+    fn database_name(&self, id: Option<&str>) -> Option<String> {
+        self.connections.get(id).ok().map(|connection| connection.info.database.clone())
+    }
+
+    fn database(&self, id: Option<&str>, tenant: Option<&str>) -> Result<Database, MongoPluginError> {
+        Ok(self.connections.get(id)?.database(tenant))
+    }
+
+    fn client(&self, id: Option<&str>) -> Result<Client, MongoPluginError> {
+        Ok(self.connections.get(id)?.client.clone())
+    }
+}
+
+#[derive(Default)]
+pub struct MongoPlugin {
+    policy: Option<Box<policy::PolicyFn>>,
+    acl: policy::Acl,
+    tenant: Option<Box<tenancy::TenantFn>>,
+    retry: Option<retry::RetryPolicy>,
+    runtime: Option<runtime::RuntimeChoice>,
+    interceptors: interceptors::Interceptors,
+    id_generators: HashMap<String, Arc<ids::IdFn>>,
+    result_transforms: HashMap<String, Vec<Arc<results::ResultFn>>>,
+    migrations: Vec<migrations::Migration>,
+}
+
+#[allow(dead_code)]
+impl MongoPlugin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The same as [`new`](Self::new), reading better before a chain of
+    /// `allow_*` and `deny_commands` calls.
+    pub fn builder() -> Self {
+        Self::default()
+    }
+
+    /// Only lets commands reach these databases, for every window and
+    /// whatever the [`policy`](Self::policy) grants. `connectDBServer` is
+    /// refused for any other.
+    pub fn allow_databases<S: AsRef<str>>(mut self, databases: &[S]) -> Self {
+        self.acl.databases.get_or_insert_with(Vec::new).extend(databases.iter().map(|db| db.as_ref().to_string()));
+        self
+    }
+
+    /// Only lets commands reach these collections, in any allowed database.
+    pub fn allow_collections<S: AsRef<str>>(mut self, collections: &[S]) -> Self {
+        self.acl.collections.get_or_insert_with(Vec::new).extend(collections.iter().map(|coll| coll.as_ref().to_string()));
+        self
+    }
+
+    /// Only lets these commands run, by invoke name.
+    pub fn allow_commands<S: AsRef<str>>(mut self, commands: &[S]) -> Self {
+        self.acl.commands.get_or_insert_with(Vec::new).extend(commands.iter().map(|command| command.as_ref().to_string()));
+        self
+    }
+
+    /// Never lets these commands run, e.g. `dropDatabase`.
+    pub fn deny_commands<S: AsRef<str>>(mut self, commands: &[S]) -> Self {
+        self.acl.denied.extend(commands.iter().map(|command| command.as_ref().to_string()));
+        self
+    }
+
+    /// Decides on every invoke what the calling window may run, given its
+    /// label and the role set with [`policy::set_app_role`].
+    pub fn policy<F>(mut self, policy: F) -> Self
+    where
+        F: Fn(&str, Option<&str>) -> policy::Permissions + Send + Sync + 'static,
+    {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Runs each window's commands in its tenant's database, named by
+    /// `tenant` from the window's label and the app role, see [`tenancy`].
+    pub fn tenant<F>(mut self, tenant: F) -> Self
+    where
+        F: Fn(&str, Option<&str>) -> Option<String> + Send + Sync + 'static,
+    {
+        self.tenant = Some(Box::new(tenant));
+        self
+    }
+
+    /// Retries commands that fail for transient reasons, see [`retry`].
+    pub fn retry(mut self, retry: retry::RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Runs database work on a runtime of its own with `threads` worker
+    /// threads, so heavy queries don't hold up the rest of the app's async
+    /// work, see [`runtime`].
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.runtime = Some(runtime::RuntimeChoice::Threads(threads));
+        self
+    }
+
+    /// Runs database work on a runtime the app already has.
+    pub fn runtime(mut self, handle: tokio::runtime::Handle) -> Self {
+        self.runtime = Some(runtime::RuntimeChoice::Handle(handle));
+        self
+    }
+
+    /// Generates the `_id`s of documents inserted into `collection` without
+    /// one, in place of any `idStrategies` entry for it, see [`ids`].
+    pub fn id_generator<F>(mut self, collection: &str, generate: F) -> Self
+    where
+        F: Fn() -> Bson + Send + Sync + 'static,
+    {
+        self.id_generators.insert(collection.to_string(), Arc::new(generate));
+        self
+    }
+
+    /// Runs `hook` on every invoke's payload before the command, letting it
+    /// change the payload or reject the command, see [`interceptors`].
+    pub fn before_command<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&interceptors::Invocation, &mut JsonValue) -> Result<(), MongoPluginError> + Send + Sync + 'static,
+    {
+        self.interceptors.before.push(Box::new(hook));
+        self
+    }
+
+    /// Runs `transform` on each document of `collection` that reads return,
+    /// see [`results`].
+    pub fn transform_results<F>(mut self, collection: &str, transform: F) -> Self
+    where
+        F: Fn(&mut serde_json::Map<String, JsonValue>) + Send + Sync + 'static,
+    {
+        self.result_transforms.entry(collection.to_string()).or_default().push(Arc::new(transform));
+        self
+    }
+
+    /// Registers a migration for `migrateUp` and `migrateDown`, in place of
+    /// any registered with the same version, see [`migrations`].
+    pub fn migration(mut self, migration: migrations::Migration) -> Self {
+        self.migrations.retain(|registered| registered.version() != migration.version());
+        self.migrations.push(migration);
+        self
+    }
+
+    /// Runs `hook` on every command's result before it goes back to the
+    /// window.
+    pub fn after_command<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&interceptors::Invocation, &mut JsonValue) -> Result<(), MongoPluginError> + Send + Sync + 'static,
+    {
+        self.interceptors.after.push(Arc::new(hook));
+        self
+    }
+}
 
-use std::process::Command;
-use tauri::Runtime;
 impl<R: Runtime> Plugin<R> for MongoPlugin {
     fn name(&self) -> &'static str {
         "mongo"
     }
 
-fn extend_api(&mut self, message: Command<'_, R>) {
-        tauri::generate_handler!(message, 
-            "connectDBServer" => |_ctx, payload: DBInfo| async move {
-                let client = match Client::with_uri_str(&payload.server) {
-                    Ok(client) => client,
-                    Err(e) => {
-                        return Err(format!("Failed to connect: {}", e));
-                    }
-                };
-                let db = client.database(&payload.database);
-                Ok(db)
-            },
-
-            "accessDB" => |_ctx, db| async move {
-                Ok(db)
-            },
-
-            "find" => |_ctx, db, args: FindArgs| async move {
-                let coll = db.collection(&args.collection);
-                let query = match serde_json::from_str(&args.query) {
-                    Ok(query) => query,
-                    Err(e) => return Err(format!("Failed to parse query: {}", e)),
-                };
-                let cursor = match coll.find(query, None).await {
-                    Ok(cursor) => cursor,
-                    Err(e) => return Err(format!("Failed to execute query: {}", e)),
-                };
-                let results = match cursor.into_vec().await {
-                    Ok(results) => results,
-                    Err(e) => return Err(format!("Failed to read results: {}", e)),
-                };
-                Ok(serde_json::to_value(results).unwrap())
-            },
-
-            "findOne" => |_ctx, db, args: FindArgs| async move {
-                let coll = db.collection(&args.collection);
-                let query = match serde_json::from_str(&args.query) {
-                    Ok(query) => query,
-                    Err(e) => return Err(format!("Failed to parse query: {}", e)),
-                };
-                let result = match coll.find_one(query, None).await {
-                    Ok(result) => result,
-                    Err(e) => return Err(format!("Failed to execute query: {}", e)),
-                };
-                Ok(serde_json::to_value(result).unwrap())
-            },
-
-            "insertOne" => |_ctx, db, args: InsertOneArgs| async move {
-                let coll = db.collection(&args.collection);
-                let doc = match serde_json::from_str(&args.data) {
-                    Ok(doc) => doc,
-                    Err(e) => return Err(format!("Failed to parse document: {}", e)),
-                };
-                match coll.insert_one(doc, None).await {
-                    Ok(_) => Ok(serde_json::to_value("success").unwrap()),
-                    Err(e) => Err(format!("Failed to insert document: {}", e)),
+    fn initialize(&mut self, app: &AppHandle<R>, config: JsonValue) -> tauri::plugin::Result<()> {
+        let config: MongoConfig = if config.is_null() { MongoConfig::default() } else { serde_json::from_value(config)? };
+        let app_data_dir = app.path_resolver().app_data_dir();
+        let mut transforms = DocumentTransforms::default();
+        if let Some(settings) = &config.field_encryption {
+            let encryption = encryption::FieldEncryption::load(settings, app_data_dir.clone())?;
+            transforms.encryption = Some(Arc::new(encryption));
+        }
+        if let Some(settings) = &config.document_signing {
+            let app = app.clone();
+            let signing = signing::DocumentSigning::load(settings, app_data_dir.clone(), move |tampered| {
+                let _ = app.emit_all(signing::TAMPERED_EVENT, tampered);
+            })?;
+            transforms.signing = Some(Arc::new(signing));
+        }
+        if !self.result_transforms.is_empty() {
+            transforms.results = Some(Arc::new(results::ResultTransforms::new(std::mem::take(&mut self.result_transforms))));
+        }
+        let archive_dir = encryption::app_data_path(config.archive.directory.as_deref(), archive::DEFAULT_DIRECTORY, app_data_dir.clone());
+        let history = match &config.query_history {
+            Some(settings) => Some(Arc::new(history::QueryHistory::load(settings, app_data_dir.clone())?)),
+            None => None,
+        };
+        let offline_queue = match &config.offline_queue {
+            Some(settings) => Some(Arc::new(offline::OfflineQueue::load(settings, app_data_dir.clone())?)),
+            None => None,
+        };
+        let csfle = match &config.csfle {
+            Some(settings) => Some(Arc::new(csfle::Csfle::load(settings, app_data_dir)?)),
+            None => None,
+        };
+        let soft_delete = config.soft_delete.as_ref().map(|settings| Arc::new(softdelete::SoftDelete::new(settings)));
+        let tracking = WriteTracking {
+            audit: config.audit_trail.as_ref().map(|settings| Arc::new(audit::AuditTrail::new(settings))),
+            versioning: config.versioning.as_ref().map(|settings| Arc::new(versioning::Versioning::new(settings))),
+        };
+        let trash = config.trash.as_ref().map(|settings| Arc::new(trash::Trash::new(settings)));
+        let confirmations = config.confirmations.as_ref().map(|settings| Arc::new(confirmations::Confirmations::new(settings)));
+        let ids = ids::IdStrategies::new(&config.id_strategies, std::mem::take(&mut self.id_generators));
+        let runtime = runtime::DbRuntime::start(self.runtime.take())?;
+        let cursors = Arc::new(cursors::Cursors::new(config.stale_cursors));
+        let query_shapes = Arc::new(advisor::QueryShapes::new(config.explain_sampling.as_ref()));
+        app.manage(MongoState {
+            connections: connections::ConnectionManager::default(),
+            config: Arc::new(config),
+            transforms,
+            events: events::sink(app),
+            query_shapes,
+            query_stats: Arc::default(),
+            history,
+            soft_delete,
+            tracking,
+            trash,
+            confirmations,
+            offline_queue,
+            csfle,
+            ids: Arc::new(ids),
+            streams: Arc::default(),
+            cursors,
+            sessions: Arc::default(),
+            operations: Arc::default(),
+            migrations: Arc::new(migrations::Migrations::new(std::mem::take(&mut self.migrations))),
+            retry: self.retry.take().map(Arc::new),
+            runtime: Arc::new(runtime),
+            archive_dir: archive_dir.ok().map(Arc::new),
+        });
+        app.manage(leader::LeaderState::default());
+        app.manage(responses::ResultBuffers::default());
+        app.manage(policy::AppRole::default());
+        app.manage(pipelines::Pipelines::default());
+        app.manage(queries::Queries::default());
+        app.manage(watch::Watches::default());
+        Ok(())
+    }
+
+    fn on_event(&mut self, app: &AppHandle<R>, event: &RunEvent) {
+        match event {
+            RunEvent::ExitRequested { api, .. } if shutdown::exit_requested(app) => api.prevent_exit(),
+            RunEvent::WindowEvent { label, event: WindowEvent::Destroyed, .. } => {
+                app.state::<watch::Watches>().close_window(label);
+            }
+            _ => {}
+        }
+    }
+
+    fn extend_api(&mut self, invoke: Invoke<R>) {
+        let Invoke { message, resolver } = invoke;
+        let app = message.window().app_handle();
+        let mut payload = message.payload().clone();
+        convert::encode_arguments(message.command(), &mut payload);
+
+        let runtime = app.state::<MongoState>().runtime.clone();
+        let role = policy::app_role(&app);
+        let actor = audit::actor(message.window().label(), role.as_deref());
+        let granted = self.policy.as_ref().map(|policy| policy(message.window().label(), role.as_deref()));
+        let permissions = match (granted, self.acl.permissions()) {
+            (Some(granted), Some(acl)) => Some(granted.within(Arc::new(acl))),
+            (granted, acl) => granted.or(acl),
+        };
+        let tenant = match tenancy::resolve(self.tenant.as_deref(), message.window().label(), role.as_deref(), message.command()) {
+            Ok(tenant) => tenant,
+            Err(e) => return resolver.reject(e),
+        };
+        let invocation = interceptors::Invocation {
+            command: message.command().to_string(),
+            window: message.window().label().to_string(),
+            role: role.clone(),
+            tenant: tenant.clone(),
+        };
+        if let Err(e) = self.interceptors.before(&invocation, &mut payload) {
+            return resolver.reject(e);
+        }
+        let after = self.interceptors.after(invocation);
+        let owner = Owner { window: message.window().label().to_string(), tenant: tenant.clone() };
+        let outgoing = Outgoing { after, app: app.clone(), owner: owner.clone() };
+        if let Err(e) = tenancy::check_pipeline(tenant.as_deref(), message.command(), &payload) {
+            return resolver.reject(e);
+        }
+        let connection = connections::connection_id(&payload);
+        if let Some(permissions) = &permissions {
+            let database = tenant.clone().or_else(|| app.state::<MongoState>().database_name(connection.as_deref()));
+            if let Err(e) = policy::authorize(permissions, database.as_deref(), message.command(), &payload) {
+                return resolver.reject(e);
+            }
+        }
+
+        match message.command() {
+            "connectDBServer" => respond(resolver, outgoing, payload, move |args| runtime.run(connect_db_server(app, args))),
+            "accessDB" => respond(resolver, outgoing, payload, move |_: NoArgs| access_db(app, connection, tenant)),
+            "listConnections" => respond(resolver, outgoing, payload, move |args| connections::list_connections(app, args)),
+            "closeConnection" => respond(resolver, outgoing, payload, move |args| runtime.run(connections::close_connection(app, args))),
+            "startLeaderElection" => respond(resolver, outgoing, payload, move |args| leader::start_leader_election(app, args)),
+            "stopLeaderElection" => respond(resolver, outgoing, payload, move |args| leader::stop_leader_election(app, args)),
+            "isLeader" => respond(resolver, outgoing, payload, move |args| leader::is_leader_command(app, args)),
+            "getQueryHistory" => respond(resolver, outgoing, payload, move |args| history::get_query_history(app, connection, args)),
+            "clearQueryHistory" => respond(resolver, outgoing, payload, move |args| history::clear_query_history(app, connection, args)),
+            "saveQuery" => respond(resolver, outgoing, payload, move |args| saved::save_query(app, connection, tenant, args)),
+            "listSavedQueries" => respond(resolver, outgoing, payload, move |_: NoArgs| saved::list_saved_queries(app, connection, tenant)),
+            "deleteSavedQuery" => respond(resolver, outgoing, payload, move |args| saved::delete_saved_query(app, connection, tenant, args)),
+            "runSavedQuery" => respond(resolver, outgoing, payload, move |args| {
+                runtime.run(saved::run_saved_query(app, permissions, actor, connection, tenant, args))
+            }),
+            "runPipeline" => respond(resolver, outgoing, payload, move |args| {
+                runtime.run(pipelines::run_pipeline(app, permissions, actor, connection, tenant, args))
+            }),
+            "listPipelines" => respond(resolver, outgoing, payload, move |args| pipelines::list_pipelines(app, args)),
+            "runQuery" => respond(resolver, outgoing, payload, move |args| {
+                runtime.run(queries::run_query(app, permissions, actor, connection, tenant, args))
+            }),
+            "listQueries" => respond(resolver, outgoing, payload, move |args| queries::list_queries(app, args)),
+            "listDatabases" => {
+                respond(resolver, outgoing, payload, move |args| runtime.run(admin::list_databases(app, permissions, connection, tenant, args)))
+            }
+            "getQueryStats" => respond(resolver, outgoing, payload, move |args| query_stats::get_query_stats(app, connection, tenant, args)),
+            "resetQueryStats" => respond(resolver, outgoing, payload, move |args| query_stats::reset_query_stats(app, args)),
+            "globalSearch" => respond(resolver, outgoing, payload, move |args| {
+                runtime.run(search::global_search(app, permissions, connection, tenant, args))
+            }),
+            "diffDocuments" => respond(resolver, outgoing, payload, diff::diff_documents),
+            "deleteExportFile" => respond(resolver, outgoing, payload, export::delete_export_file),
+            "ackStreamBatch" => respond(resolver, outgoing, payload, move |args| stream::ack_stream_batch(app, owner, args)),
+            "cancelStream" => respond(resolver, outgoing, payload, move |args| stream::cancel_stream(app, owner, args)),
+            "cursorNext" => respond(resolver, outgoing, payload, move |args| cursors::cursor_next(app, owner, args)),
+            "cursorClose" => respond(resolver, outgoing, payload, move |args| cursors::cursor_close(app, owner, args)),
+            "getOperationStatus" => respond(resolver, outgoing, payload, move |args| operations::get_operation_status(app, owner, args)),
+            "listOperations" => respond(resolver, outgoing, payload, move |args| operations::list_operations(app, owner, args)),
+            "pauseOperation" => respond(resolver, outgoing, payload, move |args| operations::pause_operation(app, owner, args)),
+            "resumeOperation" => respond(resolver, outgoing, payload, move |args| operations::resume_operation(app, owner, args)),
+            "cancelOperation" => respond(resolver, outgoing, payload, move |args| operations::cancel_operation(app, owner, args)),
+            "getTopology" => respond(resolver, outgoing, payload, move |args| topology::get_topology(app, connection, args)),
+            "replicaSetHealth" => {
+                respond(resolver, outgoing, payload, move |args| runtime.run(replset::replica_set_health(app, connection, args)))
+            }
+            "watch" => {
+                let window = message.window().label().to_string();
+                respond(resolver, outgoing, payload, move |args| runtime.run(watch::watch(app, window, connection, tenant, args)))
+            }
+            "unwatch" => respond(resolver, outgoing, payload, move |args| watch::unwatch(app, args)),
+            "readResultPage" => respond(resolver, outgoing, payload, move |args| responses::read_result_page(app, owner, args)),
+            "releaseResult" => respond(resolver, outgoing, payload, move |args| responses::release_result(app, owner, args)),
+            "executeTransactionalBatch" => respond(resolver, outgoing, payload, move |args| {
+                runtime.run(batch::execute_transactional_batch(app, actor, connection, tenant, args))
+            }),
+            command => with_db(resolver, outgoing, actor, tenant, command, payload),
+        }
+    }
+}
+
+/// What a result goes through on its way back to the frontend, once its
+/// command completes: the `after` hooks, the Extended JSON the payload's
+/// `extendedJson` asks for, the `maxResponseBytes` check and the configured
+/// compression, in that order.
+struct Outgoing<R: Runtime> {
+    after: interceptors::After,
+    app: AppHandle<R>,
+    owner: Owner,
+}
+
+impl<R: Runtime> Outgoing<R> {
+    async fn finish(self, mut result: JsonValue, extended_json: convert::ExtendedJson) -> Result<JsonValue, InvokeError> {
+        self.after.apply(&mut result).map_err(InvokeError::from)?;
+        let result = convert::extjson_result(result, extended_json).await.map_err(MongoPluginError::from)?;
+        let config = self.app.state::<MongoState>().config.clone();
+        let result = match config.max_response_bytes {
+            Some(max_bytes) => {
+                let (app, owner, mode) = (self.app, self.owner, config.oversized_responses);
+                let enforced = convert::offload(convert::is_large(&result), move || responses::enforce(&app, owner, max_bytes, mode, result));
+                enforced.await.map_err(MongoPluginError::from).and_then(|enforced| enforced)?
+            }
+            None => result,
+        };
+        if config.compression.is_none() {
+            return Ok(result);
+        }
+        let compressed = convert::offload(convert::is_large(&result), move || compression::compress(config.compression.as_ref().unwrap(), result));
+        compressed.await.and_then(|compressed| compressed).map_err(|e| InvokeError::from(MongoPluginError::from(e)))
+    }
+}
+
+/// Parses the invoke payload into the handler's argument struct and replies
+/// with the handler's result once it completes, through `outgoing`.
+fn respond<R, A, E, F, Fut>(resolver: InvokeResolver<R>, outgoing: Outgoing<R>, payload: JsonValue, handler: F)
+where
+    R: Runtime,
+    A: DeserializeOwned,
+    E: Serialize,
+    F: FnOnce(A) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<JsonValue, E>> + Send + 'static,
+{
+    let extended_json = match convert::ExtendedJson::of(&payload) {
+        Ok(extended_json) => extended_json,
+        Err(e) => return resolver.reject(MongoPluginError::new(errors::ErrorKind::InvalidArgument, e)),
+    };
+    resolver.respond_async(async move {
+        let args = match serde_json::from_value(payload) {
+            Ok(args) => args,
+            Err(e) => return Err(InvokeError::from(errors::invalid("Failed to parse arguments", e))),
+        };
+        let result = handler(args).await.map_err(InvokeError::from)?;
+        outgoing.finish(result, extended_json).await
+    });
+}
+
+/// Runs a database command against the connected database, or the tenant's,
+/// replying with "Unknown command" for names [`execute`] does not know.
+/// Results go back through `outgoing`.
+fn with_db<R: Runtime>(
+    resolver: InvokeResolver<R>,
+    outgoing: Outgoing<R>,
+    actor: JsonValue,
+    tenant: Option<String>,
+    command: &str,
+    payload: JsonValue,
+) {
+    let app = outgoing.app.clone();
+    let mut ctx = match app.state::<MongoState>().context(connections::connection_id(&payload).as_deref(), tenant.as_deref()) {
+        Ok(ctx) => ctx,
+        Err(e) => return resolver.reject(e),
+    };
+    let extended_json = match convert::ExtendedJson::of(&payload) {
+        Ok(extended_json) => extended_json,
+        Err(e) => return resolver.reject(MongoPluginError::new(errors::ErrorKind::InvalidArgument, e)),
+    };
+    ctx.actor = actor;
+    let queue = ctx.offline_queue.clone().filter(|_| offline::queues(command, &payload)).map(|queue| (queue, payload.clone()));
+    match execute(&ctx, command, payload) {
+        Some(task) => {
+            let task = match queue {
+                Some((queue, payload)) => queue.on_failure(&ctx, command, payload, task),
+                None => task,
+            };
+            let task = app.state::<MongoState>().runtime.run(task);
+            resolver.respond_async(async move {
+                let result = task.await.map_err(|e| InvokeError::from(errors::from_json(e)))?;
+                outgoing.finish(result, extended_json).await
+            })
+        }
+        None => resolver.reject(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown command: {}", command))),
+    }
+}
+
+type CommandFuture = BoxFuture<'static, Result<JsonValue, JsonValue>>;
+
+/// A command's arguments made ready to run, see [`prepare`].
+struct Prepared {
+    limits: timeouts::Limits,
+    /// The strict-mode check for collection scans, to pass before it runs.
+    precheck: Option<BoxFuture<'static, Result<(), MongoPluginError>>>,
+}
+
+/// The rewrites and checks a command's arguments go through before it runs,
+/// the same whether it runs on its own, as a batch step or inside a
+/// transaction.
+fn prepare(ctx: &CommandContext, command: &str, payload: &mut JsonValue) -> Result<Prepared, MongoPluginError> {
+    let limits = timeouts::prepare(command, payload);
+    guards::apply_limits(&ctx.config, command, payload)?;
+    if let Some(soft_delete) = &ctx.soft_delete {
+        soft_delete.scope_reads(command, payload);
+    }
+    defaults::apply(&ctx.config, command, payload);
+    ctx.ids.assign(command, payload);
+    timestamps::stamp(&ctx.config, command, payload);
+    ctx.tracking.prepare(command, payload);
+    bulk_write::check_kept(ctx, command, payload)?;
+    ctx.transforms.prepare(command, payload)?;
+    let precheck: Option<BoxFuture<'static, Result<(), MongoPluginError>>> = match guards::needs_explain(&ctx.config, command) {
+        true => Some(Box::pin(guards::reject_collection_scans(ctx.db.clone(), command.to_string(), payload.clone()))),
+        false => None,
+    };
+    Ok(Prepared { limits, precheck })
+}
+
+/// The table of commands that only need a database. `extend_api` and
+/// `executeBatch` both dispatch through it, so a batched step takes exactly
+/// the arguments the standalone command does and passes the same guards.
+fn execute(ctx: &CommandContext, command: &str, mut payload: JsonValue) -> Option<CommandFuture> {
+    convert::encode_arguments(command, &mut payload);
+    let overridden;
+    let ctx = match concerns::Concerns::of(&payload).and_then(|concerns| concerns.map(|concerns| concerns.database(&ctx.client, ctx.db.name())).transpose()) {
+        Ok(Some(db)) => {
+            overridden = CommandContext { db, ..ctx.clone() };
+            &overridden
+        }
+        Ok(None) => ctx,
+        Err(e) => return Some(Box::pin(async move { Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, e).into()) })),
+    };
+    let Prepared { limits, precheck } = match prepare(ctx, command, &mut payload) {
+        Ok(prepared) => prepared,
+        Err(e) => return Some(Box::pin(async move { Err(e.into()) })),
+    };
+    ctx.query_shapes.record(&ctx.db, command, &payload);
+    let stat = ctx.query_stats.key(ctx.db.name(), command, &payload).map(|key| (ctx.query_stats.clone(), key));
+    let slow = ctx.config.slow_query_ms.map(|threshold| (threshold, payload.clone()));
+    let recorded = ctx.history.clone().map(|history| (history, ctx.profile.clone(), payload.clone()));
+
+    let collection = payload.get("collection").and_then(JsonValue::as_str).map(str::to_string);
+    let soft_field = ctx.soft_delete.as_ref().and_then(|soft_delete| soft_delete.field_for(&payload)).map(str::to_string);
+    if dry_run::requested(command, &payload) {
+        return Some(dry_run::preview(ctx, command, payload, soft_field));
+    }
+    if let Some(challenge) = confirmations::check(ctx, command, &payload) {
+        return Some(challenge);
+    }
+    let session = sessions::session_id(&payload);
+    // Commands on a session record their changes on the session themselves.
+    let tracked = match (&collection, &session) {
+        (Some(collection), None) => ctx.tracking.target(command, &payload, soft_field.as_deref()).map(|target| (target, collection.clone())),
+        _ => None,
+    };
+    let cascade = match (&collection, &session) {
+        (Some(collection), None) if references::cascades(ctx, command, collection) => changes::target(command, &payload, soft_field.as_deref())
+            .map(|target| (target, collection.clone(), soft_field.clone())),
+        _ => None,
+    };
+    let retry = ctx.retry.as_ref().and_then(|retry| retry.for_command(command, &payload)).cloned();
+    let task = match (session.clone(), retry) {
+        // Never retried, as they may be part of a transaction.
+        (Some(id), _) => sessions::run(ctx, command, payload, soft_field, id),
+        (None, Some(retry)) => {
+            let first = dispatch(ctx, command, payload.clone(), soft_field.clone())?;
+            let (ctx, command) = (ctx.clone(), command.to_string());
+            let mut first = Some(first);
+            Box::pin(async move {
+                let attempt = || first.take().unwrap_or_else(|| dispatch(&ctx, &command, payload.clone(), soft_field.clone()).unwrap());
+                retry.run(attempt).await
+            })
+        }
+        // Bulk updates, archive moves and imports wait out elections batch
+        // by batch themselves, and a bulk write can't be sent again once
+        // some of its batches went through.
+        (None, None)
+            if events::write_operation(command).is_some()
+                && !matches!(command, "updateManyWithProgress" | "archiveDocuments" | "unarchive" | "importCollection" | "bulkWrite") =>
+        {
+            let first = dispatch(ctx, command, payload.clone(), soft_field.clone())?;
+            failover_task(ctx, command, payload, soft_field, first)
+        }
+        (None, None) => dispatch(ctx, command, payload, soft_field)?,
+    };
+    let task = match tracked {
+        Some((target, collection)) => tracked_task(ctx, command, collection, target, task),
+        None => task,
+    };
+    let task = match cascade {
+        Some((target, collection, soft_field)) => references::cascade_task(ctx, command, collection, target, soft_field, task),
+        None => task,
+    };
+    let transforms = ctx.transforms.clone();
+    let emit = ctx.events.clone();
+    let database = ctx.db.name().to_string();
+    let counts = ctx.counts.clone();
+    let cursors = ctx.cursors.clone();
+    let command = command.to_string();
+    let task: CommandFuture = Box::pin(async move {
+        if let Some(precheck) = precheck {
+            precheck.await?;
+        }
+        let started = Instant::now();
+        let outcome = task.await;
+        let duration_ms = started.elapsed().as_millis() as u64;
+        if let Some((_, payload)) = slow.filter(|(threshold, _)| duration_ms >= *threshold) {
+            let key = stat.as_ref().map(|(_, key)| key);
+            emit(query_stats::SLOW_QUERY_EVENT, query_stats::slow_query_event(&database, &command, &payload, key, duration_ms, outcome.is_ok()));
+        }
+        if let Some((history, profile, payload)) = recorded {
+            history.record(&profile, &command, &payload, duration_ms, outcome.is_ok());
+        }
+        if let Some((stats, key)) = stat {
+            stats.record(key, duration_ms, outcome.is_ok());
+        }
+        let mut result = outcome?;
+        if !transforms.is_empty() {
+            let (command, collection) = (command.clone(), collection.clone());
+            result = convert::offload(convert::is_large(&result), move || {
+                transforms.finish(&command, collection.as_deref(), &mut result);
+                result
+            })
+            .await?;
+        }
+        // A session's writes send their events themselves.
+        if let (Some(event), None) = (events::write_event(&command, &database, collection.as_deref()), &session) {
+            counts.invalidate(&database, collection.as_deref().unwrap_or_default());
+            cursors.written(&emit, &event);
+            emit(events::WRITE_EVENT, event);
+        }
+        Ok(result)
+    });
+    Some(limits.bound(ctx, task))
+}
+
+/// Builds the future for one attempt at a command, or `None` for names
+/// [`execute`] does not know.
+fn dispatch(ctx: &CommandContext, command: &str, payload: JsonValue, soft_field: Option<String>) -> Option<CommandFuture> {
+    let db = ctx.db.clone();
+    let task = match command {
+        _ if explain::requested(command, &payload) => {
+            let command = command.to_string();
+            Box::pin(async move { Ok(explain::explain_in_place(db, command, payload).await?) })
+        }
+        "find" if export::to_file(&payload) => call(ctx.clone(), payload, export::find_to_file),
+        "find" if stream::to_stream(&payload) => call(ctx.clone(), payload, stream::find_stream),
+        "find" => call(db, payload, find),
+        "findCursor" => call(ctx.clone(), payload, cursors::find_cursor),
+        "startSession" => call(ctx.clone(), payload, sessions::start_session),
+        "startTransaction" => call(ctx.clone(), payload, sessions::start_transaction),
+        "commitTransaction" => call(ctx.clone(), payload, sessions::commit_transaction),
+        "abortTransaction" => call(ctx.clone(), payload, sessions::abort_transaction),
+        "endSession" => call(ctx.clone(), payload, sessions::end_session),
+        "findOne" => call(db, payload, find_one),
+        "insertOne" if ctx.ids.assigns(&payload) => call(db, payload, ids::insert_one),
+        "insertOne" => call(db, payload, insert_one),
+        "insertMany" if ctx.ids.assigns(&payload) => call(db, payload, ids::insert_many),
+        "insertMany" => call(db, payload, insert_many),
+        "exists" => call(db, payload, exists),
+        "count" => call(ctx.clone(), payload, counts::count),
+        "countDocuments" => call(db, payload, counts::count_documents),
+        "estimatedDocumentCount" => {
+            let soft_deletes = soft_field.is_some();
+            call(db, payload, move |db, args| counts::estimated_document_count(db, args, soft_deletes))
+        }
+        "distinct" => call(db, payload, distinct),
+        "findFieldValue" => call(db, payload, find_field_value),
+        "findById" => match soft_field {
+            Some(field) => call(db, payload, move |db, args| softdelete::find_by_id(db, field, args)),
+            None => call(db, payload, find_by_id),
+        },
+        "findByIds" => call(db, payload, move |db, args| find_by_ids(db, soft_field, args)),
+        "updateById" => call(db, payload, update_by_id),
+        "updateManyWithProgress" => call(ctx.clone(), payload, bulk::update_many_with_progress),
+        "getDocumentHistory" => call(ctx.clone(), payload, audit::get_document_history),
+        "getVersions" => call(ctx.clone(), payload, versioning::get_versions),
+        "revertToVersion" => call(ctx.clone(), payload, versioning::revert_to_version),
+        "deleteById" => match soft_field {
+            Some(field) => call(db, payload, move |db, args| softdelete::delete_by_id(db, field, args)),
+            None if ctx.trash.as_ref().is_some_and(|trash| trash.trashes(&payload)) => call(ctx.clone(), payload, trash::delete_by_id),
+            None => call(db, payload, delete_by_id),
+        },
+        "deleteByIds" => call(ctx.clone(), payload, move |ctx, args| delete_by_ids(ctx, soft_field, args)),
+        "updateOne" => call(db, payload, |db, args| update_matching(db, args, false)),
+        "updateMany" => call(db, payload, |db, args| update_matching(db, args, true)),
+        "replaceOne" => call(db, payload, replace_one),
+        "findOneAndUpdate" => call(db, payload, find_one_and_update),
+        "deleteOne" | "deleteMany" => {
+            let many = command == "deleteMany";
+            match soft_field {
+                Some(field) => call(db, payload, move |db, args| softdelete::delete_matching(db, field, args, many)),
+                None if ctx.trash.as_ref().is_some_and(|trash| trash.trashes(&payload)) => {
+                    call(ctx.clone(), payload, move |ctx, args| trash::delete_matching(ctx, args, many))
                 }
-            },
-
-            "insertMany" => |_ctx, db, args: InsertManyArgs| async move {
-                let coll = db.collection(&args.collection);
-                let docs = match serde_json::from_str(&args.data) {
-                    Ok(docs) => docs,
-                    Err(e) => return Err(format!("Failed to parse documents: {}", e)),
-                };
-                match coll.insert_many(docs, None).await {
-                    Ok(_) => Ok(serde_json::to_value("success").unwrap()),
-                    Err(e) => Err(format!("Failed to insert documents: {}", e)),
+                None => call(db, payload, move |db, args| delete_matching(db, args, many)),
+            }
+        }
+        "findOneAndDelete" => match soft_field {
+            Some(field) => call(db, payload, move |db, args| softdelete::find_one_and_delete(db, field, args)),
+            None if ctx.trash.as_ref().is_some_and(|trash| trash.trashes(&payload)) => call(ctx.clone(), payload, trash::find_one_and_delete),
+            None => call(db, payload, find_one_and_delete),
+        },
+        "listTrash" => call(ctx.clone(), payload, trash::list_trash),
+        "restoreFromTrash" => call(ctx.clone(), payload, trash::restore_from_trash),
+        "emptyTrash" => call(ctx.clone(), payload, trash::empty_trash),
+        "archiveDocuments" => call(ctx.clone(), payload, archive::archive_documents),
+        "unarchive" => call(ctx.clone(), payload, archive::unarchive),
+        "restore" => call(ctx.clone(), payload, softdelete::restore),
+        "purge" => call(ctx.clone(), payload, softdelete::purge),
+        "incrementField" => call(db, payload, increment_field),
+        "pushToArray" => call(db, payload, push_to_array),
+        "pullFromArray" => call(db, payload, pull_from_array),
+        "upsertMany" => call(db, payload, upsert_many),
+        "bulkWrite" => call(ctx.clone(), payload, bulk_write::bulk_write),
+        "exportXlsx" => call(ctx.clone(), payload, xlsx::export_xlsx),
+        "aggregate" if export::to_file(&payload) => call(ctx.clone(), payload, export::aggregate_to_file),
+        "aggregate" if stream::to_stream(&payload) => call(ctx.clone(), payload, stream::aggregate_stream),
+        "aggregate" => call(db, payload, aggregate),
+        "aggregateCursor" => call(ctx.clone(), payload, cursors::aggregate_cursor),
+        "timeSeriesAggregate" => call(db, payload, series::time_series_aggregate),
+        "explain" => call(db, payload, explain::explain),
+        "diffWithCurrent" => call(ctx.clone(), payload, diff::diff_with_current),
+        "analyzeCollection" => call(db, payload, schema::analyze_collection),
+        "findDuplicates" => call(db, payload, duplicates::find_duplicates),
+        "mergeDocuments" => call(ctx.clone(), payload, move |ctx, args| merge::merge_documents(ctx, args, soft_field)),
+        "checkReferences" => call(ctx.clone(), payload, references::check_references),
+        "suggestIndexes" => call(ctx.clone(), payload, advisor::suggest_indexes),
+        "listCollections" => call(ctx.clone(), payload, admin::list_collections),
+        "createCollection" => call(ctx.clone(), payload, admin::create_collection),
+        "getViewDefinition" => call(ctx.clone(), payload, admin::get_view_definition),
+        "createView" => call(ctx.clone(), payload, admin::create_view),
+        "modifyView" => call(ctx.clone(), payload, admin::modify_view),
+        "dropCollection" => call(ctx.clone(), payload, admin::drop_collection),
+        "renameCollection" => call(ctx.clone(), payload, admin::rename_collection),
+        "cloneCollection" => call(ctx.clone(), payload, clone::clone_collection),
+        "exportCollection" => call(ctx.clone(), payload, dump::export_collection),
+        "importCollection" => call(ctx.clone(), payload, dump::import_collection),
+        "dropDatabase" => call(ctx.clone(), payload, admin::drop_database),
+        "runCommand" => call(ctx.clone(), payload, admin::run_command),
+        "ping" => call(ctx.clone(), payload, health::ping),
+        "serverStatus" => call(ctx.clone(), payload, health::server_status),
+        "exportSchema" => call(ctx.clone(), payload, schema_sync::export_schema),
+        "applySchema" => call(ctx.clone(), payload, schema_sync::apply_schema),
+        "listQueuedWrites" => call(ctx.clone(), payload, offline::list_queued_writes),
+        #[cfg(feature = "csfle")]
+        "createDataKey" => call(ctx.clone(), payload, csfle::create_data_key),
+        #[cfg(feature = "csfle")]
+        "listDataKeys" => call(ctx.clone(), payload, csfle::list_data_keys),
+        #[cfg(feature = "csfle")]
+        "deleteDataKey" => call(ctx.clone(), payload, csfle::delete_data_key),
+        #[cfg(feature = "csfle")]
+        "addKeyAltName" => call(ctx.clone(), payload, csfle::add_key_alt_name),
+        #[cfg(feature = "csfle")]
+        "removeKeyAltName" => call(ctx.clone(), payload, csfle::remove_key_alt_name),
+        "discardQueuedWrite" => call(ctx.clone(), payload, offline::discard_queued_write),
+        "createIndex" => call(db, payload, indexes::create_index),
+        "createIndexes" => call(db, payload, indexes::create_indexes),
+        "dropIndex" => call(db, payload, indexes::drop_index),
+        "listIndexes" => call(db, payload, indexes::list_indexes),
+        "gridfsUpload" => call(ctx.clone(), payload, gridfs::upload),
+        "gridfsDownload" => call(ctx.clone(), payload, gridfs::download),
+        "gridfsFind" => call(db, payload, gridfs::find),
+        "gridfsDelete" => call(db, payload, gridfs::delete),
+        "gridfsCleanup" => call(db, payload, gridfs_cleanup),
+        "updateWithVersion" => call(db, payload, update_with_version),
+        "acquireLock" => call(db, payload, locks::acquire_lock),
+        "renewLock" => call(db, payload, locks::renew_lock),
+        "releaseLock" => call(db, payload, locks::release_lock),
+        "migrateUp" => call(ctx.clone(), payload, migrations::migrate_up),
+        "migrateDown" => call(ctx.clone(), payload, migrations::migrate_down),
+        "migrationStatus" => call(ctx.clone(), payload, migrations::migration_status),
+        "enqueueJob" => call(db, payload, jobs::enqueue_job),
+        "claimNextJob" => call(db, payload, jobs::claim_next_job),
+        "completeJob" => call(db, payload, jobs::complete_job),
+        "failJob" => call(db, payload, jobs::fail_job),
+        "executeBatch" => call(ctx.clone(), payload, batch::execute_batch),
+        _ => return None,
+    };
+    Some(task)
+}
+
+/// Holds a write back while the replica set elects a new primary, and
+/// sends it once more if it reached a primary that had just stepped down.
+fn failover_task(ctx: &CommandContext, command: &str, payload: JsonValue, soft_field: Option<String>, first: CommandFuture) -> CommandFuture {
+    let (ctx, command) = (ctx.clone(), command.to_string());
+    Box::pin(async move {
+        ctx.topology.wait_for_primary(topology::FAILOVER_WAIT).await;
+        match first.await {
+            Err(e) if retry::unsent_for_election(&e) && ctx.topology.wait_for_primary(topology::FAILOVER_WAIT).await => {
+                dispatch(&ctx, &command, payload, soft_field).unwrap().await
+            }
+            outcome => outcome,
+        }
+    })
+}
+
+/// Wraps a write to a tracked collection so the documents it touches are
+/// read before and after it runs and the changes recorded.
+fn tracked_task(ctx: &CommandContext, command: &str, collection: String, target: changes::Target, task: CommandFuture) -> CommandFuture {
+    let db = ctx.db.clone();
+    let tracking = ctx.tracking.clone();
+    let actor = ctx.actor.clone();
+    let command = command.to_string();
+    Box::pin(async move {
+        let snapshot = match changes::Snapshot::take(&db, &collection, target, None).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Err(errors::failed("Failed to read documents before writing", e).into()),
+        };
+        let result = task.await?;
+        let recorded = match snapshot.changes(&db, &result, None).await {
+            Ok(changes) => tracking.record(&db, &collection, &command, &actor, &changes, None).await,
+            Err(e) => Err(e),
+        };
+        match recorded {
+            Ok(()) => Ok(result),
+            Err(e) => Err(errors::failed("The write succeeded but recording its changes failed", e).into()),
+        }
+    })
+}
+
+/// Parses `payload` into the handler's argument struct and runs it.
+fn call<C, A, E, F, Fut>(ctx: C, payload: JsonValue, handler: F) -> CommandFuture
+where
+    C: Send + 'static,
+    A: DeserializeOwned,
+    E: Serialize,
+    F: FnOnce(C, A) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<JsonValue, E>> + Send + 'static,
+{
+    Box::pin(async move {
+        let args = match serde_json::from_value(payload) {
+            Ok(args) => args,
+            Err(e) => return Err(errors::invalid("Failed to parse arguments", e).into()),
+        };
+        handler(ctx, args).await.map_err(|e| serde_json::to_value(e).unwrap())
+    })
+}
+
+/// Converts an id sent by the frontend into the BSON value stored in `_id`.
+/// 24-digit hex strings become ObjectIds, as do `{ "$oid": ... }` wrappers;
+/// other Extended JSON values and plain scalars are kept as they are.
+fn coerce_id(id: &JsonValue) -> Result<Bson, MongoPluginError> {
+    match id {
+        JsonValue::String(s) => match ObjectId::parse_str(s) {
+            Ok(oid) if s.len() == 24 => Ok(Bson::ObjectId(oid)),
+            _ => Ok(Bson::String(s.clone())),
+        },
+        JsonValue::Null => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Id must not be null")),
+        other => Bson::try_from(other.clone()).map_err(|e| errors::invalid("Failed to parse id", e)),
+    }
+}
+
+/// Looks up a dotted field path such as `stats.views` in a document.
+fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        value = match value {
+            Bson::Document(inner) => inner.get(part)?,
+            Bson::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+fn update_result_json(result: &UpdateResult) -> JsonValue {
+    json!({
+        "matchedCount": result.matched_count,
+        "modifiedCount": result.modified_count,
+        "upsertedId": result.upserted_id.clone().map(convert::to_json),
+    })
+}
+
+fn delete_result_json(result: &DeleteResult) -> JsonValue {
+    json!({ "deletedCount": result.deleted_count })
+}
+
+/// Whether the driver error is an E11000 duplicate key violation.
+fn is_duplicate_key(error: &MongoError) -> bool {
+    const DUPLICATE_KEY: i32 = 11000;
+    match error.kind.as_ref() {
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY,
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+async fn connect_db_server<R: Runtime>(app: AppHandle<R>, payload: DBInfo) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let mut options = match ClientOptions::parse(&payload.server).await {
+        Ok(options) => options,
+        Err(e) => {
+            return Err(errors::failed("Failed to connect", e));
+        }
+    };
+    if let Err(e) = payload.options.apply(&mut options) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, e));
+    }
+    let mut info = payload;
+    let package = app.package_info();
+    let app_name = info.app_name.clone().or(options.app_name.take());
+    options.app_name = Some(app_name.unwrap_or_else(|| format!("{} {}", package.name, package.version)));
+    info.app_name = options.app_name.clone();
+    let driver = info.driver_info.get_or_insert_with(|| DriverMetadata {
+        name: "tauri".to_string(),
+        version: Some(tauri::VERSION.to_string()),
+        platform: None,
+    });
+    let driver = DriverInfo::builder().name(driver.name.clone()).version(driver.version.clone()).platform(driver.platform.clone());
+    options.driver_info = Some(driver.build());
+    let id = info.connection_id.get_or_insert_with(connections::new_id).clone();
+    let topology = Arc::new(topology::TopologyMonitor::new(
+        state.events.clone(),
+        state.config.topology_events,
+        topology::srv_host(&info.server),
+        id.clone(),
+    ));
+    options.sdam_event_handler = Some(topology.clone());
+    let connectivity = Arc::new(connectivity::ConnectionMonitor::new(state.events.clone(), topology.clone()));
+    options.command_event_handler = Some(connectivity.clone());
+    options.cmap_event_handler = Some(connectivity);
+    #[cfg(feature = "csfle")]
+    let client = match &state.csfle {
+        Some(csfle) => csfle.client(options).await,
+        None => Client::with_options(options),
+    };
+    #[cfg(not(feature = "csfle"))]
+    let client = Client::with_options(options);
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            return Err(errors::failed("Failed to connect", e));
+        }
+    };
+    let db = client.database(&info.database);
+    let counts = Arc::new(counts::CountCache::new(state.config.count_cache_watches));
+    state.connections.insert(id.clone(), Connection { info, client, db, topology, counts });
+    // Writes queued on this connection before a restart go out once it's up.
+    if let (Some(queue), Ok(ctx)) = (&state.offline_queue, state.context(Some(&id), None)) {
+        queue.flush(&ctx);
+    }
+    Ok(json!(id))
+}
+
+async fn access_db<R: Runtime>(app: AppHandle<R>, connection: Option<String>, tenant: Option<String>) -> Result<JsonValue, MongoPluginError> {
+    let connection = app.state::<MongoState>().connections.get(connection.as_deref())?;
+    let mut info = serde_json::to_value(&connection.info).unwrap();
+    if let Some(tenant) = tenant {
+        info["database"] = json!(tenant);
+    }
+    Ok(info)
+}
+
+async fn find(db: Database, args: FindArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let query: Document = match convert::from_extjson(&args.query) {
+        Ok(query) => query,
+        Err(e) => return Err(errors::invalid("Failed to parse query", e)),
+    };
+    let cursor = match coll.find(query, args.options.find(args.max_time_ms)).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute query", e)),
+    };
+    match convert::collect_json(cursor).await {
+        Ok(results) => Ok(results),
+        Err(e) => Err(errors::failed("Failed to read results", e)),
+    }
+}
+
+async fn find_one(db: Database, args: FindArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let query: Document = match convert::from_extjson(&args.query) {
+        Ok(query) => query,
+        Err(e) => return Err(errors::invalid("Failed to parse query", e)),
+    };
+    let result = match coll.find_one(query, args.options.find_one(args.max_time_ms)).await {
+        Ok(result) => result,
+        Err(e) => return Err(errors::failed("Failed to execute query", e)),
+    };
+    Ok(convert::to_json(result))
+}
+
+/// Checks for a match without transferring the document: only `_id` is projected.
+async fn exists(db: Database, args: ExistsArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let options = FindOneOptions::builder()
+        .projection(doc! { "_id": 1 })
+        .max_time(args.max_time_ms.map(Duration::from_millis))
+        .build();
+    match coll.find_one(filter, options).await {
+        Ok(result) => Ok(JsonValue::Bool(result.is_some())),
+        Err(e) => Err(errors::failed("Failed to execute query", e)),
+    }
+}
+
+/// Reads one field of the first match, or null when nothing matches or the
+/// field is missing. Only that field is projected, up to the first array
+/// index in the path, so reading a setting doesn't transfer the document.
+async fn find_field_value(db: Database, args: FindFieldValueArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let projected = args.field_path.split('.').take_while(|part| part.parse::<usize>().is_err()).collect::<Vec<_>>().join(".");
+    if projected.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "fieldPath must start with a field name"));
+    }
+    let mut projection = doc! { &projected: 1 };
+    if projected != "_id" && !projected.starts_with("_id.") {
+        projection.insert("_id", 0);
+    }
+    let options = FindOneOptions::builder()
+        .projection(projection)
+        .max_time(args.max_time_ms.map(Duration::from_millis))
+        .build();
+    match coll.find_one(filter, options).await {
+        Ok(Some(doc)) => Ok(convert::to_json(get_path(&doc, &args.field_path).cloned())),
+        Ok(None) => Ok(JsonValue::Null),
+        Err(e) => Err(errors::failed("Failed to execute query", e)),
+    }
+}
+
+/// The distinct values of a field across the matching documents, with
+/// each value of an array field counted separately.
+async fn distinct(db: Database, args: DistinctArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter: Option<Document> = match args.filter.as_deref().map(convert::from_extjson) {
+        Some(Ok(filter)) => Some(filter),
+        Some(Err(e)) => return Err(errors::invalid("Failed to parse filter", e)),
+        None => None,
+    };
+    let options = args.options.distinct(args.max_time_ms);
+    match db.collection::<Document>(&args.collection).distinct(&args.field, filter, options).await {
+        Ok(values) => Ok(convert::to_json(Bson::Array(values))),
+        Err(e) => Err(errors::failed("Failed to execute query", e)),
+    }
+}
+
+async fn find_by_id(db: Database, args: FindByIdArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let id = coerce_id(&args.id)?;
+    match coll.find_one(doc! { "_id": id }, None).await {
+        Ok(result) => Ok(convert::to_json(result)),
+        Err(e) => Err(errors::failed("Failed to execute query", e)),
+    }
+}
+
+/// A key that is the same for ids the server matches to each other, numbers
+/// comparing by value whatever their BSON type.
+fn id_key(id: &Bson) -> String {
+    match id {
+        Bson::Int32(n) => format!("n:{}", f64::from(*n)),
+        Bson::Int64(n) => format!("n:{}", *n as f64),
+        Bson::Double(n) => format!("n:{}", n),
+        other => other.clone().into_canonical_extjson().to_string(),
+    }
+}
+
+/// Finds the documents with the given ids in one `$in` query and returns
+/// them in the order the ids came in, with `null` for ids no document has.
+async fn find_by_ids(db: Database, soft_field: Option<String>, args: FindByIdsArgs) -> Result<JsonValue, MongoPluginError> {
+    let ids = args.ids.iter().map(coerce_id).collect::<Result<Vec<Bson>, String>>()?;
+    let mut filter = doc! { "_id": { "$in": &ids } };
+    if let (Some(field), false) = (&soft_field, args.include_deleted) {
+        filter.insert(field, Bson::Null);
+    }
+    // The documents are put in order by their `_id`, so a projection that
+    // leaves it out only drops it afterwards.
+    let mut projection = args.projection;
+    let hide_id = match projection.as_mut() {
+        Some(projection) if projection.get("_id").is_some_and(|id| matches!(id, Bson::Boolean(false) | Bson::Int32(0) | Bson::Int64(0))) => {
+            projection.remove("_id");
+            true
+        }
+        _ => false,
+    };
+    let options = FindOptions::builder().projection(projection).build();
+    let found: Result<Vec<Document>, MongoError> = match db.collection::<Document>(&args.collection).find(filter, options).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    let found = match found {
+        Ok(found) => found,
+        Err(e) => return Err(errors::failed("Failed to execute query", e)),
+    };
+    let by_id: HashMap<String, Document> = found
+        .into_iter()
+        .filter_map(|mut doc| {
+            let key = id_key(doc.get("_id")?);
+            if hide_id {
+                doc.remove("_id");
+            }
+            Some((key, doc))
+        })
+        .collect();
+    let ordered = ids.iter().map(|id| by_id.get(&id_key(id)).cloned().map_or(Bson::Null, Bson::Document)).collect();
+    Ok(convert::to_json(Bson::Array(ordered)))
+}
+
+async fn update_by_id(db: Database, args: UpdateByIdArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let id = coerce_id(&args.id)?;
+    let update: Document = match convert::from_extjson(&args.update) {
+        Ok(update) => update,
+        Err(e) => return Err(errors::invalid("Failed to parse update", e)),
+    };
+    let options = UpdateOptions::builder().upsert(args.upsert).build();
+    match coll.update_one(doc! { "_id": id }, update, options).await {
+        Ok(result) => Ok(update_result_json(&result)),
+        Err(e) => Err(errors::failed("Failed to update document", e)),
+    }
+}
+
+async fn delete_by_id(db: Database, args: DeleteByIdArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let id = coerce_id(&args.id)?;
+    match coll.delete_one(doc! { "_id": id }, None).await {
+        Ok(result) => Ok(delete_result_json(&result)),
+        Err(e) => Err(errors::failed("Failed to delete document", e)),
+    }
+}
+
+/// Deletes the documents with the given ids in one `$in` delete, run as
+/// `deleteMany` so soft deletes, the trash, cascades, change tracking and
+/// write events apply as they do there, and lists the ids no document had.
+async fn delete_by_ids(ctx: CommandContext, soft_field: Option<String>, args: DeleteByIdsArgs) -> Result<JsonValue, MongoPluginError> {
+    let ids = args.ids.iter().map(coerce_id).collect::<Result<Vec<Bson>, String>>()?;
+    if ids.is_empty() {
+        return Ok(json!({ "deletedCount": 0, "notFound": [] }));
+    }
+    let filter = doc! { "_id": { "$in": &ids } };
+    let live = match &soft_field {
+        Some(field) => doc! { "$and": [filter.clone(), { field: Bson::Null }] },
+        None => filter.clone(),
+    };
+    let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+    let found: Result<Vec<Document>, MongoError> = match ctx.db.collection::<Document>(&args.collection).find(live, options).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    let found: Vec<Bson> = match found {
+        Ok(docs) => docs.into_iter().filter_map(|mut doc| doc.remove("_id")).collect(),
+        Err(e) => return Err(errors::failed("Failed to delete documents", e)),
+    };
+    let not_found: Vec<JsonValue> = args.ids.iter().zip(&ids).filter(|(_, id)| !found.contains(id)).map(|(given, _)| given.clone()).collect();
+    let payload = json!({
+        "collection": args.collection,
+        "filter": convert::to_extjson_text(Bson::Document(filter)),
+        "dryRun": args.dry_run,
+    });
+    let deleted = execute(&ctx, "deleteMany", payload).expect("deleteMany is a command");
+    let mut result = deleted.await.map_err(errors::from_failure)?;
+    if let JsonValue::Object(fields) = &mut result {
+        fields.insert("notFound".to_string(), JsonValue::Array(not_found));
+    }
+    Ok(result)
+}
+
+/// Updates the first document matching the filter or, with `many`, all of them.
+async fn update_matching(db: Database, args: UpdateArgs, many: bool) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let update: Document = match convert::from_extjson(&args.update) {
+        Ok(update) => update,
+        Err(e) => return Err(errors::invalid("Failed to parse update", e)),
+    };
+    let options = UpdateOptions::builder().upsert(args.upsert).build();
+    let result = if many {
+        coll.update_many(filter, update, options).await
+    } else {
+        coll.update_one(filter, update, options).await
+    };
+    match result {
+        Ok(result) => Ok(update_result_json(&result)),
+        Err(e) => Err(errors::failed("Failed to update documents", e)),
+    }
+}
+
+async fn replace_one(db: Database, args: ReplaceOneArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let replacement: Document = match convert::from_extjson(&args.replacement) {
+        Ok(replacement) => replacement,
+        Err(e) => return Err(errors::invalid("Failed to parse replacement", e)),
+    };
+    let options = ReplaceOptions::builder().upsert(args.upsert).build();
+    match coll.replace_one(filter, replacement, options).await {
+        Ok(result) => Ok(update_result_json(&result)),
+        Err(e) => Err(errors::failed("Failed to replace document", e)),
+    }
+}
+
+/// Deletes the first document matching the filter or, with `many`, all of them.
+async fn delete_matching(db: Database, args: DeleteArgs, many: bool) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let result = if many {
+        coll.delete_many(filter, None).await
+    } else {
+        coll.delete_one(filter, None).await
+    };
+    match result {
+        Ok(result) => Ok(delete_result_json(&result)),
+        Err(e) => Err(errors::failed("Failed to delete documents", e)),
+    }
+}
+
+/// Updates the first match and returns it, or null when nothing matched
+/// and nothing was upserted.
+async fn find_one_and_update(db: Database, args: FindOneAndUpdateArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let update: Document = match convert::from_extjson(&args.update) {
+        Ok(update) => update,
+        Err(e) => return Err(errors::invalid("Failed to parse update", e)),
+    };
+    let returned = match args.return_document {
+        Returned::Before => ReturnDocument::Before,
+        Returned::After => ReturnDocument::After,
+    };
+    let options = FindOneAndUpdateOptions::builder().upsert(args.upsert).return_document(returned).build();
+    match coll.find_one_and_update(filter, update, options).await {
+        Ok(result) => Ok(convert::to_json(result)),
+        Err(e) => Err(errors::failed("Failed to update document", e)),
+    }
+}
+
+/// Deletes the first match and returns it, or null when nothing matched.
+async fn find_one_and_delete(db: Database, args: DeleteArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    match coll.find_one_and_delete(filter, None).await {
+        Ok(result) => Ok(convert::to_json(result)),
+        Err(e) => Err(errors::failed("Failed to delete document", e)),
+    }
+}
+
+fn increment_amount(amount: Option<&JsonValue>) -> Result<Bson, MongoPluginError> {
+    match amount {
+        None => Ok(Bson::Int64(1)),
+        Some(JsonValue::Number(n)) => match n.as_i64() {
+            Some(n) => Ok(Bson::Int64(n)),
+            None => Ok(Bson::Double(n.as_f64().unwrap_or_default())),
+        },
+        Some(_) => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Amount must be a number")),
+    }
+}
+
+/// Atomically adds `amount` (default 1) to a numeric field and returns the new value.
+async fn increment_field(db: Database, args: IncrementFieldArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let amount = increment_amount(args.amount.as_ref())?;
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(args.upsert)
+        .projection(doc! { args.field.as_str(): 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+    match coll.find_one_and_update(filter, doc! { "$inc": { args.field.as_str(): amount } }, options).await {
+        Ok(Some(doc)) => Ok(convert::to_json(get_path(&doc, &args.field).cloned())),
+        Ok(None) => Err(MongoPluginError::new(errors::ErrorKind::NotFound, "No document matches the filter")),
+        Err(e) => Err(errors::failed("Failed to increment field", e)),
+    }
+}
+
+/// Runs an array update against one or, with `many`, all matching documents.
+async fn update_array(db: Database, collection: &str, filter: &str, update: Document, many: bool) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(collection);
+    let filter: Document = match convert::from_extjson(filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let result = if many {
+        coll.update_many(filter, update, None).await
+    } else {
+        coll.update_one(filter, update, None).await
+    };
+    match result {
+        Ok(result) => Ok(update_result_json(&result)),
+        Err(e) => Err(errors::failed("Failed to update array", e)),
+    }
+}
+
+fn parse_array_value(value: &str, each: bool) -> Result<Bson, MongoPluginError> {
+    let value: Bson = match convert::from_extjson(value) {
+        Ok(value) => value,
+        Err(e) => return Err(errors::invalid("Failed to parse value", e)),
+    };
+    if each && !matches!(value, Bson::Array(_)) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Value must be an array when each is set"));
+    }
+    Ok(value)
+}
+
+/// Appends `value` to the array, or every element of it with `each`.
+/// `unique` switches to `$addToSet` so existing elements are not duplicated.
+async fn push_to_array(db: Database, args: PushToArrayArgs) -> Result<JsonValue, MongoPluginError> {
+    let each = args.each.unwrap_or(false);
+    let value = parse_array_value(&args.value, each)?;
+    let value = if each { Bson::Document(doc! { "$each": value }) } else { value };
+    let operator = if args.unique.unwrap_or(false) { "$addToSet" } else { "$push" };
+    let update = doc! { operator: { args.field.as_str(): value } };
+    update_array(db, &args.collection, &args.filter, update, args.many.unwrap_or(false)).await
+}
+
+/// Removes elements equal to `value` (or to any element of it with `each`).
+/// A query document such as `{ "$lt": 5 }` removes every element it matches.
+async fn pull_from_array(db: Database, args: PullFromArrayArgs) -> Result<JsonValue, MongoPluginError> {
+    let each = args.each.unwrap_or(false);
+    let value = parse_array_value(&args.value, each)?;
+    let update = if each {
+        doc! { "$pullAll": { args.field.as_str(): value } }
+    } else {
+        doc! { "$pull": { args.field.as_str(): value } }
+    };
+    update_array(db, &args.collection, &args.filter, update, args.many.unwrap_or(false)).await
+}
+
+/// Statements sent per `update` command by `upsertMany`, well under the
+/// server's batch and message size limits for typical documents.
+const UPSERT_MANY_BATCH: usize = 1000;
+
+/// Replaces each document matched on `keyFields`, inserting it when no match
+/// exists. Uses the raw `update` command so inserts and updates can be told
+/// apart per statement.
+async fn upsert_many(db: Database, args: UpsertManyArgs) -> Result<JsonValue, MongoPluginError> {
+    let docs = convert::parse_documents(args.documents, "documents").await?;
+    if args.key_fields.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "keyFields must name at least one field"));
+    }
+    let ordered = args.ordered.unwrap_or(false);
+
+    let mut statements = Vec::with_capacity(docs.len());
+    for (index, doc) in docs.into_iter().enumerate() {
+        let mut filter = Document::new();
+        for key in &args.key_fields {
+            match get_path(&doc, key) {
+                Some(value) => filter.insert(key.as_str(), value.clone()),
+                None => {
+                    let message = format!("Document {} is missing key field '{}'", index, key);
+                    return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
                 }
-            },
-
-            "aggregate" => |_ctx, db, args: AggregateArgs| async move {
-                let coll = db.collection(&args.collection);
-                let pipeline = match serde_json::from_str(&args.pipeline) {
-                    Ok(pipeline) => pipeline,
-                    Err(e) => return Err(format!("Failed to parse pipeline: {}", e)),
-                };
-                let cursor = match coll.aggregate(pipeline, None).await {
-                    Ok(cursor) => cursor,
-                    Err(e) => return Err(format!("Failed to execute aggregation: {}", e)),
-                };
-                let results = match cursor.into_vec().await {
-                    Ok(results) => results,
-                    Err(e) => return Err(format!("Failed to read aggregation results: {}", e)),
-                };
-                Ok(serde_json::to_value(results).unwrap())
+            };
+        }
+        statements.push(doc! { "q": filter, "u": doc, "upsert": true });
+    }
+
+    let mut matched: i64 = 0;
+    let mut modified: i64 = 0;
+    let mut upserted_ids = Vec::new();
+    let mut write_errors = Vec::new();
+    for (batch_index, batch) in statements.chunks(UPSERT_MANY_BATCH).enumerate() {
+        let offset = (batch_index * UPSERT_MANY_BATCH) as i64;
+        let command = doc! { "update": &args.collection, "updates": batch, "ordered": ordered };
+        let reply = match db.run_command(command, None).await {
+            Ok(reply) => reply,
+            Err(e) => return Err(errors::failed("Failed to upsert documents", e)),
+        };
+        let upserted = reply.get_array("upserted").map(|u| u.as_slice()).unwrap_or_default();
+        matched += numeric_field(&reply, "n") - upserted.len() as i64;
+        modified += numeric_field(&reply, "nModified");
+        for item in upserted.iter().filter_map(Bson::as_document) {
+            upserted_ids.push(json!({
+                "index": offset + numeric_field(item, "index"),
+                "_id": item.get("_id").cloned().map(convert::to_json),
+            }));
+        }
+        let errors = reply.get_array("writeErrors").map(|e| e.as_slice()).unwrap_or_default();
+        for error in errors.iter().filter_map(Bson::as_document) {
+            write_errors.push(json!({
+                "index": offset + numeric_field(error, "index"),
+                "code": numeric_field(error, "code"),
+                "message": error.get_str("errmsg").unwrap_or_default(),
+            }));
+        }
+        if ordered && !write_errors.is_empty() {
+            break;
+        }
+    }
+
+    Ok(json!({
+        "insertedCount": upserted_ids.len(),
+        "updatedCount": matched,
+        "modifiedCount": modified,
+        "upsertedIds": upserted_ids,
+        "writeErrors": write_errors,
+    }))
+}
+
+async fn insert_one(db: Database, args: InsertOneArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let doc: Document = match convert::from_extjson(&args.data) {
+        Ok(doc) => doc,
+        Err(e) => return Err(errors::invalid("Failed to parse document", e)),
+    };
+    match coll.insert_one(doc, None).await {
+        Ok(_) => Ok(serde_json::to_value("success").unwrap()),
+        Err(e) => Err(errors::failed("Failed to insert document", e)),
+    }
+}
+
+async fn insert_many(db: Database, args: InsertManyArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let docs = convert::parse_documents(args.data, "documents").await?;
+    match coll.insert_many(docs, None).await {
+        Ok(_) => Ok(serde_json::to_value("success").unwrap()),
+        Err(e) => Err(errors::failed("Failed to insert documents", e)),
+    }
+}
+
+async fn aggregate(db: Database, args: AggregateArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let pipeline: Vec<Document> = match convert::from_extjson(&args.pipeline) {
+        Ok(pipeline) => pipeline,
+        Err(e) => return Err(errors::invalid("Failed to parse pipeline", e)),
+    };
+    let cursor = match coll.aggregate(pipeline, args.options.aggregate(args.max_time_ms)).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute aggregation", e)),
+    };
+    match convert::collect_json(cursor).await {
+        Ok(results) => Ok(results),
+        Err(e) => Err(errors::failed("Failed to read aggregation results", e)),
+    }
+}
+
+/// Applies `update` only if the filter's version field still matches the
+/// stored document, incrementing that field in the same atomic operation.
+async fn update_with_version(db: Database, args: UpdateWithVersionArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let field = args.version_field.unwrap_or_else(|| "_version".to_string());
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let mut update: Document = match convert::from_extjson(&args.update) {
+        Ok(update) => update,
+        Err(e) => return Err(errors::invalid("Failed to parse update", e)),
+    };
+    let expected = match filter.get(&field) {
+        Some(version) => convert::to_json(version.clone()),
+        None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Filter must include the version field '{}'", field))),
+    };
+    for (operator, fields) in update.iter() {
+        if !operator.starts_with('$') {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Update must only contain update operators"));
+        }
+        if matches!(fields, Bson::Document(fields) if fields.contains_key(&field)) {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Update must not modify the version field '{}'", field)));
+        }
+    }
+    match update.get_document_mut("$inc") {
+        Ok(inc) => {
+            inc.insert(field.as_str(), 1);
+        }
+        Err(_) => {
+            update.insert("$inc", doc! { field.as_str(): 1 });
+        }
+    }
+
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    match coll.find_one_and_update(filter.clone(), update, options).await {
+        Ok(Some(updated)) => return Ok(convert::to_json(updated)),
+        Ok(None) => {}
+        Err(e) => return Err(errors::failed("Failed to update document", e)),
+    }
+
+    // Nothing matched: tell a stale version apart from a missing document.
+    let mut unversioned = filter;
+    unversioned.remove(&field);
+    match coll.find_one(unversioned, None).await {
+        Ok(Some(current)) => {
+            let message = format!("Document was modified concurrently ('{}' is stale)", field);
+            let version = current.get(&field).cloned().map(convert::to_json);
+            Err(MongoPluginError::new(errors::ErrorKind::VersionConflict, message)
+                .with("field", json!(field))
+                .with("expected", expected)
+                .with("current", version.unwrap_or(JsonValue::Null)))
+        }
+        Ok(None) => Err(MongoPluginError::new(errors::ErrorKind::NotFound, "No document matches the filter")),
+        Err(e) => Err(errors::failed("Failed to read current version", e)),
+    }
+}
+
+/// Number of ids sent per `$in` delete when removing orphans.
+const GRIDFS_CLEANUP_BATCH: usize = 1000;
+/// How old an orphan must be before cleanup removes it, unless the caller
+/// says otherwise.
+const GRIDFS_CLEANUP_GRACE_MS: i64 = 10 * 60 * 1000;
+
+/// Removes chunks whose files document is missing (left behind by
+/// interrupted uploads) and files documents that have no chunks at all.
+/// GridFS writes a file's chunks before its files document, so a file whose
+/// newest chunk was written within `olderThanMs` belongs to an upload that
+/// may still be running and its chunks are kept, as are files uploaded since
+/// then. Chunks are dated by their own ObjectId `_id`, since `files_id` can
+/// be any value.
+async fn gridfs_cleanup(db: Database, args: GridFsCleanupArgs) -> Result<JsonValue, MongoPluginError> {
+    let bucket = args.bucket.as_deref().unwrap_or("fs");
+    let older_than_ms = args.older_than_ms.unwrap_or(GRIDFS_CLEANUP_GRACE_MS);
+    if older_than_ms < 0 {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "olderThanMs must not be negative"));
+    }
+    let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - older_than_ms);
+    let files_name = format!("{}.files", bucket);
+    let chunks_name = format!("{}.chunks", bucket);
+    let files = db.collection::<Document>(&files_name);
+    let chunks = db.collection::<Document>(&chunks_name);
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+
+    // Group chunks per file first so the lookup runs once per file, not per chunk.
+    let orphan_chunks_pipeline = vec![
+        doc! { "$group": {
+            "_id": "$files_id",
+            "chunks": { "$sum": 1 },
+            "bytes": { "$sum": { "$binarySize": "$data" } },
+            "newest": { "$max": "$_id" },
+        } },
+        doc! { "$lookup": { "from": &files_name, "localField": "_id", "foreignField": "_id", "as": "file" } },
+        doc! { "$match": { "file": { "$size": 0 } } },
+        doc! { "$project": { "file": 0 } },
+    ];
+    let cursor = match chunks.aggregate(orphan_chunks_pipeline, options.clone()).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to scan chunks", e)),
+    };
+    let orphan_chunk_groups: Vec<Document> = match cursor.try_collect().await {
+        Ok(groups) => groups,
+        Err(e) => return Err(errors::failed("Failed to scan chunks", e)),
+    };
+
+    let mut reclaimed_bytes: i64 = 0;
+    let mut orphan_file_ids: Vec<Bson> = Vec::with_capacity(orphan_chunk_groups.len());
+    for group in &orphan_chunk_groups {
+        if let Some(Bson::ObjectId(newest)) = group.get("newest") {
+            if newest.timestamp() > cutoff {
+                continue;
             }
-    );
+        }
+        let id = match group.get("_id") {
+            Some(id) => id,
+            None => continue,
+        };
+        reclaimed_bytes += numeric_field(group, "bytes");
+        orphan_file_ids.push(id.clone());
+    }
+
+    let mut deleted_chunks: u64 = 0;
+    for ids in orphan_file_ids.chunks(GRIDFS_CLEANUP_BATCH) {
+        match chunks.delete_many(doc! { "files_id": { "$in": ids } }, None).await {
+            Ok(result) => deleted_chunks += result.deleted_count,
+            Err(e) => return Err(errors::failed("Failed to delete orphaned chunks", e)),
+        }
+    }
+
+    // Empty files legitimately have no chunks, so only non-empty ones count as orphans.
+    let orphan_files_pipeline = vec![
+        doc! { "$match": { "length": { "$gt": 0 }, "uploadDate": { "$lt": cutoff } } },
+        doc! { "$lookup": {
+            "from": &chunks_name,
+            "let": { "id": "$_id" },
+            "pipeline": [
+                { "$match": { "$expr": { "$eq": ["$files_id", "$$id"] } } },
+                { "$limit": 1 },
+                { "$project": { "_id": 1 } },
+            ],
+            "as": "chunk",
+        } },
+        doc! { "$match": { "chunk": { "$size": 0 } } },
+        doc! { "$project": { "_id": 1 } },
+    ];
+    let cursor = match files.aggregate(orphan_files_pipeline, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to scan files", e)),
+    };
+    let orphan_files: Vec<Document> = match cursor.try_collect().await {
+        Ok(files) => files,
+        Err(e) => return Err(errors::failed("Failed to scan files", e)),
+    };
+    let file_ids: Vec<Bson> = orphan_files.iter().filter_map(|file| file.get("_id").cloned()).collect();
+
+    let mut deleted_files: u64 = 0;
+    for ids in file_ids.chunks(GRIDFS_CLEANUP_BATCH) {
+        match files.delete_many(doc! { "_id": { "$in": ids } }, None).await {
+            Ok(result) => deleted_files += result.deleted_count,
+            Err(e) => return Err(errors::failed("Failed to delete orphaned files", e)),
+        }
+    }
+
+    Ok(json!({
+        "bucket": bucket,
+        "orphanedChunks": deleted_chunks,
+        "orphanedFiles": deleted_files,
+        "reclaimedBytes": reclaimed_bytes,
+    }))
 }
+
+/// Reads an aggregation sum, which the server returns as int32 or int64
+/// depending on magnitude.
+fn numeric_field(doc: &Document, key: &str) -> i64 {
+    match doc.get(key) {
+        Some(Bson::Int32(n)) => i64::from(*n),
+        Some(Bson::Int64(n)) => *n,
+        Some(Bson::Double(n)) => *n as i64,
+        _ => 0,
+    }
 }
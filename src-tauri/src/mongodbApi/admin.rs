@@ -0,0 +1,303 @@
+//! Database and collection administration, for apps that set up their own
+//! schema on first run.
+//!
+//! `listDatabases` names the databases on the server, only the tenant's
+//! with a tenant callback and only those the window is allowed into, and `listCollections` describes the collections
+//! and views in the connected database, a view with its `viewOn` and
+//! `pipeline`. `getViewDefinition` returns one view's definition and the
+//! `sourceCollection` its documents come from, through any views it is
+//! defined on. `createView` defines a view, given its `viewOn` and
+//! `pipeline`, and `modifyView` redefines one. `createCollection` takes an
+//! `options` object with `capped`, `size`, `max`, `validator`,
+//! `validationLevel` and `validationAction`. `renameCollection` moves a
+//! collection to a new name in the same database, replacing an existing one
+//! only with `dropTarget`. `runCommand` sends any command document, given as
+//! JSON text, to the connected database and returns the server's reply; it
+//! can reach other databases, so it is refused under tenancy.
+
+use futures::TryStreamExt;
+use mongodb::bson::{self, doc, Document};
+use mongodb::options::{Collation, CreateCollectionOptions, ValidationAction, ValidationLevel};
+use mongodb::results::{CollectionSpecification, CollectionType};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, policy, CommandContext, MongoState, NoArgs};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ListDatabasesArgs {
+    /// A filter on the databases' `name`, `sizeOnDisk` and `empty`.
+    filter: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ListCollectionsArgs {
+    filter: Option<String>,
+    /// Return only the names, which doesn't lock the collections.
+    #[serde(default)]
+    name_only: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct CreateCollectionCommandOptions {
+    capped: Option<bool>,
+    /// Largest size in bytes of a capped collection.
+    size: Option<u64>,
+    /// Most documents a capped collection keeps.
+    max: Option<u64>,
+    validator: Option<Document>,
+    validation_level: Option<ValidationLevel>,
+    validation_action: Option<ValidationAction>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct CreateCollectionArgs {
+    collection: String,
+    #[serde(default)]
+    options: CreateCollectionCommandOptions,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ViewArgs {
+    collection: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DefineViewArgs {
+    collection: String,
+    view_on: String,
+    pipeline: String,
+    collation: Option<Collation>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct DropCollectionArgs {
+    collection: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct RenameCollectionArgs {
+    collection: String,
+    to: String,
+    #[serde(default)]
+    drop_target: bool,
+}
+
+#[derive(Deserialize)]
+pub(super) struct RunCommandArgs {
+    command: String,
+}
+
+fn parse_filter(filter: Option<&str>) -> Result<Option<Document>, MongoPluginError> {
+    match filter.map(convert::from_extjson::<Document>) {
+        Some(Ok(filter)) => Ok(Some(filter)),
+        Some(Err(e)) => Err(errors::invalid("Failed to parse filter", e)),
+        None => Ok(None),
+    }
+}
+
+pub(super) async fn list_databases<R: Runtime>(
+    app: AppHandle<R>,
+    permissions: Option<policy::Permissions>,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: ListDatabasesArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let client = app.state::<MongoState>().client(connection.as_deref())?;
+    let mut filter = parse_filter(args.filter.as_deref())?;
+    if let Some(tenant) = tenant {
+        filter = Some(match filter {
+            Some(filter) => doc! { "$and": [filter, { "name": tenant }] },
+            None => doc! { "name": tenant },
+        });
+    }
+    match client.list_databases(filter, None).await {
+        Ok(databases) => Ok(databases
+            .into_iter()
+            .filter(|database| permissions.as_ref().is_none_or(|permissions| permissions.allows_database(&database.name)))
+            .map(|database| json!({ "name": database.name, "sizeOnDisk": database.size_on_disk, "empty": database.empty }))
+            .collect()),
+        Err(e) => Err(errors::failed("Failed to list databases", e)),
+    }
+}
+
+pub(super) async fn list_collections(ctx: CommandContext, args: ListCollectionsArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter = parse_filter(args.filter.as_deref())?;
+    if args.name_only {
+        return match ctx.db.list_collection_names(filter).await {
+            Ok(names) => Ok(json!(names)),
+            Err(e) => Err(errors::failed("Failed to list collections", e)),
+        };
+    }
+    let cursor = match ctx.db.list_collections(filter, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to list collections", e)),
+    };
+    let specs: Vec<CollectionSpecification> = match cursor.try_collect().await {
+        Ok(specs) => specs,
+        Err(e) => return Err(errors::failed("Failed to read collections", e)),
+    };
+    let mut collections = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let mut collection = match bson::to_document(&spec) {
+            Ok(collection) => collection,
+            Err(e) => return Err(errors::failed("Failed to read collections", e)),
+        };
+        if let (Some(view_on), Some(pipeline)) = (spec.options.view_on, spec.options.pipeline) {
+            collection.insert("viewOn", view_on);
+            collection.insert("pipeline", pipeline);
+        }
+        collections.push(convert::to_json(collection));
+    }
+    Ok(JsonValue::Array(collections))
+}
+
+/// How many views a view may be defined on in turn, as the server allows.
+const MAX_VIEW_DEPTH: usize = 20;
+
+async fn specification(ctx: &CommandContext, name: &str) -> Result<Option<CollectionSpecification>, MongoPluginError> {
+    let cursor = match ctx.db.list_collections(doc! { "name": name }, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to list collections", e)),
+    };
+    match cursor.try_collect::<Vec<_>>().await {
+        Ok(specs) => Ok(specs.into_iter().next()),
+        Err(e) => Err(errors::failed("Failed to read collections", e)),
+    }
+}
+
+pub(super) async fn get_view_definition(ctx: CommandContext, args: ViewArgs) -> Result<JsonValue, MongoPluginError> {
+    let view = match specification(&ctx, &args.collection).await? {
+        Some(spec) if spec.collection_type == CollectionType::View => spec,
+        Some(_) => {
+            let message = format!("'{}' is a collection, not a view", args.collection);
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+        None => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("View '{}' doesn't exist", args.collection))),
+    };
+    let view_on = view.options.view_on.clone().unwrap_or_default();
+    // Views defined on views, up to the collection the documents come from.
+    let (mut through, mut source) = (Vec::new(), view_on.clone());
+    let source_exists = loop {
+        match specification(&ctx, &source).await? {
+            Some(spec) if spec.collection_type == CollectionType::View && through.len() < MAX_VIEW_DEPTH => {
+                through.push(source);
+                source = spec.options.view_on.unwrap_or_default();
+            }
+            found => break found.is_some(),
+        }
+    };
+    let collation = match view.options.collation.as_ref().map(bson::to_bson) {
+        Some(Ok(collation)) => convert::to_json(collation),
+        Some(Err(e)) => return Err(errors::failed("Failed to read collation", e)),
+        None => JsonValue::Null,
+    };
+    Ok(json!({
+        "name": view.name,
+        "viewOn": view_on,
+        "pipeline": convert::to_json(view.options.pipeline.unwrap_or_default()),
+        "collation": collation,
+        "through": through,
+        "sourceCollection": source,
+        "sourceExists": source_exists,
+    }))
+}
+
+fn parse_pipeline(pipeline: &str) -> Result<Vec<Document>, MongoPluginError> {
+    match convert::from_extjson(pipeline) {
+        Ok(pipeline) => Ok(pipeline),
+        Err(e) => Err(errors::invalid("Failed to parse pipeline", e)),
+    }
+}
+
+pub(super) async fn create_view(ctx: CommandContext, args: DefineViewArgs) -> Result<JsonValue, MongoPluginError> {
+    let options = CreateCollectionOptions::builder()
+        .view_on(Some(args.view_on))
+        .pipeline(Some(parse_pipeline(&args.pipeline)?))
+        .collation(args.collation)
+        .build();
+    match ctx.db.create_collection(&args.collection, options).await {
+        Ok(()) => Ok(json!("success")),
+        Err(e) => Err(errors::failed("Failed to create view", e)),
+    }
+}
+
+/// Redefines a view with `collMod`; a view's collation can't be changed.
+pub(super) async fn modify_view(ctx: CommandContext, args: DefineViewArgs) -> Result<JsonValue, MongoPluginError> {
+    if args.collation.is_some() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "A view's collation can't be changed; drop and create it again"));
+    }
+    let command = doc! { "collMod": &args.collection, "viewOn": &args.view_on, "pipeline": parse_pipeline(&args.pipeline)? };
+    match ctx.db.run_command(command, None).await {
+        Ok(_) => Ok(json!("success")),
+        Err(e) => Err(errors::failed("Failed to modify view", e)),
+    }
+}
+
+pub(super) async fn create_collection(ctx: CommandContext, args: CreateCollectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let settings = args.options;
+    if settings.capped == Some(true) && settings.size.is_none() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "A capped collection needs a size"));
+    }
+    let options = CreateCollectionOptions::builder()
+        .capped(settings.capped)
+        .size(settings.size)
+        .max(settings.max)
+        .validator(settings.validator)
+        .validation_level(settings.validation_level)
+        .validation_action(settings.validation_action)
+        .build();
+    match ctx.db.create_collection(&args.collection, options).await {
+        Ok(()) => Ok(json!("success")),
+        Err(e) => Err(errors::failed("Failed to create collection", e)),
+    }
+}
+
+pub(super) async fn drop_collection(ctx: CommandContext, args: DropCollectionArgs) -> Result<JsonValue, MongoPluginError> {
+    match ctx.db.collection::<Document>(&args.collection).drop(None).await {
+        Ok(()) => Ok(json!("success")),
+        Err(e) => Err(errors::failed("Failed to drop collection", e)),
+    }
+}
+
+/// Renames through the admin database, where `renameCollection` must run.
+pub(super) async fn rename_collection(ctx: CommandContext, args: RenameCollectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let database = ctx.db.name();
+    let command = doc! {
+        "renameCollection": format!("{}.{}", database, args.collection),
+        "to": format!("{}.{}", database, args.to),
+        "dropTarget": args.drop_target,
+    };
+    match ctx.client.database("admin").run_command(command, None).await {
+        Ok(_) => Ok(json!("success")),
+        Err(e) => Err(errors::failed("Failed to rename collection", e)),
+    }
+}
+
+pub(super) async fn drop_database(ctx: CommandContext, _args: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    match ctx.db.drop(None).await {
+        Ok(()) => Ok(json!("success")),
+        Err(e) => Err(errors::failed("Failed to drop database", e)),
+    }
+}
+
+pub(super) async fn run_command(ctx: CommandContext, args: RunCommandArgs) -> Result<JsonValue, MongoPluginError> {
+    let command: Document = match convert::from_extjson(&args.command) {
+        Ok(command) => command,
+        Err(e) => return Err(errors::invalid("Failed to parse command", e)),
+    };
+    if command.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "command must not be empty"));
+    }
+    match ctx.db.run_command(command, None).await {
+        Ok(reply) => Ok(convert::to_json(reply)),
+        Err(e) => Err(errors::failed("Failed to run command", e)),
+    }
+}
@@ -0,0 +1,358 @@
+//! Index suggestions from the shapes of queries the app actually runs.
+//!
+//! Every read records its shape: which fields it matches by equality, which
+//! by range, and how it sorts. `suggestIndexes` turns the recorded shapes of
+//! a collection into candidate indexes following the equality, sort, range
+//! rule, leaving out those an existing index already serves. Shapes are kept
+//! in memory for the life of the app.
+//!
+//! Whether an existing index serves a shape is a guess from its key,
+//! unless `explainSampling` is set in the plugin config. Then once a shape
+//! has run `minQueries` times, and every `every` runs after that, the read
+//! is explained with `executionStats` in the background and the advisor
+//! goes by what the server did: a shape whose sampled plan scanned the
+//! collection, or examined more than ten documents per document returned,
+//! is suggested an index even if one looks like it fits, and one that used
+//! an index efficiently is not. Each suggested shape reports its `sampled`
+//! plan summary, `null` when it has none.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::errors::{self, MongoPluginError};
+use super::{explain, CommandContext};
+
+/// Distinct shapes remembered per collection.
+const MAX_SHAPES_PER_COLLECTION: usize = 200;
+
+const DEFAULT_SAMPLE_EVERY: u64 = 100;
+const DEFAULT_MIN_QUERIES: u64 = 20;
+
+/// Documents a sampled plan may examine per document returned and still
+/// count as served by its index.
+const EXAMINED_PER_RETURNED: i64 = 10;
+
+/// Operators that select a range of values rather than specific ones.
+const RANGE_OPERATORS: &[&str] = &["$gt", "$gte", "$lt", "$lte", "$ne", "$nin", "$regex", "$exists"];
+
+#[derive(Clone, PartialEq, Eq, Hash, Default)]
+struct QueryShape {
+    equality: Vec<String>,
+    sort: Vec<(String, i32)>,
+    range: Vec<String>,
+}
+
+/// The `explainSampling` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExplainSamplingConfig {
+    /// Runs of a shape between two samples.
+    pub every: Option<u64>,
+    /// Runs before a shape is hot enough to sample.
+    pub min_queries: Option<u64>,
+}
+
+/// The last sampled execution of a shape.
+#[derive(Clone)]
+struct Sampled {
+    samples: u64,
+    collection_scan: bool,
+    indexes_used: Vec<String>,
+    returned: i64,
+    keys_examined: i64,
+    docs_examined: i64,
+    time_ms: i64,
+}
+
+impl Sampled {
+    fn from_summary(summary: &JsonValue, samples: u64) -> Self {
+        let number = |key: &str| summary[key].as_i64().unwrap_or_default();
+        Self {
+            samples,
+            collection_scan: summary["collectionScan"].as_bool().unwrap_or_default(),
+            indexes_used: summary["indexesUsed"].as_array().into_iter().flatten().filter_map(JsonValue::as_str).map(str::to_string).collect(),
+            returned: number("nReturned"),
+            keys_examined: number("keysExamined"),
+            docs_examined: number("docsExamined"),
+            time_ms: number("timeMs"),
+        }
+    }
+
+    /// Whether the server answered the shape from an index without reading
+    /// many more documents than it returned.
+    fn indexed(&self) -> bool {
+        !self.collection_scan && !self.indexes_used.is_empty() && self.docs_examined <= self.returned.max(1) * EXAMINED_PER_RETURNED
+    }
+
+    fn view(&self) -> JsonValue {
+        json!({
+            "samples": self.samples,
+            "collectionScan": self.collection_scan,
+            "indexesUsed": self.indexes_used,
+            "nReturned": self.returned,
+            "keysExamined": self.keys_examined,
+            "docsExamined": self.docs_examined,
+            "timeMs": self.time_ms,
+        })
+    }
+}
+
+#[derive(Default)]
+struct ShapeUsage {
+    count: u64,
+    sampled: Option<Sampled>,
+}
+
+pub(super) struct QueryShapes {
+    collections: Mutex<HashMap<String, HashMap<QueryShape, ShapeUsage>>>,
+    /// Runs between samples and runs before the first one, when sampling.
+    sampling: Option<(u64, u64)>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct SuggestIndexesArgs {
+    collection: String,
+}
+
+/// Adds the fields `filter` constrains to `shape`. `$or`/`$nor` branches
+/// need an index each and are left out.
+fn add_filter(shape: &mut QueryShape, filter: &serde_json::Map<String, JsonValue>) {
+    for (key, value) in filter {
+        if key == "$and" {
+            for clause in value.as_array().into_iter().flatten().filter_map(JsonValue::as_object) {
+                add_filter(shape, clause);
+            }
+            continue;
+        }
+        if key.starts_with('$') {
+            continue;
+        }
+        let is_range = value
+            .as_object()
+            .is_some_and(|ops| ops.keys().any(|op| RANGE_OPERATORS.contains(&op.as_str())));
+        let fields = if is_range { &mut shape.range } else { &mut shape.equality };
+        if !fields.contains(key) {
+            fields.push(key.clone());
+        }
+    }
+}
+
+fn parsed(payload: &JsonValue, key: &str) -> Option<JsonValue> {
+    payload.get(key).and_then(JsonValue::as_str).and_then(|text| serde_json::from_str(text).ok())
+}
+
+/// The shape of a read command, from its filter or its leading `$match`
+/// and `$sort` stages.
+fn shape_of(command: &str, payload: &JsonValue) -> Option<QueryShape> {
+    let mut shape = QueryShape::default();
+    match command {
+        "find" | "findOne" | "findCursor" => add_filter(&mut shape, parsed(payload, "query")?.as_object()?),
+        "exists" | "count" | "countDocuments" | "distinct" | "findFieldValue" | "exportXlsx" | "timeSeriesAggregate" => add_filter(&mut shape, parsed(payload, "filter")?.as_object()?),
+        "aggregate" | "aggregateCursor" => {
+            let pipeline = parsed(payload, "pipeline")?;
+            for stage in pipeline.as_array()? {
+                if let Some(filter) = stage.get("$match").and_then(JsonValue::as_object) {
+                    add_filter(&mut shape, filter);
+                } else if let Some(sort) = stage.get("$sort").and_then(JsonValue::as_object) {
+                    shape.sort = sort
+                        .iter()
+                        .map(|(field, direction)| (field.clone(), if direction.as_i64() == Some(-1) { -1 } else { 1 }))
+                        .collect();
+                    break;
+                } else {
+                    break;
+                }
+            }
+        }
+        _ => return None,
+    }
+    // A field matched by equality needs no range or sort position.
+    shape.range.retain(|field| !shape.equality.contains(field));
+    shape.sort.retain(|(field, _)| !shape.equality.contains(field));
+    shape.equality.sort();
+    shape.range.sort();
+    if shape.equality.is_empty() && shape.sort.is_empty() && shape.range.is_empty() {
+        return None;
+    }
+    Some(shape)
+}
+
+impl QueryShapes {
+    pub(super) fn new(sampling: Option<&ExplainSamplingConfig>) -> Self {
+        let sampling = sampling.map(|config| {
+            (config.every.unwrap_or(DEFAULT_SAMPLE_EVERY).max(1), config.min_queries.unwrap_or(DEFAULT_MIN_QUERIES).max(1))
+        });
+        Self { collections: Mutex::default(), sampling }
+    }
+
+    /// Remembers the shape of a read, if the command is one, and explains it
+    /// on `db` in the background when it is due a sample.
+    pub(super) fn record(self: &Arc<Self>, db: &Database, command: &str, payload: &JsonValue) {
+        let collection = match payload.get("collection").and_then(JsonValue::as_str) {
+            Some(collection) => collection,
+            None => return,
+        };
+        let shape = match shape_of(command, payload) {
+            Some(shape) => shape,
+            None => return,
+        };
+        let count = {
+            let mut collections = self.collections.lock().unwrap();
+            let shapes = collections.entry(collection.to_string()).or_default();
+            match shapes.get_mut(&shape) {
+                Some(usage) => {
+                    usage.count += 1;
+                    usage.count
+                }
+                None if shapes.len() < MAX_SHAPES_PER_COLLECTION => {
+                    shapes.insert(shape.clone(), ShapeUsage { count: 1, sampled: None });
+                    1
+                }
+                None => return,
+            }
+        };
+        let due = self.sampling.is_some_and(|(every, min_queries)| count >= min_queries && (count - min_queries) % every == 0);
+        if let (true, Some(explained)) = (due, explain::explainable(command, payload)) {
+            let (shapes, db, collection) = (self.clone(), db.clone(), collection.to_string());
+            tokio::spawn(async move {
+                // Sampling is best effort: a read that can't be explained
+                // keeps its previous sample, if any.
+                let output = match db.run_command(doc! { "explain": explained, "verbosity": "executionStats" }, None).await {
+                    Ok(output) => output,
+                    Err(_) => return,
+                };
+                if let Some((_, summary)) = explain::summarized(&output) {
+                    shapes.sampled(&collection, &shape, &summary);
+                }
+            });
+        }
+    }
+
+    fn sampled(&self, collection: &str, shape: &QueryShape, summary: &JsonValue) {
+        let mut collections = self.collections.lock().unwrap();
+        if let Some(usage) = collections.get_mut(collection).and_then(|shapes| shapes.get_mut(shape)) {
+            let samples = usage.sampled.as_ref().map_or(0, |sampled| sampled.samples) + 1;
+            usage.sampled = Some(Sampled::from_summary(summary, samples));
+        }
+    }
+
+    fn shapes(&self, collection: &str) -> Vec<(QueryShape, u64, Option<Sampled>)> {
+        let collections = self.collections.lock().unwrap();
+        let shapes = collections.get(collection).into_iter().flatten();
+        shapes.map(|(shape, usage)| (shape.clone(), usage.count, usage.sampled.clone())).collect()
+    }
+}
+
+/// Equality fields first, then the sort, then range fields.
+fn index_key(shape: &QueryShape) -> Document {
+    let mut key = Document::new();
+    for field in &shape.equality {
+        key.insert(field.as_str(), 1);
+    }
+    for (field, direction) in &shape.sort {
+        key.insert(field.as_str(), *direction);
+    }
+    for field in &shape.range {
+        if !key.contains_key(field) {
+            key.insert(field.as_str(), 1);
+        }
+    }
+    key
+}
+
+/// The direction of an ascending/descending key, however it was stored.
+fn direction(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(n) => Some(n.signum() as i64),
+        Bson::Int64(n) => Some(n.signum()),
+        Bson::Double(n) => Some(n.signum() as i64),
+        _ => None,
+    }
+}
+
+/// Whether `key` is a prefix of the index key `existing`.
+fn is_prefix(key: &Document, existing: &Document) -> bool {
+    key.len() <= existing.len()
+        && key
+            .iter()
+            .zip(existing.iter())
+            .all(|((a, x), (b, y))| a == b && direction(x).is_some() && direction(x) == direction(y))
+}
+
+fn index_name(key: &Document) -> String {
+    let parts: Vec<String> = key.iter().map(|(field, direction)| format!("{}_{}", field, direction)).collect();
+    parts.join("_")
+}
+
+async fn existing_indexes(db: &Database, collection: &str) -> Result<Vec<Document>, MongoPluginError> {
+    let cursor = match db.collection::<Document>(collection).list_indexes(None).await {
+        Ok(cursor) => cursor,
+        // A collection that doesn't exist yet has no indexes.
+        Err(_) => return Ok(Vec::new()),
+    };
+    match cursor.try_collect::<Vec<_>>().await {
+        Ok(indexes) => Ok(indexes.into_iter().map(|index| index.keys).collect()),
+        Err(e) => Err(errors::failed("Failed to list indexes", e)),
+    }
+}
+
+/// Proposes indexes for the recorded query shapes of a collection, most
+/// frequently needed first. Each suggestion carries a `createIndexes`
+/// command ready to run.
+pub(super) async fn suggest_indexes(ctx: CommandContext, args: SuggestIndexesArgs) -> Result<JsonValue, MongoPluginError> {
+    let existing = existing_indexes(&ctx.db, &args.collection).await?;
+
+    let mut candidates: Vec<(Document, u64, Vec<(QueryShape, Option<Sampled>)>)> = Vec::new();
+    for (shape, count, sampled) in ctx.query_shapes.shapes(&args.collection) {
+        let key = index_key(&shape);
+        let served = match &sampled {
+            // The index the key asks for exists and still wasn't enough.
+            Some(sampled) if !sampled.indexed() => existing.iter().any(|index| is_prefix(&key, index) && index.len() == key.len()),
+            Some(_) => true,
+            None => existing.iter().any(|index| is_prefix(&key, index)),
+        };
+        if served {
+            continue;
+        }
+        let shape = (shape, sampled);
+        match candidates.iter_mut().find(|(candidate, _, _)| is_prefix(&key, candidate) || is_prefix(candidate, &key)) {
+            // One index serves every query whose key is a prefix of it.
+            Some((candidate, total, shapes)) => {
+                if key.len() > candidate.len() {
+                    *candidate = key;
+                }
+                *total += count;
+                shapes.push(shape);
+            }
+            None => candidates.push((key, count, vec![shape])),
+        }
+    }
+    candidates.sort_by_key(|(_, queries, _)| std::cmp::Reverse(*queries));
+
+    let suggestions: Vec<JsonValue> = candidates
+        .into_iter()
+        .map(|(key, queries, shapes)| {
+            let shapes: Vec<JsonValue> = shapes
+                .iter()
+                .map(|(shape, sampled)| {
+                    let sort: Vec<JsonValue> = shape.sort.iter().map(|(field, direction)| json!({ field: direction })).collect();
+                    json!({ "equality": shape.equality, "sort": sort, "range": shape.range, "sampled": sampled.as_ref().map(Sampled::view) })
+                })
+                .collect();
+            let name = index_name(&key);
+            let key = Bson::Document(key).into_relaxed_extjson();
+            json!({
+                "key": key,
+                "queries": queries,
+                "shapes": shapes,
+                "createIndex": { "createIndexes": args.collection, "indexes": [{ "key": key, "name": name }] },
+            })
+        })
+        .collect();
+    Ok(json!({ "collection": args.collection, "suggestions": suggestions }))
+}
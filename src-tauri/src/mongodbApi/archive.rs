@@ -0,0 +1,478 @@
+//! Moving documents out of a collection into cold storage and back.
+//!
+//! `archiveDocuments` moves the documents matching `filter` either into a
+//! gzip-compressed NDJSON file in the archive directory (`mongo-archives` in
+//! the app data directory unless `archive.directory` says otherwise) or,
+//! with `destination: "collection"`, into an archive collection,
+//! `<collection>_archive` by default. Documents go `batchSize` (500 by
+//! default) at a time, each batch read and deleted in one transaction that
+//! only commits once the batch is safely in the archive, so a failure never
+//! loses documents; at worst a file keeps a copy of a batch that is still
+//! in the collection too. Documents are archived as stored, in canonical
+//! extended JSON, so encrypted fields stay sealed and signatures stay valid.
+//!
+//! `unarchive` puts documents back from a file given by `path` or from an
+//! archive collection, optionally only those matching `filter`. Documents
+//! already back in the collection are left as they are and counted as
+//! `skipped`; a file is removed once all of its documents were restored.
+//! Both run as operations of kind `archive` reporting `{ count }`. Archiving
+//! needs a replica set or sharded cluster, for the transactions.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use mongodb::error::{Error as MongoError, ErrorKind, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use mongodb::options::{FindOptions, InsertManyOptions};
+use mongodb::{ClientSession, Collection};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::{convert, CommandContext};
+
+pub(super) const DEFAULT_DIRECTORY: &str = "mongo-archives";
+const FILE_EXTENSION: &str = ".ndjson.gz";
+const ARCHIVE_SUFFIX: &str = "_archive";
+const DEFAULT_BATCH_SIZE: i64 = 500;
+const MAX_TRANSACTION_ATTEMPTS: u32 = 5;
+
+/// The `archive` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ArchiveConfig {
+    /// Where archive files go, relative to the app data directory unless
+    /// absolute.
+    pub directory: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum Destination {
+    #[default]
+    File,
+    Collection,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ArchiveDocumentsArgs {
+    collection: String,
+    filter: String,
+    #[serde(default)]
+    destination: Destination,
+    archive_collection: Option<String>,
+    batch_size: Option<i64>,
+    operation_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct UnarchiveArgs {
+    collection: String,
+    /// An archive file `archiveDocuments` wrote.
+    path: Option<PathBuf>,
+    archive_collection: Option<String>,
+    filter: Option<String>,
+    batch_size: Option<i64>,
+    operation_id: Option<String>,
+}
+
+/// Where archived batches go, each before the transaction deleting it
+/// commits.
+enum Sink {
+    File { file: tokio::fs::File, path: PathBuf },
+    Collection { name: String, archive: Collection<Document> },
+}
+
+fn archive_collection(collection: &str, named: Option<&String>) -> String {
+    named.cloned().unwrap_or_else(|| format!("{}{}", collection, ARCHIVE_SUFFIX))
+}
+
+/// The archive collection `command` reads or writes besides its own
+/// collection, for authorizing it too.
+pub(super) fn archive_collection_of(command: &str, payload: &JsonValue) -> Option<String> {
+    let collection = payload.get("collection").and_then(JsonValue::as_str)?;
+    let named = payload.get("archiveCollection").and_then(JsonValue::as_str).map(str::to_string);
+    let uses = match command {
+        "archiveDocuments" => payload.get("destination").and_then(JsonValue::as_str) == Some("collection"),
+        "unarchive" => payload.get("path").is_none_or(JsonValue::is_null),
+        _ => false,
+    };
+    uses.then(|| archive_collection(collection, named.as_ref()))
+}
+
+fn archive_dir(ctx: &CommandContext) -> Result<&Path, MongoPluginError> {
+    match &ctx.archive_dir {
+        Some(dir) => Ok(dir.as_path()),
+        None => Err(MongoPluginError::new(errors::ErrorKind::Io, "No app data directory to keep archives in")),
+    }
+}
+
+/// One batch as a gzip member of its own, so a file can be cut back to the
+/// batch before it if the batch's transaction fails.
+fn compress(docs: &[Document]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for doc in docs {
+        let line = Bson::Document(doc.clone()).into_canonical_extjson();
+        serde_json::to_writer(&mut encoder, &line).unwrap();
+        encoder.write_all(b"\n").unwrap();
+    }
+    encoder.finish().unwrap()
+}
+
+/// Reads, archives and deletes one batch in a transaction, returning how
+/// many documents it moved. The archive copy is written before committing
+/// and, for a file, cut off again if the commit fails.
+async fn move_batch(
+    source: &Collection<Document>,
+    filter: &Document,
+    limit: i64,
+    sink: &mut Sink,
+    session: &mut ClientSession,
+) -> Result<usize, MongoPluginError> {
+    let mut last_error = None;
+    for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+        if let Err(e) = session.start_transaction(None).await {
+            return Err(errors::failed("Failed to start transaction", e));
+        }
+        let mut written_from = None;
+        let outcome: Result<usize, MongoError> = async {
+            let options = FindOptions::builder().limit(limit).build();
+            let mut cursor = source.find_with_session(filter.clone(), options, &mut *session).await?;
+            let docs: Vec<Document> = cursor.stream(&mut *session).try_collect().await?;
+            if docs.is_empty() {
+                return Ok(0);
+            }
+            let ids: Vec<Bson> = docs.iter().filter_map(|doc| doc.get("_id").cloned()).collect();
+            source.delete_many_with_session(doc! { "_id": { "$in": &ids } }, None, &mut *session).await?;
+            match sink {
+                Sink::Collection { archive, .. } => {
+                    // A copy archived before and restored since is replaced.
+                    archive.delete_many_with_session(doc! { "_id": { "$in": &ids } }, None, &mut *session).await?;
+                    archive.insert_many_with_session(&docs, None, &mut *session).await?;
+                }
+                Sink::File { file, .. } => {
+                    let length = file.metadata().await?.len();
+                    written_from = Some(length);
+                    file.write_all(&compress(&docs)).await?;
+                    file.sync_data().await?;
+                }
+            }
+            Ok(docs.len())
+        }
+        .await;
+        let moved = match outcome {
+            Ok(moved) => moved,
+            Err(e) => {
+                let _ = session.abort_transaction().await;
+                truncate(sink, written_from).await;
+                if e.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+                    last_error = Some(e);
+                    continue;
+                }
+                return Err(errors::failed("Failed to archive documents", e));
+            }
+        };
+        let mut commit_attempts = 1;
+        let committed = loop {
+            match session.commit_transaction().await {
+                Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && commit_attempts < MAX_TRANSACTION_ATTEMPTS => {
+                    commit_attempts += 1;
+                }
+                committed => break committed,
+            }
+        };
+        match committed {
+            Ok(()) => return Ok(moved),
+            Err(e) => {
+                truncate(sink, written_from).await;
+                if e.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+                    last_error = Some(e);
+                    continue;
+                }
+                return Err(errors::failed("Failed to archive documents", e));
+            }
+        }
+    }
+    match last_error {
+        Some(e) => Err(errors::failed("Failed to archive documents", e)),
+        None => Err("Failed to archive documents".into()),
+    }
+}
+
+/// Cuts an archive file back to `length`, dropping a batch that wasn't
+/// deleted from the collection after all.
+async fn truncate(sink: &mut Sink, length: Option<u64>) {
+    if let (Sink::File { file, .. }, Some(length)) = (sink, length) {
+        let _ = file.set_len(length).await;
+    }
+}
+
+pub(super) async fn archive_documents(ctx: CommandContext, args: ArchiveDocumentsArgs) -> Result<JsonValue, MongoPluginError> {
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "archive", args.operation_id.clone())?;
+    let outcome = archive_into(&ctx, &args, &mut operation).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn archive_into(ctx: &CommandContext, args: &ArchiveDocumentsArgs, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let source = ctx.db.collection::<Document>(&args.collection);
+    let limit = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut session = match ctx.client.start_session(None).await {
+        Ok(session) => session,
+        Err(e) => return Err(errors::failed("Failed to start session", e)),
+    };
+
+    let mut sink = match args.destination {
+        Destination::File => {
+            let dir = archive_dir(ctx)?;
+            if let Err(e) = tokio::fs::create_dir_all(dir).await {
+                return Err(errors::failed("Failed to create archive directory", e));
+            }
+            let name = format!("{}.{}-{}{}", ctx.db.name(), args.collection, ObjectId::new().to_hex(), FILE_EXTENSION);
+            let path = dir.join(name);
+            match tokio::fs::File::create(&path).await {
+                Ok(file) => Sink::File { file, path },
+                Err(e) => return Err(errors::failed("Failed to create archive file", e)),
+            }
+        }
+        Destination::Collection => {
+            let name = archive_collection(&args.collection, args.archive_collection.as_ref());
+            if name == args.collection {
+                return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "A collection can't be archived into itself"));
+            }
+            let archive = ctx.db.collection::<Document>(&name);
+            Sink::Collection { name, archive }
+        }
+    };
+
+    let mut count: u64 = 0;
+    let moved: Result<(), MongoPluginError> = async {
+        loop {
+            if !operation.proceed().await {
+                return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "Archiving was cancelled"));
+            }
+            let batch = move_batch(&source, &filter, limit, &mut sink, &mut session).await?;
+            if batch == 0 {
+                return Ok(());
+            }
+            count += batch as u64;
+            operation.progress(json!({ "count": count }));
+        }
+    }
+    .await;
+    match sink {
+        Sink::File { file, path } => {
+            drop(file);
+            if count == 0 {
+                // Nothing was archived, so there's no archive to keep.
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+            match moved {
+                Ok(()) if count == 0 => Ok(json!({ "operationId": operation.id(), "count": 0 })),
+                Ok(()) => Ok(json!({ "operationId": operation.id(), "count": count, "path": path })),
+                Err(e) if count == 0 => Err(e),
+                Err(mut e) => {
+                    e.message = format!("{}; {} documents were archived to {} before that", e.message, count, path.display());
+                    Err(e)
+                }
+            }
+        }
+        Sink::Collection { name, .. } => match moved {
+            Ok(()) => Ok(json!({ "operationId": operation.id(), "count": count, "archiveCollection": name })),
+            Err(mut e) => {
+                e.message = format!("{}; {} documents were archived to '{}' before that", e.message, count, name);
+                Err(e)
+            }
+        },
+    }
+}
+
+/// Whether `path` is an archive file in the archive directory, so
+/// `unarchive` can't be pointed at any other file.
+fn is_archive(dir: &Path, path: &Path) -> bool {
+    let named = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.ends_with(FILE_EXTENSION));
+    let inside = match (dir.canonicalize(), path.parent().map(Path::canonicalize)) {
+        (Ok(dir), Some(Ok(parent))) => parent == dir,
+        _ => false,
+    };
+    named && inside
+}
+
+/// Inserts a batch, counting documents already in the collection as
+/// skipped rather than failing, and returns `(restored, skipped)`.
+pub(super) async fn restore_batch(target: &Collection<Document>, docs: Vec<Document>) -> Result<(u64, u64), MongoError> {
+    let total = docs.len() as u64;
+    let options = InsertManyOptions::builder().ordered(false).build();
+    match target.insert_many(docs, options).await {
+        Ok(_) => Ok((total, 0)),
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::BulkWrite(failure)
+                if failure.write_concern_error.is_none()
+                    && failure.write_errors.as_ref().is_some_and(|errors| errors.iter().all(|error| error.code == 11000)) =>
+            {
+                let skipped = failure.write_errors.as_ref().map_or(0, |errors| errors.len() as u64);
+                Ok((total - skipped, skipped))
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+pub(super) async fn unarchive(ctx: CommandContext, args: UnarchiveArgs) -> Result<JsonValue, MongoPluginError> {
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "archive", args.operation_id.clone())?;
+    let outcome = match (&args.path, &args.archive_collection) {
+        (Some(path), None) => unarchive_file(&ctx, &args, path, &mut operation).await,
+        (None, _) => unarchive_collection(&ctx, &args, &mut operation).await,
+        (Some(_), Some(_)) => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Give either a path or an archiveCollection, not both")),
+    };
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn unarchive_file(ctx: &CommandContext, args: &UnarchiveArgs, path: &Path, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    if args.filter.is_some() {
+        let message = "An archive file is restored whole; filter only applies to archive collections";
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+    }
+    if !is_archive(archive_dir(ctx)?, path) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Only files in the archive directory can be restored"));
+    }
+    let compressed = match tokio::fs::read(path).await {
+        Ok(compressed) => compressed,
+        Err(e) => return Err(errors::failed("Failed to read archive file", e)),
+    };
+    let target = ctx.db.collection::<Document>(&args.collection);
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1) as usize;
+    let mut lines = BufReader::new(MultiGzDecoder::new(compressed.as_slice())).lines();
+    let (mut count, mut skipped) = (0u64, 0u64);
+    loop {
+        if !operation.proceed().await {
+            return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "Restoring was cancelled"));
+        }
+        // Grown as documents arrive: the batch size is the caller's.
+        let mut docs = Vec::new();
+        for line in lines.by_ref().take(batch_size) {
+            let line = line.map_err(|e| errors::failed("Failed to read archive file", e))?;
+            let value: JsonValue = serde_json::from_str(&line).map_err(|e| errors::invalid("Failed to read archive file", e))?;
+            match Bson::try_from(value) {
+                Ok(Bson::Document(doc)) => docs.push(doc),
+                _ => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Failed to read archive file: a line is not a document")),
+            }
+        }
+        if docs.is_empty() {
+            break;
+        }
+        let (restored, existing) = match restore_batch(&target, docs).await {
+            Ok(outcome) => outcome,
+            Err(e) => return Err(errors::failed("Failed to restore documents", e)),
+        };
+        count += restored;
+        skipped += existing;
+        operation.progress(json!({ "count": count }));
+    }
+    let removed = skipped == 0 && tokio::fs::remove_file(path).await.is_ok();
+    Ok(json!({ "operationId": operation.id(), "count": count, "skipped": skipped, "removed": removed }))
+}
+
+/// Moves documents back from an archive collection, a batch per
+/// transaction. Documents already back in the collection stay archived.
+async fn unarchive_collection(ctx: &CommandContext, args: &UnarchiveArgs, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match &args.filter {
+        Some(filter) => match convert::from_extjson(filter) {
+            Ok(filter) => filter,
+            Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+        },
+        None => Document::new(),
+    };
+    let archive = ctx.db.collection::<Document>(&archive_collection(&args.collection, args.archive_collection.as_ref()));
+    let target = ctx.db.collection::<Document>(&args.collection);
+    let limit = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut session = match ctx.client.start_session(None).await {
+        Ok(session) => session,
+        Err(e) => return Err(errors::failed("Failed to start session", e)),
+    };
+    let (mut count, mut skipped) = (0u64, 0u64);
+    // Batches go in `_id` order, so the skipped documents left behind
+    // aren't read again.
+    let mut after: Option<Bson> = None;
+    'batches: loop {
+        if !operation.proceed().await {
+            return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "Restoring was cancelled"));
+        }
+        let page = match &after {
+            Some(after) => doc! { "$and": [filter.clone(), { "_id": { "$gt": after } }] },
+            None => filter.clone(),
+        };
+        for attempt in 1..=MAX_TRANSACTION_ATTEMPTS {
+            if let Err(e) = session.start_transaction(None).await {
+                return Err(errors::failed("Failed to start transaction", e));
+            }
+            let outcome: Result<Option<(u64, u64, Bson)>, MongoError> = async {
+                let options = FindOptions::builder().sort(doc! { "_id": 1 }).limit(limit).build();
+                let mut cursor = archive.find_with_session(page.clone(), options, &mut session).await?;
+                let docs: Vec<Document> = cursor.stream(&mut session).try_collect().await?;
+                let last = match docs.last().and_then(|doc| doc.get("_id")) {
+                    Some(last) => last.clone(),
+                    None => return Ok(None),
+                };
+                let ids: Vec<Bson> = docs.iter().filter_map(|doc| doc.get("_id").cloned()).collect();
+                let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+                let mut cursor = target.find_with_session(doc! { "_id": { "$in": ids } }, options, &mut session).await?;
+                let present: Vec<Document> = cursor.stream(&mut session).try_collect().await?;
+                let present: Vec<&Bson> = present.iter().filter_map(|doc| doc.get("_id")).collect();
+                let restoring: Vec<Document> = docs.into_iter().filter(|doc| doc.get("_id").is_none_or(|id| !present.contains(&id))).collect();
+                if !restoring.is_empty() {
+                    let ids: Vec<Bson> = restoring.iter().filter_map(|doc| doc.get("_id").cloned()).collect();
+                    target.insert_many_with_session(&restoring, None, &mut session).await?;
+                    archive.delete_many_with_session(doc! { "_id": { "$in": ids } }, None, &mut session).await?;
+                }
+                Ok(Some((restoring.len() as u64, present.len() as u64, last)))
+            }
+            .await;
+            let restored = match outcome {
+                Ok(restored) => restored,
+                Err(e) => {
+                    let _ = session.abort_transaction().await;
+                    if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS {
+                        continue;
+                    }
+                    return Err(errors::failed("Failed to restore documents", e));
+                }
+            };
+            let mut commit_attempts = 1;
+            let committed = loop {
+                match session.commit_transaction().await {
+                    Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && commit_attempts < MAX_TRANSACTION_ATTEMPTS => {
+                        commit_attempts += 1;
+                    }
+                    committed => break committed,
+                }
+            };
+            match (committed, restored) {
+                (Ok(()), None) => break 'batches,
+                (Ok(()), Some((restored, existing, last))) => {
+                    count += restored;
+                    skipped += existing;
+                    after = Some(last);
+                    operation.progress(json!({ "count": count }));
+                    continue 'batches;
+                }
+                (Err(e), _) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => continue,
+                (Err(e), _) => return Err(errors::failed("Failed to restore documents", e)),
+            }
+        }
+        return Err("Restoring did not succeed after retrying transient errors".into());
+    }
+    Ok(json!({ "operationId": operation.id(), "count": count, "skipped": skipped }))
+}
@@ -0,0 +1,135 @@
+//! An audit trail of the writes made to configured collections.
+//!
+//! Every write the plugin performs on an audited collection is recorded in
+//! its shadow collection, `<collection>_history`, one entry per document
+//! changed: who made it, when, which command, and the document before and
+//! after (see [`changes`](super::changes)). Audited inserts without an `_id`
+//! are given one by the plugin so their entries can name the document.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::error::Result as MongoResult;
+use mongodb::options::FindOptions;
+use mongodb::{ClientSession, Database};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashSet;
+
+use super::errors::{self, MongoPluginError};
+use super::changes::Change;
+use super::{coerce_id, CommandContext};
+
+const SHADOW_SUFFIX: &str = "_history";
+
+/// The `auditTrail` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AuditTrailConfig {
+    /// Collections whose writes are recorded.
+    pub collections: Vec<String>,
+}
+
+pub(super) struct AuditTrail {
+    collections: HashSet<String>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct GetDocumentHistoryArgs {
+    collection: String,
+    id: JsonValue,
+    limit: Option<i64>,
+}
+
+/// Who made a write: the window that invoked it and the app role at the time.
+pub(super) fn actor(window: &str, role: Option<&str>) -> JsonValue {
+    json!({ "window": window, "role": role })
+}
+
+fn shadow(collection: &str) -> String {
+    format!("{}{}", collection, SHADOW_SUFFIX)
+}
+
+impl AuditTrail {
+    pub(super) fn new(config: &AuditTrailConfig) -> Self {
+        Self { collections: config.collections.iter().cloned().collect() }
+    }
+
+    pub(super) fn audits(&self, collection: &str) -> bool {
+        self.collections.contains(collection)
+    }
+
+    /// Gives the documents of an audited insert an `_id` if they lack one.
+    pub(super) fn assign_ids(&self, command: &str, payload: &mut JsonValue) {
+        if !matches!(command, "insertOne" | "insertMany") {
+            return;
+        }
+        if !payload.get("collection").and_then(JsonValue::as_str).is_some_and(|collection| self.audits(collection)) {
+            return;
+        }
+        // Kept as JSON, so the rest of the text reaches the command unchanged.
+        let data = payload.get("data").and_then(JsonValue::as_str).map(serde_json::from_str::<JsonValue>);
+        let mut data = match data {
+            Some(Ok(data)) => data,
+            _ => return,
+        };
+        let docs = match &mut data {
+            JsonValue::Array(docs) => docs.iter_mut().collect(),
+            doc => vec![doc],
+        };
+        for doc in docs.into_iter().filter_map(JsonValue::as_object_mut) {
+            doc.entry("_id").or_insert_with(|| json!({ "$oid": ObjectId::new().to_hex() }));
+        }
+        payload["data"] = JsonValue::String(data.to_string());
+    }
+}
+
+/// Writes an entry for each change to the collection's shadow.
+pub(super) async fn record(
+    db: &Database,
+    collection: &str,
+    command: &str,
+    actor: &JsonValue,
+    changes: &[Change],
+    session: Option<&mut ClientSession>,
+) -> MongoResult<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let actor = Bson::try_from(actor.clone()).unwrap_or(Bson::Null);
+    let at = DateTime::now();
+    let entries = changes.iter().map(|change| {
+        doc! {
+            "documentId": change.id.clone(),
+            "operation": change.operation(),
+            "command": command,
+            "actor": actor.clone(),
+            "at": at,
+            "before": change.before.clone().map_or(Bson::Null, Bson::Document),
+            "after": change.after.clone().map_or(Bson::Null, Bson::Document),
+        }
+    });
+    let history = db.collection::<Document>(&shadow(collection));
+    match session {
+        Some(session) => history.insert_many_with_session(entries, None, session).await.map(|_| ()),
+        None => history.insert_many(entries, None).await.map(|_| ()),
+    }
+}
+
+/// A document's recorded history, newest entry first.
+pub(super) async fn get_document_history(ctx: CommandContext, args: GetDocumentHistoryArgs) -> Result<JsonValue, MongoPluginError> {
+    let history = ctx.db.collection::<Document>(&shadow(&args.collection));
+    let options = FindOptions::builder().sort(doc! { "at": -1, "_id": -1 }).limit(args.limit).build();
+    let cursor = match history.find(doc! { "documentId": coerce_id(&args.id)? }, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to read document history", e)),
+    };
+    let entries: Vec<Document> = match cursor.try_collect().await {
+        Ok(entries) => entries,
+        Err(e) => return Err(errors::failed("Failed to read document history", e)),
+    };
+    let mut entries = serde_json::to_value(entries).unwrap();
+    if let Some(encryption) = &ctx.transforms.encryption {
+        encryption.open_result(&mut entries);
+    }
+    Ok(entries)
+}
@@ -0,0 +1,529 @@
+//! Running several database commands in a single invoke, optionally inside
+//! one multi-document transaction.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::error::{Error as MongoError, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReplaceOptions, ReturnDocument, UpdateOptions};
+use mongodb::{ClientSession, Database};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{
+    changes, coerce_id, confirmations, convert, delete_result_json, events, execute, get_path, increment_amount, prepare, softdelete, trash,
+    update_result_json, CommandContext, DeleteArgs, DeleteByIdArgs, FindArgs, FindByIdArgs, IncrementFieldArgs, InsertManyArgs, InsertOneArgs,
+    MongoState, ReplaceOneArgs, UpdateArgs, UpdateByIdArgs, WriteTracking,
+};
+
+/// Attempts made for the whole transaction when the server reports a
+/// transient error, and for a commit whose outcome is unknown.
+const MAX_TRANSACTION_ATTEMPTS: u32 = 5;
+
+#[derive(Deserialize)]
+pub(super) struct BatchOperation {
+    command: String,
+    #[serde(default = "empty_args")]
+    args: JsonValue,
+}
+
+fn empty_args() -> JsonValue {
+    json!({})
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ExecuteBatchArgs {
+    operations: Vec<BatchOperation>,
+    stop_on_error: Option<bool>,
+}
+
+/// Runs the operations one after another, in order. Each entry of `results`
+/// is `{ ok, result }` or `{ ok, error }`; with `stopOnError` (the default)
+/// operations after the first failure are not run.
+pub(super) async fn execute_batch(ctx: CommandContext, args: ExecuteBatchArgs) -> Result<JsonValue, MongoPluginError> {
+    let stop_on_error = args.stop_on_error.unwrap_or(true);
+    let total = args.operations.len();
+
+    // Building the futures runs nothing yet, so bad names are rejected up front.
+    let mut tasks = Vec::with_capacity(total);
+    for (index, operation) in args.operations.into_iter().enumerate() {
+        if operation.command == "executeBatch" {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Operation {}: executeBatch cannot be nested", index)));
+        }
+        match execute(&ctx, &operation.command, operation.args) {
+            Some(task) => tasks.push(task),
+            None => {
+                let message = format!("Operation {}: unknown command '{}'", index, operation.command);
+                return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+            }
+        }
+    }
+
+    let mut results = Vec::with_capacity(total);
+    for task in tasks {
+        match task.await {
+            Ok(result) => results.push(json!({ "ok": true, "result": result })),
+            Err(error) => {
+                results.push(json!({ "ok": false, "error": errors::from_json(error) }));
+                if stop_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(json!({ "completed": results.len(), "total": total, "results": results }))
+}
+
+#[derive(Deserialize)]
+pub(super) struct ExecuteTransactionalBatchArgs {
+    operations: Vec<BatchOperation>,
+}
+
+pub(super) enum StepError {
+    /// The step's arguments are malformed or it can't run here.
+    Invalid(String),
+    /// A check the step failed, with a kind of its own.
+    Plugin(MongoPluginError),
+    Mongo(MongoError),
+}
+
+impl From<String> for StepError {
+    fn from(message: String) -> Self {
+        StepError::Invalid(message)
+    }
+}
+
+impl From<MongoPluginError> for StepError {
+    fn from(error: MongoPluginError) -> Self {
+        StepError::Plugin(error)
+    }
+}
+
+impl From<MongoError> for StepError {
+    fn from(error: MongoError) -> Self {
+        StepError::Mongo(error)
+    }
+}
+
+fn parse_args<A: DeserializeOwned>(args: JsonValue) -> Result<A, String> {
+    serde_json::from_value(args).map_err(|e| format!("Failed to parse arguments: {}", e))
+}
+
+fn parse_json<T: DeserializeOwned>(json: &str, what: &str) -> Result<T, String> {
+    convert::from_extjson(json).map_err(|e| format!("Failed to parse {}: {}", what, e))
+}
+
+/// Looks up a step reference such as `0.insertedId` or `1.insertedIds.2`:
+/// the index of an earlier step followed by a path into its result.
+fn lookup<'a>(reference: &str, results: &'a [JsonValue]) -> Result<&'a JsonValue, String> {
+    let (step, path) = reference.split_once('.').unwrap_or((reference, ""));
+    let step: usize = step.parse().map_err(|_| format!("Invalid placeholder '{{{{{}}}}}'", reference))?;
+    let result = results.get(step).ok_or_else(|| format!("Placeholder '{{{{{}}}}}' refers to a step that has not run", reference))?;
+    if path.is_empty() {
+        return Ok(result);
+    }
+    let pointer = format!("/{}", path.replace('.', "/"));
+    result.pointer(&pointer).ok_or_else(|| format!("Placeholder '{{{{{}}}}}' does not match the step's result", reference))
+}
+
+/// Replaces `"{{reference}}"` placeholders with the values `lookup` gives
+/// for them. A string argument that is exactly a placeholder becomes the
+/// referenced value; inside JSON-text arguments (`data`, `query`, ...) the
+/// quoted placeholder is replaced by the value's JSON.
+pub(super) fn resolve_placeholders<F>(value: JsonValue, lookup: &F) -> Result<JsonValue, String>
+where
+    F: Fn(&str) -> Result<JsonValue, String>,
+{
+    match value {
+        JsonValue::String(s) => {
+            if let Some(reference) = s.strip_prefix("{{").and_then(|rest| rest.strip_suffix("}}")) {
+                if !reference.contains("{{") {
+                    return lookup(reference);
+                }
+            }
+            // One pass from left to right, so a value that itself reads
+            // like a placeholder is inserted as is.
+            let mut text = String::with_capacity(s.len());
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("\"{{") {
+                let end = match rest[start..].find("}}\"") {
+                    Some(end) => start + end,
+                    None => break,
+                };
+                text.push_str(&rest[..start]);
+                text.push_str(&lookup(&rest[start + 3..end])?.to_string());
+                rest = &rest[end + 3..];
+            }
+            text.push_str(rest);
+            Ok(JsonValue::String(text))
+        }
+        JsonValue::Array(items) => items.into_iter().map(|item| resolve_placeholders(item, lookup)).collect(),
+        JsonValue::Object(map) => {
+            map.into_iter().map(|(key, value)| Ok((key, resolve_placeholders(value, lookup)?))).collect::<Result<_, String>>().map(JsonValue::Object)
+        }
+        other => Ok(other),
+    }
+}
+
+/// How a step's collection treats deletes: removing documents, stamping
+/// them with a soft-delete field, or moving them to the trash on behalf of
+/// an actor.
+#[derive(Clone, Copy)]
+pub(super) enum Deletes<'a> {
+    Hard,
+    Soft(&'a str),
+    Trash(&'a trash::Trash, &'a JsonValue),
+}
+
+/// Whether a step deletes documents, which on a trash collection needs
+/// the trash's index in place first.
+pub(super) fn is_delete(command: &str) -> bool {
+    matches!(command, "deleteById" | "deleteOne" | "deleteMany")
+}
+
+/// Runs one step on a session, the transaction's or one `startSession`
+/// opened. Only the commands listed here can run on a session; inserts
+/// report their ids so later steps can reference them.
+pub(super) async fn run_step(
+    db: &Database,
+    session: &mut ClientSession,
+    command: &str,
+    args: JsonValue,
+    deletes: Deletes<'_>,
+) -> Result<JsonValue, StepError> {
+    match command {
+        "insertOne" => {
+            let args: InsertOneArgs = parse_args(args)?;
+            let doc: Document = parse_json(&args.data, "document")?;
+            let coll = db.collection::<Document>(&args.collection);
+            let result = coll.insert_one_with_session(doc, None, session).await?;
+            Ok(json!({ "insertedId": serde_json::to_value(result.inserted_id).unwrap() }))
+        }
+        "insertMany" => {
+            let args: InsertManyArgs = parse_args(args)?;
+            let docs: Vec<Document> = parse_json(&args.data, "documents")?;
+            let coll = db.collection::<Document>(&args.collection);
+            let result = coll.insert_many_with_session(docs, None, session).await?;
+            let mut ids: Vec<_> = result.inserted_ids.into_iter().collect();
+            ids.sort_by_key(|(index, _)| *index);
+            let ids: Vec<_> = ids.into_iter().map(|(_, id)| serde_json::to_value(id).unwrap()).collect();
+            Ok(json!({ "insertedIds": ids }))
+        }
+        "findOne" => {
+            let args: FindArgs = parse_args(args)?;
+            let query: Document = parse_json(&args.query, "query")?;
+            let coll = db.collection::<Document>(&args.collection);
+            let result = coll.find_one_with_session(query, args.options.find_one(args.max_time_ms), session).await?;
+            Ok(convert::to_json(result))
+        }
+        "findById" => {
+            let include_deleted = args.get("includeDeleted").and_then(JsonValue::as_bool).unwrap_or(false);
+            let args: FindByIdArgs = parse_args(args)?;
+            let coll = db.collection::<Document>(&args.collection);
+            let id = coerce_id(&args.id)?;
+            let filter = match deletes {
+                Deletes::Soft(field) if !include_deleted => softdelete::live_by_id(id, field),
+                _ => doc! { "_id": id },
+            };
+            let result = coll.find_one_with_session(filter, None, session).await?;
+            Ok(convert::to_json(result))
+        }
+        "updateById" => {
+            let args: UpdateByIdArgs = parse_args(args)?;
+            let update: Document = parse_json(&args.update, "update")?;
+            let options = UpdateOptions::builder().upsert(args.upsert).build();
+            let coll = db.collection::<Document>(&args.collection);
+            let filter = doc! { "_id": coerce_id(&args.id)? };
+            let result = coll.update_one_with_session(filter, update, options, session).await?;
+            Ok(update_result_json(&result))
+        }
+        "deleteById" => {
+            let args: DeleteByIdArgs = parse_args(args)?;
+            let coll = db.collection::<Document>(&args.collection);
+            let id = coerce_id(&args.id)?;
+            match deletes {
+                Deletes::Soft(field) => {
+                    let (filter, update) = (softdelete::live_by_id(id, field), softdelete::stamp(field));
+                    let result = coll.update_one_with_session(filter, update, None, session).await?;
+                    Ok(json!({ "deletedCount": result.modified_count }))
+                }
+                Deletes::Trash(trash, actor) => Ok(trash.delete_with_session(db, &args.collection, id, actor, session).await?),
+                Deletes::Hard => {
+                    let result = coll.delete_one_with_session(doc! { "_id": id }, None, session).await?;
+                    Ok(delete_result_json(&result))
+                }
+            }
+        }
+        "updateOne" | "updateMany" => {
+            let args: UpdateArgs = parse_args(args)?;
+            let filter: Document = parse_json(&args.filter, "filter")?;
+            let update: Document = parse_json(&args.update, "update")?;
+            let options = UpdateOptions::builder().upsert(args.upsert).build();
+            let coll = db.collection::<Document>(&args.collection);
+            let result = if command == "updateMany" {
+                coll.update_many_with_session(filter, update, options, session).await?
+            } else {
+                coll.update_one_with_session(filter, update, options, session).await?
+            };
+            Ok(update_result_json(&result))
+        }
+        "replaceOne" => {
+            let args: ReplaceOneArgs = parse_args(args)?;
+            let filter: Document = parse_json(&args.filter, "filter")?;
+            let replacement: Document = parse_json(&args.replacement, "replacement")?;
+            let options = ReplaceOptions::builder().upsert(args.upsert).build();
+            let coll = db.collection::<Document>(&args.collection);
+            let result = coll.replace_one_with_session(filter, replacement, options, session).await?;
+            Ok(update_result_json(&result))
+        }
+        "deleteOne" | "deleteMany" => {
+            let many = command == "deleteMany";
+            let args: DeleteArgs = parse_args(args)?;
+            let coll = db.collection::<Document>(&args.collection);
+            match deletes {
+                Deletes::Soft(field) => {
+                    let (filter, update) = (softdelete::live(&args.filter, field)?, softdelete::stamp(field));
+                    let result = if many {
+                        coll.update_many_with_session(filter, update, None, session).await?
+                    } else {
+                        coll.update_one_with_session(filter, update, None, session).await?
+                    };
+                    Ok(json!({ "deletedCount": result.modified_count }))
+                }
+                Deletes::Trash(trash, actor) => {
+                    let filter: Document = parse_json(&args.filter, "filter")?;
+                    let options = FindOptions::builder().projection(doc! { "_id": 1 }).limit((!many).then_some(1)).build();
+                    let mut cursor = coll.find_with_session(filter, options, &mut *session).await?;
+                    let matched: Vec<Document> = cursor.stream(&mut *session).try_collect().await?;
+                    let mut deleted = 0;
+                    for id in matched.into_iter().filter_map(|doc| doc.get("_id").cloned()) {
+                        let result = trash.delete_with_session(db, &args.collection, id, actor, session).await?;
+                        deleted += result["deletedCount"].as_u64().unwrap_or(0);
+                    }
+                    Ok(json!({ "deletedCount": deleted }))
+                }
+                Deletes::Hard => {
+                    let filter: Document = parse_json(&args.filter, "filter")?;
+                    let result = if many {
+                        coll.delete_many_with_session(filter, None, session).await?
+                    } else {
+                        coll.delete_one_with_session(filter, None, session).await?
+                    };
+                    Ok(delete_result_json(&result))
+                }
+            }
+        }
+        "incrementField" => {
+            let args: IncrementFieldArgs = parse_args(args)?;
+            let filter: Document = parse_json(&args.filter, "filter")?;
+            let amount = increment_amount(args.amount.as_ref())?;
+            let options = FindOneAndUpdateOptions::builder().upsert(args.upsert).return_document(ReturnDocument::After).build();
+            let coll = db.collection::<Document>(&args.collection);
+            let update = doc! { "$inc": { args.field.as_str(): amount } };
+            match coll.find_one_and_update_with_session(filter, update, options, session).await? {
+                Some(doc) => Ok(convert::to_json(get_path(&doc, &args.field).cloned())),
+                None => Err(MongoPluginError::new(errors::ErrorKind::NotFound, "No document matches the filter").into()),
+            }
+        }
+        other => Err(format!("'{}' cannot run inside a transaction or on a session", other).into()),
+    }
+}
+
+/// Runs a step, recording its changes inside the same transaction when
+/// `target` says its collection is tracked.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn tracked_step(
+    db: &Database,
+    session: &mut ClientSession,
+    command: &str,
+    args: JsonValue,
+    deletes: Deletes<'_>,
+    tracking: &WriteTracking,
+    actor: &JsonValue,
+    target: Option<changes::Target>,
+) -> Result<JsonValue, StepError> {
+    let (target, collection) = match (target, args.get("collection").and_then(JsonValue::as_str)) {
+        (Some(target), Some(collection)) => (target, collection.to_string()),
+        _ => return run_step(db, session, command, args, deletes).await,
+    };
+    let snapshot = changes::Snapshot::take(db, &collection, target, Some(&mut *session)).await?;
+    let result = run_step(db, session, command, args, deletes).await?;
+    let changes = snapshot.changes(db, &result, Some(&mut *session)).await?;
+    tracking.record(db, &collection, command, actor, &changes, Some(session)).await?;
+    Ok(result)
+}
+
+/// A failed step's message.
+pub(super) fn step_message(error: StepError) -> MongoPluginError {
+    match error {
+        StepError::Invalid(message) => MongoPluginError::new(errors::ErrorKind::InvalidArgument, message),
+        StepError::Plugin(e) => e,
+        StepError::Mongo(e) => e.into(),
+    }
+}
+
+fn step_error(index: usize, error: StepError) -> MongoPluginError {
+    match error {
+        StepError::Invalid(message) => MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Operation {}: {}", index, message)),
+        StepError::Plugin(mut e) => {
+            e.message = format!("Operation {}: {}", index, e.message);
+            e
+        }
+        StepError::Mongo(e) => MongoPluginError::mongo(&format!("Operation {}", index), &e),
+    }
+}
+
+/// Runs the operations in order inside one transaction: either every write
+/// commits or none does. The whole sequence is retried when the server
+/// reports a transient transaction error, and `{{step.path}}` placeholders
+/// let a step use an earlier step's result, e.g. `{{0.insertedId}}`. `mongo://write`
+/// events for the steps are emitted only once the transaction commits, while
+/// audit entries and prior versions are written inside the transaction.
+pub(super) async fn execute_transactional_batch<R: Runtime>(
+    app: AppHandle<R>,
+    actor: JsonValue,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: ExecuteTransactionalBatchArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let mut ctx = state.context(connection.as_deref(), tenant.as_deref())?;
+    ctx.actor = actor.clone();
+    let CommandContext { client, db, counts, transforms, soft_delete, tracking, trash, .. } = ctx.clone();
+    let confirming = ctx.confirmations.is_some();
+    if let Some(trash) = &trash {
+        // Index creation can't run inside the transaction.
+        if args.operations.iter().any(|operation| is_delete(&operation.command) && trash.trashes(&operation.args)) {
+            trash.ensure_ttl(&db).await?;
+        }
+    }
+    let mut session = match client.start_session(None).await {
+        Ok(session) => session,
+        Err(e) => return Err(errors::failed("Failed to start session", e)),
+    };
+
+    'attempts: for attempt in 1..=MAX_TRANSACTION_ATTEMPTS {
+        if let Err(e) = session.start_transaction(None).await {
+            return Err(errors::failed("Failed to start transaction", e));
+        }
+
+        let mut results = Vec::with_capacity(args.operations.len());
+        let mut writes = Vec::new();
+        for (index, operation) in args.operations.iter().enumerate() {
+            let step_lookup = |reference: &str| lookup(reference, &results).cloned();
+            // A step's `timeoutMs` only reaches the server, as its `maxTimeMS`:
+            // the plugin can't give up on a step midway through the transaction.
+            let resolved = resolve_placeholders(operation.args.clone(), &step_lookup).map_err(StepError::from).and_then(|mut step_args| {
+                if confirming && confirmations::destructive(&operation.command, &step_args) {
+                    return Err(format!("{} without a filter needs confirming and can't run in a transaction", operation.command).into());
+                }
+                let prepared = prepare(&ctx, &operation.command, &mut step_args)?;
+                Ok((step_args, prepared.precheck))
+            });
+            let step_args = match resolved {
+                Ok((step_args, None)) => Ok(step_args),
+                Ok((step_args, Some(precheck))) => precheck.await.map(|_| step_args).map_err(StepError::from),
+                Err(e) => Err(e),
+            };
+            let step_args = match step_args {
+                Ok(step_args) => step_args,
+                Err(e) => {
+                    let _ = session.abort_transaction().await;
+                    return Err(step_error(index, e));
+                }
+            };
+            let collection = step_args.get("collection").and_then(JsonValue::as_str).map(str::to_string);
+            let soft_field = soft_delete.as_ref().and_then(|soft_delete| soft_delete.field_for(&step_args)).map(str::to_string);
+            let target = tracking.target(&operation.command, &step_args, soft_field.as_deref());
+            let deletes = match (&soft_field, &trash) {
+                (Some(field), _) => Deletes::Soft(field),
+                (None, Some(trash)) if trash.trashes(&step_args) => Deletes::Trash(trash, &actor),
+                _ => Deletes::Hard,
+            };
+            let outcome = tracked_step(&db, &mut session, &operation.command, step_args, deletes, &tracking, &actor, target);
+            match outcome.await {
+                Ok(mut result) => {
+                    transforms.finish(&operation.command, collection.as_deref(), &mut result);
+                    writes.extend(events::write_event(&operation.command, db.name(), collection.as_deref()));
+                    results.push(result);
+                }
+                Err(StepError::Mongo(e)) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    let _ = session.abort_transaction().await;
+                    continue 'attempts;
+                }
+                Err(e) => {
+                    let _ = session.abort_transaction().await;
+                    return Err(step_error(index, e));
+                }
+            }
+        }
+
+        let mut commit_attempts = 1;
+        loop {
+            match session.commit_transaction().await {
+                Ok(()) => {
+                    for event in writes {
+                        counts.invalidate(db.name(), event["collection"].as_str().unwrap_or_default());
+                        state.cursors.written(&state.events, &event);
+                        (state.events)(events::WRITE_EVENT, event);
+                    }
+                    return Ok(json!({ "results": results, "attempts": attempt }));
+                }
+                Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && commit_attempts < MAX_TRANSACTION_ATTEMPTS => {
+                    commit_attempts += 1;
+                }
+                Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    continue 'attempts;
+                }
+                Err(e) => return Err(errors::failed("Failed to commit transaction", e)),
+            }
+        }
+    }
+    Err("Transaction did not succeed after retrying transient errors".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(value: JsonValue, results: &[JsonValue]) -> Result<JsonValue, String> {
+        resolve_placeholders(value, &|reference: &str| lookup(reference, results).cloned())
+    }
+
+    #[test]
+    fn whole_string_placeholder_becomes_the_value() {
+        let results = [json!({ "insertedId": { "$oid": "64b7f0c2a1b2c3d4e5f60718" } })];
+        assert_eq!(resolve(json!({ "id": "{{0.insertedId}}" }), &results), Ok(json!({ "id": { "$oid": "64b7f0c2a1b2c3d4e5f60718" } })));
+    }
+
+    #[test]
+    fn json_text_placeholder_is_replaced_by_the_value_json() {
+        let results = [json!({ "insertedIds": [1, 2, 3] })];
+        let query = json!({ "query": r#"{"a": "{{0.insertedIds.2}}", "b": "{{0.insertedIds.0}}"}"# });
+        assert_eq!(resolve(query, &results), Ok(json!({ "query": r#"{"a": 3, "b": 1}"# })));
+    }
+
+    #[test]
+    fn nested_paths_reach_into_the_result() {
+        let results = [json!({ "upserted": { "doc": { "owner": { "name": "ada" } } } })];
+        assert_eq!(resolve(json!("{{0.upserted.doc.owner.name}}"), &results), Ok(json!("ada")));
+    }
+
+    #[test]
+    fn a_value_reading_like_a_placeholder_is_not_resolved_again() {
+        let results = [json!({ "name": "\"{{0.name}}\"" })];
+        let data = json!({ "data": r#"{"copy": "{{0.name}}"}"# });
+        assert_eq!(resolve(data, &results), Ok(json!({ "data": r#"{"copy": "\"{{0.name}}\""}"# })));
+    }
+
+    #[test]
+    fn missing_steps_and_paths_are_errors() {
+        let results = [json!({ "insertedId": 1 })];
+        assert!(resolve(json!("{{1.insertedId}}"), &results).unwrap_err().contains("has not run"));
+        assert!(resolve(json!("{{0.missing}}"), &results).unwrap_err().contains("does not match"));
+        assert!(resolve(json!("{{x}}"), &results).unwrap_err().contains("Invalid placeholder"));
+    }
+}
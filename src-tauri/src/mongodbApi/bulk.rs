@@ -0,0 +1,126 @@
+//! Updating every document matching a filter in `_id`-ordered batches.
+//!
+//! `updateManyWithProgress` walks the matching `_id`s in ascending order,
+//! `batchSize` (1000 by default) at a time, updating each batch with an
+//! `$in` on those ids, so no single write holds the collection for long.
+//! It runs as a `bulkUpdate` [operation](super::operations), reporting
+//! `{ processed, matched, modified, total }` after each batch, where `total`
+//! is the count taken before starting, and checking for a pause or cancel
+//! between batches; whatever was updated stays updated. The command returns
+//! a summary once it stops. A batch rejected because the primary stepped
+//! down is sent again once a new one is elected.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::time::{Duration, Instant};
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::{convert, retry, topology, CommandContext};
+
+const DEFAULT_BATCH_SIZE: i64 = 1000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct UpdateManyWithProgressArgs {
+    collection: String,
+    filter: String,
+    update: String,
+    batch_size: Option<i64>,
+    /// Lets the caller name the operation up front, to pause or cancel it
+    /// before the command returns.
+    operation_id: Option<String>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+pub(super) async fn update_many_with_progress(ctx: CommandContext, args: UpdateManyWithProgressArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let update: Document = match convert::from_extjson(&args.update) {
+        Ok(update) => update,
+        Err(e) => return Err(errors::invalid("Failed to parse update", e)),
+    };
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "bulkUpdate", args.operation_id)?;
+    let outcome = run(&ctx, &args.collection, filter, update, batch_size, args.max_time_ms, &mut operation).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn run(
+    ctx: &CommandContext,
+    collection: &str,
+    filter: Document,
+    update: Document,
+    batch_size: i64,
+    max_time_ms: Option<u64>,
+    operation: &mut Operation,
+) -> Result<JsonValue, MongoPluginError> {
+    let coll = ctx.db.collection::<Document>(collection);
+    let started = Instant::now();
+    let total = coll.count_documents(filter.clone(), None).await.ok();
+    let (mut processed, mut matched, mut modified, mut batches): (u64, u64, u64, u64) = (0, 0, 0, 0);
+    let mut last_id: Option<Bson> = None;
+    let cancelled = loop {
+        if !operation.proceed().await {
+            break true;
+        }
+        let mut scope = filter.clone();
+        if let Some(last_id) = &last_id {
+            scope = doc! { "$and": [scope, { "_id": { "$gt": last_id } }] };
+        }
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .sort(doc! { "_id": 1 })
+            .limit(batch_size)
+            .max_time(max_time_ms.map(Duration::from_millis))
+            .build();
+        let ids: Vec<Bson> = match coll.find(scope, options).await {
+            Ok(cursor) => match cursor.try_collect::<Vec<Document>>().await {
+                Ok(docs) => docs.into_iter().filter_map(|doc| doc.get("_id").cloned()).collect(),
+                Err(e) => return Err(errors::failed(&format!("Failed to read batch {}", batches), e)),
+            },
+            Err(e) => return Err(errors::failed(&format!("Failed to read batch {}", batches), e)),
+        };
+        let last = match ids.last() {
+            Some(last) => last.clone(),
+            None => break false,
+        };
+        // Documents that stopped matching since they were read are left alone.
+        let batch = doc! { "$and": [filter.clone(), { "_id": { "$in": &ids } }] };
+        let mut result = coll.update_many(batch.clone(), update.clone(), None).await;
+        if let Err(e) = &result {
+            if retry::unsent_for_election(&json!(e.to_string())) && ctx.topology.wait_for_primary(topology::FAILOVER_WAIT).await {
+                result = coll.update_many(batch, update.clone(), None).await;
+            }
+        }
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => return Err(errors::failed(&format!("Failed to update batch {}", batches), e)),
+        };
+        processed += ids.len() as u64;
+        matched += result.matched_count;
+        modified += result.modified_count;
+        batches += 1;
+        last_id = Some(last);
+        operation.progress(json!({ "processed": processed, "matched": matched, "modified": modified, "total": total }));
+        if (ids.len() as i64) < batch_size {
+            break false;
+        }
+    };
+    Ok(json!({
+        "operationId": operation.id(),
+        "processed": processed,
+        "matchedCount": matched,
+        "modifiedCount": modified,
+        "batches": batches,
+        "cancelled": cancelled,
+        "durationMs": started.elapsed().as_millis() as u64,
+    }))
+}
@@ -0,0 +1,316 @@
+//! Mixed writes to one collection in as few round trips as possible.
+//!
+//! `bulkWrite` takes `operations` as JSON text in the shell's form:
+//! `{ insertOne: { document } }`, `{ updateOne | updateMany: { filter,
+//! update, upsert } }`, `{ replaceOne: { filter, replacement, upsert } }`
+//! and `{ deleteOne | deleteMany: { filter } }`. Runs of the same kind of
+//! write go to the server as one `insert`, `update` or `delete` command.
+//! Ordered, the default, stops at the first failed operation; unordered
+//! groups all writes of a kind together and attempts every operation.
+//!
+//! The reply counts what was inserted, matched, modified, deleted and
+//! upserted, lists the `writeErrors` by operation index and gives each
+//! operation a `status` of `ok`, `failed` or `skipped`, with the
+//! `insertedId` or `upsertedId` it produced.
+//!
+//! Its writes go to the server as they are, so deletes are refused on
+//! soft-delete and trash collections, and updates, replacements and
+//! deletes on audited and versioned ones, where the single-document
+//! commands keep what they need of each write.
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::ClientSession;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use super::errors::{self, MongoPluginError};
+use super::{concerns, convert, numeric_field, CommandContext};
+
+/// Statements sent per command, well under the server's batch limits.
+const BATCH: usize = 1000;
+
+#[derive(Deserialize)]
+pub(super) struct BulkWriteArgs {
+    collection: String,
+    operations: String,
+    ordered: Option<bool>,
+}
+
+/// What a document in a bulk write's operations will be written as.
+pub(super) enum Written {
+    Inserted,
+    Replacement,
+    Update,
+}
+
+/// Runs `rewrite` on every inserted document, replacement and update
+/// document of the `bulkWrite` in `payload`, and puts the result back.
+/// Update pipelines are left alone.
+pub(super) fn rewrite_documents<F>(payload: &mut JsonValue, mut rewrite: F) -> Result<(), String>
+where
+    F: FnMut(Written, &mut Document) -> Result<(), String>,
+{
+    let mut operations = match payload.get("operations").and_then(JsonValue::as_str).map(convert::from_extjson::<Vec<Document>>) {
+        Some(Ok(operations)) => operations,
+        // Malformed arguments are reported by the command itself.
+        _ => return Ok(()),
+    };
+    for operation in operations.iter_mut() {
+        let (name, spec) = match operation.iter_mut().next() {
+            Some((name, Bson::Document(spec))) => (name.as_str(), spec),
+            _ => continue,
+        };
+        let (key, written) = match name {
+            "insertOne" => ("document", Written::Inserted),
+            "replaceOne" => ("replacement", Written::Replacement),
+            "updateOne" | "updateMany" => ("update", Written::Update),
+            _ => continue,
+        };
+        if let Ok(doc) = spec.get_document_mut(key) {
+            rewrite(written, doc)?;
+        }
+    }
+    let operations = operations.into_iter().map(Bson::Document).collect();
+    payload["operations"] = JsonValue::String(convert::to_extjson_text(Bson::Array(operations)));
+    Ok(())
+}
+
+/// The operations of the `bulkWrite` in `payload`, none if they don't parse.
+pub(super) fn operations_of(payload: &JsonValue) -> Vec<Document> {
+    match payload.get("operations").and_then(JsonValue::as_str).map(convert::from_extjson::<Vec<Document>>) {
+        Some(Ok(operations)) => operations,
+        // Malformed arguments are reported by the command itself.
+        _ => Vec::new(),
+    }
+}
+
+/// Refuses the operations of a `bulkWrite` that would bypass the soft
+/// deletes, trash, audit trail or versions of its collection.
+pub(super) fn check_kept(ctx: &CommandContext, command: &str, payload: &JsonValue) -> Result<(), MongoPluginError> {
+    let collection = match (command, payload.get("collection").and_then(JsonValue::as_str)) {
+        ("bulkWrite", Some(collection)) => collection,
+        _ => return Ok(()),
+    };
+    let deletes_kept = ctx.soft_delete.as_ref().is_some_and(|soft_delete| soft_delete.field_for(payload).is_some())
+        || ctx.trash.as_ref().is_some_and(|trash| trash.trashes(payload));
+    let writes_kept = ctx.tracking.audit.as_ref().is_some_and(|audit| audit.audits(collection))
+        || ctx.tracking.versioning.as_ref().is_some_and(|versioning| versioning.versions(command, collection));
+    for (index, operation) in operations_of(payload).iter().enumerate() {
+        let name = operation.keys().next().map(String::as_str).unwrap_or_default();
+        let refused = match name {
+            "deleteOne" | "deleteMany" => deletes_kept || writes_kept,
+            "updateOne" | "updateMany" | "replaceOne" => writes_kept,
+            _ => false,
+        };
+        if refused {
+            let message = format!(
+                "bulkWrite operation {} can't {} in '{}', which keeps a record of each write; send it as its own command instead",
+                index, name, collection
+            );
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// One operation as the statement its command sends.
+struct Statement {
+    index: usize,
+    kind: Kind,
+    body: Document,
+}
+
+fn spec_document(spec: &Document, key: &str, index: usize) -> Result<Document, MongoPluginError> {
+    match spec.get_document(key) {
+        Ok(doc) => Ok(doc.clone()),
+        Err(_) => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Operation {} needs a '{}' document", index, key))),
+    }
+}
+
+fn statement(ctx: &CommandContext, collection: &str, index: usize, operation: &Document) -> Result<Statement, MongoPluginError> {
+    let (name, spec) = match operation.iter().next() {
+        Some((name, Bson::Document(spec))) if operation.len() == 1 => (name.as_str(), spec),
+        _ => {
+            let message = format!("Operation {} must be an object with one operation name", index);
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+    };
+    let upsert = spec.get_bool("upsert").unwrap_or(false);
+    let (kind, body) = match name {
+        "insertOne" => {
+            let mut document = spec_document(spec, "document", index)?;
+            if !document.contains_key("_id") {
+                // Needed up front to report it as the operation's insertedId.
+                let mut with_id = doc! { "_id": ctx.ids.id_for(collection) };
+                with_id.extend(document);
+                document = with_id;
+            }
+            (Kind::Insert, document)
+        }
+        "updateOne" | "updateMany" => {
+            let update = match spec.get("update") {
+                Some(update @ (Bson::Document(_) | Bson::Array(_))) => update.clone(),
+                _ => {
+                    let message = format!("Operation {} needs an 'update' document or pipeline", index);
+                    return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+                }
+            };
+            let filter = spec_document(spec, "filter", index)?;
+            (Kind::Update, doc! { "q": filter, "u": update, "upsert": upsert, "multi": name == "updateMany" })
+        }
+        "replaceOne" => {
+            let replacement = spec_document(spec, "replacement", index)?;
+            if replacement.keys().any(|key| key.starts_with('$')) {
+                let message = format!("Operation {}'s replacement must not contain update operators", index);
+                return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+            }
+            let filter = spec_document(spec, "filter", index)?;
+            (Kind::Update, doc! { "q": filter, "u": replacement, "upsert": upsert, "multi": false })
+        }
+        "deleteOne" | "deleteMany" => {
+            let filter = spec_document(spec, "filter", index)?;
+            let limit = if name == "deleteMany" { 0 } else { 1 };
+            (Kind::Delete, doc! { "q": filter, "limit": limit })
+        }
+        other => {
+            let message = format!("Operation {} has an unknown name '{}'", index, other);
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+    };
+    Ok(Statement { index, kind, body })
+}
+
+/// The statements grouped into the commands that send them: runs of one
+/// kind when ordered, every statement of a kind otherwise.
+fn batches(statements: &[Statement], ordered: bool) -> Vec<Vec<&Statement>> {
+    let mut groups: Vec<Vec<&Statement>> = Vec::new();
+    if ordered {
+        for statement in statements {
+            match groups.last_mut() {
+                Some(group) if group[0].kind == statement.kind => group.push(statement),
+                _ => groups.push(vec![statement]),
+            }
+        }
+    } else {
+        for kind in [Kind::Insert, Kind::Update, Kind::Delete] {
+            let group: Vec<&Statement> = statements.iter().filter(|statement| statement.kind == kind).collect();
+            if !group.is_empty() {
+                groups.push(group);
+            }
+        }
+    }
+    groups.into_iter().flat_map(|group| group.chunks(BATCH).map(<[_]>::to_vec).collect::<Vec<_>>()).collect()
+}
+
+pub(super) async fn bulk_write(ctx: CommandContext, args: BulkWriteArgs) -> Result<JsonValue, MongoPluginError> {
+    write(&ctx, args, None).await
+}
+
+/// Runs the bulk write, on `session` when given one.
+pub(super) async fn write(ctx: &CommandContext, args: BulkWriteArgs, mut session: Option<&mut ClientSession>) -> Result<JsonValue, MongoPluginError> {
+    let operations: Vec<Document> = match convert::from_extjson(&args.operations) {
+        Ok(operations) => operations,
+        Err(e) => return Err(errors::invalid("Failed to parse operations", e)),
+    };
+    if operations.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "operations must list at least one operation"));
+    }
+    let ordered = args.ordered.unwrap_or(true);
+    let statements =
+        operations.iter().enumerate().map(|(index, operation)| statement(ctx, &args.collection, index, operation)).collect::<Result<Vec<_>, _>>()?;
+
+    let mut results: Vec<JsonValue> = statements
+        .iter()
+        .map(|statement| match statement.kind {
+            Kind::Insert => json!({ "status": "skipped", "insertedId": convert::to_json(statement.body.get("_id").cloned()) }),
+            _ => json!({ "status": "skipped" }),
+        })
+        .collect();
+    let (mut inserted, mut matched, mut modified, mut deleted, mut upserted_count) = (0i64, 0i64, 0i64, 0i64, 0i64);
+    let mut write_errors = Vec::new();
+    let mut write_concern_errors = Vec::new();
+    for batch in batches(&statements, ordered) {
+        let bodies: Vec<Document> = batch.iter().map(|statement| statement.body.clone()).collect();
+        let mut command = match batch[0].kind {
+            Kind::Insert => doc! { "insert": &args.collection, "documents": bodies, "ordered": ordered },
+            Kind::Update => doc! { "update": &args.collection, "updates": bodies, "ordered": ordered },
+            Kind::Delete => doc! { "delete": &args.collection, "deletes": bodies, "ordered": ordered },
+        };
+        // Inside a transaction the write concern is the transaction's.
+        if let (Some(concern), None) = (concerns::write_concern_field(&ctx.db), &session) {
+            command.insert("writeConcern", concern);
+        }
+        let reply = match session.as_deref_mut() {
+            Some(session) => ctx.db.run_command_with_session(command, None, session).await,
+            None => ctx.db.run_command(command, None).await,
+        };
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => return Err(errors::failed("Failed to run bulk write", e)),
+        };
+        let upserted = reply.get_array("upserted").map(|u| u.as_slice()).unwrap_or_default();
+        match batch[0].kind {
+            Kind::Insert => inserted += numeric_field(&reply, "n"),
+            Kind::Update => {
+                matched += numeric_field(&reply, "n") - upserted.len() as i64;
+                modified += numeric_field(&reply, "nModified");
+                upserted_count += upserted.len() as i64;
+            }
+            Kind::Delete => deleted += numeric_field(&reply, "n"),
+        }
+        let errors: Vec<&Document> = reply.get_array("writeErrors").map(|e| e.iter().filter_map(Bson::as_document).collect()).unwrap_or_default();
+        // An ordered batch stops at its first error; the rest stay skipped.
+        let attempted = match (ordered, errors.first()) {
+            (true, Some(error)) => numeric_field(error, "index") as usize + 1,
+            _ => batch.len(),
+        };
+        for statement in &batch[..attempted.min(batch.len())] {
+            results[statement.index]["status"] = json!("ok");
+        }
+        for item in upserted.iter().filter_map(Bson::as_document) {
+            if let Some(statement) = batch.get(numeric_field(item, "index") as usize) {
+                results[statement.index]["upsertedId"] = convert::to_json(item.get("_id").cloned());
+            }
+        }
+        for error in &errors {
+            let statement = match batch.get(numeric_field(error, "index") as usize) {
+                Some(statement) => statement,
+                None => continue,
+            };
+            results[statement.index]["status"] = json!("failed");
+            write_errors.push(json!({
+                "index": statement.index,
+                "code": numeric_field(error, "code"),
+                "message": error.get_str("errmsg").unwrap_or_default(),
+            }));
+        }
+        if let Ok(error) = reply.get_document("writeConcernError") {
+            write_concern_errors.push(json!({
+                "code": numeric_field(error, "code"),
+                "message": error.get_str("errmsg").unwrap_or_default(),
+            }));
+        }
+        if ordered && !write_errors.is_empty() {
+            break;
+        }
+    }
+    write_errors.sort_by_key(|error| error["index"].as_u64());
+
+    Ok(json!({
+        "insertedCount": inserted,
+        "matchedCount": matched,
+        "modifiedCount": modified,
+        "deletedCount": deleted,
+        "upsertedCount": upserted_count,
+        "results": results,
+        "writeErrors": write_errors,
+        "writeConcernErrors": write_concern_errors,
+    }))
+}
@@ -0,0 +1,158 @@
+//! The documents a write changed, read just before and just after it runs.
+//!
+//! The audit trail and document versioning both work from these changes.
+//! Outside a transaction a concurrent write in between the two reads can
+//! show up in them.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::Result as MongoResult;
+use mongodb::options::FindOptions;
+use mongodb::{ClientSession, Collection, Database};
+use serde_json::Value as JsonValue;
+use std::collections::HashSet;
+
+use super::{coerce_id, convert, get_path};
+
+/// The documents a write can touch, as known before it runs.
+pub(super) enum Target {
+    Ids(Vec<Bson>),
+    Filter { filter: Document, many: bool },
+}
+
+/// The documents a write is about to touch, as they were before it ran.
+pub(super) struct Snapshot {
+    collection: String,
+    target: Target,
+    before: Vec<Document>,
+}
+
+/// One document changed by a write. `before` is `None` for an insert and
+/// `after` is `None` for a delete.
+pub(super) struct Change {
+    pub(super) id: Bson,
+    pub(super) before: Option<Document>,
+    pub(super) after: Option<Document>,
+}
+
+impl Change {
+    pub(super) fn operation(&self) -> &'static str {
+        match (&self.before, &self.after) {
+            (None, _) => "insert",
+            (_, None) => "delete",
+            _ => "update",
+        }
+    }
+}
+
+pub(super) fn parse_text<T: serde::de::DeserializeOwned>(payload: &JsonValue, key: &str) -> Option<T> {
+    convert::from_extjson(payload.get(key)?.as_str()?).ok()
+}
+
+fn id_of(doc: &Document) -> Option<Bson> {
+    doc.get("_id").cloned()
+}
+
+async fn read(coll: &Collection<Document>, filter: Document, limit: Option<i64>, session: Option<&mut ClientSession>) -> MongoResult<Vec<Document>> {
+    let options = FindOptions::builder().limit(limit).build();
+    match session {
+        Some(session) => {
+            let mut cursor = coll.find_with_session(filter, options, &mut *session).await?;
+            cursor.stream(session).try_collect().await
+        }
+        None => coll.find(filter, options).await?.try_collect().await,
+    }
+}
+
+/// What a write will touch, or `None` when `command` doesn't write
+/// documents. `soft_field` is the deletion field of a soft-delete
+/// collection, which `purge` selects on.
+pub(super) fn target(command: &str, payload: &JsonValue, soft_field: Option<&str>) -> Option<Target> {
+    let by_id = || coerce_id(payload.get("id")?).ok().map(|id| Target::Ids(vec![id]));
+    let by_filter = |many: bool| parse_text(payload, "filter").map(|filter| Target::Filter { filter, many });
+    let many = payload.get("many").and_then(JsonValue::as_bool).unwrap_or(false);
+    match command {
+        "insertOne" => parse_text::<Document>(payload, "data").map(|doc| Target::Ids(id_of(&doc).into_iter().collect())),
+        "insertMany" => {
+            let docs: Vec<Document> = parse_text(payload, "data")?;
+            Some(Target::Ids(docs.iter().filter_map(id_of).collect()))
+        }
+        "updateById" | "deleteById" | "restore" | "revertToVersion" | "restoreFromTrash" => by_id(),
+        "incrementField" | "updateWithVersion" | "updateOne" | "replaceOne" | "findOneAndUpdate" => by_filter(false),
+        "deleteOne" | "findOneAndDelete" => by_filter(false),
+        "updateMany" | "deleteMany" => by_filter(true),
+        "pushToArray" | "pullFromArray" => by_filter(many),
+        "upsertMany" => {
+            let docs: Vec<Document> = parse_text(payload, "documents")?;
+            let keys: Vec<String> = serde_json::from_value(payload.get("keyFields")?.clone()).ok()?;
+            if keys.is_empty() {
+                return None;
+            }
+            // A document missing a key field fails the whole command.
+            let matches: Vec<Document> = docs
+                .iter()
+                .map(|doc| keys.iter().map(|key| Some((key.clone(), get_path(doc, key)?.clone()))).collect())
+                .collect::<Option<_>>()?;
+            if matches.is_empty() {
+                return Some(Target::Ids(Vec::new()));
+            }
+            Some(Target::Filter { filter: doc! { "$or": matches }, many: true })
+        }
+        "purge" => match (payload.get("id"), soft_field) {
+            (Some(_), _) => by_id(),
+            (None, Some(field)) => Some(Target::Filter { filter: doc! { field: { "$ne": Bson::Null } }, many: true }),
+            (None, None) => None,
+        },
+        _ => None,
+    }
+}
+
+impl Snapshot {
+    /// Reads the documents `target` selects before the write runs.
+    pub(super) async fn take(db: &Database, collection: &str, target: Target, session: Option<&mut ClientSession>) -> MongoResult<Self> {
+        let coll = db.collection::<Document>(collection);
+        let before = match &target {
+            Target::Ids(ids) if ids.is_empty() => Vec::new(),
+            Target::Ids(ids) => read(&coll, doc! { "_id": { "$in": ids } }, None, session).await?,
+            Target::Filter { filter, many } => read(&coll, filter.clone(), (!many).then_some(1), session).await?,
+        };
+        Ok(Self { collection: collection.to_string(), target, before })
+    }
+
+    /// Reads the touched documents again after the write, given its result,
+    /// and returns those that changed.
+    pub(super) async fn changes(self, db: &Database, result: &JsonValue, mut session: Option<&mut ClientSession>) -> MongoResult<Vec<Change>> {
+        let coll = db.collection::<Document>(&self.collection);
+        let mut ids: Vec<Bson> = self.before.iter().filter_map(id_of).collect();
+        if let Target::Ids(targets) = &self.target {
+            ids.extend(targets.iter().cloned());
+        }
+        let upserted = result.get("upsertedIds").and_then(JsonValue::as_array).into_iter().flatten().filter_map(|item| item.get("_id"));
+        for id in result.get("upsertedId").into_iter().chain(upserted) {
+            ids.extend(Bson::try_from(id.clone()).ok().filter(|id| *id != Bson::Null));
+        }
+        let mut after = match ids.is_empty() {
+            true => Vec::new(),
+            false => read(&coll, doc! { "_id": { "$in": &ids } }, None, session.as_deref_mut()).await?,
+        };
+        // A single-document upsert through a filter only shows up by filter.
+        if let (true, Target::Filter { filter, many: false }) = (self.before.is_empty(), &self.target) {
+            after = read(&coll, filter.clone(), Some(1), session).await?;
+            ids.extend(after.iter().filter_map(id_of));
+        }
+
+        let mut seen = HashSet::new();
+        let mut changes = Vec::new();
+        for id in ids {
+            if !seen.insert(id.to_string()) {
+                continue;
+            }
+            let before = self.before.iter().find(|doc| doc.get("_id") == Some(&id)).cloned();
+            let after = after.iter().find(|doc| doc.get("_id") == Some(&id)).cloned();
+            if before != after {
+                changes.push(Change { id, before, after });
+            }
+        }
+        Ok(changes)
+    }
+}
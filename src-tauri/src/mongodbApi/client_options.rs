@@ -0,0 +1,133 @@
+//! Client settings `connectDBServer` takes beside the connection string.
+//!
+//! Everything here can also be written into the URI; set here, it overrides
+//! the URI's value, so credentials and certificate paths needn't be spliced
+//! into a string. `tls` turns TLS on or off, and `tlsCAFile`,
+//! `tlsCertificateKeyFile` and `tlsAllowInvalidCertificates` configure it,
+//! turning it on. `auth` takes a `mechanism` (`SCRAM-SHA-1`,
+//! `SCRAM-SHA-256`, `MONGODB-X509`, `MONGODB-AWS` or `PLAIN`), `username`,
+//! `password`, `source` and `mechanismProperties`; a password is never
+//! returned by `accessDB`. A `mongodb+srv://` server is resolved through
+//! DNS as usual, with `srvMaxHosts` limiting how many of its hosts are used.
+//! Timeouts are in milliseconds. `readPreference`, `readConcern` and
+//! `writeConcern` set the connection's defaults, see
+//! [`concerns`](super::concerns).
+
+use mongodb::bson::Document;
+use mongodb::options::{AuthMechanism, ClientOptions, Tls, TlsOptions};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::concerns::Concerns;
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct AuthOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mechanism: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing)]
+    password: Option<String>,
+    /// The database holding the user, `admin` or the connected one by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// e.g. `AWS_SESSION_TOKEN` for `MONGODB-AWS`.
+    #[serde(skip_serializing)]
+    mechanism_properties: Option<Document>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ConnectionOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<bool>,
+    #[serde(rename = "tlsCAFile", skip_serializing_if = "Option::is_none")]
+    tls_ca_file: Option<PathBuf>,
+    /// A PEM file holding the client certificate and its private key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_certificate_key_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_allow_invalid_certificates: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<AuthOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    srv_max_hosts: Option<u32>,
+    #[serde(rename = "connectTimeoutMS", skip_serializing_if = "Option::is_none")]
+    connect_timeout_ms: Option<u64>,
+    #[serde(rename = "serverSelectionTimeoutMS", skip_serializing_if = "Option::is_none")]
+    server_selection_timeout_ms: Option<u64>,
+    #[serde(rename = "heartbeatFrequencyMS", skip_serializing_if = "Option::is_none")]
+    heartbeat_frequency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_pool_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_pool_size: Option<u32>,
+    /// How long a pooled connection may sit unused before it is closed.
+    #[serde(rename = "maxIdleTimeMS", skip_serializing_if = "Option::is_none")]
+    max_idle_time_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replica_set: Option<String>,
+    /// Talk to the one server named rather than discovering its replica set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direct_connection: Option<bool>,
+    #[serde(flatten)]
+    concerns: Concerns,
+}
+
+impl ConnectionOptions {
+    fn configures_tls(&self) -> bool {
+        self.tls_ca_file.is_some() || self.tls_certificate_key_file.is_some() || self.tls_allow_invalid_certificates.is_some()
+    }
+
+    /// Applies the settings that were given over those parsed from the URI.
+    pub(super) fn apply(&self, options: &mut ClientOptions) -> Result<(), String> {
+        if self.tls == Some(false) {
+            if self.configures_tls() {
+                return Err("tls is false but TLS files or settings are given".to_string());
+            }
+            options.tls = Some(Tls::Disabled);
+        } else if self.tls == Some(true) || self.configures_tls() {
+            let mut tls = match options.tls.take() {
+                Some(Tls::Enabled(tls)) => tls,
+                _ => TlsOptions::default(),
+            };
+            tls.ca_file_path = self.tls_ca_file.clone().or(tls.ca_file_path.take());
+            tls.cert_key_file_path = self.tls_certificate_key_file.clone().or(tls.cert_key_file_path.take());
+            tls.allow_invalid_certificates = self.tls_allow_invalid_certificates.or(tls.allow_invalid_certificates);
+            options.tls = Some(Tls::Enabled(tls));
+        }
+
+        if let Some(auth) = &self.auth {
+            let mut credential = options.credential.take().unwrap_or_default();
+            if let Some(mechanism) = &auth.mechanism {
+                credential.mechanism = Some(AuthMechanism::from_str(mechanism).map_err(|e| format!("Unknown auth mechanism '{}': {}", mechanism, e))?);
+            }
+            credential.username = auth.username.clone().or(credential.username.take());
+            credential.password = auth.password.clone().or(credential.password.take());
+            credential.source = auth.source.clone().or(credential.source.take());
+            credential.mechanism_properties = auth.mechanism_properties.clone().or(credential.mechanism_properties.take());
+            options.credential = Some(credential);
+        }
+
+        let millis = |value: Option<u64>| value.map(Duration::from_millis);
+        options.srv_max_hosts = self.srv_max_hosts.or(options.srv_max_hosts);
+        options.connect_timeout = millis(self.connect_timeout_ms).or(options.connect_timeout);
+        options.server_selection_timeout = millis(self.server_selection_timeout_ms).or(options.server_selection_timeout);
+        options.heartbeat_freq = millis(self.heartbeat_frequency_ms).or(options.heartbeat_freq);
+        options.min_pool_size = self.min_pool_size.or(options.min_pool_size);
+        options.max_pool_size = self.max_pool_size.or(options.max_pool_size);
+        options.max_idle_time = millis(self.max_idle_time_ms).or(options.max_idle_time);
+        options.repl_set_name = self.replica_set.clone().or(options.repl_set_name.take());
+        options.direct_connection = self.direct_connection.or(options.direct_connection);
+        self.concerns.apply(options)?;
+        if let (Some(min), Some(max)) = (options.min_pool_size, options.max_pool_size) {
+            if max != 0 && min > max {
+                return Err(format!("minPoolSize {} is larger than maxPoolSize {}", min, max));
+            }
+        }
+        Ok(())
+    }
+}
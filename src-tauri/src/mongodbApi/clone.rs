@@ -0,0 +1,152 @@
+//! Copying a collection into a new one.
+//!
+//! `cloneCollection` creates `to` with the options of `collection` (its
+//! validator, collation, capped size and so on) and copies the documents
+//! matching `filter`, all of them by default, in `_id`-ordered batches of
+//! `batchSize` (1000 by default). With `withIndexes`, the default, the
+//! source's indexes are then built on the copy; building them once the data
+//! is in is quicker than keeping them up to date batch by batch. It runs as
+//! a `cloneCollection` [operation](super::operations), reporting
+//! `{ copied, total }` after each batch and stopping between batches when
+//! cancelled, which leaves the documents copied so far in `to`. `to` must
+//! not exist yet.
+
+use futures::TryStreamExt;
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::options::FindOptions;
+use mongodb::results::{CollectionSpecification, CollectionType};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::time::Instant;
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::{convert, schema_sync, CommandContext};
+
+const DEFAULT_BATCH_SIZE: i64 = 1000;
+
+fn default_with_indexes() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CloneCollectionArgs {
+    collection: String,
+    to: String,
+    #[serde(default = "default_with_indexes")]
+    with_indexes: bool,
+    filter: Option<String>,
+    batch_size: Option<i64>,
+    operation_id: Option<String>,
+}
+
+pub(super) async fn clone_collection(ctx: CommandContext, args: CloneCollectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match args.filter.as_deref().map(convert::from_extjson) {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => return Err(errors::invalid("Failed to parse filter", e)),
+        None => Document::new(),
+    };
+    let names = match ctx.db.list_collection_names(doc! { "name": { "$in": [&args.collection, &args.to] } }).await {
+        Ok(names) => names,
+        Err(e) => return Err(errors::failed("Failed to list collections", e)),
+    };
+    if !names.contains(&args.collection) {
+        return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("Collection '{}' doesn't exist", args.collection)));
+    }
+    if names.contains(&args.to) {
+        let message = format!("Collection '{}' already exists", args.to);
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+    }
+
+    let mut create = doc! { "create": &args.to };
+    create.extend(options_of(&ctx, &args.collection).await?);
+    if let Err(e) = ctx.db.run_command(create, None).await {
+        return Err(errors::failed("Failed to create collection", e));
+    }
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "cloneCollection", args.operation_id.clone())?;
+    let outcome = run(&ctx, &args, filter, batch_size, &mut operation).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+/// The options `collection` was created with, refusing views.
+async fn options_of(ctx: &CommandContext, collection: &str) -> Result<Document, MongoPluginError> {
+    let cursor = match ctx.db.list_collections(doc! { "name": collection }, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to read collection options", e)),
+    };
+    let specs: Vec<CollectionSpecification> = match cursor.try_collect().await {
+        Ok(specs) => specs,
+        Err(e) => return Err(errors::failed("Failed to read collection options", e)),
+    };
+    if specs.first().is_some_and(|spec| spec.collection_type == CollectionType::View) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("'{}' is a view, which has no documents to copy", collection)));
+    }
+    let options = match specs.first().map(|spec| bson::to_document(&spec.options)) {
+        Some(Ok(options)) => options,
+        Some(Err(e)) => return Err(errors::failed("Failed to read collection options", e)),
+        None => Document::new(),
+    };
+    Ok(options.into_iter().filter(|(_, value)| !matches!(value, Bson::Null)).collect())
+}
+
+async fn run(ctx: &CommandContext, args: &CloneCollectionArgs, filter: Document, batch_size: i64, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let (source, target) = (ctx.db.collection::<Document>(&args.collection), ctx.db.collection::<Document>(&args.to));
+    let started = Instant::now();
+    let total = source.count_documents(filter.clone(), None).await.ok();
+    let (mut copied, mut batches): (u64, u64) = (0, 0);
+    let mut last_id: Option<Bson> = None;
+    let cancelled = loop {
+        if !operation.proceed().await {
+            break true;
+        }
+        let mut scope = filter.clone();
+        if let Some(last_id) = &last_id {
+            scope = doc! { "$and": [scope, { "_id": { "$gt": last_id } }] };
+        }
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).limit(batch_size).build();
+        let documents: Vec<Document> = match source.find(scope, options).await {
+            Ok(cursor) => match cursor.try_collect().await {
+                Ok(documents) => documents,
+                Err(e) => return Err(errors::failed(&format!("Failed to read batch {}", batches), e)),
+            },
+            Err(e) => return Err(errors::failed(&format!("Failed to read batch {}", batches), e)),
+        };
+        let last = match documents.last().and_then(|document| document.get("_id")) {
+            Some(last) => last.clone(),
+            None => break false,
+        };
+        let count = documents.len();
+        if let Err(e) = target.insert_many(documents, None).await {
+            return Err(errors::failed(&format!("Failed to copy batch {}", batches), e));
+        }
+        copied += count as u64;
+        batches += 1;
+        last_id = Some(last);
+        operation.progress(json!({ "copied": copied, "total": total }));
+        if (count as i64) < batch_size {
+            break false;
+        }
+    };
+
+    let mut indexes = Vec::new();
+    if args.with_indexes && !cancelled {
+        indexes = schema_sync::index_definitions(ctx, &args.collection).await?;
+        if !indexes.is_empty() {
+            let command = doc! { "createIndexes": &args.to, "indexes": indexes.iter().cloned().map(Bson::Document).collect::<Vec<_>>() };
+            if let Err(e) = ctx.db.run_command(command, None).await {
+                return Err(errors::failed("The documents were copied but creating the indexes failed", e));
+            }
+        }
+    }
+    Ok(json!({
+        "operationId": operation.id(),
+        "copied": copied,
+        "batches": batches,
+        "indexes": indexes.iter().filter_map(|index| index.get_str("name").ok()).collect::<Vec<_>>(),
+        "cancelled": cancelled,
+        "durationMs": started.elapsed().as_millis() as u64,
+    }))
+}
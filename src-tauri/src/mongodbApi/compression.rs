@@ -0,0 +1,38 @@
+//! Zstandard compression of large responses.
+//!
+//! With a `compression` section in the plugin config, a database command's
+//! response whose JSON text is over `thresholdKb` (64 by default) is sent
+//! as `{ "$zstd": <base64>, "bytes": <uncompressed length> }` instead, the
+//! base64 of the zstd-compressed JSON text. Compressing at `level` (3 by
+//! default) takes far less time than the webview spends parsing a large
+//! JSON string, so big result sets arrive sooner on slow machines; the
+//! frontend reverses it before use.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+const DEFAULT_THRESHOLD_KB: usize = 64;
+const DEFAULT_LEVEL: i32 = 3;
+
+/// The `compression` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompressionConfig {
+    /// Responses larger than this many KiB are compressed, 64 by default.
+    pub threshold_kb: Option<usize>,
+    /// zstd level from 1 to 22, 3 by default.
+    pub level: Option<i32>,
+}
+
+/// `result`, compressed if its JSON text is over the threshold.
+pub(super) fn compress(config: &CompressionConfig, result: JsonValue) -> Result<JsonValue, String> {
+    let text = serde_json::to_vec(&result).map_err(|e| format!("Failed to serialize response: {}", e))?;
+    if text.len() <= config.threshold_kb.unwrap_or(DEFAULT_THRESHOLD_KB) * 1024 {
+        return Ok(result);
+    }
+    let compressed = zstd::bulk::compress(&text, config.level.unwrap_or(DEFAULT_LEVEL))
+        .map_err(|e| format!("Failed to compress response: {}", e))?;
+    Ok(json!({ "$zstd": BASE64.encode(compressed), "bytes": text.len() }))
+}
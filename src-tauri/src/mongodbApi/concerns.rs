@@ -0,0 +1,159 @@
+//! Which members serve reads, how current reads are, and how durable writes
+//! are before they are acknowledged.
+//!
+//! `connectDBServer` takes `readPreference`, `readConcern` and
+//! `writeConcern` as the connection's defaults, and every command that runs
+//! against the connected database takes them to override those defaults for
+//! one run. `readPreference` is a mode, `primary`, `primaryPreferred`,
+//! `secondary`, `secondaryPreferred` or `nearest`, or `{ mode, tags, maxStalenessSeconds }` where `tags` are the
+//! tag sets to try in order, e.g. `[{ "region": "eu" }, {}]`. `readConcern`
+//! is a level: `local`, `available`, `majority`, `linearizable` or
+//! `snapshot`. `writeConcern` is `{ w, j, wtimeoutMS }`, `w` being a number
+//! of members, `"majority"` or a tag set name. Writes always go to the
+//! primary whatever the read preference; commands on a session take their
+//! concerns from its transaction, so giving them one is an error.
+
+use mongodb::bson::Document;
+use mongodb::options::{
+    Acknowledgment, ClientOptions, DatabaseOptions, ReadConcern, ReadPreference, ReadPreferenceOptions, SelectionCriteria, WriteConcern,
+};
+use mongodb::{Client, Database};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::sessions;
+
+const KEYS: &[&str] = &["readPreference", "readConcern", "writeConcern"];
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+enum ReadPreferenceSpec {
+    Mode(String),
+    Options {
+        mode: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<HashMap<String, String>>,
+        #[serde(rename = "maxStalenessSeconds", skip_serializing_if = "Option::is_none")]
+        max_staleness_seconds: Option<u64>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct WriteConcernSpec {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    w: Option<JsonValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    j: Option<bool>,
+    #[serde(rename = "wtimeoutMS", skip_serializing_if = "Option::is_none")]
+    wtimeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Concerns {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_preference: Option<ReadPreferenceSpec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_concern: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_concern: Option<WriteConcernSpec>,
+}
+
+impl ReadPreferenceSpec {
+    fn criteria(&self) -> Result<SelectionCriteria, String> {
+        let (mode, tags, max_staleness_seconds) = match self {
+            Self::Mode(mode) => (mode, &[][..], None),
+            Self::Options { mode, tags, max_staleness_seconds } => (mode, &tags[..], *max_staleness_seconds),
+        };
+        let options = ReadPreferenceOptions::builder()
+            .tag_sets((!tags.is_empty()).then(|| tags.to_vec()))
+            .max_staleness(max_staleness_seconds.map(Duration::from_secs))
+            .build();
+        let preference = match mode.as_str() {
+            "primary" if tags.is_empty() && max_staleness_seconds.is_none() => ReadPreference::Primary,
+            "primary" => return Err("readPreference primary takes no tags or maxStalenessSeconds".to_string()),
+            "primaryPreferred" => ReadPreference::PrimaryPreferred { options },
+            "secondary" => ReadPreference::Secondary { options },
+            "secondaryPreferred" => ReadPreference::SecondaryPreferred { options },
+            "nearest" => ReadPreference::Nearest { options },
+            other => return Err(format!("Unknown readPreference mode '{}'", other)),
+        };
+        Ok(SelectionCriteria::ReadPreference(preference))
+    }
+}
+
+fn read_concern(level: &str) -> Result<ReadConcern, String> {
+    match level {
+        "local" => Ok(ReadConcern::local()),
+        "available" => Ok(ReadConcern::available()),
+        "majority" => Ok(ReadConcern::majority()),
+        "linearizable" => Ok(ReadConcern::linearizable()),
+        "snapshot" => Ok(ReadConcern::snapshot()),
+        other => Err(format!("Unknown readConcern level '{}'", other)),
+    }
+}
+
+impl WriteConcernSpec {
+    fn concern(&self) -> Result<WriteConcern, String> {
+        let w = match &self.w {
+            None => None,
+            Some(JsonValue::Number(n)) => match n.as_u64().and_then(|n| u32::try_from(n).ok()) {
+                Some(n) => Some(Acknowledgment::Nodes(n)),
+                None => return Err(format!("writeConcern w must be a number of members, not {}", n)),
+            },
+            Some(JsonValue::String(w)) if w == "majority" => Some(Acknowledgment::Majority),
+            Some(JsonValue::String(tag)) => Some(Acknowledgment::Custom(tag.clone())),
+            Some(other) => return Err(format!("writeConcern w must be a number or a string, not {}", other)),
+        };
+        Ok(WriteConcern::builder().w(w).journal(self.j).w_timeout(self.wtimeout_ms.map(Duration::from_millis)).build())
+    }
+}
+
+impl Concerns {
+    /// The overrides a command's payload gives, if any.
+    pub(super) fn of(payload: &JsonValue) -> Result<Option<Self>, String> {
+        let given: Map<String, JsonValue> = KEYS.iter().filter_map(|key| Some((key.to_string(), payload.get(*key)?.clone()))).collect();
+        if given.is_empty() {
+            return Ok(None);
+        }
+        if sessions::session_id(payload).is_some() {
+            return Err("Commands on a session take their read and write concerns from it".to_string());
+        }
+        match serde_json::from_value(JsonValue::Object(given)) {
+            Ok(concerns) => Ok(Some(concerns)),
+            Err(e) => Err(format!("Failed to parse read or write concern: {}", e)),
+        }
+    }
+
+    /// `database` on `client`, with these concerns over the client's.
+    pub(super) fn database(&self, client: &Client, database: &str) -> Result<Database, String> {
+        let options = DatabaseOptions::builder()
+            .selection_criteria(self.read_preference.as_ref().map(ReadPreferenceSpec::criteria).transpose()?)
+            .read_concern(self.read_concern.as_deref().map(read_concern).transpose()?)
+            .write_concern(self.write_concern.as_ref().map(WriteConcernSpec::concern).transpose()?)
+            .build();
+        Ok(client.database_with_options(database, options))
+    }
+
+    /// Sets these as the connection's defaults.
+    pub(super) fn apply(&self, options: &mut ClientOptions) -> Result<(), String> {
+        if let Some(preference) = &self.read_preference {
+            options.selection_criteria = Some(preference.criteria()?);
+        }
+        if let Some(level) = &self.read_concern {
+            options.read_concern = Some(read_concern(level)?);
+        }
+        if let Some(concern) = &self.write_concern {
+            options.write_concern = Some(concern.concern()?);
+        }
+        Ok(())
+    }
+}
+
+/// The `writeConcern` field for a raw write command on `db`, which
+/// `run_command` doesn't add by itself.
+pub(super) fn write_concern_field(db: &Database) -> Option<Document> {
+    db.write_concern().and_then(|concern| mongodb::bson::to_document(concern).ok()).filter(|concern| !concern.is_empty())
+}
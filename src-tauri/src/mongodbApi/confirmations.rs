@@ -0,0 +1,169 @@
+//! Two-step confirmation of the commands that wipe data.
+//!
+//! With a `confirmations` section in the plugin config, `dropDatabase`,
+//! `dropCollection`, an `importCollection` with `dropBeforeImport`, a
+//! `deleteMany` with an empty filter and a `bulkWrite` with such a
+//! `deleteMany` among its operations don't run when first called. They
+//! reply with `{ confirmationRequired: true, confirmationToken,
+//! expiresInMs, impact }` instead, where `impact` tells
+//! what would be lost: the database's collections and documents, or the
+//! collection's documents and indexes. Sending the command again with its
+//! `confirmationToken` runs it. A token is good for one run of the same
+//! command on the same database and collection, within `ttlSeconds` (30 by
+//! default), so a frontend bug that sends the command once can't do it.
+//! Inside `executeTransactionalBatch` such a `deleteMany` is refused, as
+//! nothing could be confirmed there.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::errors::{self, MongoPluginError};
+use super::{bulk_write, convert, CommandContext, CommandFuture};
+
+const DEFAULT_TTL_SECONDS: u64 = 30;
+
+/// The `confirmations` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConfirmationsConfig {
+    /// How long a confirmation token stays valid, 30 seconds by default.
+    pub ttl_seconds: Option<u64>,
+}
+
+/// What a token was issued for.
+#[derive(PartialEq)]
+struct Operation {
+    database: String,
+    command: String,
+    collection: Option<String>,
+}
+
+struct Pending {
+    operation: Operation,
+    expires: Instant,
+}
+
+/// The tokens issued and not yet used.
+pub(super) struct Confirmations {
+    ttl: Duration,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl Confirmations {
+    pub(super) fn new(config: &ConfirmationsConfig) -> Self {
+        Self { ttl: Duration::from_secs(config.ttl_seconds.unwrap_or(DEFAULT_TTL_SECONDS)), pending: Mutex::default() }
+    }
+
+    fn issue(&self, operation: Operation) -> String {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, pending| pending.expires > Instant::now());
+        pending.insert(token.clone(), Pending { operation, expires: Instant::now() + self.ttl });
+        token
+    }
+
+    /// Uses up `token` if it was issued for `operation` and is still valid.
+    fn redeem(&self, token: &str, operation: &Operation) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(token) {
+            Some(issued) if issued.operation == *operation && issued.expires > Instant::now() => {
+                pending.remove(token);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the command needs confirming before it runs.
+pub(super) fn destructive(command: &str, payload: &JsonValue) -> bool {
+    match command {
+        "dropDatabase" | "dropCollection" => true,
+        "importCollection" => payload.get("dropBeforeImport").and_then(JsonValue::as_bool) == Some(true),
+        "deleteMany" => match payload.get("filter").and_then(JsonValue::as_str).map(convert::from_extjson::<Document>) {
+            Some(Ok(filter)) => filter.is_empty(),
+            // Malformed arguments are reported by the command itself.
+            _ => false,
+        },
+        "bulkWrite" => bulk_write::operations_of(payload).iter().any(|operation| match operation.get_document("deleteMany") {
+            Ok(spec) => spec.get_document("filter").is_ok_and(Document::is_empty),
+            Err(_) => false,
+        }),
+        _ => false,
+    }
+}
+
+/// The reply that stands in for a destructive command until it is
+/// confirmed, or `None` when the command may run.
+pub(super) fn check(ctx: &CommandContext, command: &str, payload: &JsonValue) -> Option<CommandFuture> {
+    let confirmations = ctx.confirmations.as_ref()?;
+    if !destructive(command, payload) {
+        return None;
+    }
+    let operation = Operation {
+        database: ctx.db.name().to_string(),
+        command: command.to_string(),
+        collection: payload.get("collection").and_then(JsonValue::as_str).map(str::to_string),
+    };
+    match payload.get("confirmationToken").and_then(JsonValue::as_str) {
+        Some(token) if confirmations.redeem(token, &operation) => None,
+        Some(_) => Some(Box::pin(async {
+            let message = "The confirmation token is unknown, expired, already used or for another operation";
+            Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message).into())
+        })),
+        None => {
+            let (ctx, confirmations) = (ctx.clone(), confirmations.clone());
+            Some(Box::pin(async move {
+                let impact = impact(&ctx, &operation).await?;
+                let token = confirmations.issue(operation);
+                Ok(json!({
+                    "confirmationRequired": true,
+                    "confirmationToken": token,
+                    "expiresInMs": confirmations.ttl.as_millis() as u64,
+                    "impact": impact,
+                }))
+            }))
+        }
+    }
+}
+
+async fn document_count(ctx: &CommandContext, collection: &str) -> Result<u64, MongoPluginError> {
+    match ctx.db.collection::<Document>(collection).estimated_document_count(None).await {
+        Ok(count) => Ok(count),
+        Err(e) => Err(errors::failed("Failed to count documents", e)),
+    }
+}
+
+/// What running the operation would remove, by the collections' metadata
+/// counts.
+async fn impact(ctx: &CommandContext, operation: &Operation) -> Result<JsonValue, MongoPluginError> {
+    let collection = match &operation.collection {
+        Some(collection) => collection,
+        None => {
+            let names = match ctx.db.list_collection_names(doc! { "type": "collection" }).await {
+                Ok(names) => names,
+                Err(e) => return Err(errors::failed("Failed to list collections", e)),
+            };
+            let mut documents = 0;
+            for name in &names {
+                documents += document_count(ctx, name).await?;
+            }
+            return Ok(json!({ "database": operation.database, "collections": names.len(), "documents": documents }));
+        }
+    };
+    let documents = document_count(ctx, collection).await?;
+    if matches!(operation.command.as_str(), "deleteMany" | "bulkWrite") {
+        return Ok(json!({ "database": operation.database, "collection": collection, "documents": documents }));
+    }
+    // A collection that doesn't exist yet has no indexes to list.
+    let indexes = ctx.db.collection::<Document>(collection).list_index_names().await.map_or(0, |names| names.len());
+    Ok(json!({ "database": operation.database, "collection": collection, "documents": documents, "indexes": indexes }))
+}
@@ -0,0 +1,136 @@
+//! The connections the app has open, each under an id.
+//!
+//! `connectDBServer` opens a client and returns its id: the `connectionId`
+//! it was given, replacing any connection already under that id, or a
+//! generated one. Every other command picks the connection it runs against
+//! with a `connectionId` argument, so one app can talk to several clusters
+//! at once, each through its own pooled client. Without one, commands use
+//! the default connection: the one opened last, or the only one left once
+//! that is closed. `listConnections` describes the open connections and
+//! `closeConnection` shuts one down.
+
+use mongodb::bson::oid::ObjectId;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{history, shutdown, watch, Connection, MongoState, NoArgs};
+
+const NOT_CONNECTED: &str = "Not connected: call connectDBServer first";
+/// How long closing a connection waits for its cursors and sessions.
+const CLOSE_GRACE: Duration = Duration::from_secs(3);
+
+#[derive(Default)]
+struct Registry {
+    connections: HashMap<String, Arc<Connection>>,
+    default: Option<String>,
+}
+
+#[derive(Default)]
+pub(super) struct ConnectionManager(Mutex<Registry>);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CloseConnectionArgs {
+    connection_id: Option<String>,
+}
+
+/// The connection a command's arguments ask for.
+pub(super) fn connection_id(payload: &JsonValue) -> Option<String> {
+    payload.get("connectionId").and_then(JsonValue::as_str).map(str::to_string)
+}
+
+/// A new connection's id when the caller didn't choose one.
+pub(super) fn new_id() -> String {
+    ObjectId::new().to_hex()
+}
+
+impl ConnectionManager {
+    /// The connection under `id`, or the default one.
+    pub(super) fn get(&self, id: Option<&str>) -> Result<Arc<Connection>, MongoPluginError> {
+        let registry = self.0.lock().unwrap();
+        let id = match id.or(registry.default.as_deref()) {
+            Some(id) => id,
+            None => return Err(MongoPluginError::new(errors::ErrorKind::Connection, NOT_CONNECTED)),
+        };
+        match registry.connections.get(id) {
+            Some(connection) => Ok(connection.clone()),
+            None => Err(MongoPluginError::new(errors::ErrorKind::Connection, format!("No connection '{}': call connectDBServer first", id))),
+        }
+    }
+
+    /// Adds `connection` under `id` and makes it the default.
+    pub(super) fn insert(&self, id: String, connection: Connection) {
+        let mut registry = self.0.lock().unwrap();
+        registry.connections.insert(id.clone(), Arc::new(connection));
+        registry.default = Some(id);
+    }
+
+    pub(super) fn remove(&self, id: &str) -> Option<Arc<Connection>> {
+        let mut registry = self.0.lock().unwrap();
+        let removed = registry.connections.remove(id)?;
+        if registry.default.as_deref() == Some(id) {
+            registry.default = match registry.connections.len() {
+                1 => registry.connections.keys().next().cloned(),
+                _ => None,
+            };
+        }
+        Some(removed)
+    }
+
+    /// Removes every connection, for shutting them down.
+    pub(super) fn take_all(&self) -> Vec<Arc<Connection>> {
+        let mut registry = self.0.lock().unwrap();
+        registry.default = None;
+        registry.connections.drain().map(|(_, connection)| connection).collect()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().connections.is_empty()
+    }
+}
+
+/// The open connections, without the credentials their URIs may carry.
+pub(super) async fn list_connections<R: Runtime>(app: AppHandle<R>, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let registry = state.connections.0.lock().unwrap();
+    let mut connections: Vec<JsonValue> = registry
+        .connections
+        .iter()
+        .map(|(id, connection)| {
+            json!({
+                "connectionId": id,
+                "database": connection.info.database,
+                "appName": connection.info.app_name,
+                "profile": history::profile_id(&connection.info),
+                "default": registry.default.as_deref() == Some(id.as_str()),
+            })
+        })
+        .collect();
+    connections.sort_by(|a, b| a["connectionId"].as_str().cmp(&b["connectionId"].as_str()));
+    Ok(JsonValue::Array(connections))
+}
+
+/// Closes a connection, the default one without a `connectionId`. Commands
+/// already running on it finish first, for up to a few seconds.
+pub(super) async fn close_connection<R: Runtime>(app: AppHandle<R>, args: CloseConnectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let id = match args.connection_id {
+        Some(id) => id,
+        None => match state.connections.0.lock().unwrap().default.clone() {
+            Some(id) => id,
+            None => return Err(MongoPluginError::new(errors::ErrorKind::Connection, NOT_CONNECTED)),
+        },
+    };
+    let connection = match state.connections.remove(&id) {
+        Some(connection) => connection,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::Connection, format!("No connection '{}'", id))),
+    };
+    app.state::<watch::Watches>().close_connection(&connection);
+    shutdown::close_client(connection.client.clone(), CLOSE_GRACE).await;
+    Ok(json!({ "connectionId": id, "closed": true }))
+}
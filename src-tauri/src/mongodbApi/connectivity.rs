@@ -0,0 +1,72 @@
+//! Notifications for connections breaking and coming back.
+//!
+//! The driver's command and pool events tell us which host a failure
+//! happened on, which the error a command returns doesn't. A host is
+//! reported once with `mongo://connection-lost` when a command or
+//! connection attempt to it fails at the network level, and once with
+//! `mongo://connection-restored` when a command on it next succeeds.
+
+use mongodb::error::{Error as MongoError, ErrorKind};
+use mongodb::event::cmap::{CmapEventHandler, ConnectionCheckoutFailedEvent, ConnectionCheckoutFailedReason};
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use super::events::EventSink;
+use super::topology::TopologyMonitor;
+
+pub(super) struct ConnectionMonitor {
+    lost_hosts: Mutex<HashSet<String>>,
+    events: EventSink,
+    topology: Arc<TopologyMonitor>,
+}
+
+/// The kind of network failure, or `None` for errors the server returned.
+fn network_error_kind(error: &MongoError) -> Option<String> {
+    match error.kind.as_ref() {
+        ErrorKind::Io(e) => Some(format!("{:?}", e.kind())),
+        ErrorKind::ConnectionPoolCleared { .. } => Some("PoolCleared".to_string()),
+        _ => None,
+    }
+}
+
+impl ConnectionMonitor {
+    pub(super) fn new(events: EventSink, topology: Arc<TopologyMonitor>) -> Self {
+        Self { lost_hosts: Mutex::new(HashSet::new()), events, topology }
+    }
+
+    fn lost(&self, host: String, kind: String, message: String) {
+        if self.lost_hosts.lock().unwrap().insert(host.clone()) {
+            (self.events)("mongo://connection-lost", json!({ "host": host, "kind": kind, "message": message }));
+        }
+    }
+
+    fn restored(&self, host: String) {
+        if self.lost_hosts.lock().unwrap().remove(&host) {
+            (self.events)("mongo://connection-restored", json!({ "host": host }));
+        }
+    }
+}
+
+impl CommandEventHandler for ConnectionMonitor {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.topology.record_command(event.connection.address.to_string());
+        self.restored(event.connection.address.to_string());
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        if let Some(kind) = network_error_kind(&event.failure) {
+            self.lost(event.connection.address.to_string(), kind, event.failure.to_string());
+        }
+    }
+}
+
+impl CmapEventHandler for ConnectionMonitor {
+    fn handle_connection_checkout_failed_event(&self, event: ConnectionCheckoutFailedEvent) {
+        if event.reason == ConnectionCheckoutFailedReason::ConnectionError {
+            let message = "Could not establish a connection".to_string();
+            self.lost(event.address.to_string(), "ConnectionError".to_string(), message);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! BSON↔JSON conversion of large values away from the async runtime.
+//!
+//! Converting a big result set holds a runtime thread long enough to stall
+//! every other command waiting on it. Past a size threshold the work moves
+//! onto the blocking pool instead; small values are converted in place,
+//! where handing them off would cost more than it saves. Result sets are
+//! converted as they arrive from the cursor, one document at a time, so the
+//! full BSON and JSON copies of a result never exist side by side.
+//!
+//! Queries, documents and pipelines are read as Extended JSON, relaxed or
+//! canonical, so `{ "$oid": ... }`, `{ "$date": ... }`,
+//! `{ "$numberDecimal": ... }`, `{ "$binary": ... }` and the rest become the
+//! BSON values they spell. Results go back as relaxed Extended JSON, or
+//! canonical when the command's `extendedJson` argument says `"canonical"`.
+//! Integers in canonical output are typed by their size, `$numberInt` when
+//! they fit in 32 bits and `$numberLong` otherwise.
+//!
+//! Commands read them from JSON text, but any `query`, `filter`, `update`,
+//! `data`, `replacement`, `pipeline` or `spec` argument, and the
+//! `operations` of `bulkWrite`, may also be given as a JSON value, which
+//! [`encode_arguments`] writes out as that text before the command sees it.
+//! The same goes for the steps of `executeBatch` and
+//! `executeTransactionalBatch`.
+
+use futures::TryStreamExt;
+use mongodb::bson::{self, Bson, Document};
+use mongodb::error::Result as MongoResult;
+use mongodb::Cursor;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use super::errors::{self, MongoPluginError};
+
+/// Documents in a result from which processing it is offloaded.
+const BLOCKING_DOCUMENTS: usize = 256;
+/// Bytes of JSON text from which parsing it is offloaded.
+const BLOCKING_TEXT_BYTES: usize = 256 * 1024;
+
+/// Arguments carrying documents, filters or pipelines as Extended JSON text.
+const DOCUMENT_ARGS: &[&str] = &["query", "filter", "update", "data", "replacement", "pipeline", "spec"];
+
+/// The Extended JSON flavour a command's result is written in.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(super) enum ExtendedJson {
+    #[default]
+    Relaxed,
+    Canonical,
+}
+
+impl ExtendedJson {
+    /// The flavour a command's `extendedJson` argument asks for.
+    pub(super) fn of(payload: &JsonValue) -> Result<Self, String> {
+        match payload.get("extendedJson") {
+            None | Some(JsonValue::Null) => Ok(Self::Relaxed),
+            Some(mode) => serde_json::from_value(mode.clone()).map_err(|_| "extendedJson must be \"relaxed\" or \"canonical\"".to_string()),
+        }
+    }
+
+    fn write(self, value: Bson) -> JsonValue {
+        match self {
+            Self::Relaxed => value.into_relaxed_extjson(),
+            Self::Canonical => value.into_canonical_extjson(),
+        }
+    }
+}
+
+/// Parses Extended JSON text into `T`, keeping the BSON types it spells.
+pub(super) fn from_extjson<T: DeserializeOwned>(text: &str) -> Result<T, String> {
+    let json: JsonValue = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    from_extjson_value(json)
+}
+
+/// Reads an Extended JSON value, such as a command's result, into `T`.
+pub(super) fn from_extjson_value<T: DeserializeOwned>(json: JsonValue) -> Result<T, String> {
+    let value = Bson::try_from(json).map_err(|e| e.to_string())?;
+    bson::from_bson(value).map_err(|e| e.to_string())
+}
+
+/// Writes the document arguments `command` was given as JSON values as the
+/// JSON text commands read. Text is left as it is.
+pub(super) fn encode_arguments(command: &str, payload: &mut JsonValue) {
+    match command {
+        // Its spec is a command with its arguments, kept as given.
+        "saveQuery" => return,
+        "executeBatch" | "executeTransactionalBatch" => {
+            let operations = payload.get_mut("operations").and_then(JsonValue::as_array_mut);
+            for operation in operations.into_iter().flatten() {
+                let command = operation.get("command").and_then(JsonValue::as_str).unwrap_or_default().to_string();
+                if let Some(args) = operation.get_mut("args") {
+                    encode_arguments(&command, args);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
+    let args = match payload.as_object_mut() {
+        Some(args) => args,
+        None => return,
+    };
+    let keys = DOCUMENT_ARGS.iter().copied().chain((command == "bulkWrite").then_some("operations"));
+    for key in keys {
+        if let Some(value) = args.get_mut(key).filter(|value| value.is_object() || value.is_array()) {
+            *value = JsonValue::String(value.to_string());
+        }
+    }
+}
+
+/// Canonical Extended JSON text for `value`, which [`from_extjson`] reads
+/// back to exactly the same BSON.
+pub(super) fn to_extjson_text(value: Bson) -> String {
+    value.into_canonical_extjson().to_string()
+}
+
+/// Rewrites a result in `mode`. The BSON values in it are objects keyed by
+/// `$` names, which are converted one at a time; everything else is plain
+/// JSON already, apart from numbers in canonical output.
+pub(super) fn write_extjson(value: &mut JsonValue, mode: ExtendedJson) {
+    match value {
+        JsonValue::Array(items) => items.iter_mut().for_each(|item| write_extjson(item, mode)),
+        JsonValue::Object(map) if map.keys().next().is_some_and(|key| key.starts_with('$')) => {
+            // Objects that merely look like one are left as they are.
+            match Bson::try_from(map.clone()) {
+                Ok(Bson::Document(_)) | Err(_) => map.values_mut().for_each(|item| write_extjson(item, mode)),
+                Ok(converted) => *value = mode.write(converted),
+            }
+        }
+        JsonValue::Object(map) => map.values_mut().for_each(|item| write_extjson(item, mode)),
+        JsonValue::Number(_) if mode == ExtendedJson::Canonical => {
+            if let Ok(number) = Bson::try_from(value.clone()) {
+                *value = mode.write(number);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A command's result rewritten in `mode`, on the blocking pool when large.
+pub(super) async fn extjson_result(mut result: JsonValue, mode: ExtendedJson) -> Result<JsonValue, String> {
+    offload(is_large(&result), move || {
+        write_extjson(&mut result, mode);
+        result
+    })
+    .await
+}
+
+/// Runs `work` on the blocking pool when `heavy`, or right here otherwise.
+pub(super) async fn offload<T, F>(heavy: bool, work: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    if !heavy {
+        return Ok(work());
+    }
+    tokio::task::spawn_blocking(work).await.map_err(|e| format!("Conversion failed: {}", e))
+}
+
+/// Whether a JSON result is big enough for its processing to be offloaded.
+pub(super) fn is_large(value: &JsonValue) -> bool {
+    value.as_array().is_some_and(|items| items.len() >= BLOCKING_DOCUMENTS)
+}
+
+/// `value` as relaxed Extended JSON, the form results take inside the
+/// plugin until [`write_extjson`] gives them the caller's flavour.
+pub(super) fn to_json(value: impl Into<Bson>) -> JsonValue {
+    value.into().into_relaxed_extjson()
+}
+
+/// Drains a cursor into a JSON array, converting each document as it
+/// arrives and dropping its BSON before the next is read. Each conversion
+/// is small, and every new server batch is an await point, so this doesn't
+/// need the blocking pool.
+pub(super) async fn collect_json(mut cursor: Cursor<Document>) -> MongoResult<JsonValue> {
+    let mut items = Vec::new();
+    while let Some(doc) = cursor.try_next().await? {
+        items.push(to_json(doc));
+    }
+    Ok(JsonValue::Array(items))
+}
+
+/// Parses an array of documents sent as JSON text, `what` naming it in the
+/// error.
+pub(super) async fn parse_documents(text: String, what: &'static str) -> Result<Vec<Document>, MongoPluginError> {
+    let parsed = offload(text.len() >= BLOCKING_TEXT_BYTES, move || from_extjson::<Vec<Document>>(&text)).await?;
+    parsed.map_err(|e| errors::invalid(&format!("Failed to parse {}", what), e))
+}
@@ -0,0 +1,262 @@
+//! Counting documents, with counts cached until the collection changes.
+//!
+//! `count` takes an optional `filter` and a `mode`: `estimated` reads the
+//! collection's metadata count, which is instant but ignores filters,
+//! `exact` counts the matching documents, and `auto`, the default, picks
+//! `estimated` when there is no filter and `exact` otherwise. The filter
+//! soft deletes add makes a count exact, so `estimated` on such a
+//! collection needs `includeDeleted: true`.
+//!
+//! Counts are cached per collection and filter while a change stream on the
+//! collection is open; any change it reports, or any write made through the
+//! plugin, drops the collection's cached counts. At most
+//! `countCacheWatches` collections (4 by default) are watched at a time,
+//! since every change stream keeps a connection busy; counts on others, and
+//! on servers without change streams, are never cached. `refresh: true`
+//! skips the cache.
+//!
+//! `countDocuments` and `estimatedDocumentCount` are the driver's two counts
+//! as they are, uncached, and return the number alone. `countDocuments`
+//! takes an optional `filter` and `options` with `limit`, `skip`, `hint`,
+//! `collation` and `maxTimeMS`; like `estimated`, `estimatedDocumentCount`
+//! on a soft-delete collection needs `includeDeleted: true`.
+
+use futures::StreamExt;
+use mongodb::bson::Document;
+use mongodb::options::{CountOptions, EstimatedDocumentCountOptions};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::AbortHandle;
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, read_options, CommandContext};
+
+const DEFAULT_WATCHES: usize = 4;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum CountMode {
+    #[default]
+    Auto,
+    Estimated,
+    Exact,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CountArgs {
+    collection: String,
+    filter: Option<String>,
+    #[serde(default)]
+    mode: CountMode,
+    #[serde(default)]
+    refresh: bool,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CountDocumentsArgs {
+    collection: String,
+    filter: Option<String>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::CountCommandOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct EstimatedCountArgs {
+    collection: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+struct Cached {
+    count: u64,
+    at: u64,
+}
+
+/// A watched collection's cached counts, keyed by mode and filter.
+struct Namespace {
+    counts: HashMap<String, Cached>,
+    /// Bumped on every invalidation, so a count that raced a change isn't
+    /// cached.
+    generation: u64,
+    watcher: AbortHandle,
+}
+
+/// The counts cached for one connection. Dropping it closes its change
+/// streams.
+pub(super) struct CountCache {
+    namespaces: Mutex<HashMap<String, Namespace>>,
+    max_watches: usize,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+impl CountCache {
+    pub(super) fn new(max_watches: Option<usize>) -> Self {
+        Self { namespaces: Mutex::new(HashMap::new()), max_watches: max_watches.unwrap_or(DEFAULT_WATCHES) }
+    }
+
+    /// Drops the cached counts of `database.collection`.
+    pub(super) fn invalidate(&self, database: &str, collection: &str) {
+        self.invalidate_namespace(&format!("{}.{}", database, collection));
+    }
+
+    fn invalidate_namespace(&self, namespace: &str) {
+        if let Some(entry) = self.namespaces.lock().unwrap().get_mut(namespace) {
+            entry.counts.clear();
+            entry.generation += 1;
+        }
+    }
+
+    /// Stops caching a namespace whose change stream ended.
+    fn forget(&self, namespace: &str) {
+        self.namespaces.lock().unwrap().remove(namespace);
+    }
+
+    fn cached(&self, namespace: &str, key: &str) -> Option<(u64, u64)> {
+        let namespaces = self.namespaces.lock().unwrap();
+        namespaces.get(namespace)?.counts.get(key).map(|cached| (cached.count, cached.at))
+    }
+
+    /// The namespace's generation once it is watched, opening a change
+    /// stream for it if there's room for one, or `None` if it can't be.
+    async fn watch(self: &Arc<Self>, db: &Database, collection: &str, namespace: &str) -> Option<u64> {
+        {
+            let namespaces = self.namespaces.lock().unwrap();
+            if let Some(entry) = namespaces.get(namespace) {
+                return Some(entry.generation);
+            }
+            if namespaces.len() >= self.max_watches {
+                return None;
+            }
+        }
+        // Servers without change streams, such as standalones, refuse here.
+        let mut changes = db.collection::<Document>(collection).watch(None, None).await.ok()?;
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if let Some(entry) = namespaces.get(namespace) {
+            // Another count started watching it meanwhile; this stream is dropped.
+            return Some(entry.generation);
+        }
+        if namespaces.len() >= self.max_watches {
+            return None;
+        }
+        let cache = Arc::downgrade(self);
+        let watched = namespace.to_string();
+        let watcher = tokio::spawn(async move {
+            while let Some(change) = changes.next().await {
+                let cache = match cache.upgrade() {
+                    Some(cache) => cache,
+                    None => return,
+                };
+                if change.is_err() {
+                    break;
+                }
+                cache.invalidate_namespace(&watched);
+            }
+            if let Some(cache) = cache.upgrade() {
+                cache.forget(&watched);
+            }
+        });
+        namespaces.insert(namespace.to_string(), Namespace { counts: HashMap::new(), generation: 0, watcher: watcher.abort_handle() });
+        Some(0)
+    }
+
+    fn store(&self, namespace: &str, key: String, generation: u64, count: u64) {
+        if let Some(entry) = self.namespaces.lock().unwrap().get_mut(namespace) {
+            if entry.generation == generation {
+                entry.counts.insert(key, Cached { count, at: now_ms() });
+            }
+        }
+    }
+}
+
+impl Drop for CountCache {
+    fn drop(&mut self) {
+        for entry in self.namespaces.get_mut().unwrap().values() {
+            entry.watcher.abort();
+        }
+    }
+}
+
+pub(super) async fn count(ctx: CommandContext, args: CountArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match &args.filter {
+        Some(filter) => match convert::from_extjson(filter) {
+            Ok(filter) => filter,
+            Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+        },
+        None => Document::new(),
+    };
+    let estimated = match args.mode {
+        CountMode::Estimated if !filter.is_empty() => {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "An estimated count can't take a filter"));
+        }
+        CountMode::Estimated => true,
+        CountMode::Exact => false,
+        CountMode::Auto => filter.is_empty(),
+    };
+    let namespace = format!("{}.{}", ctx.db.name(), args.collection);
+    let key = format!("{}:{}", if estimated { "estimated" } else { "exact" }, serde_json::to_string(&filter).unwrap());
+    if !args.refresh {
+        if let Some((count, at)) = ctx.counts.cached(&namespace, &key) {
+            return Ok(json!({ "count": count, "estimated": estimated, "cached": true, "cachedAt": at }));
+        }
+    }
+    // Watching starts before counting so no change between the two is missed.
+    let generation = ctx.counts.watch(&ctx.db, &args.collection, &namespace).await;
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    let max_time = args.max_time_ms.map(Duration::from_millis);
+    let counted = if estimated {
+        coll.estimated_document_count(EstimatedDocumentCountOptions::builder().max_time(max_time).build()).await
+    } else {
+        coll.count_documents(filter, CountOptions::builder().max_time(max_time).build()).await
+    };
+    let count = match counted {
+        Ok(count) => count,
+        Err(e) => return Err(errors::failed("Failed to count documents", e)),
+    };
+    if let Some(generation) = generation {
+        ctx.counts.store(&namespace, key, generation, count);
+    }
+    Ok(json!({ "count": count, "estimated": estimated, "cached": false }))
+}
+
+pub(super) async fn count_documents(db: Database, args: CountDocumentsArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match args.filter.as_deref().map(convert::from_extjson) {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => return Err(errors::invalid("Failed to parse filter", e)),
+        None => Document::new(),
+    };
+    let options = args.options.count(args.max_time_ms);
+    match db.collection::<Document>(&args.collection).count_documents(filter, options).await {
+        Ok(count) => Ok(json!(count)),
+        Err(e) => Err(errors::failed("Failed to count documents", e)),
+    }
+}
+
+/// The collection's metadata count; `soft_deletes` when deleted documents
+/// are only stamped, which the count can't leave out.
+pub(super) async fn estimated_document_count(db: Database, args: EstimatedCountArgs, soft_deletes: bool) -> Result<JsonValue, MongoPluginError> {
+    if soft_deletes && !args.include_deleted {
+        let message = "An estimated count includes soft-deleted documents: pass includeDeleted: true";
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+    }
+    let options = EstimatedDocumentCountOptions::builder().max_time(args.max_time_ms.map(Duration::from_millis)).build();
+    match db.collection::<Document>(&args.collection).estimated_document_count(options).await {
+        Ok(count) => Ok(json!(count)),
+        Err(e) => Err(errors::failed("Failed to count documents", e)),
+    }
+}
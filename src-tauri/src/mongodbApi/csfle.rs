@@ -0,0 +1,322 @@
+//! The driver's client-side field level encryption, for builds with the
+//! `csfle` feature.
+//!
+//! With a `csfle` section in the plugin config, every connection encrypts
+//! the fields its `schemaMap` marks before documents leave the device and
+//! decrypts them in results, and can query fields encrypted with the
+//! deterministic algorithm. The schema map holds a `$jsonSchema` per
+//! namespace, `db.collection`, with `encrypt` on each protected field:
+//!
+//! ```json
+//! "csfle": {
+//!   "kmsProviders": { "local": {} },
+//!   "schemaMap": {
+//!     "clinic.patients": {
+//!       "bsonType": "object",
+//!       "encryptMetadata": { "keyId": [{ "$binary": { "base64": "...", "subType": "04" } }] },
+//!       "properties": {
+//!         "ssn": { "encrypt": { "bsonType": "string", "algorithm": "AEAD_AES_256_CBC_HMAC_SHA_512-Deterministic" } }
+//!       }
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! Data keys live in `keyVaultNamespace`, `encryption.__keyVault` by
+//! default, each wrapped by a master key from a KMS provider: `local`, a
+//! 96-byte key kept in `keyFile` under the app data directory and generated
+//! when missing; `aws`; or `azure`. AWS and Azure take their credentials
+//! from the config, or from the environment when they are left out.
+//! `createDataKey` makes a key, `listDataKeys` lists them without their key
+//! material, `deleteDataKey` removes one, and `addKeyAltName` and
+//! `removeKeyAltName` manage the names a schema can refer to a key by.
+//! `mongocryptd` is spawned for automatic encryption unless
+//! `cryptSharedLibPath` points at the crypt_shared library.
+//!
+//! Without the `csfle` feature, configuring it fails the plugin's
+//! initialization; see [`encryption`](super::encryption) for the plugin's
+//! own field encryption, which needs no native library.
+
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[cfg(feature = "csfle")]
+use {
+    super::encryption::{app_data_path, load_or_create_key},
+    super::errors::{self, MongoPluginError},
+    super::{convert, CommandContext, NoArgs},
+    futures::TryStreamExt,
+    mongodb::bson::{doc, spec::BinarySubtype, Binary, Bson, Document, Uuid},
+    mongodb::client_encryption::{ClientEncryption, MasterKey},
+    mongodb::mongocrypt::ctx::KmsProvider,
+    mongodb::options::{ClientOptions, TlsOptions},
+    mongodb::{Client, Namespace},
+    serde_json::json,
+    std::sync::Arc,
+};
+
+#[cfg(feature = "csfle")]
+const DEFAULT_KEY_VAULT: &str = "encryption.__keyVault";
+#[cfg(feature = "csfle")]
+const DEFAULT_LOCAL_KEY_FILE: &str = "mongo-csfle-master.key";
+
+/// The `csfle` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CsfleConfig {
+    /// `db.collection` holding the data keys.
+    pub key_vault_namespace: Option<String>,
+    pub kms_providers: KmsProvidersConfig,
+    /// `$jsonSchema` per `db.collection` naming the fields to encrypt.
+    pub schema_map: HashMap<String, JsonValue>,
+    /// The crypt_shared library to use instead of spawning `mongocryptd`.
+    pub crypt_shared_lib_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct KmsProvidersConfig {
+    pub local: Option<LocalKms>,
+    pub aws: Option<AwsKms>,
+    pub azure: Option<AzureKms>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LocalKms {
+    /// File holding the base64-encoded 96-byte master key, relative to the
+    /// app data directory unless absolute. Generated if it doesn't exist.
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AwsKms {
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AzureKms {
+    pub tenant_id: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub identity_platform_endpoint: Option<String>,
+}
+
+#[cfg(feature = "csfle")]
+type KmsProviders = Vec<(KmsProvider, Document, Option<TlsOptions>)>;
+
+#[cfg(feature = "csfle")]
+pub(super) struct Csfle {
+    key_vault: Namespace,
+    kms_providers: KmsProviders,
+    schema_map: Vec<(String, Document)>,
+    extra_options: Option<Document>,
+}
+
+/// Without the driver's encryption support there's nothing to configure.
+#[cfg(not(feature = "csfle"))]
+pub(super) struct Csfle;
+
+#[cfg(not(feature = "csfle"))]
+impl Csfle {
+    pub(super) fn load(_: &CsfleConfig, _: Option<PathBuf>) -> Result<Self, String> {
+        Err("csfle is configured but the plugin was built without the `csfle` feature".to_string())
+    }
+}
+
+#[cfg(feature = "csfle")]
+fn credentials(fields: &[(&str, &Option<String>)]) -> Document {
+    fields.iter().filter_map(|(key, value)| Some((key.to_string(), Bson::String(value.clone()?)))).collect()
+}
+
+#[cfg(feature = "csfle")]
+impl Csfle {
+    pub(super) fn load(config: &CsfleConfig, app_data_dir: Option<PathBuf>) -> Result<Self, String> {
+        let namespace = config.key_vault_namespace.as_deref().unwrap_or(DEFAULT_KEY_VAULT);
+        let key_vault = match namespace.split_once('.') {
+            Some((db, coll)) if !db.is_empty() && !coll.is_empty() => Namespace { db: db.to_string(), coll: coll.to_string() },
+            _ => return Err(format!("keyVaultNamespace '{}' must be 'database.collection'", namespace)),
+        };
+
+        let providers = &config.kms_providers;
+        let mut kms_providers = KmsProviders::new();
+        if let Some(local) = &providers.local {
+            let path = app_data_path(local.key_file.as_deref(), DEFAULT_LOCAL_KEY_FILE, app_data_dir)?;
+            let key: [u8; 96] = load_or_create_key(&path)?;
+            let key = Binary { subtype: BinarySubtype::Generic, bytes: key.to_vec() };
+            kms_providers.push((KmsProvider::Local, doc! { "key": key }, None));
+        }
+        if let Some(aws) = &providers.aws {
+            let fields = [("accessKeyId", &aws.access_key_id), ("secretAccessKey", &aws.secret_access_key), ("sessionToken", &aws.session_token)];
+            kms_providers.push((KmsProvider::Aws, credentials(&fields), None));
+        }
+        if let Some(azure) = &providers.azure {
+            let fields = [
+                ("tenantId", &azure.tenant_id),
+                ("clientId", &azure.client_id),
+                ("clientSecret", &azure.client_secret),
+                ("identityPlatformEndpoint", &azure.identity_platform_endpoint),
+            ];
+            kms_providers.push((KmsProvider::Azure, credentials(&fields), None));
+        }
+        if kms_providers.is_empty() {
+            return Err("csfle needs at least one of kmsProviders.local, aws or azure".to_string());
+        }
+
+        let mut schema_map = Vec::with_capacity(config.schema_map.len());
+        for (namespace, schema) in &config.schema_map {
+            let schema = convert::from_extjson(&schema.to_string()).map_err(|e| format!("Failed to read the csfle schema of '{}': {}", namespace, e))?;
+            schema_map.push((namespace.clone(), schema));
+        }
+        let extra_options = config.crypt_shared_lib_path.as_ref().map(|path| {
+            doc! { "cryptSharedLibPath": path.to_string_lossy().into_owned(), "cryptSharedLibRequired": true }
+        });
+        Ok(Self { key_vault, kms_providers, schema_map, extra_options })
+    }
+
+    /// A client that encrypts and decrypts automatically.
+    pub(super) async fn client(&self, options: ClientOptions) -> mongodb::error::Result<Client> {
+        let builder = Client::encrypted_builder(options, self.key_vault.clone(), self.kms_providers.clone())?;
+        let builder = builder.schema_map(self.schema_map.clone()).extra_options(self.extra_options.clone());
+        builder.build().await
+    }
+
+    fn key_vault(&self, ctx: &CommandContext) -> mongodb::Collection<Document> {
+        ctx.client.database(&self.key_vault.db).collection(&self.key_vault.coll)
+    }
+}
+
+#[cfg(feature = "csfle")]
+fn enabled(ctx: &CommandContext) -> Result<&Arc<Csfle>, MongoPluginError> {
+    match &ctx.csfle {
+        Some(csfle) => Ok(csfle),
+        None => {
+            let message = "Client-side field level encryption is not enabled: set csfle in the plugin config";
+            Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message))
+        }
+    }
+}
+
+/// A data key's id, given as a UUID string or as Extended JSON binary.
+#[cfg(feature = "csfle")]
+fn key_id(id: &JsonValue) -> Result<Binary, MongoPluginError> {
+    let invalid = |message: String| MongoPluginError::new(errors::ErrorKind::InvalidArgument, message);
+    match id {
+        JsonValue::String(text) => match Uuid::parse_str(text) {
+            Ok(uuid) => Ok(Binary { subtype: BinarySubtype::Uuid, bytes: uuid.bytes().to_vec() }),
+            Err(e) => Err(invalid(format!("keyId '{}' is not a UUID: {}", text, e))),
+        },
+        other => match Bson::try_from(other.clone()) {
+            Ok(Bson::Binary(binary)) => Ok(binary),
+            _ => Err(invalid("keyId must be a UUID string or a binary".to_string())),
+        },
+    }
+}
+
+#[cfg(feature = "csfle")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CreateDataKeyArgs {
+    /// `local`, `aws` or `azure`.
+    kms_provider: String,
+    /// For `aws` `{ region, key, endpoint }`, for `azure` `{ keyVaultEndpoint,
+    /// keyName, keyVersion }`.
+    #[serde(default)]
+    master_key: JsonValue,
+    #[serde(default)]
+    key_alt_names: Vec<String>,
+}
+
+#[cfg(feature = "csfle")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DataKeyArgs {
+    key_id: JsonValue,
+}
+
+#[cfg(feature = "csfle")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct KeyAltNameArgs {
+    key_id: JsonValue,
+    key_alt_name: String,
+}
+
+#[cfg(feature = "csfle")]
+fn master_key(provider: &str, spec: &JsonValue) -> Result<MasterKey, MongoPluginError> {
+    let text = |key: &str| spec.get(key).and_then(JsonValue::as_str).map(str::to_string);
+    let required = |key: &str| text(key).ok_or_else(|| MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("masterKey.{} is required for {}", key, provider)));
+    match provider {
+        "local" => Ok(MasterKey::Local),
+        "aws" => Ok(MasterKey::Aws { region: required("region")?, key: required("key")?, endpoint: text("endpoint") }),
+        "azure" => Ok(MasterKey::Azure { key_vault_endpoint: required("keyVaultEndpoint")?, key_name: required("keyName")?, key_version: text("keyVersion") }),
+        other => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown KMS provider '{}'", other))),
+    }
+}
+
+#[cfg(feature = "csfle")]
+pub(super) async fn create_data_key(ctx: CommandContext, args: CreateDataKeyArgs) -> Result<JsonValue, MongoPluginError> {
+    let csfle = enabled(&ctx)?;
+    let master_key = master_key(&args.kms_provider, &args.master_key)?;
+    let encryption = match ClientEncryption::new(ctx.client.clone(), csfle.key_vault.clone(), csfle.kms_providers.clone()) {
+        Ok(encryption) => encryption,
+        Err(e) => return Err(errors::failed("Failed to set up encryption", e)),
+    };
+    match encryption.create_data_key(master_key).key_alt_names(args.key_alt_names).run().await {
+        Ok(id) => Ok(json!({ "keyId": convert::to_json(id) })),
+        Err(e) => Err(errors::failed("Failed to create data key", e)),
+    }
+}
+
+/// The data keys in the key vault, without their key material.
+#[cfg(feature = "csfle")]
+pub(super) async fn list_data_keys(ctx: CommandContext, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let options = mongodb::options::FindOptions::builder().projection(doc! { "keyMaterial": 0 }).build();
+    let cursor = match enabled(&ctx)?.key_vault(&ctx).find(None, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to list data keys", e)),
+    };
+    match cursor.try_collect::<Vec<Document>>().await {
+        Ok(keys) => Ok(convert::to_json(keys.into_iter().map(Bson::Document).collect::<Vec<_>>())),
+        Err(e) => Err(errors::failed("Failed to list data keys", e)),
+    }
+}
+
+/// Deletes a data key. Fields encrypted with it can't be decrypted again.
+#[cfg(feature = "csfle")]
+pub(super) async fn delete_data_key(ctx: CommandContext, args: DataKeyArgs) -> Result<JsonValue, MongoPluginError> {
+    let id = key_id(&args.key_id)?;
+    match enabled(&ctx)?.key_vault(&ctx).delete_one(doc! { "_id": id }, None).await {
+        Ok(result) if result.deleted_count == 0 => Err(MongoPluginError::new(errors::ErrorKind::NotFound, "No such data key")),
+        Ok(_) => Ok(json!({ "deleted": true })),
+        Err(e) => Err(errors::failed("Failed to delete data key", e)),
+    }
+}
+
+#[cfg(feature = "csfle")]
+async fn update_alt_names(ctx: CommandContext, args: KeyAltNameArgs, update: Document) -> Result<JsonValue, MongoPluginError> {
+    let id = key_id(&args.key_id)?;
+    match enabled(&ctx)?.key_vault(&ctx).update_one(doc! { "_id": id }, update, None).await {
+        Ok(result) if result.matched_count == 0 => Err(MongoPluginError::new(errors::ErrorKind::NotFound, "No such data key")),
+        Ok(result) => Ok(json!({ "modified": result.modified_count > 0 })),
+        Err(e) => Err(errors::failed("Failed to update data key", e)),
+    }
+}
+
+#[cfg(feature = "csfle")]
+pub(super) async fn add_key_alt_name(ctx: CommandContext, args: KeyAltNameArgs) -> Result<JsonValue, MongoPluginError> {
+    let update = doc! { "$addToSet": { "keyAltNames": &args.key_alt_name } };
+    update_alt_names(ctx, args, update).await
+}
+
+#[cfg(feature = "csfle")]
+pub(super) async fn remove_key_alt_name(ctx: CommandContext, args: KeyAltNameArgs) -> Result<JsonValue, MongoPluginError> {
+    let update = doc! { "$pull": { "keyAltNames": &args.key_alt_name } };
+    update_alt_names(ctx, args, update).await
+}
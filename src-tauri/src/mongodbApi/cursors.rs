@@ -0,0 +1,242 @@
+//! Server cursors the frontend pages through itself.
+//!
+//! `findCursor` and `aggregateCursor` take the same arguments as `find` and
+//! `aggregate` but return a `cursorId` instead of the results. Each
+//! `cursorNext` then reads the next `batchSize` documents (100 by default)
+//! as `{ documents, done }`, and `cursorClose` lets go of a cursor before
+//! it is exhausted. Only what the frontend asks for is ever read from the
+//! server. A cursor left alone for five minutes is closed, and so is one
+//! that has returned its last batch.
+//!
+//! A cursor doesn't see writes made after it was opened in any dependable
+//! way, so with `staleCursors` in the plugin config a write through the
+//! plugin to a collection marks the cursors open on it: `annotate` has
+//! their later batches carry `stale: true`, `close` closes them. Either way
+//! `mongo://cursors-stale` is emitted with the `namespace`, the `cursorIds`
+//! and whether they were `closed`, so grids paging through them know to
+//! reload.
+//!
+//! A cursor belongs to the window, and tenant, that opened it; to any other
+//! its id reads as unknown.
+
+use futures::TryStreamExt;
+use mongodb::bson::{oid::ObjectId, Document};
+use mongodb::Cursor;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::sync::{mpsc, oneshot};
+
+use super::errors::{self, MongoPluginError};
+use super::events::EventSink;
+use super::{convert, read_options, CommandContext, MongoState, Owner};
+
+const DEFAULT_BATCH_SIZE: u32 = 100;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const STALE_EVENT: &str = "mongo://cursors-stale";
+
+/// What a write does to the cursors open on its collection.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum StaleCursors {
+    /// Mark their later batches `stale`.
+    Annotate,
+    /// Close them.
+    Close,
+}
+
+/// A `cursorNext` waiting on the task that owns the cursor.
+struct Next {
+    batch_size: usize,
+    reply: oneshot::Sender<Result<JsonValue, MongoPluginError>>,
+}
+
+struct OpenCursor {
+    sender: mpsc::Sender<Next>,
+    namespace: String,
+    stale: Arc<AtomicBool>,
+    owner: Owner,
+}
+
+/// Open cursors, each reached through the task that owns it.
+pub(super) struct Cursors {
+    open: Mutex<HashMap<String, OpenCursor>>,
+    on_write: Option<StaleCursors>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct FindCursorArgs {
+    collection: String,
+    query: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::FindCommandOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct AggregateCursorArgs {
+    collection: String,
+    pipeline: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::AggregateCommandOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CursorNextArgs {
+    id: String,
+    batch_size: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct CursorCloseArgs {
+    id: String,
+}
+
+impl Cursors {
+    pub(super) fn new(on_write: Option<StaleCursors>) -> Self {
+        Self { open: Mutex::default(), on_write }
+    }
+
+    /// Marks or closes the cursors open on the namespace of a
+    /// `mongo://write` event, as configured.
+    pub(super) fn written(&self, events: &EventSink, write: &JsonValue) {
+        let (on_write, namespace) = match (self.on_write, write["namespace"].as_str()) {
+            (Some(on_write), Some(namespace)) => (on_write, namespace),
+            _ => return,
+        };
+        let mut open = self.open.lock().unwrap();
+        let ids: Vec<String> = open.iter().filter(|(_, cursor)| cursor.namespace == namespace).map(|(id, _)| id.clone()).collect();
+        if ids.is_empty() {
+            return;
+        }
+        for id in &ids {
+            match on_write {
+                StaleCursors::Annotate => open[id].stale.store(true, Ordering::Relaxed),
+                // Dropping the sender ends the cursor's task.
+                StaleCursors::Close => drop(open.remove(id)),
+            }
+        }
+        drop(open);
+        events(STALE_EVENT, json!({ "namespace": namespace, "cursorIds": ids, "closed": on_write == StaleCursors::Close }));
+    }
+
+    /// Closes every open cursor.
+    pub(super) fn close_all(&self) {
+        self.open.lock().unwrap().clear();
+    }
+}
+
+/// Hands the cursor to a task of its own and returns its id.
+fn open(ctx: CommandContext, command: &'static str, collection: String, cursor: Cursor<Document>) -> JsonValue {
+    let id = ObjectId::new().to_hex();
+    let (sender, receiver) = mpsc::channel(1);
+    let stale = Arc::new(AtomicBool::new(false));
+    let namespace = format!("{}.{}", ctx.db.name(), collection);
+    let cursor = OpenCursor { sender, namespace, stale: stale.clone(), owner: ctx.owner() };
+    ctx.cursors.open.lock().unwrap().insert(id.clone(), cursor);
+    let cursor_id = id.clone();
+    // On whichever runtime the command runs on, see `runtime`.
+    tokio::spawn(async move {
+        serve(&ctx, command, &collection, cursor, &stale, receiver).await;
+        ctx.cursors.open.lock().unwrap().remove(&cursor_id);
+    });
+    json!({ "cursorId": id })
+}
+
+/// Answers `cursorNext` calls until the cursor is exhausted, fails, is
+/// closed or goes unused for too long.
+async fn serve(ctx: &CommandContext, command: &str, collection: &str, mut cursor: Cursor<Document>, stale: &AtomicBool, mut next: mpsc::Receiver<Next>) {
+    loop {
+        let Next { batch_size, reply } = match tokio::time::timeout(IDLE_TIMEOUT, next.recv()).await {
+            Ok(Some(request)) => request,
+            // Closed, or idle past the timeout.
+            Ok(None) | Err(_) => return,
+        };
+        // Grown as documents arrive: the batch size is the caller's.
+        let mut documents = Vec::new();
+        let mut done = false;
+        while documents.len() < batch_size {
+            match cursor.try_next().await {
+                Ok(Some(doc)) => documents.push(convert::to_json(doc)),
+                Ok(None) => {
+                    done = true;
+                    break;
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(errors::failed("Failed to read results", e)));
+                    return;
+                }
+            }
+        }
+        let mut documents = JsonValue::Array(documents);
+        ctx.transforms.finish(command, Some(collection), &mut documents);
+        let _ = reply.send(Ok(json!({ "documents": documents, "done": done, "stale": stale.load(Ordering::Relaxed) })));
+        if done {
+            return;
+        }
+    }
+}
+
+pub(super) async fn find_cursor(ctx: CommandContext, args: FindCursorArgs) -> Result<JsonValue, MongoPluginError> {
+    let query: Document = match convert::from_extjson(&args.query) {
+        Ok(query) => query,
+        Err(e) => return Err(errors::invalid("Failed to parse query", e)),
+    };
+    let mut options = args.options.find(args.max_time_ms);
+    options.batch_size = options.batch_size.or(Some(DEFAULT_BATCH_SIZE));
+    match ctx.db.collection::<Document>(&args.collection).find(query, options).await {
+        Ok(cursor) => Ok(open(ctx, "find", args.collection, cursor)),
+        Err(e) => Err(errors::failed("Failed to execute query", e)),
+    }
+}
+
+pub(super) async fn aggregate_cursor(ctx: CommandContext, args: AggregateCursorArgs) -> Result<JsonValue, MongoPluginError> {
+    let pipeline: Vec<Document> = match convert::from_extjson(&args.pipeline) {
+        Ok(pipeline) => pipeline,
+        Err(e) => return Err(errors::invalid("Failed to parse pipeline", e)),
+    };
+    let mut options = args.options.aggregate(args.max_time_ms);
+    options.batch_size = options.batch_size.or(Some(DEFAULT_BATCH_SIZE));
+    match ctx.db.collection::<Document>(&args.collection).aggregate(pipeline, options).await {
+        Ok(cursor) => Ok(open(ctx, "aggregate", args.collection, cursor)),
+        Err(e) => Err(errors::failed("Failed to execute aggregation", e)),
+    }
+}
+
+/// Reads the cursor's next batch. Only the window and tenant that opened
+/// the cursor can.
+pub(super) async fn cursor_next<R: Runtime>(app: AppHandle<R>, owner: Owner, args: CursorNextArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let sender = state.cursors.open.lock().unwrap().get(&args.id).filter(|cursor| cursor.owner == owner).map(|cursor| cursor.sender.clone());
+    let (reply, replied) = oneshot::channel();
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1) as usize;
+    match sender {
+        Some(sender) if sender.send(Next { batch_size, reply }).await.is_ok() => {}
+        _ => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No open cursor '{}'", args.id))),
+    }
+    match replied.await {
+        Ok(batch) => batch,
+        Err(_) => Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("Cursor '{}' was closed", args.id))),
+    }
+}
+
+pub(super) async fn cursor_close<R: Runtime>(app: AppHandle<R>, owner: Owner, args: CursorCloseArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let mut open = state.cursors.open.lock().unwrap();
+    let closed = match open.get(&args.id) {
+        Some(cursor) if cursor.owner == owner => open.remove(&args.id).is_some(),
+        _ => false,
+    };
+    Ok(json!({ "closed": closed }))
+}
@@ -0,0 +1,73 @@
+//! Default field values taken from a collection's JSON Schema.
+//!
+//! `schemas` in the plugin config maps a collection to a JSON Schema, given
+//! as is or as a validator's `{ $jsonSchema }`, whose properties may carry a
+//! `default`. `insertOne` and `insertMany` set each such property a
+//! document leaves out to its default, read as relaxed Extended JSON, and
+//! fill in the properties of nested objects, and of objects in arrays per
+//! their `items`, the same way. Fields the document gives, including ones
+//! set to `null`, are kept. The server's `$jsonSchema` doesn't accept
+//! `default`, so the schema lives in the config rather than on the
+//! collection.
+
+use mongodb::bson::{Bson, Document};
+use serde_json::Value as JsonValue;
+
+use super::{convert, MongoConfig};
+
+/// Sets the defaults `schema` declares on `doc` and the objects within it.
+fn fill(schema: &JsonValue, doc: &mut Document) {
+    let properties = match schema.get("properties").and_then(JsonValue::as_object) {
+        Some(properties) => properties,
+        None => return,
+    };
+    for (field, property) in properties {
+        if !doc.contains_key(field) {
+            // A default that isn't valid Extended JSON is left out.
+            if let Some(Ok(default)) = property.get("default").cloned().map(Bson::try_from) {
+                doc.insert(field, default);
+            }
+        }
+        match doc.get_mut(field) {
+            Some(Bson::Document(nested)) => fill(property, nested),
+            Some(Bson::Array(items)) => {
+                if let Some(items_schema) = property.get("items") {
+                    for item in items.iter_mut() {
+                        if let Bson::Document(item) = item {
+                            fill(items_schema, item);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fills the schema's defaults into the documents an insert carries.
+pub(super) fn apply(config: &MongoConfig, command: &str, payload: &mut JsonValue) {
+    if !matches!(command, "insertOne" | "insertMany") {
+        return;
+    }
+    let schema = match payload.get("collection").and_then(JsonValue::as_str).and_then(|collection| config.schemas.get(collection)) {
+        Some(schema) => schema.get("$jsonSchema").unwrap_or(schema),
+        None => return,
+    };
+    let mut parsed = match payload.get("data").and_then(JsonValue::as_str).map(convert::from_extjson::<Bson>) {
+        Some(Ok(parsed)) => parsed,
+        // Malformed arguments are reported by the command itself.
+        _ => return,
+    };
+    match &mut parsed {
+        Bson::Document(doc) => fill(schema, doc),
+        Bson::Array(docs) => {
+            for doc in docs.iter_mut() {
+                if let Bson::Document(doc) = doc {
+                    fill(schema, doc);
+                }
+            }
+        }
+        _ => return,
+    }
+    payload["data"] = JsonValue::String(convert::to_extjson_text(parsed));
+}
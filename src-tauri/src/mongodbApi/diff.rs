@@ -0,0 +1,133 @@
+//! Field-level differences between two documents.
+
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use super::errors::{self, MongoPluginError};
+use super::{coerce_id, convert, CommandContext};
+
+#[derive(Deserialize)]
+pub(super) struct DiffDocumentsArgs {
+    a: String,
+    b: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct DiffWithCurrentArgs {
+    collection: String,
+    id: JsonValue,
+    candidate: String,
+}
+
+#[derive(Default)]
+struct Diff {
+    added: Vec<JsonValue>,
+    removed: Vec<JsonValue>,
+    changed: Vec<JsonValue>,
+}
+
+impl Diff {
+    fn into_json(self) -> JsonValue {
+        json!({ "added": self.added, "removed": self.removed, "changed": self.changed })
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Extended JSON wrappers such as `{ "$oid": ... }` are single values, not
+/// sub-documents to descend into.
+fn is_wrapper(map: &serde_json::Map<String, JsonValue>) -> bool {
+    !map.is_empty() && map.keys().all(|key| key.starts_with('$'))
+}
+
+/// Numbers compare by value, so an `int` read back as a `long` isn't a change.
+fn same_value(old: &JsonValue, new: &JsonValue) -> bool {
+    match (old.as_f64(), new.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => old == new,
+    }
+}
+
+fn diff_values(path: &str, old: &JsonValue, new: &JsonValue, diff: &mut Diff) {
+    match (old, new) {
+        (JsonValue::Object(a), JsonValue::Object(b)) if !is_wrapper(a) && !is_wrapper(b) => {
+            for (key, value) in a {
+                match b.get(key) {
+                    Some(other) => diff_values(&child_path(path, key), value, other, diff),
+                    None => diff.removed.push(json!({ "path": child_path(path, key), "value": value })),
+                }
+            }
+            for (key, value) in b {
+                if !a.contains_key(key) {
+                    diff.added.push(json!({ "path": child_path(path, key), "value": value }));
+                }
+            }
+        }
+        (JsonValue::Array(a), JsonValue::Array(b)) => {
+            for (index, value) in a.iter().enumerate() {
+                let item_path = child_path(path, &index.to_string());
+                match b.get(index) {
+                    Some(other) => diff_values(&item_path, value, other, diff),
+                    None => diff.removed.push(json!({ "path": item_path, "value": value })),
+                }
+            }
+            for (index, value) in b.iter().enumerate().skip(a.len()) {
+                diff.added.push(json!({ "path": child_path(path, &index.to_string()), "value": value }));
+            }
+        }
+        _ if same_value(old, new) => {}
+        _ => diff.changed.push(json!({ "path": path, "old": old, "new": new })),
+    }
+}
+
+/// A document from its Extended JSON text, in the JSON form results take.
+fn parse_document(text: &str, what: &str) -> Result<JsonValue, MongoPluginError> {
+    match convert::from_extjson::<Document>(text) {
+        Ok(doc) => Ok(serde_json::to_value(doc).unwrap()),
+        Err(e) => Err(errors::invalid(&format!("Failed to parse {}", what), e)),
+    }
+}
+
+/// The fields added, removed and changed going from `a` to `b`, by dotted
+/// path (array elements by index).
+pub(super) async fn diff_documents(args: DiffDocumentsArgs) -> Result<JsonValue, MongoPluginError> {
+    let a = parse_document(&args.a, "a")?;
+    let b = parse_document(&args.b, "b")?;
+    let mut diff = Diff::default();
+    diff_values("", &a, &b, &mut diff);
+    Ok(diff.into_json())
+}
+
+/// Diffs the stored document with `candidate`, the version about to be
+/// saved. A candidate without `_id` isn't reported as removing it.
+pub(super) async fn diff_with_current(ctx: CommandContext, args: DiffWithCurrentArgs) -> Result<JsonValue, MongoPluginError> {
+    let mut candidate = parse_document(&args.candidate, "candidate")?;
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    let current = match coll.find_one(doc! { "_id": coerce_id(&args.id)? }, None).await {
+        Ok(current) => current,
+        Err(e) => return Err(errors::failed("Failed to find document", e)),
+    };
+    let mut current = match current {
+        Some(current) => serde_json::to_value(current).unwrap(),
+        None => return Ok(json!({ "exists": false, "added": [], "removed": [], "changed": [] })),
+    };
+    // Compare plaintext with plaintext.
+    if let Some(encryption) = &ctx.transforms.encryption {
+        encryption.open_result(&mut current);
+    }
+    if let (Some(stored), Some(fields)) = (current.get("_id").cloned(), candidate.as_object_mut()) {
+        fields.entry("_id").or_insert(stored);
+    }
+    let mut diff = Diff::default();
+    diff_values("", &current, &candidate, &mut diff);
+    let mut result = diff.into_json();
+    result["exists"] = json!(true);
+    Ok(result)
+}
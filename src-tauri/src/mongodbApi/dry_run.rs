@@ -0,0 +1,133 @@
+//! Previews of destructive writes that leave the data as it was.
+//!
+//! With `dryRun: true`, `updateById`, `updateOne`, `updateMany`,
+//! `replaceOne`, `deleteById`, `deleteOne`, `deleteMany` and `bulkWrite` run
+//! inside a transaction that is then aborted, and reply with the counts the
+//! write reported plus `dryRun: true` and `exact: true`. The server really
+//! applies the write, so schema validation, unique indexes and malformed
+//! updates fail the preview the way they would fail the write. On servers
+//! without transactions, such as standalones, the preview counts the
+//! documents each filter matches instead and says `exact: false`: nothing
+//! is validated then and modified counts are unknown. A preview sends no
+//! `mongo://write` event and records no history, audit entry or version.
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::CountOptions;
+use serde_json::{json, Value as JsonValue};
+
+use super::batch::{self, Deletes};
+use super::errors::{self, MongoPluginError};
+use super::{bulk_write, coerce_id, convert, sessions, CommandContext, CommandFuture};
+
+/// Commands that accept `dryRun`.
+const PREVIEWED: &[&str] = &["updateById", "updateOne", "updateMany", "replaceOne", "deleteById", "deleteOne", "deleteMany", "bulkWrite"];
+
+/// Whether the command asks to be previewed rather than run.
+pub(super) fn requested(command: &str, payload: &JsonValue) -> bool {
+    PREVIEWED.contains(&command) && payload.get("dryRun").and_then(JsonValue::as_bool).unwrap_or(false)
+}
+
+pub(super) fn preview(ctx: &CommandContext, command: &str, payload: JsonValue, soft_field: Option<String>) -> CommandFuture {
+    let (ctx, command) = (ctx.clone(), command.to_string());
+    Box::pin(async move { Ok(run(&ctx, &command, payload, soft_field.as_deref()).await?) })
+}
+
+async fn run(ctx: &CommandContext, command: &str, payload: JsonValue, soft_field: Option<&str>) -> Result<JsonValue, MongoPluginError> {
+    if sessions::session_id(&payload).is_some() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "dryRun can't be combined with sessionId"));
+    }
+    let mut session = match ctx.client.start_session(None).await {
+        Ok(session) => session,
+        Err(e) => return Err(errors::failed("Failed to start session", e)),
+    };
+    match session.start_transaction(None).await {
+        Ok(()) => {}
+        Err(e) if matches!(*e.kind, ErrorKind::Transaction { .. }) => return count_matches(ctx, command, &payload, soft_field).await,
+        Err(e) => return Err(errors::failed("Failed to start transaction", e)),
+    }
+    let written = if command == "bulkWrite" {
+        match serde_json::from_value(payload) {
+            Ok(args) => bulk_write::write(ctx, args, Some(&mut session)).await,
+            Err(e) => Err(errors::invalid("Failed to parse arguments", e)),
+        }
+    } else {
+        // A trashed document leaves its collection like a deleted one does.
+        let deletes = soft_field.map_or(Deletes::Hard, Deletes::Soft);
+        batch::run_step(&ctx.db, &mut session, command, payload, deletes).await.map_err(batch::step_message)
+    };
+    // Whatever the write did is undone, failed or not.
+    let _ = session.abort_transaction().await;
+    let mut result = written?;
+    result["dryRun"] = json!(true);
+    result["exact"] = json!(true);
+    Ok(result)
+}
+
+async fn matching(ctx: &CommandContext, collection: &str, filter: Document, one: bool) -> Result<u64, MongoPluginError> {
+    let options = CountOptions::builder().limit(one.then_some(1)).build();
+    match ctx.db.collection::<Document>(collection).count_documents(filter, options).await {
+        Ok(count) => Ok(count),
+        Err(e) => Err(errors::failed("Failed to count documents", e)),
+    }
+}
+
+fn parse_filter(value: Option<&Bson>) -> Result<Document, MongoPluginError> {
+    match value {
+        Some(Bson::Document(filter)) => Ok(filter.clone()),
+        _ => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Every update, replace and delete operation needs a 'filter' document")),
+    }
+}
+
+/// The counts a preview can give without a transaction: what each filter
+/// matches, and whether an upsert would insert.
+async fn count_matches(ctx: &CommandContext, command: &str, payload: &JsonValue, soft_field: Option<&str>) -> Result<JsonValue, MongoPluginError> {
+    let collection = match payload.get("collection").and_then(JsonValue::as_str) {
+        Some(collection) => collection,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "collection is required")),
+    };
+    if command == "bulkWrite" {
+        let operations: Vec<Document> = match payload.get("operations").and_then(JsonValue::as_str).map(convert::from_extjson) {
+            Some(Ok(operations)) => operations,
+            Some(Err(e)) => return Err(errors::invalid("Failed to parse operations", e)),
+            None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "operations is required")),
+        };
+        let (mut inserted, mut matched, mut deleted) = (0u64, 0u64, 0u64);
+        for operation in &operations {
+            let (name, spec) = match operation.iter().next() {
+                Some((name, Bson::Document(spec))) => (name.as_str(), spec),
+                _ => {
+                    let message = "Every operation must be an object with one operation name";
+                    return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+                }
+            };
+            let one = !name.ends_with("Many");
+            match name {
+                "insertOne" => inserted += 1,
+                "updateOne" | "updateMany" | "replaceOne" => matched += matching(ctx, collection, parse_filter(spec.get("filter"))?, one).await?,
+                "deleteOne" | "deleteMany" => deleted += matching(ctx, collection, parse_filter(spec.get("filter"))?, one).await?,
+                other => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown operation name '{}'", other))),
+            }
+        }
+        return Ok(json!({ "dryRun": true, "exact": false, "insertedCount": inserted, "matchedCount": matched, "deletedCount": deleted }));
+    }
+
+    let filter = match command {
+        "updateById" | "deleteById" => doc! { "_id": coerce_id(payload.get("id").unwrap_or(&JsonValue::Null))? },
+        _ => match convert::from_extjson::<Document>(payload.get("filter").and_then(JsonValue::as_str).unwrap_or_default()) {
+            Ok(filter) => filter,
+            Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+        },
+    };
+    let deletes = command.starts_with("delete");
+    let filter = match (deletes, soft_field) {
+        (true, Some(field)) => doc! { "$and": [filter, { field: Bson::Null }] },
+        _ => filter,
+    };
+    let count = matching(ctx, collection, filter, !command.ends_with("Many")).await?;
+    if deletes {
+        return Ok(json!({ "dryRun": true, "exact": false, "deletedCount": count }));
+    }
+    let upsert = payload.get("upsert").and_then(JsonValue::as_bool).unwrap_or(false);
+    Ok(json!({ "dryRun": true, "exact": false, "matchedCount": count, "upsertedCount": u64::from(count == 0 && upsert) }))
+}
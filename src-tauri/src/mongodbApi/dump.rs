@@ -0,0 +1,273 @@
+//! Dumping a collection to a local file and restoring it from one.
+//!
+//! `exportCollection` writes the documents of `collection`, or only those
+//! matching `filter`, to the file at `path`: one canonical Extended JSON
+//! document per line, or with `format: "bson"` (the default for a `.bson`
+//! path) BSON documents back to back as `mongodump` writes them. Documents
+//! are written as stored, so encrypted fields stay sealed and signatures
+//! stay valid, though those of signed collections are checked on the way
+//! like any read, and it returns `{ operationId, path, count, bytes }`.
+//! `importCollection` reads such a file back into `collection` with
+//! unordered inserts of `batchSize` (1000 by default) documents at a time,
+//! counting documents whose `_id` is already there as `skipped`, and
+//! returns `{ operationId, count, skipped, dropped }`. With
+//! `dropBeforeImport` the collection is dropped first, which needs
+//! confirming when confirmations are configured.
+//!
+//! Both run as [operations](super::operations) of kind `exportCollection`
+//! and `importCollection`, reporting `{ count, bytes }` or
+//! `{ count, skipped }` every thousand documents. A cancelled export
+//! removes its file; a cancelled import keeps the batches it inserted.
+//! Paths must lie within one of the `dump.directories` in the plugin
+//! config. Indexes are not part of a dump; `exportSchema` and `applySchema`
+//! carry them.
+
+use futures::TryStreamExt;
+use mongodb::bson::{Bson, Document};
+use mongodb::{Collection, Cursor};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::signing::DocumentSigning;
+use super::{archive, convert, gridfs, CommandContext};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const PROGRESS_EVERY: u64 = 1000;
+
+/// The `dump` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DumpConfig {
+    /// Directories `exportCollection` may write and `importCollection` may read.
+    pub directories: Vec<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum Format {
+    Ndjson,
+    Bson,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ExportCollectionArgs {
+    collection: String,
+    path: PathBuf,
+    filter: Option<String>,
+    format: Option<Format>,
+    operation_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ImportCollectionArgs {
+    collection: String,
+    path: PathBuf,
+    format: Option<Format>,
+    #[serde(default)]
+    drop_before_import: bool,
+    batch_size: Option<usize>,
+    operation_id: Option<String>,
+}
+
+/// The format asked for, or the one the file's extension suggests.
+fn format_of(format: Option<Format>, path: &Path) -> Format {
+    format.unwrap_or(match path.extension().and_then(|extension| extension.to_str()) {
+        Some("bson") => Format::Bson,
+        _ => Format::Ndjson,
+    })
+}
+
+fn allowed(ctx: &CommandContext, path: &Path) -> Result<PathBuf, MongoPluginError> {
+    gridfs::within(&ctx.config.dump.directories, path, "a dump directory")
+}
+
+pub(super) async fn export_collection(ctx: CommandContext, args: ExportCollectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let path = allowed(&ctx, &args.path)?;
+    let filter: Document = match &args.filter {
+        Some(filter) => match convert::from_extjson(filter) {
+            Ok(filter) => filter,
+            Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+        },
+        None => Document::new(),
+    };
+    let cursor = match ctx.db.collection::<Document>(&args.collection).find(filter, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute query", e)),
+    };
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "exportCollection", args.operation_id)?;
+    let signing = ctx.transforms.signing.as_deref().filter(|signing| signing.is_signed(&args.collection));
+    let signing = signing.map(|signing| (signing, args.collection.as_str()));
+    let outcome = write_dump(cursor, &path, format_of(args.format, &path), signing, &mut operation).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+/// Writes the cursor's documents to `path`, checking their signatures
+/// against `signing` when the collection is signed.
+async fn write_dump(
+    mut cursor: Cursor<Document>,
+    path: &Path,
+    format: Format,
+    signing: Option<(&DocumentSigning, &str)>,
+    operation: &mut Operation,
+) -> Result<JsonValue, MongoPluginError> {
+    let file = match File::create(path).await {
+        Ok(file) => file,
+        Err(e) => return Err(errors::failed("Failed to create dump file", e)),
+    };
+    let mut writer = BufWriter::new(file);
+    let (mut count, mut bytes) = (0u64, 0u64);
+    let written: Result<(), MongoPluginError> = async {
+        while let Some(doc) = cursor.try_next().await.map_err(|e| errors::failed("Failed to read documents", e))? {
+            if !operation.proceed().await {
+                return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "The export was cancelled"));
+            }
+            if let Some((signing, collection)) = signing {
+                signing.verify_result("findOne", collection, &convert::to_json(doc.clone()));
+            }
+            let record = match format {
+                Format::Ndjson => {
+                    let mut line = serde_json::to_vec(&Bson::Document(doc).into_canonical_extjson()).unwrap();
+                    line.push(b'\n');
+                    line
+                }
+                Format::Bson => {
+                    let mut encoded = Vec::new();
+                    doc.to_writer(&mut encoded).map_err(|e| errors::failed("Failed to encode document", e))?;
+                    encoded
+                }
+            };
+            writer.write_all(&record).await.map_err(|e| errors::failed("Failed to write dump file", e))?;
+            count += 1;
+            bytes += record.len() as u64;
+            if count.is_multiple_of(PROGRESS_EVERY) {
+                operation.progress(json!({ "count": count, "bytes": bytes }));
+            }
+        }
+        writer.flush().await.map_err(|e| errors::failed("Failed to write dump file", e))
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(path).await;
+        return Err(e);
+    }
+    operation.progress(json!({ "count": count, "bytes": bytes }));
+    Ok(json!({ "operationId": operation.id(), "path": path, "count": count, "bytes": bytes }))
+}
+
+/// The documents of a dump file, read one at a time.
+enum Records {
+    Ndjson(Lines<BufReader<File>>),
+    Bson(BufReader<File>),
+}
+
+impl Records {
+    fn open(file: File, format: Format) -> Self {
+        match format {
+            Format::Ndjson => Self::Ndjson(BufReader::new(file).lines()),
+            Format::Bson => Self::Bson(BufReader::new(file)),
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<Document>, MongoPluginError> {
+        match self {
+            Self::Ndjson(lines) => loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return Ok(None),
+                    Err(e) => return Err(errors::failed("Failed to read dump file", e)),
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let value: JsonValue = serde_json::from_str(&line).map_err(|e| errors::failed("Failed to read dump file", e))?;
+                return match Bson::try_from(value) {
+                    Ok(Bson::Document(doc)) => Ok(Some(doc)),
+                    _ => Err("Failed to read dump file: a line is not a document".into()),
+                };
+            },
+            Self::Bson(reader) => {
+                // Each document starts with its length, the four bytes included.
+                let mut length = [0u8; 4];
+                match reader.read_exact(&mut length).await {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(errors::failed("Failed to read dump file", e)),
+                }
+                let size = i32::from_le_bytes(length);
+                if size < 5 {
+                    return Err("Failed to read dump file: a document has an invalid length".into());
+                }
+                let mut bytes = vec![0u8; size as usize];
+                bytes[..4].copy_from_slice(&length);
+                reader.read_exact(&mut bytes[4..]).await.map_err(|e| errors::failed("Failed to read dump file", e))?;
+                Document::from_reader(bytes.as_slice()).map(Some).map_err(|e| errors::failed("Failed to read dump file", e))
+            }
+        }
+    }
+}
+
+pub(super) async fn import_collection(ctx: CommandContext, args: ImportCollectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let path = allowed(&ctx, &args.path)?;
+    let file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => return Err(errors::failed("Failed to open dump file", e)),
+    };
+    let records = Records::open(file, format_of(args.format, &path));
+    let target = ctx.db.collection::<Document>(&args.collection);
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "importCollection", args.operation_id.clone())?;
+    let outcome = read_dump(&target, records, batch_size, args.drop_before_import, &mut operation).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn read_dump(
+    target: &Collection<Document>,
+    mut records: Records,
+    batch_size: usize,
+    drop_first: bool,
+    operation: &mut Operation,
+) -> Result<JsonValue, MongoPluginError> {
+    if drop_first {
+        if let Err(e) = target.drop(None).await {
+            return Err(errors::failed("Failed to drop collection", e));
+        }
+    }
+    let (mut count, mut skipped) = (0u64, 0u64);
+    loop {
+        if !operation.proceed().await {
+            return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "The import was cancelled"));
+        }
+        // Grown as documents arrive: the batch size is the caller's.
+        let mut docs = Vec::new();
+        while docs.len() < batch_size {
+            match records.next().await? {
+                Some(doc) => docs.push(doc),
+                None => break,
+            }
+        }
+        if docs.is_empty() {
+            break;
+        }
+        let before = count + skipped;
+        let (inserted, existing) = match archive::restore_batch(target, docs).await {
+            Ok(outcome) => outcome,
+            Err(e) => return Err(errors::failed("Failed to import documents", e)),
+        };
+        count += inserted;
+        skipped += existing;
+        if before / PROGRESS_EVERY != (count + skipped) / PROGRESS_EVERY {
+            operation.progress(json!({ "count": count, "skipped": skipped }));
+        }
+    }
+    operation.progress(json!({ "count": count, "skipped": skipped }));
+    Ok(json!({ "operationId": operation.id(), "count": count, "skipped": skipped, "dropped": drop_first }))
+}
@@ -0,0 +1,89 @@
+//! Finding documents that share the values of some fields.
+//!
+//! `findDuplicates` groups the documents matching `filter` that have every
+//! one of `keyFields` by those fields' values and returns the groups of two
+//! or more, largest first and at most `limit` (100 by default) of them, as
+//! `{ key, count, ids }`. `key` maps each key field to the shared value, and
+//! `ids` lists the documents' `_id`s, up to 1000 per group.
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::AggregateOptions;
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use std::time::Duration;
+
+use super::errors::{self, MongoPluginError};
+use super::convert;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_IDS: i64 = 1000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct FindDuplicatesArgs {
+    collection: String,
+    key_fields: Vec<String>,
+    limit: Option<i64>,
+    filter: Option<String>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+pub(super) async fn find_duplicates(db: Database, args: FindDuplicatesArgs) -> Result<JsonValue, MongoPluginError> {
+    if args.key_fields.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "keyFields must name at least one field"));
+    }
+    if let Some(field) = args.key_fields.iter().find(|field| field.is_empty() || field.starts_with('$')) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("'{}' is not a field name", field)));
+    }
+    let filter: Document = match &args.filter {
+        Some(filter) => match convert::from_extjson(filter) {
+            Ok(filter) => filter,
+            Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+        },
+        None => Document::new(),
+    };
+    // Key fields may be dotted paths, which can't be field names in the
+    // group key, so it uses their positions instead.
+    let mut key = Document::new();
+    let mut present = vec![Bson::Document(filter)];
+    for (index, field) in args.key_fields.iter().enumerate() {
+        key.insert(format!("k{}", index), format!("${}", field));
+        present.push(Bson::Document(doc! { field: { "$exists": true } }));
+    }
+    let pipeline = vec![
+        doc! { "$match": { "$and": present } },
+        doc! { "$group": { "_id": key, "count": { "$sum": 1 }, "ids": { "$push": "$_id" } } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+        doc! { "$sort": { "count": -1 } },
+        doc! { "$limit": args.limit.unwrap_or(DEFAULT_LIMIT).max(1) },
+        doc! { "$project": { "count": 1, "ids": { "$slice": ["$ids", MAX_IDS] } } },
+    ];
+    let options = AggregateOptions::builder()
+        .allow_disk_use(true)
+        .max_time(args.max_time_ms.map(Duration::from_millis))
+        .build();
+    let cursor = match db.collection::<Document>(&args.collection).aggregate(pipeline, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to find duplicates", e)),
+    };
+    let groups = match convert::collect_json(cursor).await {
+        Ok(JsonValue::Array(groups)) => groups,
+        Ok(_) => Vec::new(),
+        Err(e) => return Err(errors::failed("Failed to read duplicates", e)),
+    };
+    let groups = groups
+        .into_iter()
+        .map(|group| {
+            let key: Map<String, JsonValue> = args
+                .key_fields
+                .iter()
+                .enumerate()
+                .map(|(index, field)| (field.clone(), group["_id"][format!("k{}", index)].clone()))
+                .collect();
+            json!({ "key": key, "count": group["count"], "ids": group["ids"] })
+        })
+        .collect();
+    Ok(JsonValue::Array(groups))
+}
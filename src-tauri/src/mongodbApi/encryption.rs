@@ -0,0 +1,338 @@
+//! Lightweight field encryption for setups that can't run
+//! [CSFLE](super::csfle).
+//!
+//! Configured fields are sealed with AES-256-GCM before documents reach the
+//! driver and opened again in results. Each value gets a fresh nonce, so
+//! encrypted fields can be stored and read back but not queried, sorted or
+//! indexed meaningfully.
+//!
+//! A sealed value is BSON binary subtype 0x80 holding `nonce || ciphertext`,
+//! where the plaintext is the BSON encoding of `{ v: <original value> }` so
+//! the original type survives the round trip.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{doc, Binary, Bson, Document};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::bulk_write::{self, Written};
+use super::convert;
+
+const SUBTYPE: u8 = 0x80;
+const NONCE_LEN: usize = 12;
+const DEFAULT_KEY_FILE: &str = "mongo-field-encryption.key";
+
+/// The `fieldEncryption` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FieldEncryptionConfig {
+    /// File holding the base64-encoded 256-bit key, relative to the app data
+    /// directory unless absolute. A new key is generated if it doesn't exist.
+    pub key_file: Option<PathBuf>,
+    /// Dotted field paths to encrypt, per collection.
+    pub collections: HashMap<String, Vec<String>>,
+}
+
+pub(super) struct FieldEncryption {
+    cipher: Aes256Gcm,
+    collections: HashMap<String, Vec<String>>,
+}
+
+/// Where a file the plugin keeps lives: as given when absolute, otherwise
+/// under the app data directory.
+pub(super) fn app_data_path(file: Option<&Path>, default: &str, app_data_dir: Option<PathBuf>) -> Result<PathBuf, String> {
+    let file = file.map_or_else(|| PathBuf::from(default), Path::to_path_buf);
+    match (file.is_absolute(), app_data_dir) {
+        (true, _) => Ok(file),
+        (false, Some(dir)) => Ok(dir.join(file)),
+        (false, None) => Err("No app data directory to keep plugin files in".to_string()),
+    }
+}
+
+/// Reads a base64-encoded key of `N` bytes, generating and saving a new one
+/// (readable only by the user on unix) if the file doesn't exist yet.
+pub(super) fn load_or_create_key<const N: usize>(path: &Path) -> Result<[u8; N], String> {
+    if path.exists() {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read key file: {}", e))?;
+        let bytes = BASE64.decode(text.trim()).map_err(|e| format!("Key file is not valid base64: {}", e))?;
+        return bytes.try_into().map_err(|_| format!("Key file must hold {} bytes", N));
+    }
+
+    let mut key = [0u8; N];
+    OsRng.fill_bytes(&mut key);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create key directory: {}", e))?;
+    }
+    fs::write(path, BASE64.encode(key)).map_err(|e| format!("Failed to write encryption key: {}", e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o600));
+    }
+    Ok(key)
+}
+
+impl FieldEncryption {
+    pub(super) fn load(config: &FieldEncryptionConfig, app_data_dir: Option<PathBuf>) -> Result<Self, String> {
+        let key: [u8; 32] = load_or_create_key(&app_data_path(config.key_file.as_deref(), DEFAULT_KEY_FILE, app_data_dir)?)?;
+        Ok(Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            collections: config.collections.clone(),
+        })
+    }
+
+    fn seal(&self, value: &Bson) -> Result<Bson, String> {
+        let plaintext = mongodb::bson::to_vec(&doc! { "v": value.clone() }).map_err(|e| e.to_string())?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| "Failed to encrypt field".to_string())?;
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        Ok(Bson::Binary(Binary { subtype: BinarySubtype::UserDefined(SUBTYPE), bytes }))
+    }
+
+    fn open(&self, bytes: &[u8]) -> Option<Bson> {
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+        let mut doc = Document::from_reader(plaintext.as_slice()).ok()?;
+        doc.remove("v")
+    }
+
+    /// Seals the value at `path` (dotted) inside `doc`, descending through
+    /// sub-documents and arrays of sub-documents.
+    fn seal_path(&self, doc: &mut Document, path: &str) -> Result<(), String> {
+        let (head, rest) = match path.split_once('.') {
+            Some((head, rest)) => (head, Some(rest)),
+            None => (path, None),
+        };
+        let value = match doc.get_mut(head) {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        match rest {
+            None => {
+                if !matches!(value, Bson::Null) && !is_sealed(value) {
+                    *value = self.seal(value)?;
+                }
+            }
+            Some(rest) => match value {
+                Bson::Document(inner) => self.seal_path(inner, rest)?,
+                Bson::Array(items) => {
+                    for item in items.iter_mut() {
+                        if let Bson::Document(inner) = item {
+                            self.seal_path(inner, rest)?;
+                        }
+                    }
+                }
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    fn seal_document(&self, doc: &mut Document, fields: &[String]) -> Result<(), String> {
+        for path in fields {
+            self.seal_path(doc, path)?;
+        }
+        Ok(())
+    }
+
+    /// Seals configured fields set by an update: whole replacement documents
+    /// and the values of `$set`/`$setOnInsert`, whose keys may themselves be
+    /// dotted paths.
+    fn seal_update(&self, update: &mut Document, fields: &[String]) -> Result<(), String> {
+        if !update.keys().any(|key| key.starts_with('$')) {
+            return self.seal_document(update, fields);
+        }
+        for operator in ["$set", "$setOnInsert"] {
+            let assignments = match update.get_document_mut(operator) {
+                Ok(assignments) => assignments,
+                Err(_) => continue,
+            };
+            for (key, value) in assignments.iter_mut() {
+                for path in fields {
+                    if path == key {
+                        if !matches!(value, Bson::Null) && !is_sealed(value) {
+                            *value = self.seal(value)?;
+                        }
+                    } else if let (Some(rest), Bson::Document(inner)) = (path.strip_prefix(&format!("{}.", key)), &mut *value) {
+                        self.seal_path(inner, rest)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypts configured fields in the JSON-text document arguments of
+    /// write commands, leaving everything else untouched.
+    pub(super) fn seal_payload(&self, command: &str, payload: &mut JsonValue) -> Result<(), String> {
+        let fields = match payload.get("collection").and_then(JsonValue::as_str).and_then(|c| self.collections.get(c)) {
+            Some(fields) => fields,
+            None => return Ok(()),
+        };
+        if command == "bulkWrite" {
+            return bulk_write::rewrite_documents(payload, |written, doc| match written {
+                Written::Update => self.seal_update(doc, fields),
+                Written::Inserted | Written::Replacement => self.seal_document(doc, fields),
+            });
+        }
+        let (key, is_update) = match command {
+            "insertOne" | "insertMany" => ("data", false),
+            "upsertMany" => ("documents", false),
+            "updateById" | "updateWithVersion" | "updateOne" | "updateMany" | "findOneAndUpdate" => ("update", true),
+            "replaceOne" => ("replacement", false),
+            _ => return Ok(()),
+        };
+        let text = match payload.get(key).and_then(JsonValue::as_str) {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+        let sealed = match convert::from_extjson::<Bson>(text) {
+            Ok(Bson::Document(mut doc)) => {
+                if is_update {
+                    self.seal_update(&mut doc, fields)?;
+                } else {
+                    self.seal_document(&mut doc, fields)?;
+                }
+                convert::to_extjson_text(Bson::Document(doc))
+            }
+            Ok(Bson::Array(mut docs)) => {
+                for doc in docs.iter_mut() {
+                    if let Bson::Document(doc) = doc {
+                        self.seal_document(doc, fields)?;
+                    }
+                }
+                convert::to_extjson_text(Bson::Array(docs))
+            }
+            // Malformed arguments are reported by the command itself.
+            _ => return Ok(()),
+        };
+        payload[key] = JsonValue::String(sealed);
+        Ok(())
+    }
+
+    /// Decrypts every sealed value found anywhere in a result. Values that
+    /// don't decrypt with our key are left as they are.
+    pub(super) fn open_result(&self, value: &mut JsonValue) {
+        match value {
+            JsonValue::Object(map) => {
+                if let Some(opened) = self.open_json(map) {
+                    *value = opened;
+                    return;
+                }
+                for item in map.values_mut() {
+                    self.open_result(item);
+                }
+            }
+            JsonValue::Array(items) => {
+                for item in items.iter_mut() {
+                    self.open_result(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Opens `{ "$binary": { "base64", "subType": "80" } }`, the JSON form
+    /// results take for a sealed value.
+    fn open_json(&self, map: &serde_json::Map<String, JsonValue>) -> Option<JsonValue> {
+        let binary = map.get("$binary")?;
+        if map.len() != 1 || binary.get("subType")?.as_str()? != format!("{:x}", SUBTYPE) {
+            return None;
+        }
+        let bytes = BASE64.decode(binary.get("base64")?.as_str()?).ok()?;
+        Some(convert::to_json(self.open(&bytes)?))
+    }
+}
+
+fn is_sealed(value: &Bson) -> bool {
+    matches!(value, Bson::Binary(Binary { subtype: BinarySubtype::UserDefined(SUBTYPE), .. }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encryption(key: u8) -> FieldEncryption {
+        FieldEncryption {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&[key; 32])),
+            collections: HashMap::from([("people".to_string(), vec!["ssn".to_string(), "cards.number".to_string()])]),
+        }
+    }
+
+    /// Seals `document` as `insertOne` would and returns it as a read would.
+    fn sealed(encryption: &FieldEncryption, document: JsonValue) -> JsonValue {
+        let mut payload = json!({ "collection": "people", "data": document.to_string() });
+        encryption.seal_payload("insertOne", &mut payload).unwrap();
+        convert::to_json(convert::from_extjson::<Bson>(payload["data"].as_str().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn values_round_trip_with_their_type() {
+        let encryption = encryption(1);
+        for value in [Bson::String("123-45-6789".to_string()), Bson::Int64(42), Bson::Document(doc! { "a": [1, 2] }), Bson::Boolean(false)] {
+            let sealed = match encryption.seal(&value).unwrap() {
+                Bson::Binary(binary) => binary,
+                other => panic!("sealed to {:?}", other),
+            };
+            assert_eq!(sealed.subtype, BinarySubtype::UserDefined(SUBTYPE));
+            assert_eq!(encryption.open(&sealed.bytes), Some(value));
+        }
+    }
+
+    #[test]
+    fn configured_fields_are_sealed_and_opened() {
+        let encryption = encryption(1);
+        let original = json!({ "name": "Ann", "ssn": "123-45-6789", "cards": [{ "number": "4111", "kind": "visa" }] });
+        let mut doc = sealed(&encryption, original.clone());
+        assert_eq!(doc["name"], json!("Ann"));
+        assert!(doc["ssn"].get("$binary").is_some());
+        assert!(doc["cards"][0]["number"].get("$binary").is_some());
+        assert_eq!(doc["cards"][0]["kind"], json!("visa"));
+        encryption.open_result(&mut doc);
+        assert_eq!(doc, original);
+    }
+
+    #[test]
+    fn updates_seal_set_values() {
+        let encryption = encryption(1);
+        let mut payload = json!({ "collection": "people", "update": json!({ "$set": { "ssn": "1", "cards": { "number": "2" } } }).to_string() });
+        encryption.seal_payload("updateOne", &mut payload).unwrap();
+        let update = convert::to_json(convert::from_extjson::<Bson>(payload["update"].as_str().unwrap()).unwrap());
+        assert!(update["$set"]["ssn"].get("$binary").is_some());
+        assert!(update["$set"]["cards"]["number"].get("$binary").is_some());
+    }
+
+    #[test]
+    fn tampered_or_foreign_values_stay_sealed() {
+        let encryption = encryption(1);
+        let sealed_bytes = match encryption.seal(&Bson::String("secret".to_string())).unwrap() {
+            Bson::Binary(binary) => binary.bytes,
+            _ => unreachable!(),
+        };
+        let mut tampered = sealed_bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(encryption.open(&tampered), None);
+        assert_eq!(encryption.open(&sealed_bytes[..NONCE_LEN - 1]), None);
+        assert_eq!(self::encryption(2).open(&sealed_bytes), None);
+        let mut doc = sealed(&encryption, json!({ "ssn": "123" }));
+        let before = doc.clone();
+        self::encryption(2).open_result(&mut doc);
+        assert_eq!(doc, before);
+    }
+}
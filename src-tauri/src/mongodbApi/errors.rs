@@ -0,0 +1,219 @@
+//! The error every command fails with.
+//!
+//! A failed invoke rejects with `{ kind, message, code, labels }`: `kind`
+//! says what went wrong in terms a frontend can branch on, `message` is the
+//! text to show, `code` the server's error code when the server reported
+//! one, and `labels` the driver's error labels, such as
+//! `TransientTransactionError`. Some kinds carry more fields; a
+//! `versionConflict` from `updateWithVersion` has the `field`, `expected`
+//! and `current` versions.
+
+use mongodb::bson;
+use mongodb::error::{BulkWriteFailure, Error as MongoError, ErrorKind as MongoErrorKind, WriteFailure};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use std::any::Any;
+use std::fmt;
+
+/// Server codes with a kind of their own.
+const DUPLICATE_KEY: i32 = 11000;
+const MAX_TIME_EXPIRED: i32 = 50;
+const WRITE_CONFLICT: i32 = 112;
+const DOCUMENT_VALIDATION_FAILURE: i32 = 121;
+const UNAUTHORIZED: i32 = 13;
+const AUTHENTICATION_FAILED: i32 = 18;
+const NAMESPACE_NOT_FOUND: i32 = 26;
+const BAD_VALUE: i32 = 2;
+const FAILED_TO_PARSE: i32 = 9;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    /// The server couldn't be reached or the connection dropped.
+    Connection,
+    /// The server or the driver gave up waiting, e.g. on `maxTimeMS`.
+    Timeout,
+    /// The server rejected the credentials or the user lacks a privilege.
+    Auth,
+    /// The plugin's policy doesn't let the window run the command.
+    PermissionDenied,
+    DuplicateKey,
+    /// Another transaction wrote the same document first.
+    WriteConflict,
+    /// The server's schema validation rejected a document.
+    Validation,
+    /// A document, collection, connection or other handle the command
+    /// names doesn't exist.
+    NotFound,
+    /// The command's arguments are malformed or out of range.
+    InvalidArgument,
+    /// A value couldn't be converted to or from BSON.
+    Bson,
+    /// `updateWithVersion` found the document at another version.
+    VersionConflict,
+    Cancelled,
+    /// A local file couldn't be read or written.
+    Io,
+    /// Any other error the server reported.
+    Server,
+    Other,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MongoPluginError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub code: Option<i32>,
+    pub labels: Vec<String>,
+    /// Boxed to keep `Result`s carrying the error small.
+    #[serde(flatten)]
+    details: Box<Map<String, JsonValue>>,
+}
+
+impl MongoPluginError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into(), code: None, labels: Vec::new(), details: Box::default() }
+    }
+
+    /// `context` followed by the driver's message, classified by the
+    /// driver's error.
+    pub fn mongo(context: &str, error: &MongoError) -> Self {
+        Self::classified(format!("{}: {}", context, error), error)
+    }
+
+    fn classified(message: String, error: &MongoError) -> Self {
+        let code = server_code(error);
+        let mut labels: Vec<String> = error.labels().iter().cloned().collect();
+        labels.sort();
+        Self { kind: classify(error, code), message, code, labels, details: Box::default() }
+    }
+
+    /// Adds a field beside `kind` and `message` in the serialized error.
+    pub fn with(mut self, key: &str, value: JsonValue) -> Self {
+        self.details.insert(key.to_string(), value);
+        self
+    }
+}
+
+impl fmt::Display for MongoPluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for MongoPluginError {}
+
+fn server_code(error: &MongoError) -> Option<i32> {
+    match error.kind.as_ref() {
+        MongoErrorKind::Command(command) => Some(command.code),
+        MongoErrorKind::Write(WriteFailure::WriteError(write)) => Some(write.code),
+        MongoErrorKind::Write(WriteFailure::WriteConcernError(concern)) => Some(concern.code),
+        MongoErrorKind::BulkWrite(BulkWriteFailure { write_errors, write_concern_error, .. }) => {
+            let first = write_errors.as_ref().and_then(|errors| errors.first()).map(|error| error.code);
+            first.or(write_concern_error.as_ref().map(|concern| concern.code))
+        }
+        _ => None,
+    }
+}
+
+fn classify(error: &MongoError, code: Option<i32>) -> ErrorKind {
+    match error.kind.as_ref() {
+        MongoErrorKind::Authentication { .. } => return ErrorKind::Auth,
+        MongoErrorKind::BsonDeserialization(_) | MongoErrorKind::BsonSerialization(_) => return ErrorKind::Bson,
+        MongoErrorKind::InvalidArgument { .. } => return ErrorKind::InvalidArgument,
+        MongoErrorKind::Io(io) if io.kind() == std::io::ErrorKind::TimedOut => return ErrorKind::Timeout,
+        MongoErrorKind::Io(_)
+        | MongoErrorKind::DnsResolve { .. }
+        | MongoErrorKind::ServerSelection { .. }
+        | MongoErrorKind::ConnectionPoolCleared { .. }
+        | MongoErrorKind::InvalidTlsConfig { .. }
+        | MongoErrorKind::Shutdown => return ErrorKind::Connection,
+        _ => {}
+    }
+    match code {
+        Some(DUPLICATE_KEY) => ErrorKind::DuplicateKey,
+        Some(MAX_TIME_EXPIRED) => ErrorKind::Timeout,
+        Some(WRITE_CONFLICT) => ErrorKind::WriteConflict,
+        Some(DOCUMENT_VALIDATION_FAILURE) => ErrorKind::Validation,
+        Some(UNAUTHORIZED | AUTHENTICATION_FAILED) => ErrorKind::Auth,
+        Some(NAMESPACE_NOT_FOUND) => ErrorKind::NotFound,
+        Some(BAD_VALUE | FAILED_TO_PARSE) => ErrorKind::InvalidArgument,
+        Some(_) => ErrorKind::Server,
+        None => ErrorKind::Other,
+    }
+}
+
+/// A plugin message raised without a kind of its own.
+impl From<String> for MongoPluginError {
+    fn from(message: String) -> Self {
+        Self::new(ErrorKind::Other, message)
+    }
+}
+
+impl From<&str> for MongoPluginError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+impl From<MongoError> for MongoPluginError {
+    fn from(error: MongoError) -> Self {
+        Self::classified(error.to_string(), &error)
+    }
+}
+
+impl From<MongoPluginError> for JsonValue {
+    fn from(error: MongoPluginError) -> Self {
+        serde_json::to_value(error).unwrap()
+    }
+}
+
+/// `context` followed by `error`'s message, classified by `error` when the
+/// driver raised it, by its type when it is a BSON or I/O error.
+pub(super) fn failed<E: fmt::Display + 'static>(context: &str, error: E) -> MongoPluginError {
+    let any = &error as &dyn Any;
+    if let Some(error) = any.downcast_ref::<MongoError>() {
+        return MongoPluginError::mongo(context, error);
+    }
+    let kind = if any.is::<bson::ser::Error>() || any.is::<bson::de::Error>() {
+        ErrorKind::Bson
+    } else if any.is::<std::io::Error>() {
+        ErrorKind::Io
+    } else {
+        ErrorKind::Other
+    };
+    MongoPluginError::new(kind, format!("{}: {}", context, error))
+}
+
+/// `context` followed by `error`'s message, for arguments that couldn't be
+/// read, such as a filter that doesn't parse.
+pub(super) fn invalid<E: fmt::Display>(context: &str, error: E) -> MongoPluginError {
+    MongoPluginError::new(ErrorKind::InvalidArgument, format!("{}: {}", context, error))
+}
+
+/// An error that was already turned into JSON on its way through a command
+/// future. Plain messages have no kind of their own; structured errors pass
+/// through.
+pub(super) fn from_json(error: JsonValue) -> JsonValue {
+    match error {
+        JsonValue::String(message) => serde_json::to_value(MongoPluginError::from(message)).unwrap(),
+        structured => structured,
+    }
+}
+
+/// The error a command future failed with, read back from its JSON.
+pub(super) fn from_failure(error: JsonValue) -> MongoPluginError {
+    let mut fields = match error {
+        JsonValue::Object(fields) => fields,
+        JsonValue::String(message) => return MongoPluginError::from(message),
+        other => return MongoPluginError::from(other.to_string()),
+    };
+    let kind = fields.remove("kind").and_then(|kind| serde_json::from_value(kind).ok()).unwrap_or(ErrorKind::Other);
+    let message = match fields.remove("message") {
+        Some(JsonValue::String(message)) => message,
+        _ => String::new(),
+    };
+    let code = fields.remove("code").and_then(|code| code.as_i64()).and_then(|code| i32::try_from(code).ok());
+    let labels = fields.remove("labels").and_then(|labels| serde_json::from_value(labels).ok()).unwrap_or_default();
+    MongoPluginError { kind, message, code, labels, details: Box::new(fields) }
+}
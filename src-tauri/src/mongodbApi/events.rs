@@ -0,0 +1,50 @@
+//! Events the plugin broadcasts to every window.
+//!
+//! Command code doesn't hold an `AppHandle`, so it emits through an
+//! [`EventSink`] captured when the plugin is initialized.
+
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+use tauri::{AppHandle, Manager, Runtime};
+
+/// Emitted after a write command succeeds, so views of the namespace can be
+/// refreshed without polling.
+pub(super) const WRITE_EVENT: &str = "mongo://write";
+
+pub(super) type EventSink = Arc<dyn Fn(&str, JsonValue) + Send + Sync>;
+
+pub(super) fn sink<R: Runtime>(app: &AppHandle<R>) -> EventSink {
+    let app = app.clone();
+    Arc::new(move |event, payload| {
+        let _ = app.emit_all(event, payload);
+    })
+}
+
+/// The kind of write a command performs, if it writes documents.
+pub(super) fn write_operation(command: &str) -> Option<&'static str> {
+    match command {
+        "insertOne" | "insertMany" | "restoreFromTrash" | "unarchive" | "importCollection" => Some("insert"),
+        "updateById" | "updateManyWithProgress" | "updateWithVersion" | "incrementField" | "pushToArray" | "pullFromArray" | "upsertMany" => {
+            Some("update")
+        }
+        "updateOne" | "updateMany" | "replaceOne" | "findOneAndUpdate" => Some("update"),
+        "restore" | "revertToVersion" | "mergeDocuments" => Some("update"),
+        "deleteById" | "deleteOne" | "deleteMany" | "findOneAndDelete" | "purge" | "archiveDocuments" => Some("delete"),
+        "bulkWrite" => Some("bulk"),
+        _ => None,
+    }
+}
+
+/// The `mongo://write` payload for a successful command, or `None` for
+/// commands that don't write documents.
+pub(super) fn write_event(command: &str, database: &str, collection: Option<&str>) -> Option<JsonValue> {
+    let operation = write_operation(command)?;
+    let collection = collection?;
+    Some(json!({
+        "namespace": format!("{}.{}", database, collection),
+        "database": database,
+        "collection": collection,
+        "operation": operation,
+        "command": command,
+    }))
+}
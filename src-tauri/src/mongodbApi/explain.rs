@@ -0,0 +1,223 @@
+//! Explaining reads, either as the server's raw output or as a normalized
+//! plan tree a frontend can draw directly.
+//!
+//! Besides the `explain` command, `find`, `findOne` and `aggregate` take an
+//! `explain` argument, `queryPlanner`, `executionStats` or
+//! `allPlansExecution` (`true` meaning `executionStats`), and then reply
+//! with the server's explain output instead of running.
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use super::errors::{self, MongoPluginError};
+use super::convert;
+
+const VERBOSITIES: &[&str] = &["queryPlanner", "executionStats", "allPlansExecution"];
+
+/// Keys under which a plan stage nests the stages feeding it.
+const CHILD_KEYS: &[&str] = &["inputStage", "inputStages", "thenStage", "elseStage", "outerStage", "innerStage"];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ExplainArgs {
+    /// The read being explained: `find`, `findOne`, `exists` or `aggregate`.
+    command: String,
+    /// That command's own arguments.
+    args: JsonValue,
+    verbosity: Option<String>,
+    /// `tree` (the default) or `raw`.
+    format: Option<String>,
+}
+
+/// The server command a read runs as, or `None` if `payload` isn't a read
+/// we can explain.
+pub(super) fn explainable(command: &str, payload: &JsonValue) -> Option<Document> {
+    let collection = payload.get("collection").and_then(JsonValue::as_str)?;
+    // A sort or hint in the read's options changes the plan it gets.
+    let option = |key: &str| payload.get("options")?.get(key).and_then(|value| Bson::try_from(value.clone()).ok());
+    let mut explained = if matches!(command, "aggregate" | "aggregateCursor") {
+        let pipeline: Vec<Document> = convert::from_extjson(payload.get("pipeline")?.as_str()?).ok()?;
+        doc! { "aggregate": collection, "pipeline": pipeline, "cursor": {} }
+    } else {
+        let filter = payload.get("query").or_else(|| payload.get("filter")).and_then(JsonValue::as_str)?;
+        let filter: Document = convert::from_extjson(filter).ok()?;
+        let mut find = doc! { "find": collection, "filter": filter };
+        if let Some(sort) = option("sort") {
+            find.insert("sort", sort);
+        }
+        find
+    };
+    if let Some(hint) = option("hint") {
+        explained.insert("hint", hint);
+    }
+    Some(explained)
+}
+
+/// Whether a read asks to be explained rather than run.
+pub(super) fn requested(command: &str, payload: &JsonValue) -> bool {
+    matches!(command, "find" | "findOne" | "aggregate") && payload.get("explain").is_some_and(|explain| !explain.is_null() && *explain != false)
+}
+
+/// The server's explain output for a read given an `explain` argument.
+pub(super) async fn explain_in_place(db: Database, command: String, payload: JsonValue) -> Result<JsonValue, MongoPluginError> {
+    let verbosity = match &payload["explain"] {
+        JsonValue::Bool(true) => "executionStats",
+        JsonValue::String(verbosity) if VERBOSITIES.contains(&verbosity.as_str()) => verbosity.as_str(),
+        other => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown explain verbosity {}", other))),
+    };
+    let mut explained = match explainable(&command, &payload) {
+        Some(explained) => explained,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Cannot explain '{}' with these arguments", command))),
+    };
+    if command == "findOne" {
+        explained.insert("limit", 1);
+    }
+    match db.run_command(doc! { "explain": explained, "verbosity": verbosity }, None).await {
+        Ok(output) => Ok(Bson::Document(output).into_relaxed_extjson()),
+        Err(e) => Err(errors::failed("Failed to explain query", e)),
+    }
+}
+
+fn number(doc: &Document, key: &str) -> Option<i64> {
+    match doc.get(key)? {
+        Bson::Int32(n) => Some(*n as i64),
+        Bson::Int64(n) => Some(*n),
+        Bson::Double(n) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+/// One plan stage and the stages under it, with the statistics explain
+/// reported for it at this verbosity.
+fn stage_node(stage: &Document) -> JsonValue {
+    // Plans from the slot-based engine wrap the classic tree in `queryPlan`.
+    let stage = stage.get_document("queryPlan").unwrap_or(stage);
+    let mut children = Vec::new();
+    for key in CHILD_KEYS {
+        match stage.get(*key) {
+            Some(Bson::Document(child)) => children.push(stage_node(child)),
+            Some(Bson::Array(items)) => children.extend(items.iter().filter_map(Bson::as_document).map(stage_node)),
+            _ => {}
+        }
+    }
+    if let Ok(shards) = stage.get_array("shards") {
+        for shard in shards.iter().filter_map(Bson::as_document) {
+            let plan = shard.get_document("executionStages").or_else(|_| shard.get_document("winningPlan"));
+            if let Ok(plan) = plan {
+                let mut node = stage_node(plan);
+                node["shard"] = json!(shard.get_str("shardName").ok());
+                children.push(node);
+            }
+        }
+    }
+    json!({
+        "stage": stage.get_str("stage").unwrap_or("UNKNOWN"),
+        "indexName": stage.get_str("indexName").ok(),
+        "keyPattern": stage.get_document("keyPattern").ok().map(|key| Bson::Document(key.clone()).into_relaxed_extjson()),
+        "direction": stage.get_str("direction").ok(),
+        "filter": stage.get_document("filter").ok().map(|filter| Bson::Document(filter.clone()).into_relaxed_extjson()),
+        "nReturned": number(stage, "nReturned"),
+        "keysExamined": number(stage, "keysExamined"),
+        "docsExamined": number(stage, "docsExamined"),
+        "timeMs": number(stage, "executionTimeMillisEstimate"),
+        "children": children,
+    })
+}
+
+/// The plan tree of a find-style explain: execution stages when they were
+/// collected, otherwise the winning plan.
+fn query_tree(explained: &Document) -> Option<JsonValue> {
+    if let Ok(stages) = explained.get_document("executionStats").and_then(|stats| stats.get_document("executionStages")) {
+        return Some(stage_node(stages));
+    }
+    let winning = explained.get_document("queryPlanner").and_then(|planner| planner.get_document("winningPlan"));
+    winning.ok().map(stage_node)
+}
+
+/// Aggregations that aren't pushed down entirely report a list of stages,
+/// the first of which holds the query plan. They become a chain with the
+/// last stage at the root.
+fn pipeline_tree(stages: &[Bson]) -> Option<JsonValue> {
+    let mut tree: Option<JsonValue> = None;
+    for stage in stages.iter().filter_map(Bson::as_document) {
+        let (name, body) = stage.iter().find(|(key, _)| key.starts_with('$'))?;
+        let node = match (name.as_str(), body) {
+            ("$cursor", Bson::Document(cursor)) => query_tree(cursor)?,
+            _ => json!({
+                "stage": name,
+                "nReturned": number(stage, "nReturned"),
+                "timeMs": number(stage, "executionTimeMillisEstimate"),
+                "children": tree.take().into_iter().collect::<Vec<_>>(),
+            }),
+        };
+        tree = Some(node);
+    }
+    tree
+}
+
+fn summarize(node: &JsonValue, indexes: &mut Vec<String>, collection_scan: &mut bool) {
+    if node["stage"] == "COLLSCAN" {
+        *collection_scan = true;
+    }
+    if let Some(index) = node["indexName"].as_str() {
+        if !indexes.iter().any(|known| known == index) {
+            indexes.push(index.to_string());
+        }
+    }
+    for child in node["children"].as_array().into_iter().flatten() {
+        summarize(child, indexes, collection_scan);
+    }
+}
+
+/// Explains a read. The `tree` format returns `{ plan, summary }`, where
+/// `plan` is a tree of `{ stage, indexName, keysExamined, docsExamined,
+/// nReturned, timeMs, children }` nodes; `raw` returns the server's output.
+pub(super) async fn explain(db: Database, args: ExplainArgs) -> Result<JsonValue, MongoPluginError> {
+    let explained = match explainable(&args.command, &args.args) {
+        Some(explained) => explained,
+        None => {
+            let message = format!("Cannot explain '{}' with these arguments", args.command);
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+    };
+    let verbosity = args.verbosity.unwrap_or_else(|| "executionStats".to_string());
+    let output = match db.run_command(doc! { "explain": explained, "verbosity": verbosity }, None).await {
+        Ok(output) => output,
+        Err(e) => return Err(errors::failed("Failed to explain query", e)),
+    };
+    match args.format.as_deref() {
+        None | Some("tree") => {}
+        Some("raw") => return Ok(Bson::Document(output).into_relaxed_extjson()),
+        Some(other) => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown explain format '{}'", other))),
+    }
+
+    match summarized(&output) {
+        Some((plan, summary)) => Ok(json!({ "plan": plan, "summary": summary })),
+        None => Err("Explain output has no plan".into()),
+    }
+}
+
+/// The plan tree of the server's explain output and its summary: the
+/// indexes used, whether the collection was scanned and, at
+/// `executionStats`, what was examined and returned.
+pub(super) fn summarized(output: &Document) -> Option<(JsonValue, JsonValue)> {
+    let plan = match output.get_array("stages") {
+        Ok(stages) => pipeline_tree(stages),
+        Err(_) => query_tree(output),
+    }?;
+    let mut indexes = Vec::new();
+    let mut collection_scan = false;
+    summarize(&plan, &mut indexes, &mut collection_scan);
+    let stats = output.get_document("executionStats").ok();
+    let summary = json!({
+        "indexesUsed": indexes,
+        "collectionScan": collection_scan,
+        "nReturned": stats.and_then(|stats| number(stats, "nReturned")),
+        "keysExamined": stats.and_then(|stats| number(stats, "totalKeysExamined")),
+        "docsExamined": stats.and_then(|stats| number(stats, "totalDocsExamined")),
+        "timeMs": stats.and_then(|stats| number(stats, "executionTimeMillis")),
+    });
+    Some((plan, summary))
+}
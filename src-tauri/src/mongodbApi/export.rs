@@ -0,0 +1,157 @@
+//! Writing large reads to a temporary NDJSON file instead of returning them.
+//!
+//! `find` and `aggregate` called with `output: "file"` stream their results,
+//! one Extended JSON document per line, into a new file in the system's
+//! temporary directory and return `{ operationId, path, count, bytes }`.
+//! The frontend reads the file at its own pace and removes it with
+//! `deleteExportFile`, which also removes workbooks
+//! [`exportXlsx`](super::xlsx) wrote there. Each export runs as an `export`
+//! [operation](super::operations), reporting `{ count, bytes }` every
+//! thousand documents; a cancelled export removes its file.
+
+use futures::TryStreamExt;
+use mongodb::bson::{oid::ObjectId, Document};
+use mongodb::Cursor;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::{convert, read_options, CommandContext};
+
+const FILE_PREFIX: &str = "mongo-export-";
+const FILE_EXTENSION: &str = "ndjson";
+/// Extensions of the files `deleteExportFile` may remove.
+const EXPORT_EXTENSIONS: &[&str] = &[FILE_EXTENSION, "xlsx"];
+const PROGRESS_EVERY: u64 = 1000;
+
+#[derive(Deserialize)]
+pub(super) struct FindToFileArgs {
+    collection: String,
+    query: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::FindCommandOptions,
+    #[serde(rename = "operationId")]
+    operation_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct AggregateToFileArgs {
+    collection: String,
+    pipeline: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::AggregateCommandOptions,
+    #[serde(rename = "operationId")]
+    operation_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct DeleteExportFileArgs {
+    path: PathBuf,
+}
+
+/// Whether a command's arguments ask for its results in a file.
+pub(super) fn to_file(payload: &JsonValue) -> bool {
+    payload.get("output").and_then(JsonValue::as_str) == Some("file")
+}
+
+/// A new file name in the temporary directory.
+pub(super) fn export_path(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}{}.{}", FILE_PREFIX, ObjectId::new().to_hex(), extension))
+}
+
+/// Whether `path` is a file this module created, so deleting it can't be
+/// turned against any other file.
+fn is_export(path: &Path) -> bool {
+    let named = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(FILE_PREFIX));
+    let extension = path.extension().and_then(|extension| extension.to_str()).is_some_and(|extension| EXPORT_EXTENSIONS.contains(&extension));
+    named && extension && path.parent() == Some(std::env::temp_dir().as_path())
+}
+
+/// Streams the cursor into a new export file, each document going through
+/// the same signature checks and decryption a `findOne` result would.
+async fn write_file(ctx: &CommandContext, collection: &str, cursor: Cursor<Document>, operation_id: Option<String>) -> Result<JsonValue, MongoPluginError> {
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "export", operation_id)?;
+    let outcome = write_documents(ctx, collection, cursor, &mut operation).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn write_documents(ctx: &CommandContext, collection: &str, mut cursor: Cursor<Document>, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let path = export_path(FILE_EXTENSION);
+    let file = match tokio::fs::File::create(&path).await {
+        Ok(file) => file,
+        Err(e) => return Err(errors::failed("Failed to create export file", e)),
+    };
+    let mut writer = BufWriter::new(file);
+    let mut count: u64 = 0;
+    let mut bytes: u64 = 0;
+    let written: Result<(), MongoPluginError> = async {
+        while let Some(doc) = cursor.try_next().await.map_err(|e| errors::failed("Failed to read results", e))? {
+            if !operation.proceed().await {
+                return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "The export was cancelled"));
+            }
+            let mut value = serde_json::to_value(doc).unwrap();
+            ctx.transforms.finish("findOne", Some(collection), &mut value);
+            let mut line = serde_json::to_vec(&value).unwrap();
+            line.push(b'\n');
+            writer.write_all(&line).await.map_err(|e| errors::failed("Failed to write export file", e))?;
+            count += 1;
+            bytes += line.len() as u64;
+            if count.is_multiple_of(PROGRESS_EVERY) {
+                operation.progress(json!({ "count": count, "bytes": bytes }));
+            }
+        }
+        writer.flush().await.map_err(|e| errors::failed("Failed to write export file", e))
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(e);
+    }
+    operation.progress(json!({ "count": count, "bytes": bytes }));
+    Ok(json!({ "operationId": operation.id(), "path": path, "count": count, "bytes": bytes }))
+}
+
+pub(super) async fn find_to_file(ctx: CommandContext, args: FindToFileArgs) -> Result<JsonValue, MongoPluginError> {
+    let query: Document = match convert::from_extjson(&args.query) {
+        Ok(query) => query,
+        Err(e) => return Err(errors::invalid("Failed to parse query", e)),
+    };
+    let options = args.options.find(args.max_time_ms);
+    let cursor = match ctx.db.collection::<Document>(&args.collection).find(query, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute query", e)),
+    };
+    write_file(&ctx, &args.collection, cursor, args.operation_id).await
+}
+
+pub(super) async fn aggregate_to_file(ctx: CommandContext, args: AggregateToFileArgs) -> Result<JsonValue, MongoPluginError> {
+    let pipeline: Vec<Document> = match convert::from_extjson(&args.pipeline) {
+        Ok(pipeline) => pipeline,
+        Err(e) => return Err(errors::invalid("Failed to parse pipeline", e)),
+    };
+    let options = args.options.aggregate(args.max_time_ms);
+    let cursor = match ctx.db.collection::<Document>(&args.collection).aggregate(pipeline, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute aggregation", e)),
+    };
+    write_file(&ctx, &args.collection, cursor, args.operation_id).await
+}
+
+/// Removes an export file once the frontend is done with it.
+pub(super) async fn delete_export_file(args: DeleteExportFileArgs) -> Result<JsonValue, MongoPluginError> {
+    if !is_export(&args.path) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Only files created by an export can be deleted"));
+    }
+    match tokio::fs::remove_file(&args.path).await {
+        Ok(()) => Ok(serde_json::to_value("success").unwrap()),
+        Err(e) => Err(errors::failed("Failed to delete export file", e)),
+    }
+}
@@ -0,0 +1,301 @@
+//! Files stored in GridFS buckets, `fs` unless a command names another
+//! `bucket`.
+//!
+//! `gridfsUpload` stores `filename` from base64 `data`, or from the local
+//! file at `path`, and returns `{ id, filename, length }`. `gridfsDownload`
+//! reads the file named by `id`, or the latest revision of `filename`, and
+//! returns `{ id, filename, length, data }` with the contents in base64, or
+//! writes them to the local file at `path` instead. Transfers to and from a
+//! path are streamed chunk by chunk and run as `gridfsUpload` and
+//! `gridfsDownload` [operations](super::operations), reporting
+//! `{ bytes, totalBytes }` every megabyte, and their results carry the
+//! `operationId`. A cancelled upload leaves nothing behind and a cancelled
+//! download removes its partial file. Paths must lie within one of the
+//! `gridfs.directories` in the plugin config; without any, only `data`
+//! transfers are allowed. `gridfsFind` lists the files documents matching
+//! `filter`, and `gridfsDelete` removes a file and its chunks.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use mongodb::gridfs::{GridFsBucket, GridFsDownloadStream, GridFsUploadStream};
+use mongodb::options::{FindOneOptions, FindOptions, GridFsBucketOptions, GridFsUploadOptions};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::{coerce_id, convert, CommandContext};
+
+const DEFAULT_BUCKET: &str = "fs";
+/// Bytes read or written at a time when streaming a file.
+const STREAM_BUFFER: usize = 256 * 1024;
+const PROGRESS_EVERY_BYTES: u64 = 1024 * 1024;
+
+/// The `gridfs` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GridFsConfig {
+    /// Directories `gridfsUpload` may read and `gridfsDownload` may write.
+    pub directories: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct UploadArgs {
+    bucket: Option<String>,
+    filename: String,
+    data: Option<String>,
+    path: Option<PathBuf>,
+    id: Option<JsonValue>,
+    metadata: Option<JsonValue>,
+    chunk_size_bytes: Option<u32>,
+    operation_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DownloadArgs {
+    bucket: Option<String>,
+    id: Option<JsonValue>,
+    filename: Option<String>,
+    path: Option<PathBuf>,
+    operation_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct DeleteArgs {
+    bucket: Option<String>,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+pub(super) struct FindArgs {
+    bucket: Option<String>,
+    filter: Option<String>,
+    sort: Option<String>,
+    limit: Option<i64>,
+    skip: Option<u64>,
+}
+
+fn bucket(db: &Database, name: Option<&str>) -> GridFsBucket {
+    let options = GridFsBucketOptions::builder().bucket_name(name.unwrap_or(DEFAULT_BUCKET).to_string()).build();
+    db.gridfs_bucket(options)
+}
+
+/// `path` once it is known to lie within one of `directories`, named as
+/// `described` in the error. A file about to be written need not exist
+/// yet, so its directory is checked.
+pub(super) fn within(directories: &[PathBuf], path: &Path, described: &str) -> Result<PathBuf, MongoPluginError> {
+    let resolved = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent.canonicalize().map(|parent| parent.join(name)),
+        _ => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("'{}' is not a file path", path.display()))),
+    };
+    let resolved = match resolved {
+        Ok(resolved) => resolved,
+        Err(e) => return Err(errors::failed("Failed to resolve path", e)),
+    };
+    let inside = directories.iter().filter_map(|dir| dir.canonicalize().ok()).any(|dir| resolved.starts_with(dir));
+    match inside {
+        true => Ok(resolved),
+        false => {
+            let message = format!("Permission denied: '{}' is not in {}", path.display(), described);
+            Err(MongoPluginError::new(errors::ErrorKind::PermissionDenied, message))
+        }
+    }
+}
+
+fn allowed(ctx: &CommandContext, path: &Path) -> Result<PathBuf, MongoPluginError> {
+    within(&ctx.config.gridfs.directories, path, "a GridFS directory")
+}
+
+pub(super) async fn upload(ctx: CommandContext, args: UploadArgs) -> Result<JsonValue, MongoPluginError> {
+    let metadata = match args.metadata.clone().map(Bson::try_from) {
+        Some(Ok(Bson::Document(metadata))) => Some(metadata),
+        Some(_) => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "metadata must be an object")),
+        None => None,
+    };
+    let id = match &args.id {
+        Some(id) => coerce_id(id)?,
+        None => Bson::ObjectId(ObjectId::new()),
+    };
+    let options = GridFsUploadOptions::builder().chunk_size_bytes(args.chunk_size_bytes).metadata(metadata).build();
+    let mut stream = bucket(&ctx.db, args.bucket.as_deref()).open_upload_stream_with_id(id.clone(), &args.filename, options);
+    let mut result = match (&args.data, &args.path) {
+        (Some(data), None) => {
+            let bytes = match BASE64.decode(data) {
+                Ok(bytes) => bytes,
+                Err(e) => return Err(errors::invalid("Failed to parse data", e)),
+            };
+            let written = async {
+                stream.write_all(&bytes).await?;
+                stream.close().await
+            };
+            if let Err(e) = written.await {
+                let _ = stream.abort().await;
+                return Err(errors::failed("Failed to upload file", e));
+            }
+            json!({ "length": bytes.len() })
+        }
+        (None, Some(path)) => {
+            let path = allowed(&ctx, path)?;
+            let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "gridfsUpload", args.operation_id)?;
+            let outcome = upload_file(&path, &mut stream, &mut operation).await;
+            operation.finish(&outcome);
+            outcome?
+        }
+        _ => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Give either data or a path")),
+    };
+    result["id"] = convert::to_json(id);
+    result["filename"] = json!(args.filename);
+    Ok(result)
+}
+
+async fn upload_file(path: &Path, stream: &mut GridFsUploadStream, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) => return Err(errors::failed("Failed to open file", e)),
+    };
+    let total = file.metadata().await.map(|metadata| metadata.len()).ok();
+    let mut buffer = vec![0; STREAM_BUFFER];
+    let mut bytes: u64 = 0;
+    let mut reported: u64 = 0;
+    let uploaded: Result<(), MongoPluginError> = async {
+        loop {
+            if !operation.proceed().await {
+                return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "The upload was cancelled"));
+            }
+            let read = file.read(&mut buffer).await.map_err(|e| errors::failed("Failed to read file", e))?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&buffer[..read]).await.map_err(|e| errors::failed("Failed to upload file", e))?;
+            bytes += read as u64;
+            if bytes - reported >= PROGRESS_EVERY_BYTES {
+                reported = bytes;
+                operation.progress(json!({ "bytes": bytes, "totalBytes": total }));
+            }
+        }
+        stream.close().await.map_err(|e| errors::failed("Failed to upload file", e))
+    }
+    .await;
+    if let Err(e) = uploaded {
+        let _ = stream.abort().await;
+        return Err(e);
+    }
+    operation.progress(json!({ "bytes": bytes, "totalBytes": total }));
+    Ok(json!({ "operationId": operation.id(), "length": bytes }))
+}
+
+pub(super) async fn download(ctx: CommandContext, args: DownloadArgs) -> Result<JsonValue, MongoPluginError> {
+    let name = args.bucket.as_deref().unwrap_or(DEFAULT_BUCKET);
+    // The latest revision of a name, as the driver picks it.
+    let (filter, options) = match (&args.id, &args.filename) {
+        (Some(id), None) => (doc! { "_id": coerce_id(id)? }, None),
+        (None, Some(filename)) => (doc! { "filename": filename }, FindOneOptions::builder().sort(doc! { "uploadDate": -1 }).build().into()),
+        _ => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Give either an id or a filename")),
+    };
+    let file = match ctx.db.collection::<Document>(&format!("{}.files", name)).find_one(filter, options).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No file in bucket '{}' matches", name))),
+        Err(e) => return Err(errors::failed("Failed to find file", e)),
+    };
+    let id = file.get("_id").cloned().unwrap_or(Bson::Null);
+    let mut stream = match bucket(&ctx.db, Some(name)).open_download_stream(id.clone()).await {
+        Ok(stream) => stream,
+        Err(e) => return Err(errors::failed("Failed to open file", e)),
+    };
+    let mut result = match &args.path {
+        Some(path) => {
+            let path = allowed(&ctx, path)?;
+            let total = file.get("length").cloned().and_then(|length| mongodb::bson::from_bson::<u64>(length).ok());
+            let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "gridfsDownload", args.operation_id)?;
+            let outcome = download_file(&path, &mut stream, total, &mut operation).await;
+            operation.finish(&outcome);
+            outcome?
+        }
+        None => {
+            let mut bytes = Vec::new();
+            if let Err(e) = stream.read_to_end(&mut bytes).await {
+                return Err(errors::failed("Failed to download file", e));
+            }
+            json!({ "data": BASE64.encode(bytes) })
+        }
+    };
+    result["id"] = convert::to_json(id);
+    result["filename"] = convert::to_json(file.get("filename").cloned());
+    result["length"] = convert::to_json(file.get("length").cloned());
+    Ok(result)
+}
+
+async fn download_file(path: &Path, stream: &mut GridFsDownloadStream, total: Option<u64>, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let mut file = match tokio::fs::File::create(path).await {
+        Ok(file) => file,
+        Err(e) => return Err(errors::failed("Failed to create file", e)),
+    };
+    let mut buffer = vec![0; STREAM_BUFFER];
+    let mut bytes: u64 = 0;
+    let mut reported: u64 = 0;
+    let downloaded: Result<(), MongoPluginError> = async {
+        loop {
+            if !operation.proceed().await {
+                return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "The download was cancelled"));
+            }
+            let read = stream.read(&mut buffer).await.map_err(|e| errors::failed("Failed to download file", e))?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buffer[..read]).await.map_err(|e| errors::failed("Failed to write file", e))?;
+            bytes += read as u64;
+            if bytes - reported >= PROGRESS_EVERY_BYTES {
+                reported = bytes;
+                operation.progress(json!({ "bytes": bytes, "totalBytes": total }));
+            }
+        }
+        file.flush().await.map_err(|e| errors::failed("Failed to write file", e))
+    }
+    .await;
+    if let Err(e) = downloaded {
+        drop(file);
+        let _ = tokio::fs::remove_file(path).await;
+        return Err(e);
+    }
+    operation.progress(json!({ "bytes": bytes, "totalBytes": total }));
+    Ok(json!({ "operationId": operation.id(), "path": path }))
+}
+
+pub(super) async fn delete(db: Database, args: DeleteArgs) -> Result<JsonValue, MongoPluginError> {
+    let id = coerce_id(&args.id)?;
+    match bucket(&db, args.bucket.as_deref()).delete(id).await {
+        Ok(()) => Ok(json!({ "deleted": true })),
+        Err(e) => Err(errors::failed("Failed to delete file", e)),
+    }
+}
+
+pub(super) async fn find(db: Database, args: FindArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match &args.filter {
+        Some(filter) => match convert::from_extjson(filter) {
+            Ok(filter) => filter,
+            Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+        },
+        None => Document::new(),
+    };
+    let sort: Option<Document> = match &args.sort {
+        Some(sort) => match convert::from_extjson(sort) {
+            Ok(sort) => Some(sort),
+            Err(e) => return Err(errors::invalid("Failed to parse sort", e)),
+        },
+        None => None,
+    };
+    let files = db.collection::<Document>(&format!("{}.files", args.bucket.as_deref().unwrap_or(DEFAULT_BUCKET)));
+    let options = FindOptions::builder().sort(sort).limit(args.limit).skip(args.skip).build();
+    let cursor = match files.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to find files", e)),
+    };
+    convert::collect_json(cursor).await.map_err(|e| errors::failed("Failed to read files", e))
+}
@@ -0,0 +1,114 @@
+//! Limits on what a frontend query may ask of the server.
+//!
+//! The checks look at the `query`/`filter` and `pipeline` arguments of any
+//! command, so they apply uniformly to direct invokes and batch steps.
+
+use mongodb::bson::{doc, Bson};
+use mongodb::Database;
+use serde_json::{json, Value as JsonValue};
+
+use super::errors::{self, MongoPluginError};
+use super::{explain, MongoConfig};
+
+/// Commands whose arguments accept `maxTimeMS`.
+pub(super) const MAX_TIME_COMMANDS: &[&str] = &[
+    "find",
+    "findOne",
+    "findCursor",
+    "exists",
+    "count",
+    "countDocuments",
+    "estimatedDocumentCount",
+    "distinct",
+    "findFieldValue",
+    "exportXlsx",
+    "aggregate",
+    "aggregateCursor",
+    "timeSeriesAggregate",
+    "findDuplicates",
+    "analyzeCollection",
+];
+
+/// Read commands that are explained before running in strict mode.
+const EXPLAINED_COMMANDS: &[&str] =
+    &["find", "findOne", "findCursor", "exists", "count", "countDocuments", "distinct", "findFieldValue", "exportXlsx", "aggregate", "aggregateCursor"];
+
+fn depth(value: &JsonValue) -> usize {
+    match value {
+        JsonValue::Object(map) => 1 + map.values().map(depth).max().unwrap_or(0),
+        JsonValue::Array(items) => 1 + items.iter().map(depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// The JSON text of a stringly-typed argument, parsed. Unparseable text is
+/// left for the command itself to report.
+fn parsed_arg(payload: &JsonValue, key: &str) -> Option<JsonValue> {
+    payload.get(key).and_then(JsonValue::as_str).and_then(|text| serde_json::from_str(text).ok())
+}
+
+/// Rejects payloads over the configured filter depth or pipeline length and
+/// caps `maxTimeMS` at the configured limit, injecting it when missing.
+pub(super) fn apply_limits(config: &MongoConfig, command: &str, payload: &mut JsonValue) -> Result<(), MongoPluginError> {
+    if let Some(max_depth) = config.max_filter_depth {
+        for key in ["query", "filter"] {
+            if let Some(filter) = parsed_arg(payload, key) {
+                if depth(&filter) > max_depth {
+                    let message = format!("Filter is nested deeper than the limit of {}", max_depth);
+                    return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+                }
+            }
+        }
+    }
+    if let Some(max_stages) = config.max_pipeline_stages {
+        if let Some(JsonValue::Array(stages)) = parsed_arg(payload, "pipeline") {
+            if stages.len() > max_stages {
+                let message = format!("Pipeline has {} stages, more than the limit of {}", stages.len(), max_stages);
+                return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+            }
+        }
+    }
+    if let (Some(limit), true) = (config.max_time_ms, MAX_TIME_COMMANDS.contains(&command)) {
+        if let JsonValue::Object(args) = payload {
+            let requested = args.get("maxTimeMS").and_then(JsonValue::as_u64);
+            let max_time = requested.map_or(limit, |requested| requested.min(limit));
+            args.insert("maxTimeMS".to_string(), json!(max_time));
+        }
+    }
+    Ok(())
+}
+
+fn contains_collscan(value: &Bson) -> bool {
+    match value {
+        Bson::Document(doc) => {
+            doc.get_str("stage") == Ok("COLLSCAN") || doc.values().any(contains_collscan)
+        }
+        Bson::Array(items) => items.iter().any(contains_collscan),
+        _ => false,
+    }
+}
+
+/// Whether strict mode needs to explain this command before it runs.
+pub(super) fn needs_explain(config: &MongoConfig, command: &str) -> bool {
+    config.strict_queries && EXPLAINED_COMMANDS.contains(&command)
+}
+
+/// Explains the read described by `payload` and rejects it if the winning
+/// plan scans the whole collection.
+pub(super) async fn reject_collection_scans(db: Database, command: String, payload: JsonValue) -> Result<(), MongoPluginError> {
+    // Arguments that don't parse are left for the command itself to report.
+    let explained = match explain::explainable(&command, &payload) {
+        Some(explained) => explained,
+        None => return Ok(()),
+    };
+    let collection = payload.get("collection").and_then(JsonValue::as_str).unwrap_or_default();
+    let plan = match db.run_command(doc! { "explain": explained, "verbosity": "queryPlanner" }, None).await {
+        Ok(plan) => plan,
+        Err(e) => return Err(errors::failed("Failed to explain query", e)),
+    };
+    if contains_collscan(&Bson::Document(plan)) {
+        let message = format!("Query on '{}' is not supported by an index (strictQueries is on)", collection);
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+    }
+    Ok(())
+}
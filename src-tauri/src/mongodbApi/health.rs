@@ -0,0 +1,63 @@
+//! Checking on the server a connection talks to.
+//!
+//! `ping` sends the server a `ping` and replies `{ ok, roundTripMs }`.
+//! Offline, the driver keeps looking for a server for its whole server
+//! selection timeout, 30 seconds by default; `timeoutMS` gives up sooner
+//! with a `timeout` error. `serverStatus` returns the server's
+//! `serverStatus` document, `sections` turning optional sections on or off,
+//! e.g. `{ "repl": 0, "locks": 1 }`. Whether the app is connected at all
+//! is also reported as it changes, see [`topology`](super::topology).
+
+use mongodb::bson::{doc, Bson};
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use std::time::{Duration, Instant};
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, CommandContext};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct PingArgs {
+    #[serde(rename = "timeoutMS")]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ServerStatusArgs {
+    #[serde(default)]
+    sections: Map<String, JsonValue>,
+}
+
+pub(super) async fn ping(ctx: CommandContext, args: PingArgs) -> Result<JsonValue, MongoPluginError> {
+    let started = Instant::now();
+    let pinged = ctx.db.run_command(doc! { "ping": 1 }, None);
+    let reply = match args.timeout_ms {
+        Some(timeout_ms) => match tokio::time::timeout(Duration::from_millis(timeout_ms), pinged).await {
+            Ok(reply) => reply,
+            Err(_) => {
+                let message = format!("The server didn't answer within {} ms", timeout_ms);
+                return Err(MongoPluginError::new(errors::ErrorKind::Timeout, message));
+            }
+        },
+        None => pinged.await,
+    };
+    match reply {
+        Ok(_) => Ok(json!({ "ok": true, "roundTripMs": started.elapsed().as_secs_f64() * 1000.0 })),
+        Err(e) => Err(errors::failed("Failed to ping the server", e)),
+    }
+}
+
+pub(super) async fn server_status(ctx: CommandContext, args: ServerStatusArgs) -> Result<JsonValue, MongoPluginError> {
+    let mut command = doc! { "serverStatus": 1 };
+    for (section, included) in args.sections {
+        match Bson::try_from(included) {
+            Ok(included) => command.insert(section, included),
+            Err(e) => return Err(errors::invalid("Failed to parse sections", e)),
+        };
+    }
+    match ctx.client.database("admin").run_command(command, None).await {
+        Ok(status) => Ok(convert::to_json(status)),
+        Err(e) => Err(errors::failed("Failed to read server status", e)),
+    }
+}
@@ -0,0 +1,172 @@
+//! Persisted history of the reads run on each connection profile.
+//!
+//! With `queryHistory` configured, every `find`, `findOne`, `exists` and
+//! `aggregate` is recorded with the values in its filter or pipeline
+//! replaced by `"?"`, so the history keeps the shape of a query but none of
+//! the data it was run with. A profile is the server address, without
+//! credentials, and database. History is kept in a JSON file in the app
+//! data directory and survives restarts.
+
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::encryption::app_data_path;
+use super::{DBInfo, MongoState};
+
+const DEFAULT_FILE: &str = "mongo-query-history.json";
+const DEFAULT_MAX_ENTRIES: usize = 500;
+
+/// The `queryHistory` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QueryHistoryConfig {
+    /// History file, relative to the app data directory unless absolute.
+    pub file: Option<PathBuf>,
+    /// Entries kept per profile; the oldest are dropped first.
+    pub max_entries: Option<usize>,
+}
+
+pub(super) struct QueryHistory {
+    path: PathBuf,
+    max_entries: usize,
+    profiles: Mutex<HashMap<String, VecDeque<JsonValue>>>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct GetQueryHistoryArgs {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ClearQueryHistoryArgs {
+    all: Option<bool>,
+}
+
+/// The profile a connection's history is kept under: the server address
+/// with any `user:password@` removed, and the database.
+pub(super) fn profile_id(info: &DBInfo) -> String {
+    let (scheme, rest) = info.server.split_once("://").unwrap_or(("mongodb", info.server.as_str()));
+    let authority = &rest[..rest.find(['/', '?']).unwrap_or(rest.len())];
+    let hosts = authority.rsplit_once('@').map_or(authority, |(_, hosts)| hosts);
+    format!("{}://{}/{}", scheme, hosts, info.database)
+}
+
+/// Replaces every value in a filter or pipeline with `"?"`, keeping field
+/// names and operators. Arrays of values collapse to a single `"?"`.
+fn parameterize(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Object(map) => JsonValue::Object(map.iter().map(|(key, value)| (key.clone(), parameterize(value))).collect()),
+        JsonValue::Array(items) if items.iter().any(JsonValue::is_object) => {
+            JsonValue::Array(items.iter().map(parameterize).collect())
+        }
+        JsonValue::Array(_) => json!(["?"]),
+        _ => json!("?"),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+impl QueryHistory {
+    pub(super) fn load(config: &QueryHistoryConfig, app_data_dir: Option<PathBuf>) -> Result<Self, String> {
+        let path = app_data_path(config.file.as_deref(), DEFAULT_FILE, app_data_dir)?;
+        let profiles = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to read query history: {}", e))?,
+            Err(_) => HashMap::new(),
+        };
+        Ok(Self {
+            path,
+            max_entries: config.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+            profiles: Mutex::new(profiles),
+        })
+    }
+
+    fn save(&self, profiles: &HashMap<String, VecDeque<JsonValue>>) {
+        if let Some(parent) = self.path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        // History is a convenience; failing to save it mustn't fail the query.
+        let _ = fs::write(&self.path, serde_json::to_vec(profiles).unwrap());
+    }
+
+    /// Records a finished read. Other commands are ignored.
+    pub(super) fn record(&self, profile: &str, command: &str, payload: &JsonValue, duration_ms: u64, ok: bool) {
+        let key = match command {
+            "find" | "findOne" | "findCursor" => "query",
+            "exists" | "count" | "countDocuments" | "distinct" | "findFieldValue" | "exportXlsx" | "timeSeriesAggregate" => "filter",
+            "aggregate" | "aggregateCursor" => "pipeline",
+            _ => return,
+        };
+        let spec = payload.get(key).and_then(JsonValue::as_str).and_then(|text| serde_json::from_str(text).ok());
+        let entry = json!({
+            "command": command,
+            "collection": payload.get("collection"),
+            key: spec.as_ref().map(parameterize),
+            "executedAt": now_ms(),
+            "durationMs": duration_ms,
+            "ok": ok,
+        });
+        let mut profiles = self.profiles.lock().unwrap();
+        let entries = profiles.entry(profile.to_string()).or_default();
+        entries.push_back(entry);
+        while entries.len() > self.max_entries {
+            entries.pop_front();
+        }
+        self.save(&profiles);
+    }
+}
+
+fn enabled<R: Runtime>(app: &AppHandle<R>, connection: Option<&str>) -> Result<(Arc<QueryHistory>, String), MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let history = match &state.history {
+        Some(history) => history.clone(),
+        None => {
+            let message = "Query history is not enabled: set queryHistory in the plugin config";
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+    };
+    let connection = state.connections.get(connection)?;
+    Ok((history, profile_id(&connection.info)))
+}
+
+/// The connected profile's history, newest first.
+pub(super) async fn get_query_history<R: Runtime>(
+    app: AppHandle<R>,
+    connection: Option<String>,
+    args: GetQueryHistoryArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let (history, profile) = enabled(&app, connection.as_deref())?;
+    let profiles = history.profiles.lock().unwrap();
+    let entries: Vec<&JsonValue> = match profiles.get(&profile) {
+        Some(entries) => entries.iter().rev().take(args.limit.unwrap_or(usize::MAX)).collect(),
+        None => Vec::new(),
+    };
+    Ok(json!({ "profile": profile, "entries": entries }))
+}
+
+/// Clears the connected profile's history, or every profile's with `all`.
+pub(super) async fn clear_query_history<R: Runtime>(
+    app: AppHandle<R>,
+    connection: Option<String>,
+    args: ClearQueryHistoryArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let (history, profile) = enabled(&app, connection.as_deref())?;
+    let mut profiles = history.profiles.lock().unwrap();
+    let cleared = if args.all.unwrap_or(false) {
+        let cleared = profiles.values().map(VecDeque::len).sum::<usize>();
+        profiles.clear();
+        cleared
+    } else {
+        profiles.remove(&profile).map_or(0, |entries| entries.len())
+    };
+    history.save(&profiles);
+    Ok(json!({ "cleared": cleared }))
+}
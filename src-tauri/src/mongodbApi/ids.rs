@@ -0,0 +1,163 @@
+//! How `_id`s are generated for the documents inserted into a collection.
+//!
+//! `idStrategies` in the plugin config maps collections to `objectId`,
+//! `uuidV4`, `uuidV7` or `ulid`, and the app can give a collection its own
+//! generator with [`MongoPlugin::id_generator`](super::MongoPlugin::id_generator).
+//! Documents `insertOne` and `insertMany` send to such a collection without
+//! an `_id` get one from it before they leave the plugin, and the commands
+//! reply with `{ insertedId }` or `{ insertedIds }` instead of `"success"`.
+//! UUIDs are stored as BSON binary subtype 4; ULIDs as their 26-character
+//! text, which sorts by creation time like UUIDv7s do.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, Document, Uuid};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, InsertManyArgs, InsertOneArgs};
+
+/// Signature of an app-supplied id generator.
+pub type IdFn = dyn Fn() -> Bson + Send + Sync;
+
+/// A built-in id strategy, as named in `idStrategies`.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum IdStrategy {
+    ObjectId,
+    UuidV4,
+    UuidV7,
+    Ulid,
+}
+
+enum Generator {
+    Builtin(IdStrategy),
+    Custom(Arc<IdFn>),
+}
+
+/// The generators of the collections that have one.
+#[derive(Default)]
+pub(super) struct IdStrategies(HashMap<String, Generator>);
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or_default()
+}
+
+/// 48 bits of milliseconds since the epoch, then 80 random bits.
+fn timestamped_random() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes[6..]);
+    bytes[..6].copy_from_slice(&now_millis().to_be_bytes()[2..]);
+    bytes
+}
+
+fn uuid_v7() -> Uuid {
+    let mut bytes = timestamped_random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x70;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Uuid::from_bytes(bytes)
+}
+
+fn ulid() -> String {
+    let value = u128::from_be_bytes(timestamped_random());
+    (0..26).rev().map(|digit| CROCKFORD[((value >> (digit * 5)) & 0x1f) as usize] as char).collect()
+}
+
+impl Generator {
+    fn generate(&self) -> Bson {
+        match self {
+            Generator::Builtin(IdStrategy::ObjectId) => Bson::ObjectId(ObjectId::new()),
+            Generator::Builtin(IdStrategy::UuidV4) => Bson::from(Uuid::new()),
+            Generator::Builtin(IdStrategy::UuidV7) => Bson::from(uuid_v7()),
+            Generator::Builtin(IdStrategy::Ulid) => Bson::String(ulid()),
+            Generator::Custom(generate) => generate(),
+        }
+    }
+}
+
+impl IdStrategies {
+    /// The configured strategies, with `custom` generators taking precedence.
+    pub(super) fn new(configured: &HashMap<String, IdStrategy>, custom: HashMap<String, Arc<IdFn>>) -> Self {
+        let mut generators: HashMap<String, Generator> =
+            configured.iter().map(|(collection, strategy)| (collection.clone(), Generator::Builtin(*strategy))).collect();
+        generators.extend(custom.into_iter().map(|(collection, generate)| (collection, Generator::Custom(generate))));
+        Self(generators)
+    }
+
+    /// Whether the insert in `payload` goes to a collection with a generator.
+    pub(super) fn assigns(&self, payload: &JsonValue) -> bool {
+        payload.get("collection").and_then(JsonValue::as_str).is_some_and(|collection| self.0.contains_key(collection))
+    }
+
+    /// A new `_id` for a document inserted into `collection`, an ObjectId
+    /// unless the collection has a generator.
+    pub(super) fn id_for(&self, collection: &str) -> Bson {
+        match self.0.get(collection) {
+            Some(generator) => generator.generate(),
+            None => Bson::ObjectId(ObjectId::new()),
+        }
+    }
+
+    /// Gives the documents of an insert the generated `_id` they lack.
+    pub(super) fn assign(&self, command: &str, payload: &mut JsonValue) {
+        if !matches!(command, "insertOne" | "insertMany") {
+            return;
+        }
+        let generator = match payload.get("collection").and_then(JsonValue::as_str).and_then(|collection| self.0.get(collection)) {
+            Some(generator) => generator,
+            None => return,
+        };
+        let mut data = match payload.get("data").and_then(JsonValue::as_str).map(convert::from_extjson::<Bson>) {
+            Some(Ok(data)) => data,
+            // Malformed arguments are reported by the command itself.
+            _ => return,
+        };
+        let docs: Vec<&mut Document> = match &mut data {
+            Bson::Document(doc) => vec![doc],
+            Bson::Array(docs) => docs.iter_mut().filter_map(Bson::as_document_mut).collect(),
+            _ => return,
+        };
+        for doc in docs {
+            if !doc.contains_key("_id") {
+                // Kept first, where the server would have put it.
+                let mut with_id = Document::new();
+                with_id.insert("_id", generator.generate());
+                with_id.extend(std::mem::take(doc));
+                *doc = with_id;
+            }
+        }
+        payload["data"] = JsonValue::String(convert::to_extjson_text(data));
+    }
+}
+
+pub(super) async fn insert_one(db: Database, args: InsertOneArgs) -> Result<JsonValue, MongoPluginError> {
+    let doc: Document = match convert::from_extjson(&args.data) {
+        Ok(doc) => doc,
+        Err(e) => return Err(errors::invalid("Failed to parse document", e)),
+    };
+    match db.collection::<Document>(&args.collection).insert_one(doc, None).await {
+        Ok(result) => Ok(json!({ "insertedId": convert::to_json(result.inserted_id) })),
+        Err(e) => Err(errors::failed("Failed to insert document", e)),
+    }
+}
+
+pub(super) async fn insert_many(db: Database, args: InsertManyArgs) -> Result<JsonValue, MongoPluginError> {
+    let docs = convert::parse_documents(args.data, "documents").await?;
+    match db.collection::<Document>(&args.collection).insert_many(docs, None).await {
+        Ok(result) => {
+            let mut ids: Vec<_> = result.inserted_ids.into_iter().collect();
+            ids.sort_by_key(|(index, _)| *index);
+            let ids: Vec<_> = ids.into_iter().map(|(_, id)| convert::to_json(id)).collect();
+            Ok(json!({ "insertedIds": ids }))
+        }
+        Err(e) => Err(errors::failed("Failed to insert documents", e)),
+    }
+}
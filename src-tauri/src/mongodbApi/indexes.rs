@@ -0,0 +1,151 @@
+//! Creating, dropping and listing a collection's indexes.
+//!
+//! `createIndex` takes the key pattern as JSON text, like a filter, and an
+//! `options` object. Text, 2dsphere, hashed and wildcard indexes are asked
+//! for in the key pattern itself, e.g. `{ "body": "text" }`. `createIndexes`
+//! takes a list of `{ keys, options }` and builds them in one command.
+//! `dropIndex` takes the index name, or `*` for every index but `_id`'s, and
+//! `listIndexes` returns each index as `{ key, name, ...options }`.
+
+use futures::TryStreamExt;
+use mongodb::bson::{self, Document};
+use mongodb::options::{Collation, IndexOptions};
+use mongodb::{Database, IndexModel};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::time::Duration;
+
+use super::errors::{self, MongoPluginError};
+use super::convert;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct IndexCommandOptions {
+    /// Generated from the keys by the server when left out.
+    name: Option<String>,
+    unique: Option<bool>,
+    sparse: Option<bool>,
+    /// Removes documents this long after the time in the indexed date field.
+    expire_after_seconds: Option<u64>,
+    partial_filter_expression: Option<Document>,
+    collation: Option<Collation>,
+    hidden: Option<bool>,
+    /// Relative weight of each field of a text index.
+    weights: Option<Document>,
+    default_language: Option<String>,
+    language_override: Option<String>,
+}
+
+impl IndexCommandOptions {
+    fn build(self) -> IndexOptions {
+        IndexOptions::builder()
+            .name(self.name)
+            .unique(self.unique)
+            .sparse(self.sparse)
+            .expire_after(self.expire_after_seconds.map(Duration::from_secs))
+            .partial_filter_expression(self.partial_filter_expression)
+            .collation(self.collation)
+            .hidden(self.hidden)
+            .weights(self.weights)
+            .default_language(self.default_language)
+            .language_override(self.language_override)
+            .build()
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct IndexSpec {
+    keys: String,
+    #[serde(default)]
+    options: IndexCommandOptions,
+}
+
+impl IndexSpec {
+    fn model(self) -> Result<IndexModel, MongoPluginError> {
+        let keys: Document = match convert::from_extjson(&self.keys) {
+            Ok(keys) => keys,
+            Err(e) => return Err(errors::invalid("Failed to parse index keys", e)),
+        };
+        if keys.is_empty() {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "keys must name at least one field"));
+        }
+        Ok(IndexModel::builder().keys(keys).options(self.options.build()).build())
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct CreateIndexArgs {
+    collection: String,
+    #[serde(flatten)]
+    index: IndexSpec,
+}
+
+#[derive(Deserialize)]
+pub(super) struct CreateIndexesArgs {
+    collection: String,
+    indexes: Vec<IndexSpec>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct DropIndexArgs {
+    collection: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ListIndexesArgs {
+    collection: String,
+}
+
+/// Builds one index and returns its name.
+pub(super) async fn create_index(db: Database, args: CreateIndexArgs) -> Result<JsonValue, MongoPluginError> {
+    let model = args.index.model()?;
+    match db.collection::<Document>(&args.collection).create_index(model, None).await {
+        Ok(result) => Ok(json!(result.index_name)),
+        Err(e) => Err(errors::failed("Failed to create index", e)),
+    }
+}
+
+/// Builds several indexes in one command and returns their names.
+pub(super) async fn create_indexes(db: Database, args: CreateIndexesArgs) -> Result<JsonValue, MongoPluginError> {
+    if args.indexes.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "indexes must list at least one index"));
+    }
+    let models = args.indexes.into_iter().map(IndexSpec::model).collect::<Result<Vec<_>, _>>()?;
+    match db.collection::<Document>(&args.collection).create_indexes(models, None).await {
+        Ok(result) => Ok(json!(result.index_names)),
+        Err(e) => Err(errors::failed("Failed to create indexes", e)),
+    }
+}
+
+pub(super) async fn drop_index(db: Database, args: DropIndexArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let dropped = if args.name == "*" {
+        coll.drop_indexes(None).await
+    } else {
+        coll.drop_index(args.name.as_str(), None).await
+    };
+    match dropped {
+        Ok(()) => Ok(json!("success")),
+        Err(e) => Err(errors::failed("Failed to drop index", e)),
+    }
+}
+
+pub(super) async fn list_indexes(db: Database, args: ListIndexesArgs) -> Result<JsonValue, MongoPluginError> {
+    let cursor = match db.collection::<Document>(&args.collection).list_indexes(None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to list indexes", e)),
+    };
+    let models: Vec<IndexModel> = match cursor.try_collect().await {
+        Ok(models) => models,
+        Err(e) => return Err(errors::failed("Failed to read indexes", e)),
+    };
+    let mut indexes = Vec::with_capacity(models.len());
+    for model in models {
+        match bson::to_bson(&model) {
+            Ok(index) => indexes.push(convert::to_json(index)),
+            Err(e) => return Err(errors::failed("Failed to read indexes", e)),
+        }
+    }
+    Ok(JsonValue::Array(indexes))
+}
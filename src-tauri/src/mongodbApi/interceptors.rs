@@ -0,0 +1,67 @@
+//! Hooks the app runs around every command, such as adding `tenantId` to
+//! every filter or stamping `updatedAt` on every update.
+//!
+//! Hooks registered with [`MongoPlugin::before_command`](super::MongoPlugin::before_command)
+//! see each invoke's payload before anything else does and may change it or
+//! reject the command; the tenant, the policy and the command itself then
+//! work from the changed payload. Hooks registered with
+//! [`MongoPlugin::after_command`](super::MongoPlugin::after_command) see each
+//! successful result, in relaxed Extended JSON, before it is written out for
+//! the window, and may change it or fail the command. Hooks run in the order
+//! they were registered, and an error from one stops the rest. The steps of
+//! `executeBatch`, pipelines and other commands that run commands pass
+//! through once, as the invoke the window made.
+
+use serde_json::Value as JsonValue;
+use std::sync::Arc;
+
+use super::errors::MongoPluginError;
+
+/// The invoke a hook runs for.
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub struct Invocation {
+    pub command: String,
+    /// The label of the window that invoked the command.
+    pub window: String,
+    /// The app role set with [`policy::set_app_role`](super::policy::set_app_role).
+    pub role: Option<String>,
+    /// The tenant's database, when the app has a tenant callback.
+    pub tenant: Option<String>,
+}
+
+/// Signature of a hook run before a command: `(invocation, payload)`.
+pub type BeforeFn = dyn Fn(&Invocation, &mut JsonValue) -> Result<(), MongoPluginError> + Send + Sync;
+
+/// Signature of a hook run on a command's result: `(invocation, result)`.
+pub type AfterFn = dyn Fn(&Invocation, &mut JsonValue) -> Result<(), MongoPluginError> + Send + Sync;
+
+#[derive(Default)]
+pub(super) struct Interceptors {
+    pub(super) before: Vec<Box<BeforeFn>>,
+    pub(super) after: Vec<Arc<AfterFn>>,
+}
+
+impl Interceptors {
+    /// Runs the before hooks on `payload`.
+    pub(super) fn before(&self, invocation: &Invocation, payload: &mut JsonValue) -> Result<(), MongoPluginError> {
+        self.before.iter().try_for_each(|hook| hook(invocation, payload))
+    }
+
+    /// The after hooks, to run once the command's result is in.
+    pub(super) fn after(&self, invocation: Invocation) -> After {
+        After { invocation, hooks: self.after.clone() }
+    }
+}
+
+/// The after hooks for one invoke.
+pub(super) struct After {
+    invocation: Invocation,
+    hooks: Vec<Arc<AfterFn>>,
+}
+
+impl After {
+    pub(super) fn apply(&self, result: &mut JsonValue) -> Result<(), MongoPluginError> {
+        self.hooks.iter().try_for_each(|hook| hook(&self.invocation, result))
+    }
+}
@@ -0,0 +1,309 @@
+//! A work queue stored in the `_jobs` collection.
+//!
+//! Jobs are claimed with an atomic `findOneAndUpdate` that stamps a lease; a
+//! worker that dies mid-job simply lets the lease lapse and the job becomes
+//! claimable again, until it has used up its attempts and is marked failed.
+//! Lease times use the server's `$$NOW`. Enqueueing creates the
+//! `(queue, status, runAt)` index claims go by.
+
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Database, IndexModel};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::MongoState;
+
+pub(super) const JOBS_COLLECTION: &str = "_jobs";
+const DEFAULT_MAX_ATTEMPTS: i32 = 3;
+const DEFAULT_LEASE_MS: i64 = 30_000;
+
+/// Databases whose jobs collection has its claim index.
+static INDEXED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct EnqueueJobArgs {
+    queue: String,
+    payload: String,
+    delay_ms: Option<i64>,
+    max_attempts: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ClaimNextJobArgs {
+    queue: String,
+    worker: Option<String>,
+    lease_ms: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CompleteJobArgs {
+    id: String,
+    worker: String,
+    result: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct FailJobArgs {
+    id: String,
+    worker: String,
+    error: String,
+    retry_delay_ms: Option<i64>,
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, MongoPluginError> {
+    ObjectId::parse_str(id).map_err(|e| errors::invalid("Invalid job id", e))
+}
+
+/// Creates the index claims go by, once per database.
+async fn ensure_index(db: &Database) -> Result<(), MongoPluginError> {
+    if INDEXED.lock().unwrap().iter().any(|name| name == db.name()) {
+        return Ok(());
+    }
+    let index = IndexModel::builder().keys(doc! { "queue": 1, "status": 1, "runAt": 1 }).build();
+    match db.collection::<Document>(JOBS_COLLECTION).create_index(index, None).await {
+        Ok(_) => {
+            INDEXED.lock().unwrap().push(db.name().to_string());
+            Ok(())
+        }
+        Err(e) => Err(errors::failed("Failed to set up the jobs collection", e)),
+    }
+}
+
+pub(super) async fn enqueue_job(db: Database, args: EnqueueJobArgs) -> Result<JsonValue, MongoPluginError> {
+    let payload: JsonValue = match serde_json::from_str(&args.payload) {
+        Ok(payload) => payload,
+        Err(e) => return Err(errors::invalid("Failed to parse payload", e)),
+    };
+    let payload = match mongodb::bson::to_bson(&payload) {
+        Ok(payload) => payload,
+        Err(e) => return Err(errors::failed("Failed to convert payload", e)),
+    };
+    ensure_index(&db).await?;
+    let id = ObjectId::new();
+    let delay_ms = args.delay_ms.unwrap_or(0).max(0);
+    let job = vec![doc! { "$set": {
+        "queue": &args.queue,
+        "payload": { "$literal": payload },
+        "status": "pending",
+        "attempts": 0,
+        "maxAttempts": args.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+        "runAt": { "$add": ["$$NOW", delay_ms] },
+        "createdAt": "$$NOW",
+        "updatedAt": "$$NOW",
+    } }];
+    // An upserting pipeline update lets the server stamp runAt instead of the client clock.
+    let options = mongodb::options::UpdateOptions::builder().upsert(true).build();
+    match db.collection::<Document>(JOBS_COLLECTION).update_one(doc! { "_id": id }, job, options).await {
+        Ok(_) => Ok(json!({ "id": id.to_hex() })),
+        Err(e) => Err(errors::failed("Failed to enqueue job", e)),
+    }
+}
+
+async fn claim(db: &Database, queue: &str, worker: &str, lease_ms: i64) -> Result<Option<Document>, MongoPluginError> {
+    let jobs = db.collection::<Document>(JOBS_COLLECTION);
+    // A job whose worker died on its last attempt is not taken again.
+    let exhausted = doc! {
+        "queue": queue,
+        "status": "running",
+        "$expr": { "$and": [{ "$lte": ["$leaseUntil", "$$NOW"] }, { "$gte": ["$attempts", "$maxAttempts"] }] },
+    };
+    let update = vec![doc! { "$set": {
+        "status": "failed",
+        "lastError": "The lease lapsed on the last attempt",
+        "leaseUntil": "$$REMOVE",
+        "updatedAt": "$$NOW",
+    } }];
+    if let Err(e) = jobs.update_many(exhausted, update, None).await {
+        return Err(errors::failed("Failed to claim job", e));
+    }
+    let filter = doc! {
+        "queue": queue,
+        "$or": [
+            { "status": "pending", "$expr": { "$lte": ["$runAt", "$$NOW"] } },
+            {
+                "status": "running",
+                "$expr": { "$and": [{ "$lte": ["$leaseUntil", "$$NOW"] }, { "$lt": ["$attempts", "$maxAttempts"] }] },
+            },
+        ],
+    };
+    let update = vec![doc! { "$set": {
+        "status": "running",
+        "worker": { "$literal": worker },
+        "leaseUntil": { "$add": ["$$NOW", lease_ms] },
+        "attempts": { "$add": ["$attempts", 1] },
+        "updatedAt": "$$NOW",
+    } }];
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "runAt": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+    match jobs.find_one_and_update(filter, update, options).await {
+        Ok(job) => Ok(job),
+        Err(e) => Err(errors::failed("Failed to claim job", e)),
+    }
+}
+
+pub(super) async fn claim_next_job(db: Database, args: ClaimNextJobArgs) -> Result<JsonValue, MongoPluginError> {
+    let worker = args.worker.unwrap_or_else(|| ObjectId::new().to_hex());
+    let lease_ms = args.lease_ms.unwrap_or(DEFAULT_LEASE_MS);
+    if lease_ms <= 0 {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "leaseMs must be positive"));
+    }
+    let job = claim(&db, &args.queue, &worker, lease_ms).await?;
+    Ok(serde_json::to_value(job).unwrap())
+}
+
+async fn complete(db: &Database, id: ObjectId, worker: &str, result: Option<JsonValue>) -> Result<bool, MongoPluginError> {
+    let result = match result.map(|result| mongodb::bson::to_bson(&result)).transpose() {
+        Ok(result) => result,
+        Err(e) => return Err(errors::failed("Failed to convert result", e)),
+    };
+    let filter = doc! { "_id": id, "worker": worker, "status": "running" };
+    let update = vec![doc! { "$set": {
+        "status": "completed",
+        "result": { "$literal": result },
+        "completedAt": "$$NOW",
+        "leaseUntil": "$$REMOVE",
+        "updatedAt": "$$NOW",
+    } }];
+    match db.collection::<Document>(JOBS_COLLECTION).update_one(filter, update, None).await {
+        Ok(outcome) => Ok(outcome.modified_count == 1),
+        Err(e) => Err(errors::failed("Failed to complete job", e)),
+    }
+}
+
+/// Puts the job back as pending after `retry_delay_ms`, or marks it failed
+/// once it has used up its attempts.
+async fn fail(db: &Database, id: ObjectId, worker: &str, error: &str, retry_delay_ms: i64) -> Result<bool, MongoPluginError> {
+    let filter = doc! { "_id": id, "worker": worker, "status": "running" };
+    let update = vec![doc! { "$set": {
+        "status": { "$cond": [{ "$lt": ["$attempts", "$maxAttempts"] }, "pending", "failed"] },
+        "runAt": { "$add": ["$$NOW", retry_delay_ms.max(0)] },
+        "lastError": { "$literal": error },
+        "leaseUntil": "$$REMOVE",
+        "updatedAt": "$$NOW",
+    } }];
+    match db.collection::<Document>(JOBS_COLLECTION).update_one(filter, update, None).await {
+        Ok(outcome) => Ok(outcome.modified_count == 1),
+        Err(e) => Err(errors::failed("Failed to fail job", e)),
+    }
+}
+
+pub(super) async fn complete_job(db: Database, args: CompleteJobArgs) -> Result<JsonValue, MongoPluginError> {
+    let result = match args.result.as_deref().map(serde_json::from_str).transpose() {
+        Ok(result) => result,
+        Err(e) => return Err(errors::invalid("Failed to parse result", e)),
+    };
+    let completed = complete(&db, parse_id(&args.id)?, &args.worker, result).await?;
+    Ok(json!({ "completed": completed }))
+}
+
+pub(super) async fn fail_job(db: Database, args: FailJobArgs) -> Result<JsonValue, MongoPluginError> {
+    let id = parse_id(&args.id)?;
+    let updated = fail(&db, id, &args.worker, &args.error, args.retry_delay_ms.unwrap_or(0)).await?;
+    Ok(json!({ "updated": updated }))
+}
+
+/// Processes jobs from one queue on the Rust side, using whichever database
+/// the plugin is currently connected to.
+///
+/// ```ignore
+/// JobWorker::new("thumbnails").spawn(&app.handle(), |job| async move {
+///     render(job["path"].as_str().unwrap()).await
+/// });
+/// ```
+pub struct JobWorker {
+    queue: String,
+    worker: String,
+    lease: Duration,
+    poll_interval: Duration,
+    retry_delay: Duration,
+}
+
+#[allow(dead_code)]
+impl JobWorker {
+    pub fn new(queue: impl Into<String>) -> Self {
+        Self {
+            queue: queue.into(),
+            worker: ObjectId::new().to_hex(),
+            lease: Duration::from_millis(DEFAULT_LEASE_MS as u64),
+            poll_interval: Duration::from_secs(1),
+            retry_delay: Duration::from_secs(5),
+        }
+    }
+
+    /// How long a claimed job stays reserved before another worker may take it.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// How long to sleep when the queue is empty or the plugin is not connected.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Delay before a failed job becomes claimable again.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Starts the loop; abort the returned handle to stop it. The handler gets
+    /// the job's payload and its `Ok` value is stored as the job result.
+    pub fn spawn<R, F, Fut>(self, app: &AppHandle<R>, handler: F) -> tauri::async_runtime::JoinHandle<()>
+    where
+        R: Runtime,
+        F: Fn(JsonValue) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<JsonValue, String>> + Send,
+    {
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            loop {
+                if !self.run_once(&app, &handler).await {
+                    tokio::time::sleep(self.poll_interval).await;
+                }
+            }
+        })
+    }
+
+    /// Claims and runs at most one job, returning whether one was processed.
+    async fn run_once<R, F, Fut>(&self, app: &AppHandle<R>, handler: &F) -> bool
+    where
+        R: Runtime,
+        F: Fn(JsonValue) -> Fut,
+        Fut: Future<Output = Result<JsonValue, String>>,
+    {
+        let db = match app.state::<MongoState>().database(None, None) {
+            Ok(db) => db,
+            Err(_) => return false,
+        };
+        let job = match claim(&db, &self.queue, &self.worker, self.lease.as_millis() as i64).await {
+            Ok(Some(job)) => job,
+            _ => return false,
+        };
+        let id = match job.get_object_id("_id") {
+            Ok(id) => id,
+            Err(_) => return false,
+        };
+        let payload = serde_json::to_value(job.get("payload")).unwrap();
+        // Bookkeeping failures are left to the lease: the job is retried once it lapses.
+        let _ = match handler(payload).await {
+            Ok(result) => complete(&db, id, &self.worker, Some(result)).await,
+            Err(error) => fail(&db, id, &self.worker, &error, self.retry_delay.as_millis() as i64).await,
+        };
+        true
+    }
+}
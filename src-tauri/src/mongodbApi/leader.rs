@@ -0,0 +1,131 @@
+//! Lease-based leader election built on the `_locks` collection.
+//!
+//! Each running election heartbeats its lock at a third of the TTL. The
+//! instance holding the lock is the leader; `mongo://leader-gained` and
+//! `mongo://leader-lost` are emitted whenever that changes for this instance.
+
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{locks, MongoState};
+
+const DEFAULT_TTL_MS: i64 = 15_000;
+
+struct Election {
+    owner: String,
+    leader: Arc<AtomicBool>,
+    task: tauri::async_runtime::JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub(super) struct LeaderState {
+    elections: Mutex<HashMap<String, Election>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct StartLeaderElectionArgs {
+    name: String,
+    ttl_ms: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct LeaderElectionArgs {
+    name: String,
+}
+
+#[derive(Serialize, Clone)]
+struct LeadershipEvent<'a> {
+    name: &'a str,
+    owner: &'a str,
+}
+
+/// Joins the election for `name`, returning this instance's owner token.
+/// Joining an election that is already running is a no-op.
+pub fn start_election<R: Runtime>(app: &AppHandle<R>, name: &str, ttl: Duration) -> String {
+    let state = app.state::<LeaderState>();
+    let mut elections = state.elections.lock().unwrap();
+    if let Some(election) = elections.get(name) {
+        return election.owner.clone();
+    }
+
+    let owner = ObjectId::new().to_hex();
+    let leader = Arc::new(AtomicBool::new(false));
+    let task = tauri::async_runtime::spawn(heartbeat(app.clone(), name.to_string(), owner.clone(), ttl, leader.clone()));
+    elections.insert(name.to_string(), Election { owner: owner.clone(), leader, task });
+    owner
+}
+
+/// Whether this instance currently leads the election for `name`.
+pub fn is_leader<R: Runtime>(app: &AppHandle<R>, name: &str) -> bool {
+    let state = app.state::<LeaderState>();
+    let elections = state.elections.lock().unwrap();
+    elections.get(name).is_some_and(|election| election.leader.load(Ordering::SeqCst))
+}
+
+async fn heartbeat<R: Runtime>(app: AppHandle<R>, name: String, owner: String, ttl: Duration, leader: Arc<AtomicBool>) {
+    let ttl_ms = ttl.as_millis() as i64;
+    loop {
+        // Connection or server errors count as lost leadership: another
+        // instance may take over once our lease lapses.
+        let acquired = match app.state::<MongoState>().database(None, None) {
+            Ok(db) => matches!(locks::try_acquire(&db, &name, &owner, ttl_ms).await, Ok(Some(_))),
+            Err(_) => false,
+        };
+        if acquired != leader.swap(acquired, Ordering::SeqCst) {
+            let event = if acquired { "mongo://leader-gained" } else { "mongo://leader-lost" };
+            let _ = app.emit_all(event, LeadershipEvent { name: &name, owner: &owner });
+        }
+        tokio::time::sleep(ttl / 3).await;
+    }
+}
+
+/// Leaves the election, handing the lock back immediately if we held it.
+pub async fn stop_election<R: Runtime>(app: &AppHandle<R>, name: &str) -> bool {
+    let election = app.state::<LeaderState>().elections.lock().unwrap().remove(name);
+    let election = match election {
+        Some(election) => election,
+        None => return false,
+    };
+    election.task.abort();
+    if election.leader.load(Ordering::SeqCst) {
+        if let Ok(db) = app.state::<MongoState>().database(None, None) {
+            let _ = locks::release(&db, name, &election.owner).await;
+        }
+        let _ = app.emit_all("mongo://leader-lost", LeadershipEvent { name, owner: &election.owner });
+    }
+    true
+}
+
+/// Leaves every running election.
+pub(super) async fn stop_all<R: Runtime>(app: &AppHandle<R>) {
+    let names: Vec<String> = app.state::<LeaderState>().elections.lock().unwrap().keys().cloned().collect();
+    for name in names {
+        stop_election(app, &name).await;
+    }
+}
+
+pub(super) async fn start_leader_election<R: Runtime>(app: AppHandle<R>, args: StartLeaderElectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let ttl_ms = args.ttl_ms.unwrap_or(DEFAULT_TTL_MS);
+    if ttl_ms <= 0 {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "ttlMs must be positive"));
+    }
+    let owner = start_election(&app, &args.name, Duration::from_millis(ttl_ms as u64));
+    Ok(json!({ "name": args.name, "owner": owner }))
+}
+
+pub(super) async fn stop_leader_election<R: Runtime>(app: AppHandle<R>, args: LeaderElectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let stopped = stop_election(&app, &args.name).await;
+    Ok(json!({ "name": args.name, "stopped": stopped }))
+}
+
+pub(super) async fn is_leader_command<R: Runtime>(app: AppHandle<R>, args: LeaderElectionArgs) -> Result<JsonValue, MongoPluginError> {
+    Ok(json!({ "name": args.name, "leader": is_leader(&app, &args.name) }))
+}
@@ -0,0 +1,135 @@
+//! Distributed locks stored in the `_locks` collection.
+//!
+//! Each lock is a single document keyed by its name. Expiry is evaluated with
+//! the server's `$$NOW`, so instances on machines with skewed clocks still
+//! agree on when a lock has lapsed.
+
+use mongodb::bson::{doc, Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, is_duplicate_key};
+
+pub(super) const LOCKS_COLLECTION: &str = "_locks";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct AcquireLockArgs {
+    name: String,
+    ttl_ms: i64,
+    owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct RenewLockArgs {
+    name: String,
+    owner: String,
+    ttl_ms: i64,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ReleaseLockArgs {
+    name: String,
+    owner: String,
+}
+
+fn options() -> FindOneAndUpdateOptions {
+    FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build()
+}
+
+fn describe(lock: &Document) -> JsonValue {
+    json!({
+        "name": lock.get_str("_id").unwrap_or_default(),
+        "owner": lock.get_str("owner").unwrap_or_default(),
+        "acquiredAt": lock.get("acquiredAt").cloned().map(convert::to_json),
+        "expiresAt": lock.get("expiresAt").cloned().map(convert::to_json),
+    })
+}
+
+/// Takes the lock for `owner` if it is free, expired, or already theirs,
+/// returning the lock document on success. Re-acquiring extends the lease.
+pub(super) async fn try_acquire(db: &Database, name: &str, owner: &str, ttl_ms: i64) -> Result<Option<Document>, MongoPluginError> {
+    let coll = db.collection::<Document>(LOCKS_COLLECTION);
+    let filter = doc! {
+        "_id": name,
+        "$or": [
+            { "$expr": { "$lte": ["$expiresAt", "$$NOW"] } },
+            { "owner": owner },
+        ],
+    };
+    let update = vec![doc! { "$set": {
+        "owner": { "$literal": owner },
+        "acquiredAt": { "$cond": [{ "$eq": ["$owner", { "$literal": owner }] }, "$acquiredAt", "$$NOW"] },
+        "expiresAt": { "$add": ["$$NOW", ttl_ms] },
+    } }];
+    let mut options = options();
+    options.upsert = Some(true);
+
+    match coll.find_one_and_update(filter, update, options).await {
+        Ok(lock) => Ok(lock),
+        // The upsert collides with the live lock held by someone else.
+        Err(e) if is_duplicate_key(&e) => Ok(None),
+        Err(e) => Err(errors::failed("Failed to acquire lock", e)),
+    }
+}
+
+pub(super) async fn release(db: &Database, name: &str, owner: &str) -> Result<bool, MongoPluginError> {
+    let coll = db.collection::<Document>(LOCKS_COLLECTION);
+    match coll.delete_one(doc! { "_id": name, "owner": owner }, None).await {
+        Ok(result) => Ok(result.deleted_count == 1),
+        Err(e) => Err(errors::failed("Failed to release lock", e)),
+    }
+}
+
+/// Command form of [`try_acquire`] that also reports who holds a busy lock.
+/// Callers that don't pass an owner get a fresh token to renew/release with.
+pub(super) async fn acquire_lock(db: Database, args: AcquireLockArgs) -> Result<JsonValue, MongoPluginError> {
+    if args.ttl_ms <= 0 {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "ttlMs must be positive"));
+    }
+    let owner = args.owner.unwrap_or_else(|| mongodb::bson::oid::ObjectId::new().to_hex());
+    if let Some(lock) = try_acquire(&db, &args.name, &owner, args.ttl_ms).await? {
+        let mut result = describe(&lock);
+        result["acquired"] = json!(true);
+        return Ok(result);
+    }
+
+    let coll = db.collection::<Document>(LOCKS_COLLECTION);
+    match coll.find_one(doc! { "_id": &args.name }, None).await {
+        Ok(Some(lock)) => {
+            let mut result = describe(&lock);
+            result["acquired"] = json!(false);
+            Ok(result)
+        }
+        Ok(None) => Ok(json!({ "name": args.name, "acquired": false })),
+        Err(e) => Err(errors::failed("Failed to read lock", e)),
+    }
+}
+
+/// Extends a lock still held by `owner`; reports `renewed: false` if it was lost.
+pub(super) async fn renew_lock(db: Database, args: RenewLockArgs) -> Result<JsonValue, MongoPluginError> {
+    if args.ttl_ms <= 0 {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "ttlMs must be positive"));
+    }
+    let coll = db.collection::<Document>(LOCKS_COLLECTION);
+    let filter = doc! { "_id": &args.name, "owner": &args.owner };
+    let update = vec![doc! { "$set": { "expiresAt": { "$add": ["$$NOW", args.ttl_ms] } } }];
+    match coll.find_one_and_update(filter, update, options()).await {
+        Ok(Some(lock)) => {
+            let mut result = describe(&lock);
+            result["renewed"] = json!(true);
+            Ok(result)
+        }
+        Ok(None) => Ok(json!({ "name": args.name, "renewed": false })),
+        Err(e) => Err(errors::failed("Failed to renew lock", e)),
+    }
+}
+
+pub(super) async fn release_lock(db: Database, args: ReleaseLockArgs) -> Result<JsonValue, MongoPluginError> {
+    let released = release(&db, &args.name, &args.owner).await?;
+    Ok(json!({ "name": args.name, "released": released }))
+}
@@ -0,0 +1,208 @@
+//! Merging duplicate documents into one.
+//!
+//! `mergeDocuments` folds the documents `sourceIds` name into the one
+//! `targetId` names and removes them, all in one transaction. Top-level
+//! fields a source has and the target lacks are always copied over; where
+//! both have a field, `strategy` decides: `keepTarget` (the default) keeps
+//! the target's value, `preferSource` takes the source's, later sources
+//! winning, and `combine` joins arrays without repeating elements and keeps
+//! the target's value otherwise. Sources are removed the way the collection
+//! deletes documents, soft-deleted or moved to the trash where configured.
+//! References to the sources listed under `merge.references` for the
+//! collection, scalar fields or arrays of ids, are pointed at the target in
+//! the same transaction. It needs a replica set or sharded cluster.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::{Error as MongoError, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use mongodb::ClientSession;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+
+use super::errors::{self, MongoPluginError};
+use super::{coerce_id, softdelete, CommandContext};
+
+const MAX_TRANSACTION_ATTEMPTS: u32 = 5;
+
+/// The `merge` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MergeConfig {
+    /// By collection, the fields elsewhere holding ids of its documents.
+    pub references: HashMap<String, Vec<Reference>>,
+}
+
+/// A field in `collection` holding the id of a document, or an array of ids.
+#[derive(Deserialize, Clone)]
+pub struct Reference {
+    pub collection: String,
+    pub field: String,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum Strategy {
+    #[default]
+    KeepTarget,
+    PreferSource,
+    Combine,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct MergeDocumentsArgs {
+    collection: String,
+    target_id: JsonValue,
+    source_ids: Vec<JsonValue>,
+    #[serde(default)]
+    strategy: Strategy,
+}
+
+/// `into` with the fields of `source` merged in as `strategy` says.
+fn merge_fields(into: &mut Document, source: Document, strategy: Strategy) {
+    for (key, value) in source {
+        if key == "_id" {
+            continue;
+        }
+        match (into.get_mut(&key), strategy) {
+            (None, _) => {
+                into.insert(key, value);
+            }
+            (Some(existing), Strategy::PreferSource) => *existing = value,
+            (Some(Bson::Array(existing)), Strategy::Combine) => {
+                if let Bson::Array(items) = value {
+                    for item in items {
+                        if !existing.contains(&item) {
+                            existing.push(item);
+                        }
+                    }
+                }
+            }
+            (Some(_), _) => {}
+        }
+    }
+}
+
+/// The pipeline update pointing `field` at `target` instead of any of
+/// `sources`, in place within an array of ids.
+fn repoint(field: &str, sources: &[Bson], target: &Bson) -> Vec<Document> {
+    let path = format!("${}", field);
+    let array = doc! { "$setUnion": [{ "$setDifference": [&path, sources] }, [target]] };
+    vec![doc! { "$set": { field: { "$cond": [{ "$isArray": &path }, array, target] } } }]
+}
+
+/// Does the merge on `session`'s transaction and returns its outcome.
+async fn merge_in(
+    ctx: &CommandContext,
+    args: &MergeDocumentsArgs,
+    soft_field: Option<&str>,
+    target: &Bson,
+    sources: &[Bson],
+    session: &mut ClientSession,
+) -> Result<Result<JsonValue, MongoPluginError>, MongoError> {
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    // Soft-deleted documents are neither merged nor merged into.
+    let (target_filter, filter) = match soft_field {
+        Some(field) => (softdelete::live_by_id(target.clone(), field), doc! { "_id": { "$in": sources }, field: Bson::Null }),
+        None => (doc! { "_id": target }, doc! { "_id": { "$in": sources } }),
+    };
+    let mut merged = match coll.find_one_with_session(target_filter, None, &mut *session).await? {
+        Some(merged) => merged,
+        None => return Ok(Err(MongoPluginError::new(errors::ErrorKind::NotFound, "No document matches targetId"))),
+    };
+    let mut cursor = coll.find_with_session(filter.clone(), None, &mut *session).await?;
+    let mut found: Vec<Document> = cursor.stream(&mut *session).try_collect().await?;
+    if found.len() != sources.len() {
+        let message = format!("Only {} of the {} sourceIds match a document", found.len(), sources.len());
+        return Ok(Err(MongoPluginError::new(errors::ErrorKind::NotFound, message)));
+    }
+    // Merged in the order the sources were given.
+    found.sort_by_key(|doc| sources.iter().position(|id| doc.get("_id") == Some(id)));
+    for source in found {
+        merge_fields(&mut merged, source, args.strategy);
+    }
+    coll.replace_one_with_session(doc! { "_id": target }, &merged, None, &mut *session).await?;
+
+    let mut references_updated = 0;
+    let references = ctx.config.merge.references.get(&args.collection).map(Vec::as_slice).unwrap_or_default();
+    for reference in references {
+        let referring = ctx.db.collection::<Document>(&reference.collection);
+        let filter = doc! { &reference.field: { "$in": sources } };
+        let result = referring.update_many_with_session(filter, repoint(&reference.field, sources, target), None, &mut *session).await?;
+        references_updated += result.modified_count;
+    }
+
+    let deleted = match (soft_field, &ctx.trash) {
+        (Some(field), _) => coll.update_many_with_session(filter, softdelete::stamp(field), None, &mut *session).await?.modified_count,
+        (None, Some(trash)) if trash.trashes(&json!({ "collection": args.collection })) => {
+            let mut deleted = 0;
+            for id in sources {
+                let result = trash.delete_with_session(&ctx.db, &args.collection, id.clone(), &ctx.actor, session).await?;
+                deleted += result["deletedCount"].as_u64().unwrap_or(0);
+            }
+            deleted
+        }
+        _ => coll.delete_many_with_session(filter, None, &mut *session).await?.deleted_count,
+    };
+    Ok(Ok(json!({
+        "merged": serde_json::to_value(&merged).unwrap(),
+        "deletedCount": deleted,
+        "referencesUpdated": references_updated,
+    })))
+}
+
+pub(super) async fn merge_documents(ctx: CommandContext, args: MergeDocumentsArgs, soft_field: Option<String>) -> Result<JsonValue, MongoPluginError> {
+    let target = coerce_id(&args.target_id)?;
+    let sources = args.source_ids.iter().map(coerce_id).collect::<Result<Vec<_>, _>>()?;
+    if sources.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "sourceIds must name at least one document"));
+    }
+    if sources.iter().enumerate().any(|(index, id)| sources[..index].contains(id)) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "sourceIds must not repeat a document"));
+    }
+    if sources.contains(&target) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "targetId can't also be one of the sourceIds"));
+    }
+    if let Some(trash) = &ctx.trash {
+        // Index creation can't run inside the transaction.
+        if soft_field.is_none() && trash.trashes(&json!({ "collection": args.collection })) {
+            trash.ensure_ttl(&ctx.db).await?;
+        }
+    }
+    let mut session = match ctx.client.start_session(None).await {
+        Ok(session) => session,
+        Err(e) => return Err(errors::failed("Failed to start session", e)),
+    };
+    'attempts: for attempt in 1..=MAX_TRANSACTION_ATTEMPTS {
+        if let Err(e) = session.start_transaction(None).await {
+            return Err(errors::failed("Failed to start transaction", e));
+        }
+        let outcome = match merge_in(&ctx, &args, soft_field.as_deref(), &target, &sources, &mut session).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(e)) => {
+                let _ = session.abort_transaction().await;
+                return Err(e);
+            }
+            Err(e) => {
+                let _ = session.abort_transaction().await;
+                if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS {
+                    continue;
+                }
+                return Err(errors::failed("Failed to merge documents", e));
+            }
+        };
+        let mut commit_attempts = 1;
+        loop {
+            match session.commit_transaction().await {
+                Ok(()) => return Ok(outcome),
+                Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && commit_attempts < MAX_TRANSACTION_ATTEMPTS => {
+                    commit_attempts += 1;
+                }
+                Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => continue 'attempts,
+                Err(e) => return Err(errors::failed("Failed to commit merge", e)),
+            }
+        }
+    }
+    Err("Merge did not succeed after retrying transient errors".into())
+}
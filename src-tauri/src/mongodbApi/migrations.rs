@@ -0,0 +1,276 @@
+//! Versioned schema migrations the app declares and the frontend runs.
+//!
+//! The app registers each [`Migration`] with
+//! [`MongoPlugin::migration`](super::MongoPlugin::migration): a version,
+//! a name, the step that applies it and optionally the step that reverts it,
+//! each a closure given the connected [`Database`]. Applied versions are
+//! recorded in the `_migrations` collection of that database, so each step
+//! runs once per database however often the app starts.
+//!
+//! `migrateUp` applies the pending migrations in version order, up to and
+//! including `to` if given, and returns `{ operationId, applied, current }`.
+//! `migrateDown` reverts the applied migrations above `to`, newest first, or
+//! only the newest without `to`, and returns `{ operationId, reverted,
+//! current }`; it refuses to start unless every one of them has a down step.
+//! Both run as `migration` [operations](super::operations), reporting
+//! `{ done, total, version, name }` before each step, and stop between steps
+//! when cancelled. A step is recorded once it returns `Ok`, so one that
+//! failed part-way runs again whole the next time. Runs hold the
+//! `migrations` [lock](super::locks), so two instances of the app never
+//! migrate the same database at once. `migrationStatus` lists every
+//! registered migration with whether and when it was applied, the
+//! `current` version and the applied versions the app no longer registers.
+//!
+//! ```ignore
+//! MongoPlugin::new().migration(
+//!     Migration::new(1, "index users by email", |db| async move {
+//!         let index = IndexModel::builder().keys(doc! { "email": 1 }).build();
+//!         db.collection::<Document>("users").create_index(index, None).await.map_err(|e| e.to_string())?;
+//!         Ok(())
+//!     })
+//!     .down(|db| async move {
+//!         db.collection::<Document>("users").drop_index("email_1", None).await.map_err(|e| e.to_string())
+//!     }),
+//! )
+//! ```
+
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Instant;
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::{convert, locks, CommandContext, NoArgs};
+
+const MIGRATIONS_COLLECTION: &str = "_migrations";
+const LOCK_NAME: &str = "migrations";
+/// How long a run holds the lock, so a crashed run doesn't block the next
+/// one for good.
+const LOCK_TTL_MS: i64 = 10 * 60 * 1000;
+
+/// Signature of a migration step.
+pub type StepFn = dyn Fn(Database) -> BoxFuture<'static, Result<(), String>> + Send + Sync;
+
+/// One versioned change to the database.
+pub struct Migration {
+    version: u32,
+    name: String,
+    up: Box<StepFn>,
+    down: Option<Box<StepFn>>,
+}
+
+fn step<F, Fut>(run: F) -> Box<StepFn>
+where
+    F: Fn(Database) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    Box::new(move |db| -> BoxFuture<'static, Result<(), String>> { Box::pin(run(db)) })
+}
+
+#[allow(dead_code)]
+impl Migration {
+    pub fn new<F, Fut>(version: u32, name: impl Into<String>, up: F) -> Self
+    where
+        F: Fn(Database) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        Self { version, name: name.into(), up: step(up), down: None }
+    }
+
+    /// The step `migrateDown` runs to revert this migration.
+    pub fn down<F, Fut>(mut self, down: F) -> Self
+    where
+        F: Fn(Database) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.down = Some(step(down));
+        self
+    }
+}
+
+impl Migration {
+    pub(super) fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+/// The registered migrations, in version order.
+#[derive(Default)]
+pub(super) struct Migrations {
+    steps: Vec<Migration>,
+}
+
+impl Migrations {
+    pub(super) fn new(mut steps: Vec<Migration>) -> Self {
+        steps.sort_by_key(|migration| migration.version);
+        Self { steps }
+    }
+
+    fn get(&self, version: u32) -> Option<&Migration> {
+        self.steps.iter().find(|migration| migration.version == version)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct MigrateArgs {
+    to: Option<u32>,
+    operation_id: Option<String>,
+}
+
+/// The `_migrations` records, by version.
+async fn applied(db: &Database) -> Result<HashMap<u32, Document>, MongoPluginError> {
+    let found: Result<Vec<Document>, _> = match db.collection::<Document>(MIGRATIONS_COLLECTION).find(None, None).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match found {
+        Ok(records) => Ok(records
+            .into_iter()
+            .filter_map(|record| {
+                let version = match record.get("_id") {
+                    Some(Bson::Int32(version)) => u32::try_from(*version).ok(),
+                    Some(Bson::Int64(version)) => u32::try_from(*version).ok(),
+                    _ => None,
+                }?;
+                Some((version, record))
+            })
+            .collect()),
+        Err(e) => Err(errors::failed("Failed to read applied migrations", e)),
+    }
+}
+
+fn current(applied: &HashMap<u32, Document>) -> Option<u32> {
+    applied.keys().max().copied()
+}
+
+/// Runs `work` holding the migrations lock.
+async fn locked<Fut>(db: &Database, work: Fut) -> Result<JsonValue, MongoPluginError>
+where
+    Fut: Future<Output = Result<JsonValue, MongoPluginError>>,
+{
+    let owner = ObjectId::new().to_hex();
+    if locks::try_acquire(db, LOCK_NAME, &owner, LOCK_TTL_MS).await?.is_none() {
+        return Err("Migrations are already running on this database".into());
+    }
+    let outcome = work.await;
+    // A lock left behind lapses after its TTL.
+    let _ = locks::release(db, LOCK_NAME, &owner).await;
+    outcome
+}
+
+pub(super) async fn migrate_up(ctx: CommandContext, args: MigrateArgs) -> Result<JsonValue, MongoPluginError> {
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "migration", args.operation_id.clone())?;
+    let outcome = locked(&ctx.db, apply(&ctx, args.to, &mut operation)).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn apply(ctx: &CommandContext, to: Option<u32>, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let mut done = applied(&ctx.db).await?;
+    let pending: Vec<&Migration> =
+        ctx.migrations.steps.iter().filter(|migration| !done.contains_key(&migration.version) && to.is_none_or(|to| migration.version <= to)).collect();
+    let records = ctx.db.collection::<Document>(MIGRATIONS_COLLECTION);
+    let mut applied_now = Vec::new();
+    for (index, migration) in pending.iter().enumerate() {
+        if !operation.proceed().await {
+            return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "Migrating was cancelled"));
+        }
+        operation.progress(json!({ "done": index, "total": pending.len(), "version": migration.version, "name": migration.name }));
+        let started = Instant::now();
+        if let Err(e) = (migration.up)(ctx.db.clone()).await {
+            let message = format!("Migration {} '{}' failed: {}", migration.version, migration.name, e);
+            return Err(MongoPluginError::new(errors::ErrorKind::Other, message));
+        }
+        let record = doc! {
+            "_id": i64::from(migration.version),
+            "name": &migration.name,
+            "appliedAt": DateTime::now(),
+            "durationMs": started.elapsed().as_millis() as i64,
+        };
+        if let Err(e) = records.insert_one(&record, None).await {
+            return Err(errors::failed(&format!("Migration {} was applied but recording it failed", migration.version), e));
+        }
+        done.insert(migration.version, record);
+        applied_now.push(migration.version);
+    }
+    operation.progress(json!({ "done": pending.len(), "total": pending.len() }));
+    Ok(json!({ "operationId": operation.id(), "applied": applied_now, "current": current(&done) }))
+}
+
+pub(super) async fn migrate_down(ctx: CommandContext, args: MigrateArgs) -> Result<JsonValue, MongoPluginError> {
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "migration", args.operation_id.clone())?;
+    let outcome = locked(&ctx.db, revert(&ctx, args.to, &mut operation)).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn revert(ctx: &CommandContext, to: Option<u32>, operation: &mut Operation) -> Result<JsonValue, MongoPluginError> {
+    let mut done = applied(&ctx.db).await?;
+    let mut versions: Vec<u32> = match to {
+        Some(to) => done.keys().copied().filter(|version| *version > to).collect(),
+        None => current(&done).into_iter().collect(),
+    };
+    versions.sort_unstable_by(|a, b| b.cmp(a));
+    let mut reverted = Vec::new();
+    for version in &versions {
+        match ctx.migrations.get(*version) {
+            Some(migration) if migration.down.is_some() => reverted.push(migration),
+            Some(_) => {
+                let message = format!("Migration {} has no down step, so it can't be reverted", version);
+                return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+            }
+            None => {
+                let message = format!("No migration {} is registered, so it can't be reverted", version);
+                return Err(MongoPluginError::new(errors::ErrorKind::NotFound, message));
+            }
+        }
+    }
+    let records = ctx.db.collection::<Document>(MIGRATIONS_COLLECTION);
+    for (index, migration) in reverted.iter().enumerate() {
+        if !operation.proceed().await {
+            return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "Migrating was cancelled"));
+        }
+        operation.progress(json!({ "done": index, "total": reverted.len(), "version": migration.version, "name": migration.name }));
+        let down = migration.down.as_ref().expect("checked above");
+        if let Err(e) = down(ctx.db.clone()).await {
+            let message = format!("Reverting migration {} '{}' failed: {}", migration.version, migration.name, e);
+            return Err(MongoPluginError::new(errors::ErrorKind::Other, message));
+        }
+        if let Err(e) = records.delete_one(doc! { "_id": i64::from(migration.version) }, None).await {
+            return Err(errors::failed(&format!("Migration {} was reverted but recording it failed", migration.version), e));
+        }
+        done.remove(&migration.version);
+    }
+    operation.progress(json!({ "done": reverted.len(), "total": reverted.len() }));
+    Ok(json!({ "operationId": operation.id(), "reverted": versions, "current": current(&done) }))
+}
+
+pub(super) async fn migration_status(ctx: CommandContext, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let done = applied(&ctx.db).await?;
+    let migrations: Vec<JsonValue> = ctx
+        .migrations
+        .steps
+        .iter()
+        .map(|migration| {
+            let record = done.get(&migration.version);
+            json!({
+                "version": migration.version,
+                "name": migration.name,
+                "applied": record.is_some(),
+                "appliedAt": record.and_then(|record| record.get("appliedAt")).cloned().map(convert::to_json),
+                "reversible": migration.down.is_some(),
+            })
+        })
+        .collect();
+    let pending = ctx.migrations.steps.iter().filter(|migration| !done.contains_key(&migration.version)).count();
+    let mut unknown: Vec<u32> = done.keys().copied().filter(|version| ctx.migrations.get(*version).is_none()).collect();
+    unknown.sort_unstable();
+    Ok(json!({ "current": current(&done), "pending": pending, "migrations": migrations, "unknown": unknown }))
+}
@@ -0,0 +1,85 @@
+//! The namespaces an aggregation reaches beyond its own collection.
+//!
+//! A pipeline reads other collections with `$lookup`, `$graphLookup` and
+//! `$unionWith`, and writes them with `$out` and `$merge`, each of which may
+//! also name another database. [`pipeline_targets`] walks the stages of the
+//! pipeline a command carries, including those nested in `$lookup` and
+//! `$unionWith` pipelines and in `$facet`, so the policy and tenancy can
+//! check every namespace it reaches, not only the collection it runs on.
+
+use serde_json::Value as JsonValue;
+
+/// A collection a pipeline reads or writes.
+pub(super) struct Target {
+    /// The database it is in, when the stage names one.
+    pub(super) database: Option<String>,
+    pub(super) collection: String,
+    /// Whether the pipeline writes to it.
+    pub(super) writes: bool,
+}
+
+/// A stage's namespace, written as a collection name or as `{ db, coll }`.
+fn namespace(value: &JsonValue) -> Option<(Option<String>, String)> {
+    match value {
+        JsonValue::String(collection) => Some((None, collection.clone())),
+        JsonValue::Object(spec) => {
+            let collection = spec.get("coll").and_then(JsonValue::as_str)?;
+            Some((spec.get("db").and_then(JsonValue::as_str).map(str::to_string), collection.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn push(targets: &mut Vec<Target>, value: Option<&JsonValue>, writes: bool) {
+    if let Some((database, collection)) = value.and_then(namespace) {
+        targets.push(Target { database, collection, writes });
+    }
+}
+
+fn walk(stages: &[JsonValue], targets: &mut Vec<Target>) {
+    for (name, spec) in stages.iter().filter_map(JsonValue::as_object).flatten() {
+        match name.as_str() {
+            "$out" => push(targets, Some(spec), true),
+            "$merge" => push(targets, Some(spec.get("into").unwrap_or(spec)), true),
+            "$lookup" | "$graphLookup" => {
+                // `from` is a collection name, or `{ db, coll }` on Atlas.
+                push(targets, spec.get("from"), false);
+                if let Some(pipeline) = spec.get("pipeline").and_then(JsonValue::as_array) {
+                    walk(pipeline, targets);
+                }
+            }
+            "$unionWith" => {
+                push(targets, Some(spec), false);
+                if let Some(pipeline) = spec.get("pipeline").and_then(JsonValue::as_array) {
+                    walk(pipeline, targets);
+                }
+            }
+            "$facet" => {
+                for pipeline in spec.as_object().into_iter().flat_map(|facets| facets.values()).filter_map(JsonValue::as_array) {
+                    walk(pipeline, targets);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The namespaces the pipeline in `payload` reads or writes, `explain`ed
+/// ones included. A pipeline that doesn't parse has none; the command
+/// itself reports it.
+pub(super) fn pipeline_targets(command: &str, payload: &JsonValue) -> Vec<Target> {
+    let pipeline = match command {
+        "explain" => payload.pointer("/args/pipeline"),
+        _ => payload.get("pipeline"),
+    };
+    let stages = match pipeline {
+        Some(JsonValue::String(text)) => serde_json::from_str(text).unwrap_or(JsonValue::Null),
+        Some(stages) => stages.clone(),
+        None => return Vec::new(),
+    };
+    let mut targets = Vec::new();
+    if let JsonValue::Array(stages) = stages {
+        walk(&stages, &mut targets);
+    }
+    targets
+}
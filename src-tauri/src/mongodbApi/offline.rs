@@ -0,0 +1,301 @@
+//! Holding on to writes made while the server can't be reached.
+//!
+//! With an `offlineQueue` section in the plugin config, a write that fails
+//! with a `connection` error, after any retries and failover wait, is kept
+//! in a JSON file in the app data directory and replies `{ queued: true,
+//! queueId, error }` instead of failing. Queued writes are sent again in the
+//! order they were made, once the connection's servers answer again, or
+//! when `connectDBServer` opens a connection under the same `connectionId`
+//! after a restart. Each one that goes through emits `mongo://queue-flushed`
+//! with its result; one the server rejects, e.g. for a duplicate key or a
+//! version conflict, is dropped from the queue and emits
+//! `mongo://queue-conflict` with the error, so the frontend can reconcile.
+//! `listQueuedWrites` lists the writes queued for the connected database
+//! and `discardQueuedWrite` drops one before it is sent.
+//!
+//! Queued are `insertOne`, `insertMany`, `updateById`, `updateOne`,
+//! `updateMany`, `replaceOne`, `deleteById`, `deleteOne`, `deleteMany`,
+//! `incrementField`, `pushToArray`, `pullFromArray`, `upsertMany`,
+//! `updateWithVersion` and `bulkWrite`, unless they run on a session. A
+//! write whose connection dropped after the server applied it is applied
+//! again on replay; inserted documents that carry their `_id` conflict
+//! rather than being inserted twice.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::encryption::app_data_path;
+use super::errors::{self, MongoPluginError};
+use super::{connections, execute, sessions, CommandContext, CommandFuture, NoArgs};
+
+const DEFAULT_FILE: &str = "mongo-offline-queue.json";
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+/// How long a replay that failed for want of a connection waits before the
+/// next try, once the servers answer again.
+const REPLAY_DELAY: Duration = Duration::from_secs(2);
+
+const FLUSHED_EVENT: &str = "mongo://queue-flushed";
+const CONFLICT_EVENT: &str = "mongo://queue-conflict";
+
+const QUEUED: &[&str] = &[
+    "insertOne",
+    "insertMany",
+    "updateById",
+    "updateOne",
+    "updateMany",
+    "replaceOne",
+    "deleteById",
+    "deleteOne",
+    "deleteMany",
+    "incrementField",
+    "pushToArray",
+    "pullFromArray",
+    "upsertMany",
+    "updateWithVersion",
+    "bulkWrite",
+];
+
+/// The `offlineQueue` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OfflineQueueConfig {
+    /// Queue file, relative to the app data directory unless absolute.
+    pub file: Option<PathBuf>,
+    /// Writes kept at most; once full, writes fail as they would without a queue.
+    pub max_entries: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct QueuedWrite {
+    queue_id: String,
+    connection_id: String,
+    database: String,
+    command: String,
+    payload: JsonValue,
+    actor: JsonValue,
+    queued_at: u64,
+    /// Why the write was queued.
+    error: JsonValue,
+}
+
+impl QueuedWrite {
+    fn view(&self) -> JsonValue {
+        json!({
+            "queueId": self.queue_id,
+            "connectionId": self.connection_id,
+            "database": self.database,
+            "command": self.command,
+            "collection": self.payload.get("collection"),
+            "queuedAt": self.queued_at,
+            "error": self.error,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    writes: VecDeque<QueuedWrite>,
+    /// Connections a replay task is running for.
+    flushing: HashSet<String>,
+}
+
+pub(super) struct OfflineQueue {
+    path: PathBuf,
+    max_entries: usize,
+    queue: Mutex<Queue>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DiscardQueuedWriteArgs {
+    queue_id: String,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Whether a command's failure means the server couldn't be reached.
+fn offline(error: &JsonValue) -> bool {
+    error.get("kind").and_then(JsonValue::as_str) == Some("connection")
+}
+
+/// Whether the command is queued when it fails for want of a connection.
+pub(super) fn queues(command: &str, payload: &JsonValue) -> bool {
+    QUEUED.contains(&command)
+        && sessions::session_id(payload).is_none()
+        && payload.get("dryRun").and_then(JsonValue::as_bool) != Some(true)
+        && payload.get("confirmationToken").is_none()
+}
+
+fn enabled(ctx: &CommandContext) -> Result<&Arc<OfflineQueue>, MongoPluginError> {
+    match &ctx.offline_queue {
+        Some(queue) => Ok(queue),
+        None => {
+            let message = "The offline queue is not enabled: set offlineQueue in the plugin config";
+            Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message))
+        }
+    }
+}
+
+impl OfflineQueue {
+    pub(super) fn load(config: &OfflineQueueConfig, app_data_dir: Option<PathBuf>) -> Result<Self, String> {
+        let path = app_data_path(config.file.as_deref(), DEFAULT_FILE, app_data_dir)?;
+        let writes = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to read offline queue: {}", e))?,
+            Err(_) => VecDeque::new(),
+        };
+        Ok(Self {
+            path,
+            max_entries: config.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+            queue: Mutex::new(Queue { writes, flushing: HashSet::new() }),
+        })
+    }
+
+    fn save(&self, writes: &VecDeque<QueuedWrite>) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_vec(writes).unwrap())
+    }
+
+    /// Keeps the write, returning its queue id, or `None` when it can't be
+    /// kept and should fail as it did.
+    fn push(&self, write: QueuedWrite) -> Option<String> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.writes.len() >= self.max_entries {
+            return None;
+        }
+        let queue_id = write.queue_id.clone();
+        queue.writes.push_back(write);
+        if self.save(&queue.writes).is_err() {
+            queue.writes.pop_back();
+            return None;
+        }
+        Some(queue_id)
+    }
+
+    fn remove(&self, queue_id: &str) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let before = queue.writes.len();
+        queue.writes.retain(|write| write.queue_id != queue_id);
+        let removed = queue.writes.len() != before;
+        if removed {
+            // A write that stays in the file is only sent again, which the
+            // server then reports as a conflict.
+            let _ = self.save(&queue.writes);
+        }
+        removed
+    }
+
+    /// The oldest write queued on `connection_id`, or `None` after marking
+    /// the connection as no longer flushing.
+    fn next(&self, connection_id: &str) -> Option<QueuedWrite> {
+        let mut queue = self.queue.lock().unwrap();
+        let next = queue.writes.iter().find(|write| write.connection_id == connection_id).cloned();
+        if next.is_none() {
+            queue.flushing.remove(connection_id);
+        }
+        next
+    }
+
+    fn pending(&self, connection_id: &str, database: &str) -> Vec<JsonValue> {
+        let queue = self.queue.lock().unwrap();
+        queue.writes.iter().filter(|write| write.connection_id == connection_id && write.database == database).map(QueuedWrite::view).collect()
+    }
+
+    /// Replays the writes queued on the context's connection in the
+    /// background, unless that's already under way.
+    pub(super) fn flush(self: &Arc<Self>, ctx: &CommandContext) {
+        let connection_id = ctx.topology.connection_id().to_string();
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if !queue.writes.iter().any(|write| write.connection_id == connection_id) || !queue.flushing.insert(connection_id.clone()) {
+                return;
+            }
+        }
+        let (queue, ctx) = (self.clone(), ctx.clone());
+        tokio::spawn(async move {
+            while let Some(write) = queue.next(&connection_id) {
+                ctx.topology.wait_until_reachable().await;
+                let mut replay = ctx.clone();
+                replay.db = ctx.client.database(&write.database);
+                replay.actor = write.actor.clone();
+                let outcome = match execute(&replay, &write.command, write.payload.clone()) {
+                    Some(task) => task.await,
+                    None => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown command: {}", write.command)).into()),
+                };
+                let mut event = write.view();
+                match outcome {
+                    Err(e) if offline(&e) => {
+                        tokio::time::sleep(REPLAY_DELAY).await;
+                        continue;
+                    }
+                    Ok(result) => {
+                        event["result"] = result;
+                        if queue.remove(&write.queue_id) {
+                            (ctx.events)(FLUSHED_EVENT, event);
+                        }
+                    }
+                    Err(e) => {
+                        event["error"] = e;
+                        if queue.remove(&write.queue_id) {
+                            (ctx.events)(CONFLICT_EVENT, event);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Wraps a write so that failing for want of a connection queues it.
+    pub(super) fn on_failure(self: &Arc<Self>, ctx: &CommandContext, command: &str, payload: JsonValue, task: CommandFuture) -> CommandFuture {
+        let (queue, ctx, command) = (self.clone(), ctx.clone(), command.to_string());
+        Box::pin(async move {
+            let error = match task.await {
+                Err(error) if offline(&error) => error,
+                outcome => return outcome,
+            };
+            let write = QueuedWrite {
+                queue_id: connections::new_id(),
+                connection_id: ctx.topology.connection_id().to_string(),
+                database: ctx.db.name().to_string(),
+                command,
+                payload,
+                actor: ctx.actor.clone(),
+                queued_at: now_ms(),
+                error: error.clone(),
+            };
+            match queue.push(write) {
+                Some(queue_id) => {
+                    queue.flush(&ctx);
+                    Ok(json!({ "queued": true, "queueId": queue_id, "error": error }))
+                }
+                None => Err(error),
+            }
+        })
+    }
+}
+
+/// The writes queued for the connected database, oldest first.
+pub(super) async fn list_queued_writes(ctx: CommandContext, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let queue = enabled(&ctx)?;
+    Ok(json!(queue.pending(ctx.topology.connection_id(), ctx.db.name())))
+}
+
+pub(super) async fn discard_queued_write(ctx: CommandContext, args: DiscardQueuedWriteArgs) -> Result<JsonValue, MongoPluginError> {
+    let queue = enabled(&ctx)?;
+    let queued = queue.pending(ctx.topology.connection_id(), ctx.db.name());
+    if !queued.iter().any(|write| write["queueId"] == args.queue_id.as_str()) || !queue.remove(&args.queue_id) {
+        let message = format!("No write is queued under '{}'", args.queue_id);
+        return Err(MongoPluginError::new(errors::ErrorKind::NotFound, message));
+    }
+    Ok(json!({ "discarded": true }))
+}
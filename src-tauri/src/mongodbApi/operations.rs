@@ -0,0 +1,266 @@
+//! A registry of long-running operations: bulk updates, collection clones,
+//! file exports, and commands run with an `operationId`.
+//!
+//! Each operation is registered under an id, the `operationId` the caller
+//! passed or a generated one, which its result also carries. While it runs
+//! it reports `mongo://operation-progress` events of
+//! `{ operationId, kind, status, progress }`, and `getOperationStatus`
+//! returns the same view together with when it started and how it ended.
+//! `pauseOperation`, `resumeOperation` and `cancelOperation` act at the next
+//! point the operation checks, between batches or documents; a command is
+//! cancelled at once, see [`timeouts`](super::timeouts). The latest
+//! finished operations stay queryable after they end.
+//!
+//! An operation belongs to the window, and tenant, that started it: only
+//! they see it in `listOperations` or can query or steer it by id.
+
+use mongodb::bson::oid::ObjectId;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::sync::watch;
+
+use super::errors::{self, MongoPluginError};
+use super::events::EventSink;
+use super::{MongoState, NoArgs, Owner};
+
+pub(super) const PROGRESS_EVENT: &str = "mongo://operation-progress";
+/// Finished operations kept for `getOperationStatus`.
+const KEPT_FINISHED: usize = 50;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Cancel,
+}
+
+struct Entry {
+    kind: &'static str,
+    status: &'static str,
+    progress: JsonValue,
+    started_at: u64,
+    finished_at: Option<u64>,
+    result: Option<JsonValue>,
+    error: Option<String>,
+    control: watch::Sender<Control>,
+    owner: Owner,
+}
+
+#[derive(Default)]
+pub(super) struct Operations {
+    entries: Mutex<HashMap<String, Entry>>,
+    finished: Mutex<VecDeque<String>>,
+}
+
+/// A running operation's side of its registry entry.
+pub(super) struct Operation {
+    id: String,
+    kind: &'static str,
+    operations: Arc<Operations>,
+    events: EventSink,
+    control: watch::Receiver<Control>,
+    finished: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct OperationArgs {
+    operation_id: String,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+fn view(id: &str, entry: &Entry) -> JsonValue {
+    json!({
+        "operationId": id,
+        "kind": entry.kind,
+        "status": entry.status,
+        "progress": entry.progress,
+        "startedAt": entry.started_at,
+        "finishedAt": entry.finished_at,
+        "result": entry.result,
+        "error": entry.error,
+    })
+}
+
+impl Operations {
+    /// Registers a new operation of `kind` for `owner`, under `id` when the
+    /// caller chose one.
+    pub(super) fn start(
+        self: &Arc<Self>,
+        events: &EventSink,
+        owner: Owner,
+        kind: &'static str,
+        id: Option<String>,
+    ) -> Result<Operation, MongoPluginError> {
+        let id = id.unwrap_or_else(|| ObjectId::new().to_hex());
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(&id).is_some_and(|entry| entry.finished_at.is_none()) {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("An operation '{}' is already running", id)));
+        }
+        let (control, receiver) = watch::channel(Control::Run);
+        let entry = Entry {
+            kind,
+            status: "running",
+            progress: json!({}),
+            started_at: now_ms(),
+            finished_at: None,
+            result: None,
+            error: None,
+            control,
+            owner,
+        };
+        entries.insert(id.clone(), entry);
+        Ok(Operation { id, kind, operations: self.clone(), events: events.clone(), control: receiver, finished: false })
+    }
+
+    /// Cancels every running operation.
+    pub(super) fn cancel_all(&self) {
+        for entry in self.entries.lock().unwrap().values_mut().filter(|entry| entry.finished_at.is_none()) {
+            entry.control.send_replace(Control::Cancel);
+            entry.status = "cancelling";
+        }
+    }
+
+    fn set_control(&self, owner: &Owner, id: &str, control: Control) -> Result<JsonValue, MongoPluginError> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.get_mut(id).filter(|entry| entry.owner == *owner) {
+            Some(entry) if entry.finished_at.is_none() => entry,
+            Some(_) => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Operation '{}' has already finished", id))),
+            None => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No operation '{}'", id))),
+        };
+        if *entry.control.borrow() == Control::Cancel {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Operation '{}' is being cancelled", id)));
+        }
+        entry.control.send_replace(control);
+        entry.status = match control {
+            Control::Run => "running",
+            Control::Pause => "paused",
+            Control::Cancel => "cancelling",
+        };
+        Ok(view(id, entry))
+    }
+
+    fn end(&self, id: &str, status: &'static str, result: Option<JsonValue>, error: Option<String>) -> Option<JsonValue> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(id)?;
+        entry.status = status;
+        entry.finished_at = Some(now_ms());
+        entry.result = result;
+        entry.error = error;
+        let ended = view(id, entry);
+        let mut finished = self.finished.lock().unwrap();
+        finished.push_back(id.to_string());
+        while finished.len() > KEPT_FINISHED {
+            if let Some(oldest) = finished.pop_front() {
+                if entries.get(&oldest).is_some_and(|entry| entry.finished_at.is_some()) {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        Some(ended)
+    }
+}
+
+impl Operation {
+    pub(super) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Records the operation's progress and reports it to the frontend.
+    pub(super) fn progress(&self, progress: JsonValue) {
+        let status = match self.operations.entries.lock().unwrap().get_mut(&self.id) {
+            Some(entry) => {
+                entry.progress = progress.clone();
+                entry.status
+            }
+            None => "running",
+        };
+        let event = json!({ "operationId": self.id, "kind": self.kind, "status": status, "progress": progress });
+        (self.events)(PROGRESS_EVENT, event);
+    }
+
+    /// Waits out a pause, returning whether the operation should go on.
+    pub(super) async fn proceed(&mut self) -> bool {
+        match self.control.wait_for(|control| *control != Control::Pause).await {
+            Ok(control) => *control == Control::Run,
+            Err(_) => false,
+        }
+    }
+
+    /// Resolves once the operation is asked to stop, and never otherwise.
+    pub(super) async fn cancel_requested(&mut self) {
+        if self.control.wait_for(|control| *control == Control::Cancel).await.is_err() {
+            future::pending::<()>().await;
+        }
+    }
+
+    /// Whether the operation was asked to stop.
+    pub(super) fn cancelled(&self) -> bool {
+        *self.control.borrow() == Control::Cancel
+    }
+
+    /// Records how the operation ended and reports it as its last progress.
+    pub(super) fn finish<E: fmt::Display>(mut self, outcome: &Result<JsonValue, E>) {
+        self.finished = true;
+        let error = outcome.as_ref().err().map(ToString::to_string);
+        let ended = match outcome {
+            _ if self.cancelled() => self.operations.end(&self.id, "cancelled", outcome.as_ref().ok().cloned(), error),
+            Ok(result) => self.operations.end(&self.id, "completed", Some(result.clone()), None),
+            Err(_) => self.operations.end(&self.id, "failed", None, error),
+        };
+        if let Some(ended) = ended {
+            let event = json!({ "operationId": self.id, "kind": self.kind, "status": ended["status"], "progress": ended["progress"] });
+            (self.events)(PROGRESS_EVENT, event);
+        }
+    }
+}
+
+impl Drop for Operation {
+    /// An operation dropped before finishing, such as one cut off at exit,
+    /// is still recorded as having ended.
+    fn drop(&mut self) {
+        if !self.finished {
+            self.operations.end(&self.id, "failed", None, Some("Stopped before finishing".to_string()));
+        }
+    }
+}
+
+pub(super) async fn get_operation_status<R: Runtime>(app: AppHandle<R>, owner: Owner, args: OperationArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let entries = state.operations.entries.lock().unwrap();
+    match entries.get(&args.operation_id) {
+        Some(entry) if entry.owner == owner => Ok(view(&args.operation_id, entry)),
+        _ => Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No operation '{}'", args.operation_id))),
+    }
+}
+
+/// The caller's running operations and latest finished ones, most recent
+/// first.
+pub(super) async fn list_operations<R: Runtime>(app: AppHandle<R>, owner: Owner, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let entries = state.operations.entries.lock().unwrap();
+    let mut operations: Vec<(&String, &Entry)> = entries.iter().filter(|(_, entry)| entry.owner == owner).collect();
+    operations.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.started_at));
+    Ok(JsonValue::Array(operations.into_iter().map(|(id, entry)| view(id, entry)).collect()))
+}
+
+pub(super) async fn pause_operation<R: Runtime>(app: AppHandle<R>, owner: Owner, args: OperationArgs) -> Result<JsonValue, MongoPluginError> {
+    app.state::<MongoState>().operations.set_control(&owner, &args.operation_id, Control::Pause)
+}
+
+pub(super) async fn resume_operation<R: Runtime>(app: AppHandle<R>, owner: Owner, args: OperationArgs) -> Result<JsonValue, MongoPluginError> {
+    app.state::<MongoState>().operations.set_control(&owner, &args.operation_id, Control::Run)
+}
+
+pub(super) async fn cancel_operation<R: Runtime>(app: AppHandle<R>, owner: Owner, args: OperationArgs) -> Result<JsonValue, MongoPluginError> {
+    app.state::<MongoState>().operations.set_control(&owner, &args.operation_id, Control::Cancel)
+}
@@ -0,0 +1,249 @@
+//! Aggregations the app defines and the frontend runs by name.
+//!
+//! The app registers a [`PipelineTemplate`] with [`register_pipeline`],
+//! usually from its setup hook. `runPipeline` fills the template's
+//! `"{{param}}"` placeholders from the `params` it's given, each checked
+//! against the [`ParamType`] it was declared with, and runs the aggregation
+//! like `aggregate`. Strings, including those inside arrays, may not start
+//! with `$` and objects may not have `$` keys unless the type is `Any`, so a
+//! value can't smuggle in an operator or field path. The frontend only picks a name and values, never the
+//! stages, so `runPipeline` can be allowed to windows that may not run
+//! `aggregate` itself; the template's collection, and every collection its
+//! stages read or write, is still checked against the window's namespaces
+//! each time it runs.
+//!
+//! ```ignore
+//! pipelines::register_pipeline(
+//!     &app.handle(),
+//!     "monthly_sales",
+//!     PipelineTemplate::new("orders", json!([
+//!         { "$match": { "placedAt": { "$gte": "{{from}}", "$lt": "{{to}}" } } },
+//!         { "$group": { "_id": "$region", "total": { "$sum": "$amount" } } },
+//!     ]))
+//!     .param("from", ParamType::Date)
+//!     .param("to", ParamType::Date),
+//! );
+//! ```
+
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::batch::resolve_placeholders;
+use super::{execute, policy, tenancy, MongoState, NoArgs};
+
+/// The type a template parameter must have.
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+pub enum ParamType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    /// An ISO 8601 string or milliseconds since the epoch, passed on as a BSON date.
+    Date,
+    /// A 24-character hex string, passed on as a BSON ObjectId.
+    ObjectId,
+    Array,
+    /// Any JSON value, passed on as is.
+    Any,
+}
+
+#[derive(Clone)]
+pub(super) struct Param {
+    kind: ParamType,
+    default: Option<JsonValue>,
+}
+
+/// Declared parameters, by name.
+pub(super) type Params = BTreeMap<String, Param>;
+
+pub(super) fn required(kind: ParamType) -> Param {
+    Param { kind, default: None }
+}
+
+pub(super) fn optional(kind: ParamType, default: JsonValue) -> Param {
+    Param { kind, default: Some(default) }
+}
+
+/// An aggregation on one collection with typed placeholders.
+#[derive(Clone)]
+pub struct PipelineTemplate {
+    collection: String,
+    pipeline: JsonValue,
+    params: Params,
+}
+
+#[allow(dead_code)]
+impl PipelineTemplate {
+    /// A template running `pipeline`, a JSON array of stages, on `collection`.
+    pub fn new(collection: impl Into<String>, pipeline: JsonValue) -> Self {
+        Self { collection: collection.into(), pipeline, params: BTreeMap::new() }
+    }
+
+    /// Declares a parameter the caller must give.
+    pub fn param(mut self, name: &str, kind: ParamType) -> Self {
+        self.params.insert(name.to_string(), required(kind));
+        self
+    }
+
+    /// Declares a parameter that takes `default` when not given.
+    pub fn optional(mut self, name: &str, kind: ParamType, default: JsonValue) -> Self {
+        self.params.insert(name.to_string(), optional(kind, default));
+        self
+    }
+}
+
+/// The registered templates.
+#[derive(Default)]
+pub(super) struct Pipelines(Mutex<HashMap<String, PipelineTemplate>>);
+
+#[derive(Deserialize)]
+pub(super) struct RunPipelineArgs {
+    name: String,
+    #[serde(default)]
+    params: serde_json::Map<String, JsonValue>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+/// Registers `template` under `name`, replacing any registered before.
+#[allow(dead_code)]
+pub fn register_pipeline<R: Runtime>(app: &AppHandle<R>, name: &str, template: PipelineTemplate) {
+    app.state::<Pipelines>().0.lock().unwrap().insert(name.to_string(), template);
+}
+
+/// Whether a value carries an operator, field path or Extended JSON key.
+fn has_operator(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::String(s) => s.starts_with('$'),
+        JsonValue::Array(items) => items.iter().any(has_operator),
+        JsonValue::Object(map) => map.iter().any(|(key, value)| key.starts_with('$') || has_operator(value)),
+        _ => false,
+    }
+}
+
+/// Checks a parameter's value against its type, converting dates and ids to
+/// their Extended JSON form.
+fn typed(name: &str, kind: ParamType, value: JsonValue) -> Result<JsonValue, MongoPluginError> {
+    let invalid = || MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Parameter '{}' must be of type {:?}", name, kind));
+    if !matches!(kind, ParamType::Any) && has_operator(&value) {
+        let message = format!("Parameter '{}' may not start with '$' or contain '$' keys", name);
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+    }
+    match kind {
+        ParamType::String if value.is_string() => Ok(value),
+        ParamType::Number if value.is_number() => Ok(value),
+        ParamType::Integer if value.is_i64() || value.is_u64() => Ok(value),
+        ParamType::Boolean if value.is_boolean() => Ok(value),
+        ParamType::Array if value.is_array() => Ok(value),
+        ParamType::Any => Ok(value),
+        ParamType::Date => {
+            let millis = match &value {
+                JsonValue::Number(n) => n.as_i64().ok_or_else(invalid)?,
+                JsonValue::String(s) => DateTime::parse_rfc3339_str(s).map_err(|_| invalid())?.timestamp_millis(),
+                _ => return Err(invalid()),
+            };
+            Ok(json!({ "$date": { "$numberLong": millis.to_string() } }))
+        }
+        ParamType::ObjectId => match value.as_str().map(ObjectId::parse_str) {
+            Some(Ok(id)) => Ok(json!({ "$oid": id.to_hex() })),
+            _ => Err(invalid()),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+/// Fills `template`'s placeholders from `given`, which must hold a value of
+/// the declared type for every parameter without a default, and nothing else.
+pub(super) fn fill(what: &str, template: JsonValue, params: &Params, given: &serde_json::Map<String, JsonValue>) -> Result<JsonValue, MongoPluginError> {
+    if let Some(unknown) = given.keys().find(|name| !params.contains_key(*name)) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("{} has no parameter '{}'", what, unknown)));
+    }
+    let mut values = HashMap::new();
+    for (name, param) in params {
+        let value = match given.get(name).cloned().or_else(|| param.default.clone()) {
+            Some(value) => value,
+            None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Missing parameter '{}'", name))),
+        };
+        values.insert(name.as_str(), typed(name, param.kind, value)?);
+    }
+    let param = |name: &str| match values.get(name) {
+        Some(value) => Ok(value.clone()),
+        None => Err(format!("{} uses undeclared parameter '{}'", what, name)),
+    };
+    resolve_placeholders(template, &param).map_err(|e| MongoPluginError::new(errors::ErrorKind::InvalidArgument, e))
+}
+
+/// The frontend's view of declared parameters.
+pub(super) fn describe_params(params: &Params) -> JsonValue {
+    let params: Vec<JsonValue> = params
+        .iter()
+        .map(|(name, param)| json!({ "name": name, "type": format!("{:?}", param.kind), "default": param.default }))
+        .collect();
+    JsonValue::Array(params)
+}
+
+/// Names and parameters of the registered templates.
+pub(super) async fn list_pipelines<R: Runtime>(app: AppHandle<R>, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let pipelines = app.state::<Pipelines>();
+    let pipelines = pipelines.0.lock().unwrap();
+    let mut listed: Vec<JsonValue> = pipelines
+        .iter()
+        .map(|(name, template)| json!({ "name": name, "collection": template.collection, "params": describe_params(&template.params) }))
+        .collect();
+    listed.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Ok(JsonValue::Array(listed))
+}
+
+/// Runs a registered template with its parameters filled from `params`.
+pub(super) async fn run_pipeline<R: Runtime>(
+    app: AppHandle<R>,
+    permissions: Option<policy::Permissions>,
+    actor: JsonValue,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: RunPipelineArgs,
+) -> Result<JsonValue, JsonValue> {
+    let template = match app.state::<Pipelines>().0.lock().unwrap().get(&args.name) {
+        Some(template) => template.clone(),
+        None => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No pipeline named '{}'", args.name)).into()),
+    };
+    let pipeline = fill(&format!("Pipeline '{}'", args.name), template.pipeline, &template.params, &args.params)?;
+    let mut payload = json!({ "collection": template.collection, "pipeline": pipeline.to_string() });
+    if let Some(max_time_ms) = args.max_time_ms {
+        payload["maxTimeMS"] = json!(max_time_ms);
+    }
+    run_filled(app, permissions, actor, connection, tenant, "aggregate", payload).await
+}
+
+/// Runs a command built from a filled template, for a caller whose window
+/// was allowed the template's command rather than `command` itself.
+pub(super) async fn run_filled<R: Runtime>(
+    app: AppHandle<R>,
+    permissions: Option<policy::Permissions>,
+    actor: JsonValue,
+    connection: Option<String>,
+    tenant: Option<String>,
+    command: &str,
+    payload: JsonValue,
+) -> Result<JsonValue, JsonValue> {
+    let state = app.state::<MongoState>();
+    let mut ctx = state.context(connection.as_deref(), tenant.as_deref())?;
+    ctx.actor = actor;
+    let collection = payload.get("collection").and_then(JsonValue::as_str).unwrap_or_default();
+    tenancy::check_pipeline(tenant.as_deref(), command, &payload)?;
+    if let Some(permissions) = &permissions {
+        policy::authorize_namespace(permissions, ctx.db.name(), collection)?;
+        policy::authorize_pipeline(permissions, ctx.db.name(), command, &payload)?;
+    }
+    let task = match execute(&ctx, command, payload) {
+        Some(task) => task,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown command: {}", command)).into()),
+    };
+    task.await
+}
@@ -0,0 +1,356 @@
+//! Command gating decided by the embedding app.
+//!
+//! The app registers a callback with [`MongoPlugin::policy`](super::MongoPlugin::policy)
+//! that maps the invoking window's label and the current app role to
+//! [`Permissions`]. It runs on every invoke, before any argument is looked
+//! at, and batch commands are checked step by step, so a restricted window
+//! can't reach a command by wrapping it in a batch. Pipelines are checked
+//! stage by stage as well: every collection they read with `$lookup`,
+//! `$graphLookup` or `$unionWith` or write with `$out` or `$merge` must be
+//! allowed, and a pipeline that writes needs the window to be allowed
+//! `insertMany` too, so `aggregate` doesn't make a read-only window a
+//! writer. `runCommand` can reach any namespace, so it is only for windows
+//! whose namespaces aren't narrowed at all.
+//!
+//! Limits fixed when the plugin is built, with
+//! [`MongoPlugin::allow_collections`](super::MongoPlugin::allow_collections)
+//! and its siblings, hold for every window on top of what the callback
+//! grants.
+
+use serde_json::Value as JsonValue;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{archive, jobs, locks, namespaces, references, trash};
+
+/// Signature of the policy callback: `(window_label, app_role)`.
+pub type PolicyFn = dyn Fn(&str, Option<&str>) -> Permissions + Send + Sync;
+
+/// What a window may run. Both sets are unrestricted until narrowed.
+#[derive(Clone, Default)]
+pub struct Permissions {
+    commands: Option<HashSet<String>>,
+    denied: HashSet<String>,
+    namespaces: Option<Vec<String>>,
+    /// Limits that hold as well, set when the plugin was built.
+    outer: Option<Arc<Permissions>>,
+}
+
+impl Permissions {
+    /// Every command on every namespace.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Nothing at all.
+    #[allow(dead_code)]
+    pub fn none() -> Self {
+        Self::default().commands(Vec::<String>::new())
+    }
+
+    /// Only these commands, by invoke name (`find`, `insertOne`, ...).
+    pub fn commands<I, S>(mut self, commands: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.commands = Some(commands.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// None of these commands, whatever [`commands`](Self::commands) allows.
+    pub fn deny_commands<I, S>(mut self, commands: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.denied.extend(commands.into_iter().map(Into::into));
+        self
+    }
+
+    /// Only these namespaces: `db.collection`, `db.*` for a whole database,
+    /// `*.collection` for a collection in any database, or `*`.
+    pub fn namespaces<I, S>(mut self, namespaces: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.namespaces = Some(namespaces.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// These permissions, narrowed further by `outer`.
+    pub(super) fn within(mut self, outer: Arc<Permissions>) -> Self {
+        self.outer = Some(outer);
+        self
+    }
+
+    fn allows_command(&self, command: &str) -> bool {
+        !self.denied.contains(command)
+            && self.commands.as_ref().is_none_or(|commands| commands.contains(command))
+            && self.outer.as_ref().is_none_or(|outer| outer.allows_command(command))
+    }
+
+    fn allows_namespace(&self, database: &str, collection: &str) -> bool {
+        let allowed = self.namespaces.as_ref().is_none_or(|namespaces| {
+            namespaces.iter().any(|pattern| {
+                pattern == "*"
+                    || match pattern.split_once('.') {
+                        Some((db, "*")) => db == database,
+                        Some(("*", coll)) => coll == collection,
+                        Some(namespace) => namespace == (database, collection),
+                        None => false,
+                    }
+            })
+        });
+        allowed && self.outer.as_ref().is_none_or(|outer| outer.allows_namespace(database, collection))
+    }
+
+    /// Whether any namespace in `database` is allowed.
+    pub(super) fn allows_database(&self, database: &str) -> bool {
+        let allowed = self.namespaces.as_ref().is_none_or(|namespaces| {
+            namespaces.iter().any(|pattern| pattern == "*" || matches!(pattern.split_once('.'), Some((db, _)) if db == "*" || db == database))
+        });
+        allowed && self.outer.as_ref().is_none_or(|outer| outer.allows_database(database))
+    }
+
+    /// Whether every namespace is allowed.
+    fn allows_all_namespaces(&self) -> bool {
+        let allowed = self.namespaces.as_ref().is_none_or(|namespaces| namespaces.iter().any(|pattern| pattern == "*"));
+        allowed && self.outer.as_ref().is_none_or(|outer| outer.allows_all_namespaces())
+    }
+}
+
+/// The limits set when the plugin is built, see [`Acl::permissions`].
+#[derive(Default)]
+pub(super) struct Acl {
+    pub(super) databases: Option<Vec<String>>,
+    pub(super) collections: Option<Vec<String>>,
+    pub(super) commands: Option<Vec<String>>,
+    pub(super) denied: Vec<String>,
+}
+
+impl Acl {
+    /// The permissions every window is held to, or `None` if the app set no
+    /// limits. Allowed databases and collections combine, so allowing `app`
+    /// and `notes` allows only `app.notes`.
+    pub(super) fn permissions(&self) -> Option<Permissions> {
+        if self.databases.is_none() && self.collections.is_none() && self.commands.is_none() && self.denied.is_empty() {
+            return None;
+        }
+        let mut permissions = Permissions::all().deny_commands(self.denied.iter().cloned());
+        if let Some(commands) = &self.commands {
+            permissions = permissions.commands(commands.iter().cloned());
+        }
+        let namespaces: Option<Vec<String>> = match (&self.databases, &self.collections) {
+            (Some(databases), Some(collections)) => {
+                Some(databases.iter().flat_map(|db| collections.iter().map(move |coll| format!("{}.{}", db, coll))).collect())
+            }
+            (Some(databases), None) => Some(databases.iter().map(|db| format!("{}.*", db)).collect()),
+            (None, Some(collections)) => Some(collections.iter().map(|coll| format!("*.{}", coll)).collect()),
+            (None, None) => None,
+        };
+        if let Some(namespaces) = namespaces {
+            permissions = permissions.namespaces(namespaces);
+        }
+        Some(permissions)
+    }
+}
+
+/// The role the app is currently running as, passed to the policy callback.
+#[derive(Default)]
+pub(super) struct AppRole(Mutex<Option<String>>);
+
+/// Sets the role handed to the policy callback on later invokes, e.g. after
+/// the user signs in. `None` clears it.
+#[allow(dead_code)]
+pub fn set_app_role<R: Runtime>(app: &AppHandle<R>, role: Option<&str>) {
+    *app.state::<AppRole>().0.lock().unwrap() = role.map(str::to_string);
+}
+
+/// The role last set with [`set_app_role`].
+pub fn app_role<R: Runtime>(app: &AppHandle<R>) -> Option<String> {
+    app.state::<AppRole>().0.lock().unwrap().clone()
+}
+
+/// The collection a command reads or writes, if any.
+fn collection_of(command: &str, payload: &JsonValue) -> Option<String> {
+    match command {
+        "acquireLock" | "renewLock" | "releaseLock" => Some(locks::LOCKS_COLLECTION.to_string()),
+        "enqueueJob" | "claimNextJob" | "completeJob" | "failJob" => Some(jobs::JOBS_COLLECTION.to_string()),
+        "gridfsCleanup" | "gridfsUpload" | "gridfsDownload" | "gridfsFind" | "gridfsDelete" => {
+            let bucket = payload.get("bucket").and_then(JsonValue::as_str).unwrap_or("fs");
+            Some(format!("{}.files", bucket))
+        }
+        "explain" => payload.pointer("/args/collection").and_then(JsonValue::as_str).map(str::to_string),
+        // These act on the whole database.
+        "dropDatabase" => Some("*".to_string()),
+        // A schema spec may describe, create or change any collection.
+        "exportSchema" | "applySchema" => Some("*".to_string()),
+        // Migration steps may touch any collection.
+        "migrateUp" | "migrateDown" | "migrationStatus" => Some("*".to_string()),
+        // These act on the key vault, wherever it is.
+        "createDataKey" | "listDataKeys" | "deleteDataKey" | "addKeyAltName" | "removeKeyAltName" => Some("*".to_string()),
+        // Without a collection it watches the whole database.
+        "watch" => Some(payload.get("collection").and_then(JsonValue::as_str).unwrap_or("*").to_string()),
+        // Without a collection these cover the whole trash.
+        "listTrash" | "emptyTrash" => match payload.get("collection").and_then(JsonValue::as_str) {
+            Some(collection) => Some(collection.to_string()),
+            None => Some(trash::TRASH_COLLECTION.to_string()),
+        },
+        _ => payload.get("collection").and_then(JsonValue::as_str).map(str::to_string),
+    }
+}
+
+fn denied(message: impl Into<String>) -> MongoPluginError {
+    MongoPluginError::new(errors::ErrorKind::PermissionDenied, message)
+}
+
+/// Rejects access to `database.collection` unless `permissions` allow it.
+pub(super) fn authorize_namespace(permissions: &Permissions, database: &str, collection: &str) -> Result<(), MongoPluginError> {
+    if !permissions.allows_namespace(database, collection) {
+        return Err(denied(format!("Permission denied: '{}.{}' is not allowed for this window", database, collection)));
+    }
+    Ok(())
+}
+
+/// Rejects the pipeline `command` carries unless `permissions` allow every
+/// namespace it reads or writes, in `database` unless a stage names another.
+pub(super) fn authorize_pipeline(permissions: &Permissions, database: &str, command: &str, payload: &JsonValue) -> Result<(), MongoPluginError> {
+    for target in namespaces::pipeline_targets(command, payload) {
+        if target.writes && !permissions.allows_command("insertMany") {
+            return Err(denied(format!("Permission denied: '{}' writes with $out or $merge, which is not allowed for this window", command)));
+        }
+        authorize_namespace(permissions, target.database.as_deref().unwrap_or(database), &target.collection)?;
+    }
+    Ok(())
+}
+
+/// Rejects the command unless `permissions` allow it and, for batches,
+/// every step in it. `database` is the connected database, if any.
+pub(super) fn authorize(permissions: &Permissions, database: Option<&str>, command: &str, payload: &JsonValue) -> Result<(), MongoPluginError> {
+    if !permissions.allows_command(command) {
+        return Err(denied(format!("Permission denied: '{}' is not allowed for this window", command)));
+    }
+    if command == "runCommand" && !permissions.allows_all_namespaces() {
+        return Err(denied("Permission denied: 'runCommand' can reach any namespace, which is not allowed for this window"));
+    }
+    if let ("connectDBServer", Some(target)) = (command, payload.get("database").and_then(JsonValue::as_str)) {
+        if !permissions.allows_database(target) {
+            return Err(denied(format!("Permission denied: database '{}' is not allowed for this window", target)));
+        }
+    }
+    if let (Some(database), Some(collection)) = (database, collection_of(command, payload)) {
+        authorize_namespace(permissions, database, &collection)?;
+    }
+    if let (Some(database), Some(archive)) = (database, archive::archive_collection_of(command, payload)) {
+        authorize_namespace(permissions, database, &archive)?;
+    }
+    if let (Some(database), "renameCollection" | "cloneCollection", Some(to)) = (database, command, payload.get("to").and_then(JsonValue::as_str)) {
+        authorize_namespace(permissions, database, to)?;
+    }
+    // A view shows the documents of the collection it is defined on.
+    if let (Some(database), "createView" | "modifyView", Some(view_on)) = (database, command, payload.get("viewOn").and_then(JsonValue::as_str)) {
+        authorize_namespace(permissions, database, view_on)?;
+    }
+    if let Some(database) = database {
+        for collection in references::collections_of(command, payload) {
+            authorize_namespace(permissions, database, &collection)?;
+        }
+        authorize_pipeline(permissions, database, command, payload)?;
+    }
+    if let ("executeBatch" | "executeTransactionalBatch", Some(operations)) =
+        (command, payload.get("operations").and_then(JsonValue::as_array))
+    {
+        for operation in operations {
+            let step = operation.get("command").and_then(JsonValue::as_str).unwrap_or_default();
+            let args = operation.get("args").unwrap_or(&JsonValue::Null);
+            authorize(permissions, database, step, args)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn find(collection: &str) -> JsonValue {
+        json!({ "collection": collection, "query": "{}" })
+    }
+
+    fn aggregate(collection: &str, pipeline: JsonValue) -> JsonValue {
+        json!({ "collection": collection, "pipeline": pipeline.to_string() })
+    }
+
+    #[test]
+    fn commands_and_namespaces_are_checked() {
+        let permissions = Permissions::all().commands(["find"]).namespaces(["app.notes"]);
+        assert!(authorize(&permissions, Some("app"), "find", &find("notes")).is_ok());
+        assert!(authorize(&permissions, Some("app"), "find", &find("users")).is_err());
+        assert!(authorize(&permissions, Some("other"), "find", &find("notes")).is_err());
+        assert!(authorize(&permissions, Some("app"), "deleteMany", &find("notes")).is_err());
+        let denied = Permissions::all().deny_commands(["dropDatabase"]);
+        assert!(authorize(&denied, Some("app"), "dropDatabase", &json!({})).is_err());
+        assert!(authorize(&denied, Some("app"), "find", &find("notes")).is_ok());
+    }
+
+    #[test]
+    fn batch_steps_are_checked() {
+        let permissions = Permissions::all().commands(["executeBatch", "find"]).namespaces(["app.notes"]);
+        let batch = |command: &str, collection: &str| json!({ "operations": [{ "command": command, "args": find(collection) }] });
+        assert!(authorize(&permissions, Some("app"), "executeBatch", &batch("find", "notes")).is_ok());
+        assert!(authorize(&permissions, Some("app"), "executeBatch", &batch("deleteMany", "notes")).is_err());
+        assert!(authorize(&permissions, Some("app"), "executeBatch", &batch("find", "users")).is_err());
+        let nested = json!({ "operations": [{ "command": "executeBatch", "args": batch("find", "users") }] });
+        assert!(authorize(&permissions, Some("app"), "executeBatch", &nested).is_err());
+    }
+
+    #[test]
+    fn pipeline_targets_are_checked() {
+        let permissions = Permissions::all().commands(["aggregate"]).namespaces(["app.notes", "app.tags"]);
+        let lookup = |from: JsonValue| aggregate("notes", json!([{ "$lookup": { "from": from, "as": "joined" } }]));
+        assert!(authorize(&permissions, Some("app"), "aggregate", &lookup(json!("tags"))).is_ok());
+        assert!(authorize(&permissions, Some("app"), "aggregate", &lookup(json!("users"))).is_err());
+        assert!(authorize(&permissions, Some("app"), "aggregate", &lookup(json!({ "db": "other", "coll": "tags" }))).is_err());
+        let union = aggregate("notes", json!([{ "$facet": { "all": [{ "$unionWith": { "coll": "users" } }] } }]));
+        assert!(authorize(&permissions, Some("app"), "aggregate", &union).is_err());
+        // Writing with $out needs insertMany as well as the namespace.
+        let out = aggregate("notes", json!([{ "$out": "tags" }]));
+        assert!(authorize(&permissions, Some("app"), "aggregate", &out).is_err());
+        let writer = Permissions::all().commands(["aggregate", "insertMany"]).namespaces(["app.notes", "app.tags"]);
+        assert!(authorize(&writer, Some("app"), "aggregate", &out).is_ok());
+    }
+
+    #[test]
+    fn acl_limits_hold_within_granted_permissions() {
+        let acl = Acl { databases: Some(vec!["app".to_string()]), collections: Some(vec!["notes".to_string()]), ..Acl::default() };
+        let outer = Arc::new(acl.permissions().unwrap());
+        let permissions = Permissions::all().within(outer.clone());
+        assert!(authorize(&permissions, Some("app"), "find", &find("notes")).is_ok());
+        assert!(authorize(&permissions, Some("app"), "find", &find("users")).is_err());
+        let lookup = aggregate("notes", json!([{ "$lookup": { "from": "users", "as": "users" } }]));
+        assert!(authorize(&permissions, Some("app"), "aggregate", &lookup).is_err());
+        assert!(authorize(&permissions, None, "connectDBServer", &json!({ "database": "other" })).is_err());
+        // The granted permissions narrow further, never wider.
+        let narrowed = Permissions::all().commands(["find"]).within(outer);
+        assert!(authorize(&narrowed, Some("app"), "find", &find("notes")).is_ok());
+        assert!(authorize(&narrowed, Some("app"), "insertOne", &find("notes")).is_err());
+        let commands = Acl { denied: vec!["find".to_string()], ..Acl::default() };
+        let denied = Permissions::all().commands(["find"]).within(Arc::new(commands.permissions().unwrap()));
+        assert!(authorize(&denied, Some("app"), "find", &find("notes")).is_err());
+    }
+
+    #[test]
+    fn run_command_needs_every_namespace() {
+        let command = json!({ "command": "{}" });
+        assert!(authorize(&Permissions::all(), Some("app"), "runCommand", &command).is_ok());
+        assert!(authorize(&Permissions::all().namespaces(["app.*"]), Some("app"), "runCommand", &command).is_err());
+        assert!(authorize(&Permissions::all().namespaces(["*"]), Some("app"), "runCommand", &command).is_ok());
+        let outer = Arc::new(Permissions::all().namespaces(["*.notes"]));
+        assert!(authorize(&Permissions::all().within(outer), Some("app"), "runCommand", &command).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Finds on filter shapes the app registers, with only leaf values coming
+//! from the frontend.
+//!
+//! A [`QueryTemplate`] fixes a filter's fields and operators and marks the
+//! values the frontend supplies with `"{{param}}"` placeholders. `runQuery`
+//! fills them like [`runPipeline`](super::pipelines) does, type-checking each
+//! and rejecting strings that start with `$` and objects with `$` keys, so
+//! user input can never add an operator such as `$gt` or `$where` to the
+//! filter. Like pipelines, `runQuery` can be allowed to windows that may not
+//! run `find` itself.
+//!
+//! ```ignore
+//! queries::register_query(
+//!     &app.handle(),
+//!     "user_by_email",
+//!     QueryTemplate::find_one("users", json!({ "email": "{{email}}", "active": true }))
+//!         .param("email", ParamType::String),
+//! );
+//! ```
+
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::pipelines::{self, ParamType, Params};
+use super::{policy, NoArgs};
+
+/// A filter with typed placeholders for its values, run as a `find` or a
+/// `findOne` on one collection.
+#[derive(Clone)]
+pub struct QueryTemplate {
+    command: &'static str,
+    collection: String,
+    filter: JsonValue,
+    params: Params,
+}
+
+#[allow(dead_code)]
+impl QueryTemplate {
+    /// A template returning every document matching `filter`.
+    pub fn find(collection: impl Into<String>, filter: JsonValue) -> Self {
+        Self { command: "find", collection: collection.into(), filter, params: Params::new() }
+    }
+
+    /// A template returning the first document matching `filter`, or null.
+    pub fn find_one(collection: impl Into<String>, filter: JsonValue) -> Self {
+        Self { command: "findOne", ..Self::find(collection, filter) }
+    }
+
+    /// Declares a parameter the caller must give.
+    pub fn param(mut self, name: &str, kind: ParamType) -> Self {
+        self.params.insert(name.to_string(), pipelines::required(kind));
+        self
+    }
+
+    /// Declares a parameter that takes `default` when not given.
+    pub fn optional(mut self, name: &str, kind: ParamType, default: JsonValue) -> Self {
+        self.params.insert(name.to_string(), pipelines::optional(kind, default));
+        self
+    }
+}
+
+/// The registered templates.
+#[derive(Default)]
+pub(super) struct Queries(Mutex<HashMap<String, QueryTemplate>>);
+
+#[derive(Deserialize)]
+pub(super) struct RunQueryArgs {
+    name: String,
+    #[serde(default)]
+    params: serde_json::Map<String, JsonValue>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+/// Registers `template` under `name`, replacing any registered before.
+#[allow(dead_code)]
+pub fn register_query<R: Runtime>(app: &AppHandle<R>, name: &str, template: QueryTemplate) {
+    app.state::<Queries>().0.lock().unwrap().insert(name.to_string(), template);
+}
+
+/// Names and parameters of the registered templates.
+pub(super) async fn list_queries<R: Runtime>(app: AppHandle<R>, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let queries = app.state::<Queries>();
+    let queries = queries.0.lock().unwrap();
+    let mut listed: Vec<JsonValue> = queries
+        .iter()
+        .map(|(name, template)| {
+            json!({
+                "name": name,
+                "command": template.command,
+                "collection": template.collection,
+                "params": pipelines::describe_params(&template.params),
+            })
+        })
+        .collect();
+    listed.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Ok(JsonValue::Array(listed))
+}
+
+/// Runs a registered template with its values filled from `params`.
+pub(super) async fn run_query<R: Runtime>(
+    app: AppHandle<R>,
+    permissions: Option<policy::Permissions>,
+    actor: JsonValue,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: RunQueryArgs,
+) -> Result<JsonValue, JsonValue> {
+    let template = match app.state::<Queries>().0.lock().unwrap().get(&args.name) {
+        Some(template) => template.clone(),
+        None => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No query named '{}'", args.name)).into()),
+    };
+    let filter = pipelines::fill(&format!("Query '{}'", args.name), template.filter, &template.params, &args.params)?;
+    let mut payload = json!({ "collection": template.collection, "query": filter.to_string() });
+    if let Some(max_time_ms) = args.max_time_ms {
+        payload["maxTimeMS"] = json!(max_time_ms);
+    }
+    pipelines::run_filled(app, permissions, actor, connection, tenant, template.command, payload).await
+}
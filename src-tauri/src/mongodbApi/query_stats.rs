@@ -0,0 +1,176 @@
+//! Execution statistics per normalized query shape.
+//!
+//! Every command that filters documents is counted under its namespace,
+//! its name and the shape of its filter or pipeline: the same JSON with
+//! every value replaced by `"?"`, so `{ age: { $gt: 30 } }` and
+//! `{ age: { $gt: 40 } }` are one shape. `getQueryStats` returns each
+//! shape of the connected database with its `count`, `errors` and the
+//! average and highest latency, most often run first or with `sortBy:
+//! "totalMs"` the most time-consuming first. `resetQueryStats` starts over.
+//! Statistics are kept in memory for the life of the app.
+//!
+//! With `slowQueryMs` in the plugin config, every command that takes at
+//! least that long also emits `mongo://slow-query` with its `database`,
+//! `collection`, `command`, `shape` when it has one, `durationMs` and
+//! whether it succeeded.
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{MongoState, NoArgs};
+
+pub(super) const SLOW_QUERY_EVENT: &str = "mongo://slow-query";
+
+/// Distinct shapes remembered; later new shapes are not counted.
+const MAX_SHAPES: usize = 1000;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(super) struct ShapeKey {
+    database: String,
+    collection: String,
+    command: String,
+    shape: String,
+}
+
+#[derive(Default)]
+struct ShapeStats {
+    count: u64,
+    errors: u64,
+    total_ms: u64,
+    max_ms: u64,
+}
+
+#[derive(Default)]
+pub(super) struct QueryStats(Mutex<HashMap<ShapeKey, ShapeStats>>);
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum SortBy {
+    #[default]
+    Count,
+    TotalMs,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct GetQueryStatsArgs {
+    collection: Option<String>,
+    #[serde(default)]
+    sort_by: SortBy,
+    limit: Option<usize>,
+}
+
+/// `value` with every scalar replaced by `"?"` and arrays of scalars
+/// collapsed to one, keeping operators and field names.
+fn normalize(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Object(map) if is_literal(map) => json!("?"),
+        JsonValue::Object(map) => JsonValue::Object(map.iter().map(|(key, value)| (key.clone(), normalize(value))).collect::<Map<_, _>>()),
+        JsonValue::Array(items) if items.iter().all(|item| !item.is_object() && !item.is_array()) => json!(["?"]),
+        JsonValue::Array(items) => JsonValue::Array(items.iter().map(normalize).collect()),
+        _ => json!("?"),
+    }
+}
+
+/// Whether an object is an Extended JSON value such as `{ "$oid": ... }`
+/// rather than an operator applied to operands.
+fn is_literal(map: &Map<String, JsonValue>) -> bool {
+    const LITERALS: &[&str] =
+        &["$oid", "$date", "$numberLong", "$numberDecimal", "$numberInt", "$numberDouble", "$binary", "$uuid", "$timestamp", "$regularExpression"];
+    map.keys().next().is_some_and(|key| LITERALS.contains(&key.as_str()))
+}
+
+/// The argument holding what a command matches on, if it filters documents.
+fn filter_key(command: &str) -> Option<&'static str> {
+    match command {
+        "find" | "findOne" | "findCursor" => Some("query"),
+        "aggregate" | "aggregateCursor" => Some("pipeline"),
+        "exists" | "count" | "countDocuments" | "distinct" | "findFieldValue" | "exportXlsx" | "timeSeriesAggregate" | "findDuplicates"
+        | "updateOne" | "updateMany" | "replaceOne" | "findOneAndUpdate" | "deleteOne" | "deleteMany" | "findOneAndDelete" => Some("filter"),
+        _ => None,
+    }
+}
+
+/// The `mongo://slow-query` payload for a command that took `duration_ms`.
+pub(super) fn slow_query_event(database: &str, command: &str, payload: &JsonValue, key: Option<&ShapeKey>, duration_ms: u64, ok: bool) -> JsonValue {
+    json!({
+        "database": database,
+        "collection": payload.get("collection"),
+        "command": command,
+        "shape": key.and_then(|key| serde_json::from_str::<JsonValue>(&key.shape).ok()),
+        "durationMs": duration_ms,
+        "ok": ok,
+    })
+}
+
+impl QueryStats {
+    /// The shape a command will be counted under, if it is counted.
+    pub(super) fn key(&self, database: &str, command: &str, payload: &JsonValue) -> Option<ShapeKey> {
+        let collection = payload.get("collection").and_then(JsonValue::as_str)?;
+        let text = payload.get(filter_key(command)?).and_then(JsonValue::as_str).unwrap_or("{}");
+        let parsed: JsonValue = serde_json::from_str(text).ok()?;
+        Some(ShapeKey {
+            database: database.to_string(),
+            collection: collection.to_string(),
+            command: command.to_string(),
+            shape: normalize(&parsed).to_string(),
+        })
+    }
+
+    pub(super) fn record(&self, key: ShapeKey, duration_ms: u64, succeeded: bool) {
+        let mut shapes = self.0.lock().unwrap();
+        if shapes.len() >= MAX_SHAPES && !shapes.contains_key(&key) {
+            return;
+        }
+        let stats = shapes.entry(key).or_default();
+        stats.count += 1;
+        stats.errors += u64::from(!succeeded);
+        stats.total_ms += duration_ms;
+        stats.max_ms = stats.max_ms.max(duration_ms);
+    }
+}
+
+pub(super) async fn get_query_stats<R: Runtime>(
+    app: AppHandle<R>,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: GetQueryStatsArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let database = match tenant.or_else(|| state.database_name(connection.as_deref())) {
+        Some(database) => database,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::Connection, "Not connected: call connectDBServer first")),
+    };
+    let shapes = state.query_stats.0.lock().unwrap();
+    let mut matching: Vec<(&ShapeKey, &ShapeStats)> = shapes
+        .iter()
+        .filter(|(key, _)| key.database == database && args.collection.as_ref().is_none_or(|collection| *collection == key.collection))
+        .collect();
+    matching.sort_by_key(|(_, stats)| std::cmp::Reverse(if args.sort_by == SortBy::TotalMs { stats.total_ms } else { stats.count }));
+    let stats: Vec<JsonValue> = matching
+        .into_iter()
+        .take(args.limit.unwrap_or(usize::MAX))
+        .map(|(key, stats)| {
+            json!({
+                "collection": key.collection,
+                "command": key.command,
+                "shape": serde_json::from_str::<JsonValue>(&key.shape).unwrap_or_default(),
+                "count": stats.count,
+                "errors": stats.errors,
+                "totalMs": stats.total_ms,
+                "avgMs": stats.total_ms as f64 / stats.count as f64,
+                "maxMs": stats.max_ms,
+            })
+        })
+        .collect();
+    Ok(JsonValue::Array(stats))
+}
+
+pub(super) async fn reset_query_stats<R: Runtime>(app: AppHandle<R>, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    app.state::<MongoState>().query_stats.0.lock().unwrap().clear();
+    Ok(json!("success"))
+}
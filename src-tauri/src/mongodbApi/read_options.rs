@@ -0,0 +1,135 @@
+//! The `options` object `find`, `findOne`, `aggregate`, `countDocuments`
+//! and `distinct` take.
+//!
+//! Unlike the query or pipeline beside it, `options` is a JSON object rather
+//! than JSON text, and its `projection`, `sort` and `collation` are objects
+//! too. `hint` is an index name or an index's key pattern, and `comment`,
+//! on finds and aggregations, a string to trace them by. When both the
+//! command and its options carry `maxTimeMS` the shorter one applies, so the
+//! configured `maxTimeMS` cap holds either way.
+
+use mongodb::bson::Document;
+use mongodb::options::{AggregateOptions, Collation, CountOptions, DistinctOptions, FindOneOptions, FindOptions, Hint};
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct FindCommandOptions {
+    pub(super) projection: Option<Document>,
+    pub(super) sort: Option<Document>,
+    limit: Option<i64>,
+    skip: Option<u64>,
+    batch_size: Option<u32>,
+    collation: Option<Collation>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    pub(super) hint: Option<Hint>,
+    /// Tags the operation in the server's logs, profiler and `currentOp`.
+    comment: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct AggregateCommandOptions {
+    allow_disk_use: Option<bool>,
+    batch_size: Option<u32>,
+    collation: Option<Collation>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    pub(super) hint: Option<Hint>,
+    /// Variables the pipeline reads as `$$name`.
+    #[serde(rename = "let")]
+    let_vars: Option<Document>,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CountCommandOptions {
+    /// Most documents counted.
+    limit: Option<u64>,
+    skip: Option<u64>,
+    collation: Option<Collation>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    pub(super) hint: Option<Hint>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DistinctCommandOptions {
+    collation: Option<Collation>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+/// The shorter of the command's `maxTimeMS` and the one in its options.
+fn max_time(command: Option<u64>, options: Option<u64>) -> Option<Duration> {
+    match (command, options) {
+        (Some(command), Some(options)) => Some(Duration::from_millis(command.min(options))),
+        (command, options) => command.or(options).map(Duration::from_millis),
+    }
+}
+
+impl FindCommandOptions {
+    pub(super) fn find(self, max_time_ms: Option<u64>) -> FindOptions {
+        FindOptions::builder()
+            .projection(self.projection)
+            .sort(self.sort)
+            .limit(self.limit)
+            .skip(self.skip)
+            .batch_size(self.batch_size)
+            .collation(self.collation)
+            .hint(self.hint)
+            .comment(self.comment)
+            .max_time(max_time(max_time_ms, self.max_time_ms))
+            .build()
+    }
+
+    /// The options as `findOne` uses them; `limit` and `batchSize` don't
+    /// apply to a single document.
+    pub(super) fn find_one(self, max_time_ms: Option<u64>) -> FindOneOptions {
+        FindOneOptions::builder()
+            .projection(self.projection)
+            .sort(self.sort)
+            .skip(self.skip)
+            .collation(self.collation)
+            .hint(self.hint)
+            .comment(self.comment)
+            .max_time(max_time(max_time_ms, self.max_time_ms))
+            .build()
+    }
+}
+
+impl AggregateCommandOptions {
+    pub(super) fn aggregate(self, max_time_ms: Option<u64>) -> AggregateOptions {
+        AggregateOptions::builder()
+            .allow_disk_use(self.allow_disk_use)
+            .batch_size(self.batch_size)
+            .collation(self.collation)
+            .hint(self.hint)
+            .let_vars(self.let_vars)
+            .comment(self.comment)
+            .max_time(max_time(max_time_ms, self.max_time_ms))
+            .build()
+    }
+}
+
+impl CountCommandOptions {
+    pub(super) fn count(self, max_time_ms: Option<u64>) -> CountOptions {
+        CountOptions::builder()
+            .limit(self.limit)
+            .skip(self.skip)
+            .collation(self.collation)
+            .hint(self.hint)
+            .max_time(max_time(max_time_ms, self.max_time_ms))
+            .build()
+    }
+}
+
+impl DistinctCommandOptions {
+    pub(super) fn distinct(self, max_time_ms: Option<u64>) -> DistinctOptions {
+        DistinctOptions::builder().collation(self.collation).max_time(max_time(max_time_ms, self.max_time_ms)).build()
+    }
+}
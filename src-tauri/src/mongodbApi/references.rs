@@ -0,0 +1,219 @@
+//! Relations between collections kept by hand, such as `orders.customerId`
+//! holding the `_id` of a document in `customers`.
+//!
+//! Relations are listed under `relations` in the plugin config, each naming
+//! the referring `collection`, its `field`, which may hold one id or an
+//! array of them, and the collection it `references`. `checkReferences`
+//! reports, per relation, the referring documents with ids that match no
+//! document, up to `limit` (100 by default) of them, as
+//! `{ collection, field, references, orphanCount, orphans: [{ _id, missing }] }`.
+//! It checks the configured relations, or the `relations` it is given.
+//! Soft-deleted documents neither refer nor are referred to.
+//!
+//! A relation with `onDelete: "cascade"` makes `deleteById`, `deleteOne`,
+//! `deleteMany` and `findOneAndDelete` on the referenced collection go on to
+//! delete the referring documents, through `deleteMany` and so in the way
+//! their collection deletes, soft-deleted or moved to the trash where
+//! configured, and cascading in turn. Those commands' results then list
+//! what was deleted along with them under `cascaded`. Deletes on a session
+//! or in `executeTransactionalBatch` don't cascade.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{AggregateOptions, FindOptions};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use super::changes::Target;
+use super::errors::{self, MongoPluginError};
+use super::{convert, execute, CommandContext, CommandFuture};
+
+const DEFAULT_LIMIT: i64 = 100;
+
+/// A field in `collection` holding the id of a document in `references`, or
+/// an array of such ids.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Relation {
+    pub collection: String,
+    pub field: String,
+    pub references: String,
+    #[serde(default)]
+    pub on_delete: OnDelete,
+}
+
+/// What deleting a referenced document does to the documents referring to it.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OnDelete {
+    /// Leaves them referring to a missing document.
+    #[default]
+    Ignore,
+    /// Deletes them too.
+    Cascade,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct CheckReferencesArgs {
+    relations: Option<Vec<Relation>>,
+    limit: Option<i64>,
+}
+
+/// The collections a `checkReferences` call names itself, for the policy.
+pub(super) fn collections_of(command: &str, payload: &JsonValue) -> Vec<String> {
+    let relations = match (command, payload.get("relations").and_then(JsonValue::as_array)) {
+        ("checkReferences", Some(relations)) => relations,
+        _ => return Vec::new(),
+    };
+    relations
+        .iter()
+        .flat_map(|relation| ["collection", "references"].map(|key| relation.get(key).and_then(JsonValue::as_str)))
+        .flatten()
+        .map(str::to_string)
+        .collect()
+}
+
+/// The deletion field of `collection`, if it soft-deletes.
+fn soft_field(ctx: &CommandContext, collection: &str) -> Option<String> {
+    let soft_delete = ctx.soft_delete.as_ref()?;
+    soft_delete.field_for(&json!({ "collection": collection })).map(str::to_string)
+}
+
+async fn orphans(ctx: &CommandContext, relation: &Relation, limit: i64) -> Result<JsonValue, MongoPluginError> {
+    let path = format!("${}", relation.field);
+    let mut referring = doc! { &relation.field: { "$exists": true, "$ne": Bson::Null } };
+    if let Some(field) = soft_field(ctx, &relation.collection) {
+        referring.insert(field, Bson::Null);
+    }
+    let lookup = match soft_field(ctx, &relation.references) {
+        Some(field) => doc! {
+            "from": &relation.references,
+            "let": { "id": "$id" },
+            "pipeline": [{ "$match": { "$expr": { "$eq": ["$_id", "$$id"] }, field: Bson::Null } }, { "$project": { "_id": 1 } }],
+            "as": "found",
+        },
+        None => doc! { "from": &relation.references, "localField": "id", "foreignField": "_id", "as": "found" },
+    };
+    let pipeline = vec![
+        doc! { "$match": referring },
+        doc! { "$project": { "id": { "$cond": [{ "$isArray": &path }, &path, [&path]] } } },
+        doc! { "$unwind": "$id" },
+        doc! { "$lookup": lookup },
+        doc! { "$match": { "found": { "$size": 0 } } },
+        doc! { "$group": { "_id": "$_id", "missing": { "$addToSet": "$id" } } },
+        doc! { "$facet": {
+            "orphans": [{ "$sort": { "_id": 1 } }, { "$limit": limit }],
+            "count": [{ "$count": "n" }],
+        } },
+    ];
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let cursor = match ctx.db.collection::<Document>(&relation.collection).aggregate(pipeline, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to check references", e)),
+    };
+    let facets = match convert::collect_json(cursor).await {
+        Ok(JsonValue::Array(mut facets)) if !facets.is_empty() => facets.remove(0),
+        Ok(_) => JsonValue::Null,
+        Err(e) => return Err(errors::failed("Failed to check references", e)),
+    };
+    Ok(json!({
+        "collection": relation.collection,
+        "field": relation.field,
+        "references": relation.references,
+        "orphanCount": facets["count"][0]["n"].as_i64().unwrap_or(0),
+        "orphans": facets.get("orphans").cloned().unwrap_or_else(|| json!([])),
+    }))
+}
+
+pub(super) async fn check_references(ctx: CommandContext, args: CheckReferencesArgs) -> Result<JsonValue, MongoPluginError> {
+    let relations = args.relations.unwrap_or_else(|| ctx.config.relations.clone());
+    if relations.is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "No relations are configured or given"));
+    }
+    let limit = args.limit.unwrap_or(DEFAULT_LIMIT).max(1);
+    let mut checked = Vec::with_capacity(relations.len());
+    for relation in &relations {
+        checked.push(orphans(&ctx, relation, limit).await?);
+    }
+    Ok(JsonValue::Array(checked))
+}
+
+/// Whether deleting from `collection` with `command` cascades.
+pub(super) fn cascades(ctx: &CommandContext, command: &str, collection: &str) -> bool {
+    matches!(command, "deleteById" | "deleteOne" | "deleteMany" | "findOneAndDelete")
+        && ctx.config.relations.iter().any(|relation| relation.references == collection && relation.on_delete == OnDelete::Cascade)
+}
+
+/// The ids among those `target` selects of documents still in `collection`,
+/// leaving out soft-deleted ones.
+async fn live_ids(db: &Database, collection: &str, target: &Target, soft_field: Option<&str>) -> mongodb::error::Result<Vec<Bson>> {
+    let (mut filter, limit) = match target {
+        Target::Ids(ids) => (doc! { "_id": { "$in": ids } }, None),
+        Target::Filter { filter, many } => (filter.clone(), (!many).then_some(1)),
+    };
+    if let Some(field) = soft_field {
+        filter = doc! { "$and": [filter, { field: Bson::Null }] };
+    }
+    let options = FindOptions::builder().projection(doc! { "_id": 1 }).limit(limit).build();
+    let docs: Vec<Document> = db.collection::<Document>(collection).find(filter, options).await?.try_collect().await?;
+    Ok(docs.into_iter().filter_map(|doc| doc.get("_id").cloned()).collect())
+}
+
+/// Runs the delete `task`, then deletes the documents referring to those it
+/// removed.
+pub(super) fn cascade_task(
+    ctx: &CommandContext,
+    command: &str,
+    collection: String,
+    target: Target,
+    soft_field: Option<String>,
+    task: CommandFuture,
+) -> CommandFuture {
+    let (ctx, annotate) = (ctx.clone(), command != "findOneAndDelete");
+    Box::pin(async move {
+        let before = match live_ids(&ctx.db, &collection, &target, soft_field.as_deref()).await {
+            Ok(before) => before,
+            Err(e) => return Err(errors::failed("Failed to read documents before deleting", e).into()),
+        };
+        let mut result = task.await?;
+        if before.is_empty() {
+            return Ok(result);
+        }
+        let remaining = match live_ids(&ctx.db, &collection, &Target::Ids(before.clone()), soft_field.as_deref()).await {
+            Ok(remaining) => remaining,
+            Err(e) => return Err(errors::failed("The delete succeeded but reading what it removed failed", e).into()),
+        };
+        let deleted: Vec<Bson> = before.into_iter().filter(|id| !remaining.contains(id)).collect();
+        if deleted.is_empty() {
+            return Ok(result);
+        }
+        let mut cascaded = Vec::new();
+        let relations = ctx.config.relations.iter().filter(|relation| relation.references == collection && relation.on_delete == OnDelete::Cascade);
+        for relation in relations {
+            let filter = convert::to_extjson_text(Bson::Document(doc! { &relation.field: { "$in": &deleted } }));
+            let payload = json!({ "collection": relation.collection, "filter": filter });
+            let step = execute(&ctx, "deleteMany", payload).expect("deleteMany is a command");
+            let outcome = match step.await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    let mut e = errors::from_json(e);
+                    let message = e["message"].as_str().unwrap_or_default();
+                    e["message"] = json!(format!("The delete succeeded but cascading it to '{}' failed: {}", relation.collection, message));
+                    return Err(e);
+                }
+            };
+            cascaded.push(json!({
+                "collection": relation.collection,
+                "field": relation.field,
+                "deletedCount": outcome.get("deletedCount").cloned().unwrap_or(JsonValue::Null),
+                "cascaded": outcome.get("cascaded").cloned().unwrap_or_else(|| json!([])),
+            }));
+        }
+        if let (true, Some(result)) = (annotate, result.as_object_mut()) {
+            result.insert("cascaded".to_string(), JsonValue::Array(cascaded));
+        }
+        Ok(result)
+    })
+}
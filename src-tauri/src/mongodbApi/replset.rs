@@ -0,0 +1,69 @@
+//! A compact replica set health summary for status displays.
+//!
+//! `replicaSetHealth` runs `replSetGetStatus` and keeps, per member, only
+//! its state, whether it's healthy, how far its applied oplog trails the
+//! primary's and when it was last heard from. Lag is measured against the
+//! most recent optime of any member while there is no primary.
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use serde_json::{json, Value as JsonValue};
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{numeric_field, MongoState, NoArgs};
+
+const NO_REPLICATION_ENABLED: i32 = 76;
+const PRIMARY: i64 = 1;
+
+fn millis(member: &Document, key: &str) -> Option<i64> {
+    member.get_datetime(key).ok().map(|at| at.timestamp_millis())
+}
+
+fn summarize(status: &Document) -> JsonValue {
+    let members: Vec<&Document> = match status.get_array("members") {
+        Ok(members) => members.iter().filter_map(Bson::as_document).collect(),
+        Err(_) => Vec::new(),
+    };
+    let primary = members.iter().find(|member| numeric_field(member, "state") == PRIMARY);
+    let reference = match primary {
+        Some(primary) => millis(primary, "optimeDate"),
+        None => members.iter().filter_map(|member| millis(member, "optimeDate")).max(),
+    };
+    let summaries: Vec<JsonValue> = members
+        .iter()
+        .map(|member| {
+            let is_self = member.get_bool("self").unwrap_or(false);
+            json!({
+                "name": member.get_str("name").unwrap_or_default(),
+                "state": member.get_str("stateStr").unwrap_or_default(),
+                "healthy": numeric_field(member, "health") == 1,
+                "self": is_self,
+                "lagMs": reference.zip(millis(member, "optimeDate")).map(|(reference, optime)| (reference - optime).max(0)),
+                // The member answering has no heartbeat of its own.
+                "lastHeartbeatMs": if is_self { millis(status, "date") } else { millis(member, "lastHeartbeatRecv") },
+                "pingMs": member.get("pingMs").map(|_| numeric_field(member, "pingMs")),
+            })
+        })
+        .collect();
+    let max_lag = summaries.iter().filter_map(|member| member["lagMs"].as_i64()).max();
+    json!({
+        "setName": status.get_str("set").unwrap_or_default(),
+        "primary": primary.and_then(|primary| primary.get_str("name").ok()),
+        "healthy": primary.is_some() && summaries.iter().all(|member| member["healthy"] == true),
+        "maxLagMs": max_lag,
+        "at": millis(status, "date"),
+        "members": summaries,
+    })
+}
+
+pub(super) async fn replica_set_health<R: Runtime>(app: AppHandle<R>, connection: Option<String>, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let client = app.state::<MongoState>().client(connection.as_deref())?;
+    match client.database("admin").run_command(doc! { "replSetGetStatus": 1 }, None).await {
+        Ok(status) => Ok(summarize(&status)),
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(e) if e.code == NO_REPLICATION_ENABLED) => {
+            Err(MongoPluginError::new(errors::ErrorKind::Server, "The server is not part of a replica set"))
+        }
+        Err(e) => Err(errors::failed("Failed to read replica set status", e)),
+    }
+}
@@ -0,0 +1,133 @@
+//! Enforcing `maxResponseBytes` on what goes back over IPC.
+//!
+//! An oversized array result is parked in plugin state and handed out in
+//! pages that each fit under the cap; any other oversized result is an
+//! error telling the caller how to narrow the query. A parked result
+//! belongs to the window, and tenant, whose command produced it.
+
+use mongodb::bson::oid::ObjectId;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{OversizedResponses, Owner};
+
+#[derive(Default)]
+pub(super) struct ResultBuffers {
+    buffers: Mutex<HashMap<String, BufferedResult>>,
+}
+
+struct BufferedResult {
+    items: Vec<JsonValue>,
+    max_bytes: usize,
+    owner: Owner,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ReadResultPageArgs {
+    result_id: String,
+    offset: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ReleaseResultArgs {
+    result_id: String,
+}
+
+/// Counts serialized bytes without keeping them.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub(super) fn json_size(value: &JsonValue) -> usize {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, value).unwrap();
+    counter.0
+}
+
+/// Passes results under `max_bytes` through untouched and applies the
+/// configured fallback to anything larger.
+pub(super) fn enforce<R: Runtime>(
+    app: &AppHandle<R>,
+    owner: Owner,
+    max_bytes: usize,
+    mode: OversizedResponses,
+    result: JsonValue,
+) -> Result<JsonValue, MongoPluginError> {
+    let size = json_size(&result);
+    if size <= max_bytes {
+        return Ok(result);
+    }
+    let items = match (mode, result) {
+        (OversizedResponses::Buffer, JsonValue::Array(items)) => items,
+        _ => {
+            let message = format!(
+                "Response is {} bytes, over the maxResponseBytes limit of {}; add a projection or limit to the query",
+                size, max_bytes
+            );
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+    };
+    let result_id = ObjectId::new().to_hex();
+    let count = items.len();
+    app.state::<ResultBuffers>()
+        .buffers
+        .lock()
+        .unwrap()
+        .insert(result_id.clone(), BufferedResult { items, max_bytes, owner });
+    Ok(json!({ "oversized": true, "resultId": result_id, "count": count, "bytes": size }))
+}
+
+/// Returns the next run of items from `offset` that fits under the cap
+/// (always at least one). The buffer is dropped once its last page is read.
+pub(super) async fn read_result_page<R: Runtime>(app: AppHandle<R>, owner: Owner, args: ReadResultPageArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<ResultBuffers>();
+    let mut buffers = state.buffers.lock().unwrap();
+    let buffer = match buffers.get(&args.result_id) {
+        Some(buffer) if buffer.owner == owner => buffer,
+        _ => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("Unknown or released result '{}'", args.result_id))),
+    };
+    let offset = args.offset.unwrap_or(0).min(buffer.items.len());
+
+    let mut end = offset;
+    let mut bytes = 2; // the enclosing brackets
+    while end < buffer.items.len() {
+        let item_bytes = json_size(&buffer.items[end]) + 1;
+        if end > offset && bytes + item_bytes > buffer.max_bytes {
+            break;
+        }
+        bytes += item_bytes;
+        end += 1;
+    }
+    let items = buffer.items[offset..end].to_vec();
+    let next_offset = if end < buffer.items.len() { Some(end) } else { None };
+    if next_offset.is_none() {
+        buffers.remove(&args.result_id);
+    }
+    Ok(json!({ "items": items, "nextOffset": next_offset }))
+}
+
+pub(super) async fn release_result<R: Runtime>(app: AppHandle<R>, owner: Owner, args: ReleaseResultArgs) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<ResultBuffers>();
+    let mut buffers = state.buffers.lock().unwrap();
+    let released = match buffers.get(&args.result_id) {
+        Some(buffer) if buffer.owner == owner => buffers.remove(&args.result_id).is_some(),
+        _ => false,
+    };
+    Ok(json!({ "released": released }))
+}
@@ -0,0 +1,55 @@
+//! App-supplied rewrites of the documents reads return.
+//!
+//! [`MongoPlugin::transform_results`](super::MongoPlugin::transform_results)
+//! registers a function for a collection that gets each of its documents,
+//! in relaxed Extended JSON, before it goes back to the window, to add
+//! derived fields, strip internal ones, convert units and so on. It runs on
+//! the documents of `find`, `findOne`, `findById`, `findByIds`,
+//! `findOneAndUpdate`, `findOneAndDelete`, `findCursor` batches, streamed
+//! and exported finds, text searches, and the `fullDocument` of change
+//! events, after signatures are checked and encrypted fields opened.
+//! Aggregations are left alone, as their stages may have reshaped the
+//! documents. Functions registered for the same collection run in the
+//! order they were registered.
+
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Signature of a result transformer: one document, changed in place.
+pub type ResultFn = dyn Fn(&mut Map<String, JsonValue>) + Send + Sync;
+
+pub(super) struct ResultTransforms {
+    collections: HashMap<String, Vec<Arc<ResultFn>>>,
+}
+
+impl ResultTransforms {
+    pub(super) fn new(collections: HashMap<String, Vec<Arc<ResultFn>>>) -> Self {
+        Self { collections }
+    }
+
+    fn apply(transforms: &[Arc<ResultFn>], document: &mut JsonValue) {
+        if let JsonValue::Object(document) = document {
+            transforms.iter().for_each(|transform| transform(document));
+        }
+    }
+
+    /// Runs the collection's transformers on the documents in a command's
+    /// result.
+    pub(super) fn apply_result(&self, command: &str, collection: &str, result: &mut JsonValue) {
+        let transforms = match self.collections.get(collection) {
+            Some(transforms) => transforms,
+            None => return,
+        };
+        match (command, result) {
+            ("find" | "findByIds", JsonValue::Array(documents)) => documents.iter_mut().for_each(|document| Self::apply(transforms, document)),
+            ("findOne" | "findById" | "findOneAndUpdate" | "findOneAndDelete", document) => Self::apply(transforms, document),
+            ("watch", change) => {
+                if let Some(document) = change.get_mut("fullDocument") {
+                    Self::apply(transforms, document);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Retrying idempotent commands that fail for transient reasons.
+//!
+//! The app sets a [`RetryPolicy`] with [`MongoPlugin::retry`](super::MongoPlugin::retry).
+//! Reads (`find`, `findOne`, `findById`, `exists`, `aggregate` without
+//! `$out`/`$merge`, `explain`, ...) are retried by default; other commands
+//! only when given their own policy with [`RetryPolicy::command`]. Failures
+//! are classified from the driver's error message, since that is what
+//! command errors carry. Delays double from `base_delay` up to `max_delay`.
+
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
+
+/// Kinds of failure a policy can retry.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RetryOn {
+    /// The connection dropped or couldn't be made.
+    Network,
+    /// The server stepped down or is in the middle of an election.
+    Election,
+    /// No suitable server was found in time.
+    ServerSelection,
+}
+
+/// When and how often to retry a command.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    on: HashSet<RetryOn>,
+    commands: HashMap<String, Option<RetryPolicy>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            on: [RetryOn::Network, RetryOn::Election, RetryOn::ServerSelection].into_iter().collect(),
+            commands: HashMap::new(),
+        }
+    }
+}
+
+/// Commands that can run twice without a different outcome.
+fn idempotent(command: &str, payload: &JsonValue) -> bool {
+    match command {
+        "find" | "findOne" | "findCursor" | "findById" | "findByIds" | "exists" | "count" | "countDocuments" | "estimatedDocumentCount" | "distinct"
+        | "findFieldValue" | "explain" | "analyzeCollection" | "suggestIndexes"
+        | "getDocumentHistory" | "getVersions" | "listTrash" | "diffWithCurrent" | "timeSeriesAggregate" | "findDuplicates"
+        | "checkReferences" | "gridfsFind" | "listIndexes" | "serverStatus" | "exportSchema" | "getViewDefinition"
+        | "listDataKeys" | "migrationStatus" => true,
+        "aggregate" | "aggregateCursor" => {
+            let pipeline = payload.get("pipeline").and_then(JsonValue::as_str).unwrap_or_default();
+            !pipeline.contains("\"$out\"") && !pipeline.contains("\"$merge\"")
+        }
+        _ => false,
+    }
+}
+
+fn message(error: &JsonValue) -> Option<&str> {
+    match error {
+        JsonValue::String(message) => Some(message.as_str()),
+        other => other.get("message").and_then(JsonValue::as_str),
+    }
+}
+
+/// Whether a write failed for want of a primary to take it, so the server
+/// never ran it and sending it again can't apply it twice.
+pub(super) fn unsent_for_election(error: &JsonValue) -> bool {
+    const NO_PRIMARY: &[&str] = &["NotWritablePrimary", "NotPrimaryNoSecondaryOk", "NotPrimaryOrSecondary", "Server selection timeout"];
+    message(error).is_some_and(|message| NO_PRIMARY.iter().any(|name| message.contains(name)))
+}
+
+/// The kind of failure behind a command error, if it's one worth retrying.
+fn classify(error: &JsonValue) -> Option<RetryOn> {
+    let message = message(error)?;
+    const ELECTION: &[&str] = &[
+        "NotWritablePrimary",
+        "NotPrimaryNoSecondaryOk",
+        "NotPrimaryOrSecondary",
+        "InterruptedDueToReplStateChange",
+        "PrimarySteppedDown",
+        "ShutdownInProgress",
+    ];
+    if message.contains("Server selection timeout") {
+        Some(RetryOn::ServerSelection)
+    } else if ELECTION.iter().any(|name| message.contains(name)) {
+        Some(RetryOn::Election)
+    } else if message.contains("I/O error") || message.contains("ConnectionPoolCleared") || message.contains("Connection pool") {
+        Some(RetryOn::Network)
+    } else {
+        None
+    }
+}
+
+#[allow(dead_code)]
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attempts in total, the first included. 1 turns retrying off.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Delay before the first retry; each later one waits twice as long.
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    /// Longest delay between attempts.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Only retry these kinds of failure.
+    pub fn retry_on<I: IntoIterator<Item = RetryOn>>(mut self, on: I) -> Self {
+        self.on = on.into_iter().collect();
+        self
+    }
+
+    /// Uses `policy` for `command`, including commands that aren't retried
+    /// by default.
+    pub fn command(mut self, command: &str, policy: RetryPolicy) -> Self {
+        self.commands.insert(command.to_string(), Some(policy));
+        self
+    }
+
+    /// Never retries `command`.
+    pub fn never(mut self, command: &str) -> Self {
+        self.commands.insert(command.to_string(), None);
+        self
+    }
+}
+
+impl RetryPolicy {
+    /// The policy that applies to one command, if it's retried at all.
+    pub(super) fn for_command(&self, command: &str, payload: &JsonValue) -> Option<&RetryPolicy> {
+        match self.commands.get(command) {
+            Some(policy) => policy.as_ref(),
+            None if idempotent(command, payload) => Some(self),
+            None => None,
+        }
+        .filter(|policy| policy.max_attempts > 1)
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry);
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// Runs the attempts `attempt` makes until one succeeds, fails for a
+    /// reason this policy doesn't retry, or attempts run out.
+    pub(super) async fn run<F, Fut>(&self, mut attempt: F) -> Result<JsonValue, JsonValue>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<JsonValue, JsonValue>>,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(e) if retry + 1 < self.max_attempts && classify(&e).is_some_and(|kind| self.on.contains(&kind)) => {
+                    tokio::time::sleep(self.delay(retry)).await;
+                    retry += 1;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+}
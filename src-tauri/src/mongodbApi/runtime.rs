@@ -0,0 +1,143 @@
+//! Running database work on its own Tokio runtime.
+//!
+//! By default commands run on Tauri's async runtime, next to everything else
+//! the app does there. With [`MongoPlugin::worker_threads`](super::MongoPlugin::worker_threads)
+//! the plugin starts a multi-thread runtime of its own, and with
+//! [`MongoPlugin::runtime`](super::MongoPlugin::runtime) it uses one the app
+//! already runs. Connecting happens there too, so the driver's monitors and
+//! connection pools live on that runtime along with the commands, streams and
+//! conversions of large results.
+//!
+//! Work sent through [`DbRuntime::run`] is counted, so exiting can wait for
+//! it to finish, see [`shutdown`](super::shutdown).
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::Notify;
+
+use super::errors::{self, MongoPluginError};
+
+/// Where [`MongoPlugin`](super::MongoPlugin) runs database work.
+pub(super) enum RuntimeChoice {
+    /// A runtime the plugin starts with this many worker threads.
+    Threads(usize),
+    /// A runtime the app owns.
+    Handle(Handle),
+}
+
+/// The runtime database work is sent to, if not Tauri's.
+#[derive(Default)]
+pub(super) struct DbRuntime {
+    /// Kept alive for as long as the plugin when the plugin started it.
+    owned: Option<Runtime>,
+    handle: Option<Handle>,
+    work: Arc<Work>,
+}
+
+/// Database work that hasn't finished yet.
+#[derive(Default)]
+struct Work {
+    running: AtomicUsize,
+    closed: AtomicBool,
+    idle: Notify,
+}
+
+/// One piece of running work, counted until it's dropped.
+struct Running(Arc<Work>);
+
+impl Work {
+    fn begin(self: &Arc<Self>) -> Result<Running, MongoPluginError> {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let running = Running(self.clone());
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(MongoPluginError::new(errors::ErrorKind::Connection, "The app is shutting down"));
+        }
+        Ok(running)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl DbRuntime {
+    pub(super) fn start(choice: Option<RuntimeChoice>) -> std::io::Result<Self> {
+        match choice {
+            None => Ok(Self::default()),
+            Some(RuntimeChoice::Handle(handle)) => Ok(Self { owned: None, handle: Some(handle), work: Arc::default() }),
+            Some(RuntimeChoice::Threads(threads)) => {
+                let runtime = Builder::new_multi_thread()
+                    .worker_threads(threads.max(1))
+                    .thread_name("mongo-db")
+                    .enable_all()
+                    .build()?;
+                let handle = runtime.handle().clone();
+                Ok(Self { owned: Some(runtime), handle: Some(handle), work: Arc::default() })
+            }
+        }
+    }
+
+    /// Runs `task` on the database runtime, or where it's awaited when there
+    /// is none. Fails without running it once the runtime is closed.
+    pub(super) fn run<T, E, F>(&self, task: F) -> impl Future<Output = Result<T, E>> + Send + 'static
+    where
+        F: Future<Output = Result<T, E>> + Send + 'static,
+        T: Send + 'static,
+        E: From<MongoPluginError> + Send + 'static,
+    {
+        let handle = self.handle.clone();
+        let running = self.work.begin();
+        async move {
+            let _running = running.map_err(E::from)?;
+            match handle {
+                Some(handle) => match handle.spawn(task).await {
+                    Ok(outcome) => outcome,
+                    Err(e) => Err(E::from(errors::failed("Database task failed", e))),
+                },
+                None => task.await,
+            }
+        }
+    }
+
+    /// Whether any work is still running.
+    pub(super) fn is_busy(&self) -> bool {
+        self.work.running.load(Ordering::SeqCst) > 0
+    }
+
+    /// Stops taking new work, returning whether it already had.
+    pub(super) fn close(&self) -> bool {
+        self.work.closed.swap(true, Ordering::SeqCst)
+    }
+
+    /// Waits up to `grace` for running work to finish, returning whether it
+    /// all did.
+    pub(super) async fn drain(&self, grace: Duration) -> bool {
+        let idle = async {
+            loop {
+                let notified = self.work.idle.notified();
+                if !self.is_busy() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(grace, idle).await.is_ok()
+    }
+}
+
+impl Drop for DbRuntime {
+    /// A runtime can't be dropped from inside async code, which is where the
+    /// plugin's state may go away, so ours is shut down without waiting.
+    fn drop(&mut self) {
+        if let Some(runtime) = self.owned.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
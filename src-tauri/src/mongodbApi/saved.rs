@@ -0,0 +1,227 @@
+//! Named, parameterized queries saved for re-running.
+//!
+//! A saved query is a command and its arguments, like a batch operation,
+//! bound to a namespace: `database.collection`, or just a collection name
+//! for the connected database. `"{{param}}"` placeholders in the arguments are
+//! filled from the `params` given to `runSavedQuery`. Definitions live in a
+//! JSON file in the app data directory, or in a collection of the connected
+//! database when `savedQueries.collection` is set so that everyone using it
+//! sees the same set.
+
+use futures::TryStreamExt;
+use mongodb::bson::{self, doc, Document};
+use mongodb::options::ReplaceOptions;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::batch::resolve_placeholders;
+use super::encryption::app_data_path;
+use super::{execute, policy, tenancy, MongoState};
+
+const DEFAULT_FILE: &str = "mongo-saved-queries.json";
+
+/// The `savedQueries` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SavedQueriesConfig {
+    /// Keep definitions in this collection instead of a local file.
+    pub collection: Option<String>,
+    /// Local file, relative to the app data directory unless absolute.
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct SavedQuery {
+    name: String,
+    namespace: String,
+    spec: QuerySpec,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct QuerySpec {
+    command: String,
+    #[serde(default)]
+    args: JsonValue,
+}
+
+#[derive(Deserialize)]
+pub(super) struct SaveQueryArgs {
+    name: String,
+    namespace: String,
+    spec: QuerySpec,
+}
+
+#[derive(Deserialize)]
+pub(super) struct RunSavedQueryArgs {
+    name: String,
+    #[serde(default)]
+    params: serde_json::Map<String, JsonValue>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct DeleteSavedQueryArgs {
+    name: String,
+}
+
+fn file_path<R: Runtime>(app: &AppHandle<R>, config: &SavedQueriesConfig) -> Result<PathBuf, MongoPluginError> {
+    app_data_path(config.file.as_deref(), DEFAULT_FILE, app.path_resolver().app_data_dir())
+        .map_err(|e| MongoPluginError::new(errors::ErrorKind::Io, e))
+}
+
+fn read_file(path: &PathBuf) -> Result<BTreeMap<String, SavedQuery>, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Failed to read saved queries: {}", e)),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+fn write_file(path: &PathBuf, queries: &BTreeMap<String, SavedQuery>) -> Result<(), MongoPluginError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| errors::failed("Failed to save query", e))?;
+    }
+    fs::write(path, serde_json::to_vec_pretty(queries).unwrap()).map_err(|e| errors::failed("Failed to save query", e))
+}
+
+fn to_document(query: &SavedQuery) -> Document {
+    let mut doc = bson::to_document(query).unwrap();
+    doc.insert("_id", doc.get_str("name").unwrap().to_string());
+    doc
+}
+
+async fn load_all<R: Runtime>(app: &AppHandle<R>, connection: Option<&str>, tenant: Option<&str>) -> Result<Vec<SavedQuery>, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let config = &state.config.saved_queries;
+    let collection = match &config.collection {
+        Some(collection) => collection,
+        None => return Ok(read_file(&file_path(app, config)?)?.into_values().collect()),
+    };
+    let coll = state.database(connection, tenant)?.collection::<Document>(collection);
+    let cursor = match coll.find(None, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to read saved queries", e)),
+    };
+    let docs: Vec<Document> = match cursor.try_collect().await {
+        Ok(docs) => docs,
+        Err(e) => return Err(errors::failed("Failed to read saved queries", e)),
+    };
+    let mut queries: Vec<SavedQuery> = docs.into_iter().filter_map(|doc| bson::from_document(doc).ok()).collect();
+    queries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(queries)
+}
+
+/// Saves a query under `name`, replacing any saved before with that name.
+pub(super) async fn save_query<R: Runtime>(
+    app: AppHandle<R>,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: SaveQueryArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let query = SavedQuery { name: args.name, namespace: args.namespace, spec: args.spec };
+    let state = app.state::<MongoState>();
+    let config = &state.config.saved_queries;
+    match &config.collection {
+        Some(collection) => {
+            let coll = state.database(connection.as_deref(), tenant.as_deref())?.collection::<Document>(collection);
+            let options = ReplaceOptions::builder().upsert(true).build();
+            if let Err(e) = coll.replace_one(doc! { "_id": &query.name }, to_document(&query), options).await {
+                return Err(errors::failed("Failed to save query", e));
+            }
+        }
+        None => {
+            let path = file_path(&app, config)?;
+            let mut queries = read_file(&path)?;
+            queries.insert(query.name.clone(), query);
+            write_file(&path, &queries)?;
+        }
+    }
+    Ok(serde_json::to_value("success").unwrap())
+}
+
+pub(super) async fn list_saved_queries<R: Runtime>(
+    app: AppHandle<R>,
+    connection: Option<String>,
+    tenant: Option<String>,
+) -> Result<JsonValue, MongoPluginError> {
+    Ok(serde_json::to_value(load_all(&app, connection.as_deref(), tenant.as_deref()).await?).unwrap())
+}
+
+pub(super) async fn delete_saved_query<R: Runtime>(
+    app: AppHandle<R>,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: DeleteSavedQueryArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let config = &state.config.saved_queries;
+    let deleted = match &config.collection {
+        Some(collection) => {
+            let coll = state.database(connection.as_deref(), tenant.as_deref())?.collection::<Document>(collection);
+            match coll.delete_one(doc! { "_id": &args.name }, None).await {
+                Ok(result) => result.deleted_count > 0,
+                Err(e) => return Err(errors::failed("Failed to delete saved query", e)),
+            }
+        }
+        None => {
+            let path = file_path(&app, config)?;
+            let mut queries = read_file(&path)?;
+            let deleted = queries.remove(&args.name).is_some();
+            write_file(&path, &queries)?;
+            deleted
+        }
+    };
+    Ok(json!({ "deleted": deleted }))
+}
+
+/// Runs a saved query with its placeholders filled from `params`. The
+/// resolved command is checked against the caller's `permissions` just as
+/// if it had been invoked directly.
+pub(super) async fn run_saved_query<R: Runtime>(
+    app: AppHandle<R>,
+    permissions: Option<policy::Permissions>,
+    actor: JsonValue,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: RunSavedQueryArgs,
+) -> Result<JsonValue, JsonValue> {
+    let queries = load_all(&app, connection.as_deref(), tenant.as_deref()).await?;
+    let query = match queries.into_iter().find(|query| query.name == args.name) {
+        Some(query) => query,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No saved query named '{}'", args.name)).into()),
+    };
+    let param = |name: &str| match args.params.get(name) {
+        Some(value) => Ok(value.clone()),
+        None => Err(format!("Missing parameter '{}'", name)),
+    };
+    let mut payload = resolve_placeholders(query.spec.args, &param).map_err(|e| MongoPluginError::new(errors::ErrorKind::InvalidArgument, e))?;
+
+    let state = app.state::<MongoState>();
+    let mut ctx = state.context(connection.as_deref(), tenant.as_deref())?;
+    ctx.actor = actor;
+    let collection = match query.namespace.split_once('.') {
+        Some((database, collection)) => {
+            tenancy::check_database(tenant.as_deref(), database)?;
+            ctx.db = state.client(connection.as_deref())?.database(database);
+            collection
+        }
+        None => query.namespace.as_str(),
+    };
+    if !payload.is_object() {
+        payload = json!({});
+    }
+    payload["collection"] = json!(collection);
+    tenancy::check_pipeline(tenant.as_deref(), &query.spec.command, &payload)?;
+    if let Some(permissions) = &permissions {
+        policy::authorize(permissions, Some(ctx.db.name()), &query.spec.command, &payload)?;
+    }
+
+    let task = match execute(&ctx, &query.spec.command, payload) {
+        Some(task) => task,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown command: {}", query.spec.command)).into()),
+    };
+    task.await
+}
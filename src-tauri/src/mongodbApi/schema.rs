@@ -0,0 +1,178 @@
+//! Schema statistics computed from a random sample of a collection.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::AggregateOptions;
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use super::errors::{self, MongoPluginError};
+
+const DEFAULT_SAMPLE_SIZE: u32 = 1000;
+const MAX_SAMPLE_SIZE: u32 = 10_000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct AnalyzeCollectionArgs {
+    collection: String,
+    sample_size: Option<u32>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+/// What the sample showed for one field path.
+#[derive(Default)]
+struct FieldStats {
+    count: usize,
+    types: BTreeMap<&'static str, usize>,
+    min_number: Option<f64>,
+    max_number: Option<f64>,
+    min_date: Option<Bson>,
+    max_date: Option<Bson>,
+    distinct: HashSet<String>,
+}
+
+/// The type names `$type` uses.
+fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Double(_) => "double",
+        Bson::String(_) => "string",
+        Bson::Document(_) => "object",
+        Bson::Array(_) => "array",
+        Bson::Binary(_) => "binData",
+        Bson::ObjectId(_) => "objectId",
+        Bson::Boolean(_) => "bool",
+        Bson::DateTime(_) => "date",
+        Bson::Null => "null",
+        Bson::RegularExpression(_) => "regex",
+        Bson::JavaScriptCode(_) | Bson::JavaScriptCodeWithScope(_) => "javascript",
+        Bson::Int32(_) => "int",
+        Bson::Timestamp(_) => "timestamp",
+        Bson::Int64(_) => "long",
+        Bson::Decimal128(_) => "decimal",
+        Bson::MinKey => "minKey",
+        Bson::MaxKey => "maxKey",
+        _ => "other",
+    }
+}
+
+impl FieldStats {
+    fn record(&mut self, value: &Bson) {
+        *self.types.entry(type_name(value)).or_insert(0) += 1;
+        let number = match value {
+            Bson::Double(n) => Some(*n),
+            Bson::Int32(n) => Some(*n as f64),
+            Bson::Int64(n) => Some(*n as f64),
+            _ => None,
+        };
+        if let Some(n) = number {
+            self.min_number = Some(self.min_number.map_or(n, |min| min.min(n)));
+            self.max_number = Some(self.max_number.map_or(n, |max| max.max(n)));
+        }
+        if let Bson::DateTime(date) = value {
+            if !matches!(&self.min_date, Some(Bson::DateTime(min)) if min <= date) {
+                self.min_date = Some(value.clone());
+            }
+            if !matches!(&self.max_date, Some(Bson::DateTime(max)) if max >= date) {
+                self.max_date = Some(value.clone());
+            }
+        }
+        // Sub-documents and arrays are described by their own paths.
+        if !matches!(value, Bson::Document(_) | Bson::Array(_)) {
+            self.distinct.insert(value.clone().into_relaxed_extjson().to_string());
+        }
+    }
+}
+
+/// Records every path in `doc`, counting each path once per document even
+/// when it appears in several array elements.
+fn walk(doc: &Document, prefix: &str, stats: &mut BTreeMap<String, FieldStats>, seen: &mut HashSet<String>) {
+    for (key, value) in doc {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        walk_value(&path, value, stats, seen);
+    }
+}
+
+fn walk_value(path: &str, value: &Bson, stats: &mut BTreeMap<String, FieldStats>, seen: &mut HashSet<String>) {
+    let field = stats.entry(path.to_string()).or_default();
+    if seen.insert(path.to_string()) {
+        field.count += 1;
+    }
+    field.record(value);
+    match value {
+        Bson::Document(inner) => walk(inner, path, stats, seen),
+        Bson::Array(items) => {
+            for item in items {
+                if let Bson::Document(inner) = item {
+                    walk(inner, path, stats, seen);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Samples up to `sampleSize` documents and reports, per field path, how
+/// often it is present, which types it holds, the range of numeric and date
+/// values and an estimate of its cardinality.
+///
+/// The cardinality estimate is the number of distinct values in the
+/// sample, scaled up to the collection when every sampled value was unique
+/// (a sign the field is close to unique).
+pub(super) async fn analyze_collection(db: Database, args: AnalyzeCollectionArgs) -> Result<JsonValue, MongoPluginError> {
+    let sample_size = args.sample_size.unwrap_or(DEFAULT_SAMPLE_SIZE).clamp(1, MAX_SAMPLE_SIZE);
+    let coll = db.collection::<Document>(&args.collection);
+    let document_count = match coll.estimated_document_count(None).await {
+        Ok(count) => count,
+        Err(e) => return Err(errors::failed("Failed to count documents", e)),
+    };
+    let options = AggregateOptions::builder().max_time(args.max_time_ms.map(Duration::from_millis)).build();
+    let cursor = match coll.aggregate([doc! { "$sample": { "size": sample_size } }], options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to sample collection", e)),
+    };
+    let sample: Vec<Document> = match cursor.try_collect().await {
+        Ok(sample) => sample,
+        Err(e) => return Err(errors::failed("Failed to read sample", e)),
+    };
+
+    let mut stats = BTreeMap::new();
+    for doc in &sample {
+        walk(doc, "", &mut stats, &mut HashSet::new());
+    }
+
+    let sampled = sample.len();
+    let fields: Vec<JsonValue> = stats
+        .into_iter()
+        .map(|(path, field)| {
+            let frequency = field.count as f64 / sampled as f64;
+            let values: usize = field.types.values().sum();
+            let distinct = field.distinct.len();
+            let cardinality = if distinct > 0 && distinct == values {
+                (frequency * document_count as f64).round() as u64
+            } else {
+                distinct as u64
+            };
+            json!({
+                "path": path,
+                "count": field.count,
+                "frequency": frequency,
+                "types": field.types,
+                "numberRange": field.min_number.map(|min| json!({ "min": min, "max": field.max_number })),
+                "dateRange": field.min_date.map(|min| json!({ "min": min, "max": field.max_date })),
+                "distinctInSample": distinct,
+                "cardinalityEstimate": cardinality,
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "collection": args.collection,
+        "documentCount": document_count,
+        "sampleSize": sampled,
+        "fields": fields,
+    }))
+}
@@ -0,0 +1,297 @@
+//! Keeping the collections and indexes of two databases in step.
+//!
+//! `exportSchema` describes the connected database as a spec: each
+//! collection or view with its `options` (validator, validation level and
+//! action, capped size, collation, view pipeline and so on) and its
+//! `indexes` other than `_id_`, optionally only the `collections` named.
+//! Exported with `extendedJson: "canonical"`, the numbers in validators
+//! keep their exact BSON types.
+//!
+//! `applySchema` takes such a spec as JSON text and makes the connected
+//! database match it: missing collections and views are created, differing
+//! validators are changed with `collMod`, missing indexes are built and
+//! indexes whose keys or options differ are rebuilt. Indexes the spec
+//! doesn't have are reported, and dropped only with `dropExtraIndexes`;
+//! collections it doesn't have are reported and never dropped. Options a
+//! collection can't change once created, such as `capped` or `collation`,
+//! are reported as mismatches. The reply lists every `change` with its
+//! `collection` and `action`; with `dryRun: true` nothing is applied, which
+//! makes it a diff of the two schemas.
+
+use futures::TryStreamExt;
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::results::CollectionSpecification;
+use mongodb::IndexModel;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, CommandContext};
+
+/// Options `collMod` can change on an existing collection.
+const MODIFIABLE: &[&str] = &["validator", "validationLevel", "validationAction"];
+
+#[derive(Deserialize)]
+pub(super) struct ExportSchemaArgs {
+    collections: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ApplySchemaArgs {
+    spec: String,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    drop_extra_indexes: bool,
+}
+
+/// One collection or view as the spec describes it.
+struct Described {
+    name: String,
+    kind: String,
+    options: Document,
+    indexes: Vec<Document>,
+}
+
+impl Described {
+    fn to_document(&self) -> Document {
+        doc! {
+            "name": &self.name,
+            "type": &self.kind,
+            "options": self.options.clone(),
+            "indexes": self.indexes.iter().cloned().map(Bson::Document).collect::<Vec<_>>(),
+        }
+    }
+
+    fn from_document(spec: &Document) -> Result<Self, MongoPluginError> {
+        let name = match spec.get_str("name") {
+            Ok(name) => name,
+            Err(_) => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Every collection in the spec needs a 'name'")),
+        };
+        let indexes = match spec.get_array("indexes") {
+            Ok(indexes) => indexes.iter().filter_map(Bson::as_document).cloned().collect(),
+            Err(_) => Vec::new(),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            kind: spec.get_str("type").unwrap_or("collection").to_string(),
+            options: spec.get_document("options").cloned().unwrap_or_default(),
+            indexes,
+        })
+    }
+}
+
+/// `value` with every number as a double, so `1` and `1.0` compare equal.
+fn canonical(value: &Bson) -> Bson {
+    match value {
+        Bson::Int32(n) => Bson::Double(f64::from(*n)),
+        Bson::Int64(n) => Bson::Double(*n as f64),
+        Bson::Document(doc) => Bson::Document(doc.iter().map(|(key, value)| (key.clone(), canonical(value))).collect()),
+        Bson::Array(items) => Bson::Array(items.iter().map(canonical).collect()),
+        other => other.clone(),
+    }
+}
+
+fn same(a: Option<&Bson>, b: Option<&Bson>) -> bool {
+    a.map(canonical) == b.map(canonical)
+}
+
+/// Whether two index definitions build the same index. Key patterns are
+/// compared in order, since order changes the index.
+fn same_index(a: &Document, b: &Document) -> bool {
+    let key_order = |index: &Document| index.get_document("key").map(|key| key.keys().cloned().collect::<Vec<_>>()).unwrap_or_default();
+    let options = |index: &Document| {
+        let mut index = index.clone();
+        for internal in ["v", "ns", "background"] {
+            index.remove(internal);
+        }
+        Bson::Document(index)
+    };
+    key_order(a) == key_order(b) && same(Some(&options(a)), Some(&options(b)))
+}
+
+async fn describe(ctx: &CommandContext, only: Option<&[String]>) -> Result<Vec<Described>, MongoPluginError> {
+    let cursor = match ctx.db.list_collections(None, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to list collections", e)),
+    };
+    let specs: Vec<CollectionSpecification> = match cursor.try_collect().await {
+        Ok(specs) => specs,
+        Err(e) => return Err(errors::failed("Failed to read collections", e)),
+    };
+    let mut described = Vec::new();
+    for spec in specs {
+        if spec.name.starts_with("system.") || only.is_some_and(|only| !only.contains(&spec.name)) {
+            continue;
+        }
+        let mut spec = match bson::to_document(&spec) {
+            Ok(spec) => spec,
+            Err(e) => return Err(errors::failed("Failed to read collections", e)),
+        };
+        let name = spec.get_str("name").unwrap_or_default().to_string();
+        let kind = spec.get_str("type").unwrap_or("collection").to_string();
+        let mut options = spec.remove("options").and_then(|options| options.as_document().cloned()).unwrap_or_default();
+        options.retain(|_, value| !matches!(value, Bson::Null));
+        let indexes = if kind == "collection" { index_definitions(ctx, &name).await? } else { Vec::new() };
+        described.push(Described { name, kind, options, indexes });
+    }
+    described.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(described)
+}
+
+pub(super) async fn index_definitions(ctx: &CommandContext, collection: &str) -> Result<Vec<Document>, MongoPluginError> {
+    let cursor = match ctx.db.collection::<Document>(collection).list_indexes(None).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to list indexes", e)),
+    };
+    let models: Vec<IndexModel> = match cursor.try_collect().await {
+        Ok(models) => models,
+        Err(e) => return Err(errors::failed("Failed to read indexes", e)),
+    };
+    let mut indexes = Vec::with_capacity(models.len());
+    for model in models {
+        let mut index = match bson::to_document(&model) {
+            Ok(index) => index,
+            Err(e) => return Err(errors::failed("Failed to read indexes", e)),
+        };
+        if index.get_str("name") == Ok("_id_") {
+            continue;
+        }
+        index.retain(|key, value| !matches!(value, Bson::Null) && !matches!(key.as_str(), "v" | "ns"));
+        indexes.push(index);
+    }
+    Ok(indexes)
+}
+
+pub(super) async fn export_schema(ctx: CommandContext, args: ExportSchemaArgs) -> Result<JsonValue, MongoPluginError> {
+    let described = describe(&ctx, args.collections.as_deref()).await?;
+    let collections: Vec<Bson> = described.iter().map(|collection| Bson::Document(collection.to_document())).collect();
+    Ok(convert::to_json(doc! { "database": ctx.db.name(), "collections": collections }))
+}
+
+/// The changes applied, or that would be with `dryRun`.
+#[derive(Default)]
+struct Plan {
+    changes: Vec<JsonValue>,
+}
+
+impl Plan {
+    fn note(&mut self, collection: &str, action: &str, details: JsonValue) {
+        let mut change = json!({ "collection": collection, "action": action });
+        if let (JsonValue::Object(change), JsonValue::Object(details)) = (&mut change, details) {
+            change.extend(details);
+        }
+        self.changes.push(change);
+    }
+}
+
+async fn run(ctx: &CommandContext, dry_run: bool, command: Document, context: &str) -> Result<(), MongoPluginError> {
+    if dry_run {
+        return Ok(());
+    }
+    match ctx.db.run_command(command, None).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(errors::failed(context, e)),
+    }
+}
+
+async fn create_index(ctx: &CommandContext, dry_run: bool, collection: &str, index: &Document) -> Result<(), MongoPluginError> {
+    let command = doc! { "createIndexes": collection, "indexes": [index.clone()] };
+    run(ctx, dry_run, command, "Failed to create index").await
+}
+
+pub(super) async fn apply_schema(ctx: CommandContext, args: ApplySchemaArgs) -> Result<JsonValue, MongoPluginError> {
+    let spec: Document = match convert::from_extjson(&args.spec) {
+        Ok(spec) => spec,
+        Err(e) => return Err(errors::invalid("Failed to parse spec", e)),
+    };
+    let wanted = match spec.get_array("collections") {
+        Ok(collections) => collections.iter().filter_map(Bson::as_document).map(Described::from_document).collect::<Result<Vec<_>, _>>()?,
+        Err(_) => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "spec must have a 'collections' array")),
+    };
+    let existing: HashMap<String, Described> = describe(&ctx, None).await?.into_iter().map(|collection| (collection.name.clone(), collection)).collect();
+    let dry_run = args.dry_run;
+    let mut plan = Plan::default();
+
+    // Views go last, as they may be defined on collections created first.
+    let (collections, views): (Vec<&Described>, Vec<&Described>) = wanted.iter().partition(|wanted| wanted.kind != "view");
+    for wanted in collections.into_iter().chain(views) {
+        let current = match existing.get(&wanted.name) {
+            Some(current) => current,
+            None => {
+                let mut command = doc! { "create": &wanted.name };
+                command.extend(wanted.options.clone());
+                run(&ctx, dry_run, command, "Failed to create collection").await?;
+                plan.note(&wanted.name, if wanted.kind == "view" { "createView" } else { "createCollection" }, json!({}));
+                for index in &wanted.indexes {
+                    create_index(&ctx, dry_run, &wanted.name, index).await?;
+                    plan.note(&wanted.name, "createIndex", json!({ "index": index.get_str("name").unwrap_or_default() }));
+                }
+                continue;
+            }
+        };
+
+        let modified: Vec<&str> = MODIFIABLE.iter().copied().filter(|option| !same(wanted.options.get(*option), current.options.get(*option))).collect();
+        if !modified.is_empty() {
+            let mut command = doc! { "collMod": &wanted.name };
+            for option in &modified {
+                match wanted.options.get(*option) {
+                    Some(value) => command.insert(*option, value.clone()),
+                    // Dropping a validator is setting an empty one.
+                    None if *option == "validator" => command.insert("validator", Document::new()),
+                    None => continue,
+                };
+            }
+            run(&ctx, dry_run, command, "Failed to modify collection").await?;
+            plan.note(&wanted.name, "modifyCollection", json!({ "options": modified }));
+        }
+        let mismatched: Vec<&String> = wanted
+            .options
+            .keys()
+            .chain(current.options.keys())
+            .filter(|option| !MODIFIABLE.contains(&option.as_str()))
+            .filter(|option| !same(wanted.options.get(*option), current.options.get(*option)))
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if !mismatched.is_empty() {
+            plan.note(&wanted.name, "optionMismatch", json!({ "options": mismatched }));
+        }
+
+        for index in &wanted.indexes {
+            let name = index.get_str("name").unwrap_or_default();
+            match current.indexes.iter().find(|current| current.get_str("name") == Ok(name)) {
+                Some(current) if same_index(index, current) => {}
+                Some(_) => {
+                    run(&ctx, dry_run, doc! { "dropIndexes": &wanted.name, "index": name }, "Failed to drop index").await?;
+                    create_index(&ctx, dry_run, &wanted.name, index).await?;
+                    plan.note(&wanted.name, "replaceIndex", json!({ "index": name }));
+                }
+                None => {
+                    create_index(&ctx, dry_run, &wanted.name, index).await?;
+                    plan.note(&wanted.name, "createIndex", json!({ "index": name }));
+                }
+            }
+        }
+        for index in &current.indexes {
+            let name = index.get_str("name").unwrap_or_default();
+            if wanted.indexes.iter().any(|wanted| wanted.get_str("name") == Ok(name)) {
+                continue;
+            }
+            if args.drop_extra_indexes {
+                run(&ctx, dry_run, doc! { "dropIndexes": &wanted.name, "index": name }, "Failed to drop index").await?;
+                plan.note(&wanted.name, "dropIndex", json!({ "index": name }));
+            } else {
+                plan.note(&wanted.name, "extraIndex", json!({ "index": name }));
+            }
+        }
+    }
+    let mut extra: Vec<&String> = existing.keys().filter(|name| !wanted.iter().any(|wanted| wanted.name == **name)).collect();
+    extra.sort();
+    for name in extra {
+        plan.note(name, "extraCollection", json!({}));
+    }
+    Ok(json!({ "database": ctx.db.name(), "applied": !dry_run, "changes": plan.changes }))
+}
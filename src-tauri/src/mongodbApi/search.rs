@@ -0,0 +1,210 @@
+//! One search across many collections, for an app-wide search box.
+//!
+//! `globalSearch` queries the collections concurrently. Where
+//! `fieldsPerCollection` names fields for a collection, their values are
+//! matched case-insensitively against the term taken literally, and each
+//! match scores 3 for an exact value, 2 for a prefix and 1 otherwise.
+//! Other collections use their text index with its relevance score, and are
+//! reported under `skipped` when they have none. Without `collections`,
+//! every collection the window may read is searched. Results come back
+//! grouped by collection, best-scoring group first.
+
+use futures::future::join_all;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{AggregateOptions, FindOptions};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, policy, tenancy, CommandContext, MongoState};
+
+const DEFAULT_LIMIT: i64 = 10;
+const INDEX_NOT_FOUND: i32 = 27;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct GlobalSearchArgs {
+    /// Another database on the connected server.
+    database: Option<String>,
+    term: String,
+    collections: Option<Vec<String>>,
+    #[serde(default)]
+    fields_per_collection: HashMap<String, Vec<String>>,
+    /// Results per collection.
+    limit: Option<i64>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+/// `term` as a regular expression matching it literally.
+fn escape_regex(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The score of `field` for a lowercased `term`, worked out by the server
+/// so documents can be ranked before the limit is applied.
+fn field_score(field: &str, term: &str) -> Document {
+    let value = format!("${}", field);
+    doc! { "$let": {
+        "vars": { "value": { "$cond": [{ "$eq": [{ "$type": &value }, "string"] }, { "$toLower": &value }, Bson::Null] } },
+        "in": { "$switch": {
+            "branches": [
+                { "case": { "$eq": ["$$value", Bson::Null] }, "then": 0.0 },
+                { "case": { "$eq": ["$$value", term] }, "then": 3.0 },
+                { "case": { "$eq": [{ "$indexOfCP": ["$$value", term] }, 0] }, "then": 2.0 },
+                { "case": { "$gt": [{ "$indexOfCP": ["$$value", term] }, 0] }, "then": 1.0 },
+            ],
+            "default": 0.0,
+        } },
+    } }
+}
+
+/// Takes the `_score` each document was given out of it.
+fn scored(docs: Vec<Document>) -> Vec<(f64, Document)> {
+    docs.into_iter()
+        .map(|mut doc| {
+            let score = match doc.remove("_score") {
+                Some(Bson::Double(score)) => score,
+                _ => 0.0,
+            };
+            (score, doc)
+        })
+        .collect()
+}
+
+async fn search_fields(
+    ctx: &CommandContext,
+    collection: &str,
+    fields: &[String],
+    term: &str,
+    scope: Document,
+    limit: i64,
+    max_time: Option<Duration>,
+) -> Result<Vec<(f64, Document)>, MongoPluginError> {
+    let pattern = escape_regex(term);
+    let any: Vec<Document> = fields.iter().map(|field| doc! { field: { "$regex": &pattern, "$options": "i" } }).collect();
+    let term = term.to_lowercase();
+    let scores: Vec<Document> = fields.iter().map(|field| field_score(field, &term)).collect();
+    let mut pipeline = vec![
+        doc! { "$match": { "$and": [scope, { "$or": any }] } },
+        doc! { "$addFields": { "_score": { "$add": scores } } },
+        doc! { "$sort": { "_score": -1, "_id": 1 } },
+    ];
+    // Ranked first, so the limit keeps the best matches rather than the first found.
+    if limit > 0 {
+        pipeline.push(doc! { "$limit": limit });
+    }
+    let options = AggregateOptions::builder().max_time(max_time).build();
+    let cursor = ctx.db.collection::<Document>(collection).aggregate(pipeline, options).await.map_err(|e| errors::failed("Failed to search", e))?;
+    let docs: Vec<Document> = cursor.try_collect().await.map_err(|e| errors::failed("Failed to search", e))?;
+    Ok(scored(docs))
+}
+
+/// A text search, or `None` when the collection has no text index.
+async fn search_text(
+    ctx: &CommandContext,
+    collection: &str,
+    term: &str,
+    scope: Document,
+    mut options: FindOptions,
+) -> Result<Option<Vec<(f64, Document)>>, MongoPluginError> {
+    let filter = doc! { "$and": [scope, { "$text": { "$search": term } }] };
+    options.projection = Some(doc! { "_score": { "$meta": "textScore" } });
+    options.sort = Some(doc! { "_score": { "$meta": "textScore" } });
+    let cursor = match ctx.db.collection::<Document>(collection).find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(e) if e.code == INDEX_NOT_FOUND) => return Ok(None),
+        Err(e) => return Err(errors::failed("Failed to search", e)),
+    };
+    let docs: Vec<Document> = cursor.try_collect().await.map_err(|e| errors::failed("Failed to search", e))?;
+    Ok(Some(scored(docs)))
+}
+
+/// Searches one collection, reporting `Err` with the reason it was skipped.
+async fn search_collection(ctx: &CommandContext, collection: &str, args: &GlobalSearchArgs) -> Result<JsonValue, MongoPluginError> {
+    // Soft-deleted documents don't show up in searches.
+    let scope = match ctx.soft_delete.as_ref().and_then(|soft_delete| soft_delete.field_for(&json!({ "collection": collection }))) {
+        Some(field) => doc! { field: null },
+        None => Document::new(),
+    };
+    let limit = args.limit.unwrap_or(DEFAULT_LIMIT);
+    let max_time = args.max_time_ms.map(Duration::from_millis);
+    let scored = match args.fields_per_collection.get(collection).filter(|fields| !fields.is_empty()) {
+        Some(fields) => search_fields(ctx, collection, fields, &args.term, scope, limit, max_time).await?,
+        None => {
+            let options = FindOptions::builder().limit(limit).max_time(max_time).build();
+            match search_text(ctx, collection, &args.term, scope, options).await? {
+                Some(scored) => scored,
+                None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "No text index and no fields to search")),
+            }
+        }
+    };
+    let (scores, docs): (Vec<f64>, Vec<Document>) = scored.into_iter().unzip();
+    let mut docs = convert::to_json(docs);
+    ctx.transforms.finish("find", Some(collection), &mut docs);
+    let documents: Vec<JsonValue> = match docs {
+        JsonValue::Array(docs) => scores.into_iter().zip(docs).map(|(score, document)| json!({ "score": score, "document": document })).collect(),
+        _ => Vec::new(),
+    };
+    Ok(json!({ "collection": collection, "count": documents.len(), "documents": documents }))
+}
+
+pub(super) async fn global_search<R: Runtime>(
+    app: AppHandle<R>,
+    permissions: Option<policy::Permissions>,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: GlobalSearchArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    if args.term.trim().is_empty() {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "term must not be empty"));
+    }
+    let state = app.state::<MongoState>();
+    let mut ctx = state.context(connection.as_deref(), tenant.as_deref())?;
+    if let Some(database) = &args.database {
+        tenancy::check_database(tenant.as_deref(), database)?;
+        ctx.db = state.client(connection.as_deref())?.database(database);
+    }
+    let allowed = |collection: &str| {
+        permissions.as_ref().is_none_or(|permissions| policy::authorize_namespace(permissions, ctx.db.name(), collection).is_ok())
+    };
+    let collections = match &args.collections {
+        Some(collections) => {
+            if let Some(denied) = collections.iter().find(|collection| !allowed(collection)) {
+                let message = format!("Permission denied: '{}.{}' is not allowed for this window", ctx.db.name(), denied);
+                return Err(MongoPluginError::new(errors::ErrorKind::PermissionDenied, message));
+            }
+            collections.clone()
+        }
+        None => match ctx.db.list_collection_names(None).await {
+            Ok(names) => names.into_iter().filter(|name| !name.starts_with("system.") && allowed(name)).collect(),
+            Err(e) => return Err(errors::failed("Failed to list collections", e)),
+        },
+    };
+
+    let searches = collections.iter().map(|collection| search_collection(&ctx, collection, &args));
+    let mut results = Vec::new();
+    let mut skipped = Vec::new();
+    for (collection, outcome) in collections.iter().zip(join_all(searches).await) {
+        match outcome {
+            Ok(group) if group["count"] == 0 => {}
+            Ok(group) => results.push(group),
+            Err(reason) => skipped.push(json!({ "collection": collection, "reason": reason })),
+        }
+    }
+    let best = |group: &JsonValue| group["documents"][0]["score"].as_f64().unwrap_or(0.0);
+    results.sort_by(|a, b| best(b).total_cmp(&best(a)));
+    Ok(json!({ "term": args.term, "results": results, "skipped": skipped }))
+}
@@ -0,0 +1,116 @@
+//! Chart-ready time series built on the server.
+//!
+//! `timeSeriesAggregate` buckets the documents matching `filter` by their
+//! `dateField` truncated to `granularity`, `binSize` units to a bucket (1 by
+//! default) and in `timezone` (UTC by default), folds each bucket with
+//! `valueExpr`, an accumulator such as `{ "$avg": "$price" }` that defaults
+//! to counting the documents, and returns `[{ t, v }]` points in time order,
+//! `t` in milliseconds since the epoch. Documents whose `dateField` isn't a
+//! date are left out. It uses `$dateTrunc`, so it needs MongoDB 5.0.
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::AggregateOptions;
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::time::Duration;
+
+use super::errors::{self, MongoPluginError};
+use super::convert;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum Granularity {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+impl Granularity {
+    fn unit(self) -> &'static str {
+        match self {
+            Granularity::Second => "second",
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+            Granularity::Week => "week",
+            Granularity::Month => "month",
+            Granularity::Quarter => "quarter",
+            Granularity::Year => "year",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct TimeSeriesArgs {
+    collection: String,
+    date_field: String,
+    granularity: Granularity,
+    bin_size: Option<u32>,
+    timezone: Option<String>,
+    value_expr: Option<String>,
+    filter: Option<String>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+}
+
+/// The `$match`, `$group`, `$sort` and `$project` stages producing the
+/// points.
+fn pipeline(args: &TimeSeriesArgs) -> Result<Vec<Document>, MongoPluginError> {
+    let filter: Document = match &args.filter {
+        Some(filter) => match convert::from_extjson(filter) {
+            Ok(filter) => filter,
+            Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+        },
+        None => Document::new(),
+    };
+    let value: Document = match &args.value_expr {
+        Some(value) => match convert::from_extjson(value) {
+            Ok(value) => value,
+            Err(e) => return Err(errors::invalid("Failed to parse valueExpr", e)),
+        },
+        None => doc! { "$sum": 1 },
+    };
+    if value.len() != 1 || !value.keys().all(|operator| operator.starts_with('$')) {
+        let message = "valueExpr must be a single accumulator, e.g. { \"$sum\": \"$amount\" }";
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+    }
+    let field = args.date_field.trim_start_matches('$');
+    let mut truncate = doc! { "date": format!("${}", field), "unit": args.granularity.unit() };
+    if let Some(bin_size) = args.bin_size {
+        if bin_size == 0 {
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "binSize must be at least 1"));
+        }
+        truncate.insert("binSize", bin_size as i64);
+    }
+    if let Some(timezone) = &args.timezone {
+        truncate.insert("timezone", timezone);
+    }
+    let dated = doc! { field: { "$type": "date" } };
+    let filter = if filter.is_empty() { dated } else { doc! { "$and": [filter, dated] } };
+    Ok(vec![
+        doc! { "$match": filter },
+        doc! { "$group": { "_id": { "$dateTrunc": truncate }, "v": Bson::Document(value) } },
+        doc! { "$sort": { "_id": 1 } },
+        doc! { "$project": { "_id": 0, "t": { "$toLong": "$_id" }, "v": 1 } },
+    ])
+}
+
+pub(super) async fn time_series_aggregate(db: Database, args: TimeSeriesArgs) -> Result<JsonValue, MongoPluginError> {
+    let pipeline = pipeline(&args)?;
+    let options = AggregateOptions::builder().max_time(args.max_time_ms.map(Duration::from_millis)).build();
+    let cursor = match db.collection::<Document>(&args.collection).aggregate(pipeline, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute aggregation", e)),
+    };
+    match convert::collect_json(cursor).await {
+        Ok(points) => Ok(points),
+        Err(e) => Err(errors::failed("Failed to read aggregation results", e)),
+    }
+}
@@ -0,0 +1,185 @@
+//! Server sessions the frontend keeps open across invokes, for transactions
+//! it drives itself.
+//!
+//! `startSession` opens a session on a connection and returns its
+//! `sessionId`. Passing that `sessionId` to `insertOne`, `insertMany`,
+//! `findOne`, `findById`, `updateById`, `updateOne`, `updateMany`,
+//! `replaceOne`, `deleteById`, `deleteOne`, `deleteMany` or `incrementField`
+//! runs the command on the session, one command at a time. Between
+//! `startTransaction` and `commitTransaction` or `abortTransaction` those
+//! commands form one transaction, and their `mongo://write` events wait for
+//! the commit. Commands on a session are never retried, and neither is a
+//! transaction: after a transient error the frontend aborts it and starts
+//! over. `endSession` closes a session, aborting any transaction left open,
+//! and a session unused for 30 minutes, the server's own session timeout,
+//! is closed the next time one is started.
+
+use mongodb::bson::oid::ObjectId;
+use mongodb::error::UNKNOWN_TRANSACTION_COMMIT_RESULT;
+use mongodb::ClientSession;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::errors::{self, MongoPluginError};
+use super::batch::{self, Deletes};
+use super::{events, CommandContext, CommandFuture, NoArgs};
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const MAX_COMMIT_ATTEMPTS: u32 = 5;
+
+struct Held {
+    session: ClientSession,
+    transaction: bool,
+    /// The open transaction's write events with the namespace each is for,
+    /// sent once it commits.
+    writes: Vec<(String, Option<String>, JsonValue)>,
+    used: Instant,
+}
+
+/// Open sessions, by session id. Each is locked while a command runs on it.
+#[derive(Default)]
+pub(super) struct Sessions {
+    open: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Held>>>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct SessionArgs {
+    session_id: String,
+}
+
+impl Sessions {
+    fn get(&self, id: &str) -> Result<Arc<tokio::sync::Mutex<Held>>, MongoPluginError> {
+        match self.open.lock().unwrap().get(id) {
+            Some(held) => Ok(held.clone()),
+            None => Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No open session '{}'", id))),
+        }
+    }
+
+    /// Closes every open session, aborting their transactions.
+    pub(super) fn close_all(&self) {
+        self.open.lock().unwrap().clear();
+    }
+}
+
+/// The session a command's arguments ask to run on.
+pub(super) fn session_id(payload: &JsonValue) -> Option<String> {
+    payload.get("sessionId").and_then(JsonValue::as_str).map(str::to_string)
+}
+
+pub(super) async fn start_session(ctx: CommandContext, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    let session = match ctx.client.start_session(None).await {
+        Ok(session) => session,
+        Err(e) => return Err(errors::failed("Failed to start session", e)),
+    };
+    let id = ObjectId::new().to_hex();
+    let mut open = ctx.sessions.open.lock().unwrap();
+    // Sessions busy with a command are in use, however long it takes.
+    open.retain(|_, held| held.try_lock().map_or(true, |held| held.used.elapsed() < IDLE_TIMEOUT));
+    let held = Held { session, transaction: false, writes: Vec::new(), used: Instant::now() };
+    open.insert(id.clone(), Arc::new(tokio::sync::Mutex::new(held)));
+    Ok(json!({ "sessionId": id }))
+}
+
+pub(super) async fn start_transaction(ctx: CommandContext, args: SessionArgs) -> Result<JsonValue, MongoPluginError> {
+    let held = ctx.sessions.get(&args.session_id)?;
+    let mut held = held.lock().await;
+    held.used = Instant::now();
+    if let Err(e) = held.session.start_transaction(None).await {
+        return Err(errors::failed("Failed to start transaction", e));
+    }
+    held.transaction = true;
+    held.writes.clear();
+    Ok(json!({ "sessionId": args.session_id, "transaction": true }))
+}
+
+/// Commits the transaction and only then sends its write events.
+pub(super) async fn commit_transaction(ctx: CommandContext, args: SessionArgs) -> Result<JsonValue, MongoPluginError> {
+    let held = ctx.sessions.get(&args.session_id)?;
+    let mut held = held.lock().await;
+    held.used = Instant::now();
+    let mut attempts = 1;
+    let committed = loop {
+        match held.session.commit_transaction().await {
+            Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempts < MAX_COMMIT_ATTEMPTS => attempts += 1,
+            committed => break committed,
+        }
+    };
+    held.transaction = false;
+    let writes = std::mem::take(&mut held.writes);
+    if let Err(e) = committed {
+        return Err(errors::failed("Failed to commit transaction", e));
+    }
+    for (database, collection, event) in writes {
+        ctx.counts.invalidate(&database, collection.as_deref().unwrap_or_default());
+        ctx.cursors.written(&ctx.events, &event);
+        (ctx.events)(events::WRITE_EVENT, event);
+    }
+    Ok(json!({ "sessionId": args.session_id, "committed": true }))
+}
+
+pub(super) async fn abort_transaction(ctx: CommandContext, args: SessionArgs) -> Result<JsonValue, MongoPluginError> {
+    let held = ctx.sessions.get(&args.session_id)?;
+    let mut held = held.lock().await;
+    held.used = Instant::now();
+    held.transaction = false;
+    held.writes.clear();
+    if let Err(e) = held.session.abort_transaction().await {
+        return Err(errors::failed("Failed to abort transaction", e));
+    }
+    Ok(json!({ "sessionId": args.session_id, "aborted": true }))
+}
+
+pub(super) async fn end_session(ctx: CommandContext, args: SessionArgs) -> Result<JsonValue, MongoPluginError> {
+    let held = ctx.sessions.open.lock().unwrap().remove(&args.session_id);
+    let held = match held {
+        Some(held) => held,
+        None => return Ok(json!({ "closed": false })),
+    };
+    let mut held = held.lock().await;
+    if held.transaction {
+        let _ = held.session.abort_transaction().await;
+    }
+    Ok(json!({ "closed": true }))
+}
+
+/// Runs a command on session `id`. Its audit entries and prior versions
+/// are written on the session too, so a transaction covers them, and its
+/// write event waits for the transaction to commit.
+pub(super) fn run(ctx: &CommandContext, command: &str, payload: JsonValue, soft_field: Option<String>, id: String) -> CommandFuture {
+    let (ctx, command) = (ctx.clone(), command.to_string());
+    Box::pin(async move {
+        let held = ctx.sessions.get(&id)?;
+        let mut held = held.lock().await;
+        held.used = Instant::now();
+        // On the session's own connection, whichever `connectionId` says.
+        let db = held.session.client().database(ctx.db.name());
+        let collection = payload.get("collection").and_then(JsonValue::as_str).map(str::to_string);
+        let deletes = match (&soft_field, &ctx.trash) {
+            (Some(field), _) => Deletes::Soft(field),
+            (None, Some(trash)) if trash.trashes(&payload) => {
+                if batch::is_delete(&command) {
+                    trash.ensure_ttl(&db).await?;
+                }
+                Deletes::Trash(trash, &ctx.actor)
+            }
+            _ => Deletes::Hard,
+        };
+        let target = ctx.tracking.target(&command, &payload, soft_field.as_deref());
+        let step = batch::tracked_step(&db, &mut held.session, &command, payload, deletes, &ctx.tracking, &ctx.actor, target);
+        let result = step.await.map_err(batch::step_message)?;
+        if let Some(event) = events::write_event(&command, db.name(), collection.as_deref()) {
+            if held.transaction {
+                held.writes.push((db.name().to_string(), collection, event));
+            } else {
+                ctx.counts.invalidate(db.name(), collection.as_deref().unwrap_or_default());
+                ctx.cursors.written(&ctx.events, &event);
+                (ctx.events)(events::WRITE_EVENT, event);
+            }
+        }
+        Ok(result)
+    })
+}
@@ -0,0 +1,58 @@
+//! Finishing the plugin's work before the app exits.
+//!
+//! When the app is asked to exit while connected or with commands running,
+//! the plugin holds the exit back and stops taking commands. It gives running
+//! ones, transactional batches included, up to `exitGraceMs` (3 seconds by
+//! default) to finish, cancelling long-running operations, then closes open streams and change streams and leaves leader elections
+//! so their leases are handed back instead of left to expire. Last, it shuts
+//! the clients of every open connection down, which ends their server sessions, allowing the same grace
+//! for cursors still open, and lets the app exit.
+
+use mongodb::Client;
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::{leader, watch, MongoState};
+
+const DEFAULT_GRACE_MS: u64 = 3_000;
+
+/// Starts shutting down if the plugin has work to finish, returning whether
+/// the exit must be held back until that's done.
+pub(super) fn exit_requested<R: Runtime>(app: &AppHandle<R>) -> bool {
+    let state = app.state::<MongoState>();
+    if state.connections.is_empty() && !state.runtime.is_busy() {
+        return false;
+    }
+    if state.runtime.close() {
+        // Already shutting down; that exits once it's done.
+        return true;
+    }
+    let grace = Duration::from_millis(state.config.exit_grace_ms.unwrap_or(DEFAULT_GRACE_MS));
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        shut_down(&app, grace).await;
+        app.exit(0);
+    });
+    true
+}
+
+async fn shut_down<R: Runtime>(app: &AppHandle<R>, grace: Duration) {
+    let state = app.state::<MongoState>();
+    state.operations.cancel_all();
+    state.runtime.drain(grace).await;
+    state.streams.close_all();
+    state.cursors.close_all();
+    state.sessions.close_all();
+    app.state::<watch::Watches>().close_all();
+    leader::stop_all(app).await;
+    let clients = state.connections.take_all().into_iter().map(|connection| close_client(connection.client.clone(), grace));
+    futures::future::join_all(clients).await;
+}
+
+/// Shuts a client down, giving its cursors and sessions up to `grace` to
+/// be dropped before closing it regardless.
+pub(super) async fn close_client(client: Client, grace: Duration) {
+    if tokio::time::timeout(grace, client.clone().shutdown()).await.is_err() {
+        client.shutdown_immediate().await;
+    }
+}
@@ -0,0 +1,320 @@
+//! HMAC signatures on stored documents, so edits made behind the plugin's
+//! back show up when the document is next read.
+//!
+//! Documents written to a signed collection get an HMAC-SHA256 over their
+//! contents in the signature field. Reads check it and report documents
+//! whose signature is missing or doesn't match with a `mongo://tampered`
+//! event; the documents are still returned so the app decides what to do.
+//!
+//! The signature covers every field but the signature itself, `_id`
+//! included, with object keys sorted, so a document's contents can't be
+//! moved to another. Inserted documents without an `_id` are given an
+//! ObjectId before they are signed, and replacements must carry the `_id`
+//! of the document they replace. Partial updates can't be signed without
+//! reading the document first, so they are refused on signed collections;
+//! write whole documents instead.
+//!
+//! Documents are checked wherever results are read: finds, cursors,
+//! streams, searches, exports and the full documents of change events.
+//! Aggregations may reshape documents, so their results are checked only
+//! where they still carry the signature field.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use super::bulk_write::{self, Written};
+use super::encryption::{app_data_path, load_or_create_key};
+use super::convert;
+
+const DEFAULT_KEY_FILE: &str = "mongo-document-signing.key";
+const DEFAULT_FIELD: &str = "_signature";
+pub(super) const TAMPERED_EVENT: &str = "mongo://tampered";
+
+/// Commands whose updates touch only part of a document.
+const PARTIAL_UPDATES: &[&str] = &[
+    "updateById",
+    "updateOne",
+    "updateMany",
+    "updateManyWithProgress",
+    "findOneAndUpdate",
+    "updateWithVersion",
+    "incrementField",
+    "pushToArray",
+    "pullFromArray",
+    "mergeDocuments",
+];
+
+/// The `documentSigning` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DocumentSigningConfig {
+    /// File holding the base64-encoded 256-bit key, relative to the app data
+    /// directory unless absolute. A new key is generated if it doesn't exist.
+    pub key_file: Option<PathBuf>,
+    /// Field the signature is stored in, `_signature` by default.
+    pub field: Option<String>,
+    /// Collections whose documents are signed.
+    pub collections: Vec<String>,
+}
+
+/// Payload of the `mongo://tampered` event.
+#[derive(Serialize, Clone)]
+pub struct TamperedDocument {
+    pub collection: String,
+    pub id: JsonValue,
+    /// `missing` when the document has no signature, `mismatch` when it
+    /// doesn't match the contents.
+    pub reason: &'static str,
+}
+
+type TamperReporter = Box<dyn Fn(TamperedDocument) + Send + Sync>;
+
+pub(super) struct DocumentSigning {
+    key: [u8; 32],
+    field: String,
+    collections: HashSet<String>,
+    report: TamperReporter,
+}
+
+/// Sorts object keys at every level so the signed bytes don't depend on
+/// field order.
+fn canonical(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            JsonValue::Object(keys.into_iter().map(|key| (key.clone(), canonical(&map[key]))).collect())
+        }
+        JsonValue::Array(items) => JsonValue::Array(items.iter().map(canonical).collect()),
+        other => other.clone(),
+    }
+}
+
+impl DocumentSigning {
+    pub(super) fn load<F>(config: &DocumentSigningConfig, app_data_dir: Option<PathBuf>, report: F) -> Result<Self, String>
+    where
+        F: Fn(TamperedDocument) + Send + Sync + 'static,
+    {
+        let key = load_or_create_key(&app_data_path(config.key_file.as_deref(), DEFAULT_KEY_FILE, app_data_dir)?)?;
+        Ok(Self {
+            key,
+            field: config.field.clone().unwrap_or_else(|| DEFAULT_FIELD.to_string()),
+            collections: config.collections.iter().cloned().collect(),
+            report: Box::new(report),
+        })
+    }
+
+    fn mac(&self, doc: &serde_json::Map<String, JsonValue>) -> Hmac<Sha256> {
+        let mut signed = doc.clone();
+        signed.remove(&self.field);
+        // Back through BSON, so a date or binary reads the same whichever
+        // Extended JSON flavour spelled it.
+        let signed = match Bson::try_from(JsonValue::Object(signed.clone())) {
+            Ok(doc) => serde_json::to_value(doc).unwrap(),
+            Err(_) => JsonValue::Object(signed),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(&serde_json::to_vec(&canonical(&signed)).unwrap());
+        mac
+    }
+
+    /// Signs a document in the JSON form results take, which is also what
+    /// reads verify against. A document that would be inserted without an
+    /// `_id` gets one first; one that replaces another must have its `_id`.
+    fn sign(&self, collection: &str, doc: &mut Document, replaces: bool) -> Result<(), String> {
+        if !doc.contains_key("_id") {
+            if replaces {
+                return Err(format!("Replacements in the signed collection '{}' need the _id of the document they replace", collection));
+            }
+            let mut with_id = doc! { "_id": ObjectId::new() };
+            with_id.extend(std::mem::take(doc));
+            *doc = with_id;
+        }
+        if let JsonValue::Object(map) = serde_json::to_value(&*doc).unwrap() {
+            let signature = BASE64.encode(self.mac(&map).finalize().into_bytes());
+            doc.insert(self.field.as_str(), signature);
+        }
+        Ok(())
+    }
+
+    fn signed_collection<'a>(&self, payload: &'a JsonValue) -> Option<&'a str> {
+        payload.get("collection").and_then(JsonValue::as_str).filter(|c| self.collections.contains(*c))
+    }
+
+    pub(super) fn is_signed(&self, collection: &str) -> bool {
+        self.collections.contains(collection)
+    }
+
+    /// Signs the documents written by insert and upsert commands and refuses
+    /// partial updates and projected reads on signed collections.
+    pub(super) fn sign_payload(&self, command: &str, payload: &mut JsonValue) -> Result<(), String> {
+        let collection = match self.signed_collection(payload) {
+            Some(collection) => collection,
+            None => return Ok(()),
+        };
+        if matches!(command, "find" | "findOne" | "findCursor") && payload.pointer("/options/projection").is_some() {
+            return Err(format!("'{}' can't project the signed collection '{}'; its documents are verified whole", command, collection));
+        }
+        if PARTIAL_UPDATES.contains(&command) {
+            return Err(format!(
+                "'{}' can't update the signed collection '{}'; write whole documents with upsertMany or replaceOne instead",
+                command, collection
+            ));
+        }
+        if command == "bulkWrite" {
+            let collection = collection.to_string();
+            return bulk_write::rewrite_documents(payload, |written, doc| match written {
+                Written::Update => Err(format!(
+                    "bulkWrite can't update the signed collection '{}'; use replaceOne operations instead",
+                    collection
+                )),
+                Written::Inserted => self.sign(&collection, doc, false),
+                Written::Replacement => self.sign(&collection, doc, true),
+            });
+        }
+        let (key, replaces) = match command {
+            "insertOne" | "insertMany" => ("data", false),
+            // An upsert may match a stored document, whose _id it keeps.
+            "upsertMany" => ("documents", true),
+            "replaceOne" => ("replacement", true),
+            _ => return Ok(()),
+        };
+        let text = match payload.get(key).and_then(JsonValue::as_str) {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+        let signed = match convert::from_extjson::<Bson>(text) {
+            Ok(Bson::Document(mut doc)) => {
+                self.sign(collection, &mut doc, replaces)?;
+                convert::to_extjson_text(Bson::Document(doc))
+            }
+            Ok(Bson::Array(mut docs)) => {
+                for doc in docs.iter_mut() {
+                    if let Bson::Document(doc) = doc {
+                        self.sign(collection, doc, replaces)?;
+                    }
+                }
+                convert::to_extjson_text(Bson::Array(docs))
+            }
+            // Malformed arguments are reported by the command itself.
+            _ => return Ok(()),
+        };
+        payload[key] = JsonValue::String(signed);
+        Ok(())
+    }
+
+    fn verify(&self, collection: &str, doc: &JsonValue) {
+        let map = match doc.as_object() {
+            Some(map) => map,
+            None => return,
+        };
+        let signature = map.get(&self.field).and_then(JsonValue::as_str).and_then(|s| BASE64.decode(s).ok());
+        let reason = match signature {
+            None => "missing",
+            Some(signature) if self.mac(map).verify_slice(&signature).is_err() => "mismatch",
+            Some(_) => return,
+        };
+        (self.report)(TamperedDocument {
+            collection: collection.to_string(),
+            id: map.get("_id").cloned().unwrap_or(JsonValue::Null),
+            reason,
+        });
+    }
+
+    /// Checks the documents returned by reads of signed collections,
+    /// reporting any that fail. Must run before encrypted fields are opened.
+    pub(super) fn verify_result(&self, command: &str, collection: &str, result: &JsonValue) {
+        if !self.is_signed(collection) {
+            return;
+        }
+        match (command, result) {
+            ("find" | "findByIds", JsonValue::Array(docs)) => docs.iter().for_each(|doc| self.verify(collection, doc)),
+            ("aggregate", JsonValue::Array(docs)) => {
+                docs.iter().filter(|doc| doc.get(&self.field).is_some()).for_each(|doc| self.verify(collection, doc))
+            }
+            ("findOne" | "findById" | "findOneAndDelete", doc) => self.verify(collection, doc),
+            ("watch", change) => {
+                if let Some(doc) = change.get("fullDocument") {
+                    self.verify(collection, doc);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    fn signing() -> (DocumentSigning, Arc<Mutex<Vec<&'static str>>>) {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let signing = DocumentSigning {
+            key: [7; 32],
+            field: DEFAULT_FIELD.to_string(),
+            collections: HashSet::from(["notes".to_string()]),
+            report: Box::new(move |tampered| sink.lock().unwrap().push(tampered.reason)),
+        };
+        (signing, reported)
+    }
+
+    /// Signs `document` as `insertOne` would and returns it as a read would.
+    fn signed(signing: &DocumentSigning, document: JsonValue) -> JsonValue {
+        let mut payload = json!({ "collection": "notes", "data": document.to_string() });
+        signing.sign_payload("insertOne", &mut payload).unwrap();
+        convert::to_json(convert::from_extjson::<Bson>(payload["data"].as_str().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn signed_documents_verify() {
+        let (signing, reported) = signing();
+        let doc = signed(&signing, json!({ "_id": 1, "title": "a", "tags": ["x"], "at": { "$date": "2024-01-01T00:00:00Z" } }));
+        signing.verify_result("findOne", "notes", &doc);
+        // Field order doesn't matter.
+        let mut reordered = serde_json::Map::new();
+        for (key, value) in doc.as_object().unwrap().iter().rev() {
+            reordered.insert(key.clone(), value.clone());
+        }
+        signing.verify_result("find", "notes", &json!([JsonValue::Object(reordered)]));
+        assert!(reported.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tampered_documents_are_reported() {
+        let (signing, reported) = signing();
+        let doc = signed(&signing, json!({ "_id": 1, "title": "a" }));
+        let mut edited = doc.clone();
+        edited["title"] = json!("b");
+        let mut moved = doc.clone();
+        moved["_id"] = json!(2);
+        let mut unsigned = doc.clone();
+        unsigned.as_object_mut().unwrap().remove(DEFAULT_FIELD);
+        signing.verify_result("find", "notes", &json!([edited, moved, unsigned]));
+        assert_eq!(*reported.lock().unwrap(), vec!["mismatch", "mismatch", "missing"]);
+        // Reshaped aggregation results without a signature are left alone.
+        signing.verify_result("aggregate", "notes", &json!([{ "count": 3 }]));
+        assert_eq!(reported.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn ids_are_assigned_or_required() {
+        let (signing, reported) = signing();
+        let doc = signed(&signing, json!({ "title": "a" }));
+        assert!(doc["_id"].get("$oid").is_some());
+        signing.verify_result("findOne", "notes", &doc);
+        assert!(reported.lock().unwrap().is_empty());
+        let mut replace = json!({ "collection": "notes", "replacement": json!({ "title": "b" }).to_string() });
+        assert!(signing.sign_payload("replaceOne", &mut replace).is_err());
+        assert!(signing.sign_payload("updateManyWithProgress", &mut json!({ "collection": "notes" })).is_err());
+    }
+}
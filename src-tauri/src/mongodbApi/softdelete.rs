@@ -0,0 +1,299 @@
+//! Soft deletes for configured collections.
+//!
+//! In a soft-delete collection `deleteById`, `deleteOne`, `deleteMany` and
+//! `findOneAndDelete` stamp the deletion field with the server's time
+//! instead of removing documents, and reads (`find`, `findOne`, `findById`,
+//! `exists`, `count`, `countDocuments`, `distinct`, `aggregate`) leave
+//! stamped documents out unless called with `includeDeleted: true`. A
+//! pipeline is narrowed by a leading `$match`, after the stage it must
+//! start with if there is one, or in the `query` of a leading `$geoNear`.
+//! `restore` clears the stamp and `purge` removes soft-deleted documents for
+//! good.
+
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashSet;
+
+use super::errors::{self, MongoPluginError};
+use super::{coerce_id, convert, CommandContext};
+
+const DEFAULT_FIELD: &str = "deletedAt";
+
+/// Stages a pipeline must start with, which the scoping `$match` follows.
+const LEADING_STAGES: &[&str] = &[
+    "$search",
+    "$searchMeta",
+    "$vectorSearch",
+    "$collStats",
+    "$indexStats",
+    "$documents",
+    "$changeStream",
+    "$currentOp",
+    "$listSessions",
+    "$listLocalSessions",
+    "$listSearchIndexes",
+    "$planCacheStats",
+];
+
+/// The `softDelete` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SoftDeleteConfig {
+    /// Collections whose deletes are soft.
+    pub collections: Vec<String>,
+    /// Field holding the deletion time, `deletedAt` by default.
+    pub field: Option<String>,
+}
+
+pub(super) struct SoftDelete {
+    field: String,
+    collections: HashSet<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct SoftFindByIdArgs {
+    collection: String,
+    id: JsonValue,
+    include_deleted: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct SoftDeleteByIdArgs {
+    collection: String,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+pub(super) struct SoftDeleteArgs {
+    collection: String,
+    filter: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct RestoreArgs {
+    collection: String,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct PurgeArgs {
+    collection: String,
+    id: Option<JsonValue>,
+    older_than_ms: Option<i64>,
+}
+
+impl SoftDelete {
+    pub(super) fn new(config: &SoftDeleteConfig) -> Self {
+        Self {
+            field: config.field.clone().unwrap_or_else(|| DEFAULT_FIELD.to_string()),
+            collections: config.collections.iter().cloned().collect(),
+        }
+    }
+
+    /// The deletion field, if `payload` targets a soft-delete collection.
+    pub(super) fn field_for(&self, payload: &JsonValue) -> Option<&str> {
+        let collection = payload.get("collection").and_then(JsonValue::as_str)?;
+        self.collections.contains(collection).then_some(self.field.as_str())
+    }
+
+    fn collection_field(&self, collection: &str) -> Result<&str, MongoPluginError> {
+        match self.collections.contains(collection) {
+            true => Ok(&self.field),
+            false => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("'{}' is not configured for soft deletes", collection))),
+        }
+    }
+
+    /// Narrows the filter or pipeline of a read to documents that aren't
+    /// soft-deleted, unless the caller passed `includeDeleted`.
+    pub(super) fn scope_reads(&self, command: &str, payload: &mut JsonValue) {
+        let field = match self.field_for(payload) {
+            Some(field) => field.to_string(),
+            None => return,
+        };
+        if payload.get("includeDeleted").and_then(JsonValue::as_bool).unwrap_or(false) {
+            return;
+        }
+        let key = match command {
+            "find" | "findOne" | "findCursor" => "query",
+            "exists" | "count" | "countDocuments" | "distinct" | "findFieldValue" | "exportXlsx" | "timeSeriesAggregate" | "findDuplicates" => "filter",
+            "aggregate" | "aggregateCursor" => "pipeline",
+            _ => return,
+        };
+        let text = match payload.get(key).and_then(JsonValue::as_str) {
+            Some(text) => text,
+            // Scoped like an empty filter, which these may leave out.
+            None if matches!(command, "count" | "countDocuments" | "distinct") => "{}",
+            None => return,
+        };
+        let parsed: Option<JsonValue> = serde_json::from_str(text).ok();
+        let scoped = match parsed {
+            Some(JsonValue::Array(mut stages)) if key == "pipeline" => {
+                scope_pipeline(&mut stages, &field);
+                JsonValue::Array(stages)
+            }
+            Some(filter @ JsonValue::Object(_)) if key != "pipeline" => json!({ "$and": [filter, { &field: null }] }),
+            // Malformed arguments are reported by the command itself.
+            _ => return,
+        };
+        payload[key] = JsonValue::String(scoped.to_string());
+    }
+}
+
+/// Leaves soft-deleted documents out of `stages`. `$geoNear` must come
+/// first, so its `query` is narrowed instead; after the other stages that
+/// must come first, the `$match` comes second.
+fn scope_pipeline(stages: &mut Vec<JsonValue>, field: &str) {
+    let first = stages.first().and_then(JsonValue::as_object);
+    if let Some(geo_near) = first.and_then(|stage| stage.get("$geoNear")).and_then(JsonValue::as_object) {
+        let query = match geo_near.get("query") {
+            Some(query) => json!({ "$and": [query, { field: null }] }),
+            None => json!({ field: null }),
+        };
+        stages[0]["$geoNear"]["query"] = query;
+        return;
+    }
+    let position = match first {
+        Some(stage) if stage.keys().any(|name| LEADING_STAGES.contains(&name.as_str())) => 1,
+        _ => 0,
+    };
+    stages.insert(position, json!({ "$match": { field: null } }));
+}
+
+/// The filter `deleteById` and `findById` use on a soft-delete collection.
+pub(super) fn live_by_id(id: Bson, field: &str) -> Document {
+    doc! { "_id": id, field: Bson::Null }
+}
+
+/// The update that soft-deletes a document, stamped with the server's time.
+pub(super) fn stamp(field: &str) -> Document {
+    doc! { "$currentDate": { field: true } }
+}
+
+pub(super) async fn find_by_id(db: Database, field: String, args: SoftFindByIdArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let id = coerce_id(&args.id)?;
+    let filter = if args.include_deleted.unwrap_or(false) { doc! { "_id": id } } else { live_by_id(id, &field) };
+    match coll.find_one(filter, None).await {
+        Ok(result) => Ok(convert::to_json(result)),
+        Err(e) => Err(errors::failed("Failed to find document", e)),
+    }
+}
+
+/// Soft-deletes a document, reporting it in `deletedCount` like a real
+/// delete. Deleting an already deleted document counts as nothing deleted.
+pub(super) async fn delete_by_id(db: Database, field: String, args: SoftDeleteByIdArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    match coll.update_one(live_by_id(coerce_id(&args.id)?, &field), stamp(&field), None).await {
+        Ok(result) => Ok(json!({ "deletedCount": result.modified_count })),
+        Err(e) => Err(errors::failed("Failed to delete document", e)),
+    }
+}
+
+/// `filter` narrowed to documents that aren't soft-deleted yet.
+pub(super) fn live(filter: &str, field: &str) -> Result<Document, MongoPluginError> {
+    match convert::from_extjson::<Document>(filter) {
+        Ok(filter) => Ok(doc! { "$and": [filter, { field: Bson::Null }] }),
+        Err(e) => Err(errors::invalid("Failed to parse filter", e)),
+    }
+}
+
+pub(super) async fn delete_matching(db: Database, field: String, args: SoftDeleteArgs, many: bool) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    let filter = live(&args.filter, &field)?;
+    let result = if many {
+        coll.update_many(filter, stamp(&field), None).await
+    } else {
+        coll.update_one(filter, stamp(&field), None).await
+    };
+    match result {
+        Ok(result) => Ok(json!({ "deletedCount": result.modified_count })),
+        Err(e) => Err(errors::failed("Failed to delete documents", e)),
+    }
+}
+
+/// Soft-deletes the first match and returns it as it was before.
+pub(super) async fn find_one_and_delete(db: Database, field: String, args: SoftDeleteArgs) -> Result<JsonValue, MongoPluginError> {
+    let coll = db.collection::<Document>(&args.collection);
+    match coll.find_one_and_update(live(&args.filter, &field)?, stamp(&field), None).await {
+        Ok(result) => Ok(convert::to_json(result)),
+        Err(e) => Err(errors::failed("Failed to delete document", e)),
+    }
+}
+
+/// Brings a soft-deleted document back.
+pub(super) async fn restore(ctx: CommandContext, args: RestoreArgs) -> Result<JsonValue, MongoPluginError> {
+    let soft_delete = match &ctx.soft_delete {
+        Some(soft_delete) => soft_delete,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Soft deletes are not configured")),
+    };
+    let field = soft_delete.collection_field(&args.collection)?;
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    let filter = doc! { "_id": coerce_id(&args.id)?, field: { "$ne": Bson::Null } };
+    match coll.update_one(filter, doc! { "$unset": { field: "" } }, None).await {
+        Ok(result) => Ok(json!({ "restored": result.modified_count > 0 })),
+        Err(e) => Err(errors::failed("Failed to restore document", e)),
+    }
+}
+
+/// Permanently removes soft-deleted documents: one by `id`, or all of them,
+/// optionally only those deleted more than `olderThanMs` ago.
+pub(super) async fn purge(ctx: CommandContext, args: PurgeArgs) -> Result<JsonValue, MongoPluginError> {
+    let soft_delete = match &ctx.soft_delete {
+        Some(soft_delete) => soft_delete,
+        None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "Soft deletes are not configured")),
+    };
+    let field = soft_delete.collection_field(&args.collection)?;
+    let mut filter = doc! { field: { "$ne": Bson::Null } };
+    if let Some(id) = &args.id {
+        filter.insert("_id", coerce_id(id)?);
+    }
+    if let Some(older_than_ms) = args.older_than_ms {
+        let path = format!("${}", field);
+        filter.insert("$expr", doc! { "$lt": [path, { "$subtract": ["$$NOW", older_than_ms] }] });
+    }
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    match coll.delete_many(filter, None).await {
+        Ok(result) => Ok(json!({ "deletedCount": result.deleted_count })),
+        Err(e) => Err(errors::failed("Failed to purge documents", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scoped(pipeline: JsonValue) -> JsonValue {
+        let soft_delete = SoftDelete::new(&SoftDeleteConfig { collections: vec!["places".to_string()], field: None });
+        let mut payload = json!({ "collection": "places", "pipeline": pipeline.to_string() });
+        soft_delete.scope_reads("aggregate", &mut payload);
+        serde_json::from_str(payload["pipeline"].as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn geo_near_stays_first_with_its_query_narrowed() {
+        let near = json!({ "near": { "type": "Point", "coordinates": [0, 0] }, "distanceField": "distance" });
+        let mut with_query = near.clone();
+        with_query["query"] = json!({ "kind": "cafe" });
+        assert_eq!(
+            scoped(json!([{ "$geoNear": near }, { "$limit": 5 }])),
+            json!([{ "$geoNear": { "near": near["near"], "distanceField": "distance", "query": { "deletedAt": null } } }, { "$limit": 5 }])
+        );
+        assert_eq!(
+            scoped(json!([{ "$geoNear": with_query }])),
+            json!([{ "$geoNear": { "near": near["near"], "distanceField": "distance", "query": { "$and": [{ "kind": "cafe" }, { "deletedAt": null }] } } }])
+        );
+    }
+
+    #[test]
+    fn match_follows_stages_that_must_come_first() {
+        assert_eq!(
+            scoped(json!([{ "$search": { "text": { "query": "cafe", "path": "name" } } }])),
+            json!([{ "$search": { "text": { "query": "cafe", "path": "name" } } }, { "$match": { "deletedAt": null } }])
+        );
+        assert_eq!(scoped(json!([{ "$limit": 5 }])), json!([{ "$match": { "deletedAt": null } }, { "$limit": 5 }]));
+    }
+}
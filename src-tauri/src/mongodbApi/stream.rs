@@ -0,0 +1,209 @@
+//! Streaming a read to the frontend in batches, paced by the frontend.
+//!
+//! `find` and `aggregate` called with `output: "stream"` return a
+//! `streamId` at once and then emit `mongo://stream-batch` events of
+//! `{ streamId, index, documents, done }`. Only `window` batches (1 by
+//! default) are ever sent ahead of `ackStreamBatch` calls, and the cursor's
+//! server batch size is the stream's batch size, so the next batch isn't
+//! even fetched from the server until the frontend has caught up. A stream
+//! left unacknowledged for five minutes is closed. Only the window, and
+//! tenant, that started a stream can acknowledge or cancel it.
+
+use futures::TryStreamExt;
+use mongodb::bson::{oid::ObjectId, Document};
+use mongodb::Cursor;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::sync::Semaphore;
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, read_options, CommandContext, MongoState, Owner};
+
+pub(super) const BATCH_EVENT: &str = "mongo://stream-batch";
+const DEFAULT_BATCH_SIZE: u32 = 100;
+const DEFAULT_WINDOW: usize = 1;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// An open stream's permits, which let it send more batches.
+struct OpenStream {
+    permits: Arc<Semaphore>,
+    owner: Owner,
+}
+
+/// Open streams.
+#[derive(Default)]
+pub(super) struct Streams {
+    open: Mutex<HashMap<String, OpenStream>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct FindStreamArgs {
+    collection: String,
+    query: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::FindCommandOptions,
+    batch_size: Option<u32>,
+    window: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct AggregateStreamArgs {
+    collection: String,
+    pipeline: String,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    options: read_options::AggregateCommandOptions,
+    batch_size: Option<u32>,
+    window: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct StreamArgs {
+    stream_id: String,
+}
+
+impl Streams {
+    /// Stops every open stream.
+    pub(super) fn close_all(&self) {
+        for (_, stream) in self.open.lock().unwrap().drain() {
+            stream.permits.close();
+        }
+    }
+}
+
+/// Whether a command's arguments ask for its results as a stream.
+pub(super) fn to_stream(payload: &JsonValue) -> bool {
+    payload.get("output").and_then(JsonValue::as_str) == Some("stream")
+}
+
+/// Starts sending the cursor's documents and returns the stream's id.
+fn start(
+    ctx: CommandContext,
+    command: &'static str,
+    collection: String,
+    cursor: Cursor<Document>,
+    batch_size: u32,
+    window: Option<usize>,
+) -> JsonValue {
+    let stream_id = ObjectId::new().to_hex();
+    let permits = Arc::new(Semaphore::new(window.unwrap_or(DEFAULT_WINDOW).max(1)));
+    ctx.streams.open.lock().unwrap().insert(stream_id.clone(), OpenStream { permits: permits.clone(), owner: ctx.owner() });
+    let id = stream_id.clone();
+    // On whichever runtime the command runs on, see `runtime`.
+    tokio::spawn(async move {
+        send_batches(&ctx, command, &id, &collection, cursor, batch_size as usize, &permits).await;
+        ctx.streams.open.lock().unwrap().remove(&id);
+    });
+    json!({ "streamId": stream_id })
+}
+
+async fn send_batches(
+    ctx: &CommandContext,
+    command: &str,
+    id: &str,
+    collection: &str,
+    mut cursor: Cursor<Document>,
+    batch_size: usize,
+    permits: &Semaphore,
+) {
+    let mut index = 0;
+    loop {
+        match tokio::time::timeout(IDLE_TIMEOUT, permits.acquire()).await {
+            Ok(Ok(permit)) => permit.forget(),
+            // Cancelled.
+            Ok(Err(_)) => return,
+            Err(_) => {
+                let error = "Stream closed after going unacknowledged";
+                (ctx.events)(BATCH_EVENT, json!({ "streamId": id, "index": index, "error": error, "done": true }));
+                return;
+            }
+        }
+        // Grown as documents arrive: the batch size is the caller's.
+        let mut documents = Vec::new();
+        let mut done = false;
+        while documents.len() < batch_size {
+            match cursor.try_next().await {
+                Ok(Some(doc)) => documents.push(convert::to_json(doc)),
+                Ok(None) => {
+                    done = true;
+                    break;
+                }
+                Err(e) => {
+                    let error = format!("Failed to read results: {}", e);
+                    (ctx.events)(BATCH_EVENT, json!({ "streamId": id, "index": index, "error": error, "done": true }));
+                    return;
+                }
+            }
+        }
+        let mut documents = JsonValue::Array(documents);
+        ctx.transforms.finish(command, Some(collection), &mut documents);
+        (ctx.events)(BATCH_EVENT, json!({ "streamId": id, "index": index, "documents": documents, "done": done }));
+        if done {
+            return;
+        }
+        index += 1;
+    }
+}
+
+pub(super) async fn find_stream(ctx: CommandContext, args: FindStreamArgs) -> Result<JsonValue, MongoPluginError> {
+    let query: Document = match convert::from_extjson(&args.query) {
+        Ok(query) => query,
+        Err(e) => return Err(errors::invalid("Failed to parse query", e)),
+    };
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut options = args.options.find(args.max_time_ms);
+    options.batch_size = Some(batch_size);
+    let cursor = match ctx.db.collection::<Document>(&args.collection).find(query, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute query", e)),
+    };
+    Ok(start(ctx, "find", args.collection, cursor, batch_size, args.window))
+}
+
+pub(super) async fn aggregate_stream(ctx: CommandContext, args: AggregateStreamArgs) -> Result<JsonValue, MongoPluginError> {
+    let pipeline: Vec<Document> = match convert::from_extjson(&args.pipeline) {
+        Ok(pipeline) => pipeline,
+        Err(e) => return Err(errors::invalid("Failed to parse pipeline", e)),
+    };
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut options = args.options.aggregate(args.max_time_ms);
+    options.batch_size = Some(batch_size);
+    let cursor = match ctx.db.collection::<Document>(&args.collection).aggregate(pipeline, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute aggregation", e)),
+    };
+    Ok(start(ctx, "aggregate", args.collection, cursor, batch_size, args.window))
+}
+
+fn permits<R: Runtime>(app: &AppHandle<R>, owner: &Owner, stream_id: &str) -> Result<Arc<Semaphore>, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let open = state.streams.open.lock().unwrap();
+    match open.get(stream_id) {
+        Some(stream) if stream.owner == *owner => Ok(stream.permits.clone()),
+        _ => Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No open stream '{}'", stream_id))),
+    }
+}
+
+/// Lets the stream send one more batch.
+pub(super) async fn ack_stream_batch<R: Runtime>(app: AppHandle<R>, owner: Owner, args: StreamArgs) -> Result<JsonValue, MongoPluginError> {
+    permits(&app, &owner, &args.stream_id)?.add_permits(1);
+    Ok(serde_json::to_value("success").unwrap())
+}
+
+/// Stops the stream; no further batches are sent.
+pub(super) async fn cancel_stream<R: Runtime>(app: AppHandle<R>, owner: Owner, args: StreamArgs) -> Result<JsonValue, MongoPluginError> {
+    permits(&app, &owner, &args.stream_id)?.close();
+    let state = app.state::<MongoState>();
+    state.streams.open.lock().unwrap().remove(&args.stream_id);
+    Ok(serde_json::to_value("success").unwrap())
+}
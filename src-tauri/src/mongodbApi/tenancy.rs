@@ -0,0 +1,153 @@
+//! One database per customer, picked by the app rather than the frontend.
+//!
+//! The app registers a callback with [`MongoPlugin::tenant`](super::MongoPlugin::tenant)
+//! that maps the invoking window's label and the current app role to the
+//! name of the tenant's database. Like the policy callback it runs on every
+//! invoke, and every command that reads or writes documents then runs in
+//! that database rather than the one `connectDBServer` named, so commands
+//! leave the database out. Arguments that would reach another database, a
+//! `database` argument, a saved query's `db.collection` namespace or a
+//! pipeline stage's `db`, as `$out`, `$merge`, `$lookup` and `$unionWith`
+//! take, are refused, as is `runCommand`, and so is every such command while the
+//! callback has no tenant for the window, e.g. before the user signs in.
+//! Namespaces in the window's [`Permissions`](super::policy::Permissions)
+//! are matched against the tenant's database.
+
+use serde_json::Value as JsonValue;
+
+use super::errors::{self, MongoPluginError};
+use super::namespaces;
+
+/// Signature of the tenant callback: `(window_label, app_role)`.
+pub type TenantFn = dyn Fn(&str, Option<&str>) -> Option<String> + Send + Sync;
+
+/// Commands that touch no documents, which run without a tenant.
+const TENANTLESS: &[&str] = &[
+    "connectDBServer",
+    "listConnections",
+    "closeConnection",
+    "startLeaderElection",
+    "stopLeaderElection",
+    "isLeader",
+    "getQueryHistory",
+    "clearQueryHistory",
+    "listPipelines",
+    "listQueries",
+    "diffDocuments",
+    "deleteExportFile",
+    "ackStreamBatch",
+    "cancelStream",
+    "cursorNext",
+    "cursorClose",
+    "startSession",
+    "startTransaction",
+    "commitTransaction",
+    "abortTransaction",
+    "endSession",
+    "ping",
+    "serverStatus",
+    "unwatch",
+    "getOperationStatus",
+    "listOperations",
+    "pauseOperation",
+    "resumeOperation",
+    "cancelOperation",
+    "getTopology",
+    "replicaSetHealth",
+    "readResultPage",
+    "releaseResult",
+];
+
+/// Commands whose arguments can name any database, never run for a tenant.
+const UNSCOPED: &[&str] = &["runCommand", "createDataKey", "listDataKeys", "deleteDataKey", "addKeyAltName", "removeKeyAltName"];
+
+/// Databases the server keeps for itself, never a tenant's.
+const RESERVED: &[&str] = &["admin", "config", "local"];
+
+fn check_name(database: &str) -> Result<(), MongoPluginError> {
+    let valid = !database.is_empty() && database.len() < 64 && !database.contains(['/', '\\', '.', ' ', '"', '$', '\0']);
+    if !valid || RESERVED.contains(&database) {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("'{}' can't be a tenant database", database)));
+    }
+    Ok(())
+}
+
+fn denied(message: String) -> MongoPluginError {
+    MongoPluginError::new(errors::ErrorKind::PermissionDenied, message)
+}
+
+/// The tenant database `command` runs in, or `None` without tenancy and for
+/// commands that need no tenant.
+pub(super) fn resolve(tenant: Option<&TenantFn>, label: &str, role: Option<&str>, command: &str) -> Result<Option<String>, MongoPluginError> {
+    let tenant = match tenant {
+        Some(tenant) => tenant,
+        None => return Ok(None),
+    };
+    if UNSCOPED.contains(&command) {
+        return Err(denied(format!("Permission denied: '{}' can reach other databases, so it can't run for a tenant", command)));
+    }
+    match tenant(label, role) {
+        Some(database) => check_name(&database).map(|_| Some(database)),
+        None if TENANTLESS.contains(&command) => Ok(None),
+        None => Err(denied(format!("Permission denied: no tenant for this window, so '{}' can't run", command))),
+    }
+}
+
+/// Rejects `database` unless it is the tenant's own.
+pub(super) fn check_database(tenant: Option<&str>, database: &str) -> Result<(), MongoPluginError> {
+    match tenant {
+        Some(tenant) if tenant != database => Err(denied(format!("Permission denied: '{}' is not this window's tenant database", database))),
+        _ => Ok(()),
+    }
+}
+
+/// Rejects the pipeline `command` carries, or any step of a batch, if one of
+/// its stages names a database other than the tenant's.
+pub(super) fn check_pipeline(tenant: Option<&str>, command: &str, payload: &JsonValue) -> Result<(), MongoPluginError> {
+    if tenant.is_none() {
+        return Ok(());
+    }
+    if let ("executeBatch" | "executeTransactionalBatch", Some(operations)) = (command, payload.get("operations").and_then(JsonValue::as_array)) {
+        for operation in operations {
+            let step = operation.get("command").and_then(JsonValue::as_str).unwrap_or_default();
+            check_pipeline(tenant, step, operation.get("args").unwrap_or(&JsonValue::Null))?;
+        }
+    }
+    for target in namespaces::pipeline_targets(command, payload) {
+        if let Some(database) = &target.database {
+            check_database(tenant, database)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn aggregate(pipeline: JsonValue) -> JsonValue {
+        json!({ "collection": "orders", "pipeline": pipeline.to_string() })
+    }
+
+    #[test]
+    fn out_and_merge_stay_in_the_tenant_database() {
+        let tenant = Some("acme");
+        assert!(check_pipeline(tenant, "aggregate", &aggregate(json!([{ "$out": "totals" }]))).is_ok());
+        assert!(check_pipeline(tenant, "aggregate", &aggregate(json!([{ "$out": { "db": "acme", "coll": "totals" } }]))).is_ok());
+        assert!(check_pipeline(tenant, "aggregate", &aggregate(json!([{ "$out": { "db": "globex", "coll": "totals" } }]))).is_err());
+        assert!(check_pipeline(tenant, "aggregate", &aggregate(json!([{ "$merge": { "into": "totals" } }]))).is_ok());
+        assert!(check_pipeline(tenant, "aggregate", &aggregate(json!([{ "$merge": { "into": { "db": "globex", "coll": "totals" } } }]))).is_err());
+    }
+
+    #[test]
+    fn nested_and_batched_stages_are_checked() {
+        let tenant = Some("acme");
+        let lookup = json!([{ "$facet": { "all": [{ "$lookup": { "from": { "db": "globex", "coll": "users" }, "as": "users" } }] } }]);
+        assert!(check_pipeline(tenant, "aggregate", &aggregate(lookup)).is_err());
+        let union = json!([{ "$unionWith": { "coll": "archive", "pipeline": [{ "$merge": { "into": { "db": "admin", "coll": "x" } } }] } }]);
+        let batch = json!({ "operations": [{ "command": "aggregate", "args": aggregate(union) }] });
+        assert!(check_pipeline(tenant, "executeBatch", &batch).is_err());
+        assert!(check_pipeline(None, "aggregate", &aggregate(json!([{ "$out": { "db": "globex", "coll": "totals" } }]))).is_ok());
+    }
+}
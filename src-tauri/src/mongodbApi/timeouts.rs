@@ -0,0 +1,147 @@
+//! Per-command timeouts, and cancelling commands while they run.
+//!
+//! Every command on the connected database takes `timeoutMs`. Reads that
+//! take `maxTimeMS` get it as theirs, or keep the shorter of the two, so the
+//! server gives up on them as well, and the plugin stops waiting for any
+//! command after that long, failing it as a `timeout`. A write that timed
+//! out this way may still have been applied.
+//!
+//! They also take an `operationId` of the caller's choosing, and are then
+//! listed by `listOperations` as `command` operations until they end.
+//! `cancelOperation` with the id stops waiting for the command and fails it
+//! as `cancelled`: a cursor it had open is closed as it is dropped, and the
+//! server operations tagged with the id as their `comment`, which `find`,
+//! `findOne`, `aggregate` and their cursors carry, are ended with `killOp`.
+//! Commands that run as operations of their own, such as
+//! `updateManyWithProgress`, `cloneCollection` and the exports, take the
+//! same `operationId` and keep stopping between batches.
+
+use futures::future::{self, Either};
+use mongodb::bson::{doc, Bson};
+use mongodb::Client;
+use serde_json::{json, Map, Value as JsonValue};
+use std::time::Duration;
+
+use super::errors::{self, MongoPluginError};
+use super::{export, guards, CommandContext, CommandFuture};
+
+/// Reads whose `options` carry a `comment`.
+const TAGGED_COMMANDS: &[&str] = &["find", "findOne", "findCursor", "aggregate", "aggregateCursor"];
+
+/// What a command asked for beyond its own arguments.
+pub(super) struct Limits {
+    command: String,
+    timeout: Option<Duration>,
+    operation_id: Option<String>,
+}
+
+/// Whether the command registers and reports an operation itself.
+fn own_operation(command: &str, payload: &JsonValue) -> bool {
+    match command {
+        "find" | "aggregate" => export::to_file(payload),
+        _ => matches!(
+            command,
+            "updateManyWithProgress"
+                | "archiveDocuments"
+                | "unarchive"
+                | "cloneCollection"
+                | "exportCollection"
+                | "importCollection"
+                | "migrateUp"
+                | "migrateDown"
+                | "exportXlsx"
+                | "gridfsUpload"
+                | "gridfsDownload"
+        ),
+    }
+}
+
+/// Takes `timeoutMs` and `operationId` from the payload, passing the
+/// timeout on as `maxTimeMS` and the id as the `comment` of reads that
+/// take them.
+pub(super) fn prepare(command: &str, payload: &mut JsonValue) -> Limits {
+    let timeout_ms = payload.get("timeoutMs").and_then(JsonValue::as_u64);
+    let operation_id = match payload.get("operationId").and_then(JsonValue::as_str) {
+        Some(_) if own_operation(command, payload) => None,
+        Some(id) => Some(id.to_string()),
+        None => None,
+    };
+    if let JsonValue::Object(args) = payload {
+        if let (Some(timeout_ms), true) = (timeout_ms, guards::MAX_TIME_COMMANDS.contains(&command)) {
+            let requested = args.get("maxTimeMS").and_then(JsonValue::as_u64);
+            args.insert("maxTimeMS".to_string(), json!(requested.map_or(timeout_ms, |requested| requested.min(timeout_ms))));
+        }
+        if let (Some(id), true) = (&operation_id, TAGGED_COMMANDS.contains(&command)) {
+            let options = args.entry("options").or_insert_with(|| JsonValue::Object(Map::new()));
+            if let JsonValue::Object(options) = options {
+                options.insert("comment".to_string(), json!(id));
+            }
+        }
+    }
+    Limits { command: command.to_string(), timeout: timeout_ms.map(Duration::from_millis), operation_id }
+}
+
+/// Ends the server operations, including cursors' `getMore`s, that carry
+/// `tag` as their comment. Best effort: without the `inprog` and `killop`
+/// privileges the command is only abandoned.
+async fn kill_tagged(client: &Client, tag: &str) {
+    let admin = client.database("admin");
+    let current = doc! {
+        "currentOp": true,
+        "$or": [{ "command.comment": tag }, { "cursor.originatingCommand.comment": tag }],
+    };
+    let running = match admin.run_command(current, None).await {
+        Ok(running) => running,
+        Err(_) => return,
+    };
+    for op in running.get_array("inprog").into_iter().flatten().filter_map(Bson::as_document) {
+        if let Some(opid) = op.get("opid") {
+            let _ = admin.run_command(doc! { "killOp": 1, "op": opid.clone() }, None).await;
+        }
+    }
+}
+
+impl Limits {
+    /// Runs `task` within the timeout, as a cancellable operation when the
+    /// command was given an id.
+    pub(super) fn bound(self, ctx: &CommandContext, task: CommandFuture) -> CommandFuture {
+        if self.timeout.is_none() && self.operation_id.is_none() {
+            return task;
+        }
+        let mut operation = match self.operation_id.map(|id| ctx.operations.start(&ctx.events, ctx.owner(), "command", Some(id))).transpose() {
+            Ok(operation) => operation,
+            Err(e) => return Box::pin(async move { Err(e.into()) }),
+        };
+        let (command, timeout, client) = (self.command, self.timeout, ctx.client.clone());
+        Box::pin(async move {
+            let name = command.clone();
+            let run: CommandFuture = Box::pin(async move {
+                match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, task).await.unwrap_or_else(|_| {
+                        let message = format!("Command '{}' timed out after {} ms", name, timeout.as_millis());
+                        Err(MongoPluginError::new(errors::ErrorKind::Timeout, message).into())
+                    }),
+                    None => task.await,
+                }
+            });
+            let outcome = match operation.as_mut() {
+                None => run.await,
+                Some(running) => {
+                    let id = running.id().to_string();
+                    match future::select(run, Box::pin(running.cancel_requested())).await {
+                        Either::Left((outcome, _)) => outcome,
+                        Either::Right(_) => {
+                            kill_tagged(&client, &id).await;
+                            let message = format!("Command '{}' was cancelled", command);
+                            Err(MongoPluginError::new(errors::ErrorKind::Cancelled, message).into())
+                        }
+                    }
+                }
+            };
+            if let Some(operation) = operation {
+                operation.finish(&outcome);
+            }
+            outcome
+        })
+    }
+}
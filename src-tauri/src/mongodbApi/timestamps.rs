@@ -0,0 +1,160 @@
+//! Creation and update times kept on the documents of configured
+//! collections.
+//!
+//! Collections listed under `timestamps.collections` in the plugin config
+//! have `createdAt` set on the documents inserted through the plugin and
+//! `updatedAt` set on every write to them, both to the app's clock at the
+//! time of the command. Inserts set both fields where the document leaves
+//! them out. Updates set `updatedAt`, and `createdAt` on a document an
+//! upsert inserts, unless the update already sets the field. Replacements,
+//! through `replaceOne` or `upsertMany`, set `updatedAt` but keep
+//! `createdAt` only if the new document carries it. The field names can be
+//! changed with `createdField` and `updatedField`.
+
+use mongodb::bson::{Bson, DateTime, Document};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use super::bulk_write::{self, Written};
+use super::{convert, MongoConfig};
+
+const DEFAULT_CREATED_FIELD: &str = "createdAt";
+const DEFAULT_UPDATED_FIELD: &str = "updatedAt";
+
+/// The `timestamps` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TimestampsConfig {
+    /// Collections whose documents carry creation and update times.
+    pub collections: Vec<String>,
+    /// Field holding the creation time, `createdAt` by default.
+    pub created_field: Option<String>,
+    /// Field holding the time of the last write, `updatedAt` by default.
+    pub updated_field: Option<String>,
+}
+
+impl TimestampsConfig {
+    fn created_field(&self) -> &str {
+        self.created_field.as_deref().unwrap_or(DEFAULT_CREATED_FIELD)
+    }
+
+    fn updated_field(&self) -> &str {
+        self.updated_field.as_deref().unwrap_or(DEFAULT_UPDATED_FIELD)
+    }
+}
+
+/// The timestamps config, if `collection` keeps timestamps.
+fn stamped<'a>(config: &'a MongoConfig, collection: &str) -> Option<&'a TimestampsConfig> {
+    config.timestamps.as_ref().filter(|timestamps| timestamps.collections.iter().any(|c| c == collection))
+}
+
+/// Whether any operator of `update` already writes `field`.
+fn writes(update: &Document, field: &str) -> bool {
+    update.values().any(|fields| matches!(fields, Bson::Document(fields) if fields.contains_key(field)))
+}
+
+fn operator<'a>(update: &'a mut Document, name: &str) -> Option<&'a mut Document> {
+    if !update.contains_key(name) {
+        update.insert(name, Document::new());
+    }
+    update.get_document_mut(name).ok()
+}
+
+/// Adds the timestamps to an update of `collection`, if it keeps them.
+fn touch(config: &MongoConfig, collection: &str, update: &mut Document) {
+    let timestamps = match stamped(config, collection) {
+        Some(timestamps) => timestamps,
+        None => return,
+    };
+    // Replacements and malformed updates are left to the command.
+    if update.is_empty() || !update.keys().all(|key| key.starts_with('$')) {
+        return;
+    }
+    let now = DateTime::now();
+    let (created, updated) = (timestamps.created_field(), timestamps.updated_field());
+    if !writes(update, updated) {
+        if let Some(set) = operator(update, "$set") {
+            set.insert(updated, now);
+        }
+    }
+    if !writes(update, created) {
+        if let Some(set_on_insert) = operator(update, "$setOnInsert") {
+            set_on_insert.insert(created, now);
+        }
+    }
+}
+
+/// Adds the timestamps to the documents and updates in a command's payload.
+pub(super) fn stamp(config: &MongoConfig, command: &str, payload: &mut JsonValue) {
+    let timestamps = match payload.get("collection").and_then(JsonValue::as_str).and_then(|collection| stamped(config, collection)) {
+        Some(timestamps) => timestamps,
+        None => return,
+    };
+    if command == "bulkWrite" {
+        let collection = payload["collection"].as_str().unwrap_or_default().to_string();
+        let now = DateTime::now();
+        let (created, updated) = (timestamps.created_field().to_string(), timestamps.updated_field().to_string());
+        let _ = bulk_write::rewrite_documents(payload, |written, doc| {
+            match written {
+                Written::Inserted => {
+                    doc.entry(created.clone()).or_insert(Bson::DateTime(now));
+                    doc.entry(updated.clone()).or_insert(Bson::DateTime(now));
+                }
+                Written::Replacement => {
+                    doc.insert(updated.as_str(), now);
+                }
+                Written::Update => touch(config, &collection, doc),
+            }
+            Ok(())
+        });
+        return;
+    }
+    let key = match command {
+        "insertOne" | "insertMany" => "data",
+        "upsertMany" => "documents",
+        "replaceOne" => "replacement",
+        "updateById" | "updateOne" | "updateMany" | "findOneAndUpdate" | "updateWithVersion" | "updateManyWithProgress" => "update",
+        _ => return,
+    };
+    let parsed = match payload.get(key).and_then(JsonValue::as_str).map(convert::from_extjson::<Bson>) {
+        Some(Ok(parsed)) => parsed,
+        // Malformed arguments are reported by the command itself.
+        _ => return,
+    };
+    let now = DateTime::now();
+    let (created, updated) = (timestamps.created_field(), timestamps.updated_field());
+    let stamped = match (key, parsed) {
+        ("update", Bson::Document(mut update)) => {
+            touch(config, payload["collection"].as_str().unwrap_or_default(), &mut update);
+            Bson::Document(update)
+        }
+        ("data", Bson::Document(mut doc)) => {
+            doc.entry(created.to_string()).or_insert(Bson::DateTime(now));
+            doc.entry(updated.to_string()).or_insert(Bson::DateTime(now));
+            Bson::Document(doc)
+        }
+        ("data", Bson::Array(mut docs)) => {
+            for doc in docs.iter_mut() {
+                if let Bson::Document(doc) = doc {
+                    doc.entry(created.to_string()).or_insert(Bson::DateTime(now));
+                    doc.entry(updated.to_string()).or_insert(Bson::DateTime(now));
+                }
+            }
+            Bson::Array(docs)
+        }
+        (_, Bson::Document(mut doc)) => {
+            doc.insert(updated, now);
+            Bson::Document(doc)
+        }
+        (_, Bson::Array(mut docs)) => {
+            for doc in docs.iter_mut() {
+                if let Bson::Document(doc) = doc {
+                    doc.insert(updated, now);
+                }
+            }
+            Bson::Array(docs)
+        }
+        _ => return,
+    };
+    payload[key] = JsonValue::String(convert::to_extjson_text(stamped));
+}
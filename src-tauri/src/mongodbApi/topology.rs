@@ -0,0 +1,321 @@
+//! Server discovery and monitoring (SDAM) for the connected client.
+//!
+//! The driver reports topology changes to a [`TopologyMonitor`] installed on
+//! every connection. It keeps the latest description for `getTopology` and,
+//! with `topologyEvents` on, forwards the interesting events to the
+//! frontend as `mongo://server-opened`, `mongo://server-closed`,
+//! `mongo://topology-changed` and `mongo://heartbeat-failed`.
+//!
+//! Servers joining or leaving the topology, as when SRV polling on a
+//! `mongodb+srv://` connection picks up a scaled cluster's new seedlist, are
+//! also reported as `mongo://membership-changed` with the addresses `added`
+//! and `removed`. The latest changes are kept for `getTopology`.
+//!
+//! Whether or not `topologyEvents` is on, the connection is reported as
+//! `mongo://connected` when a server first answers, and again each time one
+//! answers after none did, and as `mongo://disconnected` when no server
+//! answers any more, both with the `connectionId` and the topology `type`,
+//! so the frontend can show its connection state without polling `ping`.
+//!
+//! Whether or not `topologyEvents` is on, the primary stepping down is
+//! reported as `mongo://failover` with `oldPrimary` and `newPrimary` null
+//! and `electing` true, and the primary that wins the election with both
+//! set and `electing` false. Writes sent meanwhile wait for the new primary,
+//! see [`TopologyMonitor::wait_for_primary`].
+//!
+//! Each server's latest heartbeat round trips are kept for `getTopology`
+//! as its `latency`: the last, lowest, average and highest of up to twenty,
+//! with the failed heartbeats counted. Heartbeats the server held open
+//! waiting for a change, as it does on 4.4 and later, say nothing about
+//! the link and are left out. `activeServer` is the server the latest
+//! command went to.
+
+use mongodb::event::sdam::{
+    SdamEventHandler, ServerClosedEvent, ServerHeartbeatFailedEvent, ServerHeartbeatSucceededEvent, ServerOpeningEvent,
+    TopologyDescription,
+    TopologyDescriptionChangedEvent,
+};
+use serde_json::{json, Value as JsonValue};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use mongodb::ServerType;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::sync::watch;
+
+use super::errors::MongoPluginError;
+use super::events::EventSink;
+use super::{MongoState, NoArgs};
+
+/// Membership changes kept for `getTopology`.
+const MAX_MEMBERSHIP_CHANGES: usize = 50;
+/// Heartbeat round trips kept per server.
+const LATENCY_SAMPLES: usize = 20;
+/// Longest a write waits for an election to finish, a little over the
+/// server's default election timeout.
+pub(super) const FAILOVER_WAIT: Duration = Duration::from_secs(12);
+
+pub(super) struct TopologyMonitor {
+    latest: Mutex<Option<TopologyDescription>>,
+    events: EventSink,
+    topology_events: bool,
+    /// The last primary seen, kept through an election.
+    primary: Mutex<Option<String>>,
+    /// Whether the primary has stepped down and no new one is known yet.
+    electing: watch::Sender<bool>,
+    /// The SRV record's host for `mongodb+srv://` connections.
+    srv_host: Option<String>,
+    membership_changes: Mutex<VecDeque<JsonValue>>,
+    latency: Mutex<HashMap<String, Latency>>,
+    /// The server the latest command went to, and when.
+    active_server: Mutex<Option<(String, u64)>>,
+    connection_id: String,
+    /// Whether a server answered its last heartbeat.
+    reachable: watch::Sender<bool>,
+}
+
+/// A server's recent heartbeats.
+#[derive(Default)]
+struct Latency {
+    round_trips_ms: VecDeque<f64>,
+    failures: u64,
+    last_at: Option<u64>,
+}
+
+impl Latency {
+    fn record(&mut self, round_trip: Duration) {
+        self.round_trips_ms.push_back(round_trip.as_secs_f64() * 1000.0);
+        while self.round_trips_ms.len() > LATENCY_SAMPLES {
+            self.round_trips_ms.pop_front();
+        }
+        self.last_at = Some(now_ms());
+    }
+
+    fn view(&self) -> JsonValue {
+        let samples = &self.round_trips_ms;
+        let average = (!samples.is_empty()).then(|| samples.iter().sum::<f64>() / samples.len() as f64);
+        json!({
+            "lastMs": samples.back(),
+            "minMs": samples.iter().copied().reduce(f64::min),
+            "avgMs": average,
+            "maxMs": samples.iter().copied().reduce(f64::max),
+            "samples": samples.len(),
+            "failures": self.failures,
+            "lastAt": self.last_at,
+        })
+    }
+}
+
+/// The host the seedlist is polled from, for a `mongodb+srv://` URI.
+pub(super) fn srv_host(uri: &str) -> Option<String> {
+    let rest = uri.strip_prefix("mongodb+srv://")?;
+    let authority = &rest[..rest.find(['/', '?']).unwrap_or(rest.len())];
+    Some(authority.rsplit_once('@').map_or(authority, |(_, host)| host).to_string())
+}
+
+fn addresses(topology: &TopologyDescription) -> BTreeSet<String> {
+    topology.servers().into_keys().map(|address| address.to_string()).collect()
+}
+
+fn primary_of(topology: &TopologyDescription) -> Option<String> {
+    let mut servers = topology.servers().into_iter();
+    servers.find(|(_, server)| server.server_type() == ServerType::RsPrimary).map(|(address, _)| address.to_string())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+impl TopologyMonitor {
+    /// A monitor that records the topology and reports failovers, and with
+    /// `topology_events` also forwards the other events.
+    pub(super) fn new(events: EventSink, topology_events: bool, srv_host: Option<String>, connection_id: String) -> Self {
+        Self {
+            latest: Mutex::new(None),
+            events,
+            topology_events,
+            primary: Mutex::new(None),
+            electing: watch::Sender::new(false),
+            srv_host,
+            membership_changes: Mutex::default(),
+            latency: Mutex::default(),
+            active_server: Mutex::new(None),
+            connection_id,
+            reachable: watch::Sender::new(false),
+        }
+    }
+
+    fn emit(&self, event: &str, payload: JsonValue) {
+        if self.topology_events {
+            (self.events)(event, payload);
+        }
+    }
+
+    /// Waits up to `timeout` while an election is under way, returning
+    /// whether there's a primary to write to by then.
+    pub(super) async fn wait_for_primary(&self, timeout: Duration) -> bool {
+        let mut electing = self.electing.subscribe();
+        let elected = tokio::time::timeout(timeout, electing.wait_for(|electing| !*electing)).await;
+        matches!(elected, Ok(Ok(_)))
+    }
+
+    /// Waits until a server of the connection answers its heartbeats.
+    pub(super) async fn wait_until_reachable(&self) {
+        let mut reachable = self.reachable.subscribe();
+        let _ = reachable.wait_for(|reachable| *reachable).await;
+    }
+
+    pub(super) fn connection_id(&self) -> &str {
+        &self.connection_id
+    }
+
+    fn record_failover(&self, current: &TopologyDescription) {
+        let current = primary_of(current);
+        let mut primary = self.primary.lock().unwrap();
+        let electing = *self.electing.borrow();
+        match &current {
+            Some(new) if electing || primary.as_ref().is_some_and(|old| old != new) => {
+                let event = json!({ "oldPrimary": *primary, "newPrimary": new, "electing": false, "at": now_ms() });
+                *primary = current;
+                self.electing.send_replace(false);
+                (self.events)("mongo://failover", event);
+            }
+            Some(_) => *primary = current,
+            None if primary.is_some() && !electing => {
+                self.electing.send_replace(true);
+                let event = json!({ "oldPrimary": *primary, "newPrimary": null, "electing": true, "at": now_ms() });
+                (self.events)("mongo://failover", event);
+            }
+            None => {}
+        }
+    }
+
+    fn record_reachability(&self, current: &TopologyDescription) {
+        let now = current.servers().values().any(|server| server.server_type() != ServerType::Unknown);
+        if !self.reachable.send_if_modified(|reachable| std::mem::replace(reachable, now) != now) {
+            return;
+        }
+        let event = if now { "mongo://connected" } else { "mongo://disconnected" };
+        let payload = json!({ "connectionId": self.connection_id, "type": current.topology_type().to_string(), "at": now_ms() });
+        (self.events)(event, payload);
+    }
+
+    /// Notes the server a command was just answered by.
+    pub(super) fn record_command(&self, address: String) {
+        *self.active_server.lock().unwrap() = Some((address, now_ms()));
+    }
+
+    fn snapshot(&self) -> JsonValue {
+        let mut snapshot = match self.latest.lock().unwrap().as_ref() {
+            Some(topology) => describe(topology),
+            None => return JsonValue::Null,
+        };
+        let latency = self.latency.lock().unwrap();
+        if let Some(servers) = snapshot["servers"].as_array_mut() {
+            for server in servers {
+                let address = server["address"].as_str().unwrap_or_default();
+                server["latency"] = latency.get(address).map_or(JsonValue::Null, Latency::view);
+            }
+        }
+        let active = self.active_server.lock().unwrap();
+        snapshot["activeServer"] = match active.as_ref() {
+            Some((address, at)) => json!({ "address": address, "at": at }),
+            None => JsonValue::Null,
+        };
+        snapshot["srvHost"] = json!(self.srv_host);
+        snapshot["membershipChanges"] = json!(*self.membership_changes.lock().unwrap());
+        snapshot
+    }
+
+    fn record_membership(&self, previous: &TopologyDescription, current: &TopologyDescription) {
+        let (before, after) = (addresses(previous), addresses(current));
+        let added: Vec<&String> = after.difference(&before).collect();
+        let removed: Vec<&String> = before.difference(&after).collect();
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        {
+            let mut latency = self.latency.lock().unwrap();
+            for address in &removed {
+                latency.remove(*address);
+            }
+        }
+        let change = json!({ "added": added, "removed": removed, "srvHost": self.srv_host, "at": now_ms() });
+        self.emit("mongo://membership-changed", change.clone());
+        let mut changes = self.membership_changes.lock().unwrap();
+        changes.push_back(change);
+        while changes.len() > MAX_MEMBERSHIP_CHANGES {
+            changes.pop_front();
+        }
+    }
+}
+
+/// The frontend's view of a topology: its type and the state of each server.
+fn describe(topology: &TopologyDescription) -> JsonValue {
+    let mut servers: Vec<JsonValue> = topology
+        .servers()
+        .into_iter()
+        .map(|(address, server)| {
+            json!({
+                "address": address.to_string(),
+                "type": format!("{:?}", server.server_type()),
+                "setName": server.replica_set_name(),
+                "roundTripMs": server.average_round_trip_time().map(|rtt| rtt.as_secs_f64() * 1000.0),
+                "error": server.error().map(|e| e.to_string()),
+            })
+        })
+        .collect();
+    servers.sort_by(|a, b| a["address"].as_str().cmp(&b["address"].as_str()));
+    json!({
+        "type": topology.topology_type().to_string(),
+        "setName": topology.set_name(),
+        "hasWritableServer": topology.has_writable_server(),
+        "servers": servers,
+    })
+}
+
+impl SdamEventHandler for TopologyMonitor {
+    fn handle_server_opening_event(&self, event: ServerOpeningEvent) {
+        self.emit("mongo://server-opened", json!({ "address": event.address.to_string() }));
+    }
+
+    fn handle_server_closed_event(&self, event: ServerClosedEvent) {
+        self.emit("mongo://server-closed", json!({ "address": event.address.to_string() }));
+    }
+
+    fn handle_topology_description_changed_event(&self, event: TopologyDescriptionChangedEvent) {
+        self.record_membership(&event.previous_description, &event.new_description);
+        self.record_failover(&event.new_description);
+        self.record_reachability(&event.new_description);
+        self.emit(
+            "mongo://topology-changed",
+            json!({ "previous": describe(&event.previous_description), "current": describe(&event.new_description) }),
+        );
+        *self.latest.lock().unwrap() = Some(event.new_description);
+    }
+
+    fn handle_server_heartbeat_succeeded_event(&self, event: ServerHeartbeatSucceededEvent) {
+        if !event.awaited {
+            let mut latency = self.latency.lock().unwrap();
+            latency.entry(event.server_address.to_string()).or_default().record(event.duration);
+        }
+    }
+
+    fn handle_server_heartbeat_failed_event(&self, event: ServerHeartbeatFailedEvent) {
+        self.latency.lock().unwrap().entry(event.server_address.to_string()).or_default().failures += 1;
+        self.emit(
+            "mongo://heartbeat-failed",
+            json!({
+                "address": event.server_address.to_string(),
+                "durationMs": event.duration.as_secs_f64() * 1000.0,
+                "error": event.failure.to_string(),
+            }),
+        );
+    }
+}
+
+/// The latest topology seen by the connected client; `null` until the
+/// driver has reported one.
+pub(super) async fn get_topology<R: Runtime>(app: AppHandle<R>, connection: Option<String>, _: NoArgs) -> Result<JsonValue, MongoPluginError> {
+    Ok(app.state::<MongoState>().connections.get(connection.as_deref())?.topology.snapshot())
+}
@@ -0,0 +1,299 @@
+//! A recycle bin for configured collections.
+//!
+//! Deletes on a trash collection, by `deleteById`, `deleteOne`, `deleteMany`
+//! or `findOneAndDelete`, move each document into `_trash`, together with
+//! the collection it came from and when and by whom it was deleted, instead
+//! of only removing it. A TTL index on `_trash` purges
+//! entries after `retentionDays`. `restoreFromTrash` puts the latest trashed
+//! copy of a document back. Collections using soft deletes keep doing so.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::{Error as MongoError, ErrorKind};
+use mongodb::options::{FindOneOptions, FindOptions, IndexOptions};
+use mongodb::{ClientSession, Database, IndexModel};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::errors::{self, MongoPluginError};
+use super::{coerce_id, convert, is_duplicate_key, CommandContext};
+
+pub(super) const TRASH_COLLECTION: &str = "_trash";
+const DEFAULT_RETENTION_DAYS: u64 = 30;
+const TTL_INDEX: &str = "deletedAt_ttl";
+const INDEX_OPTIONS_CONFLICT: i32 = 85;
+
+/// The `trash` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TrashConfig {
+    /// Collections whose deleted documents go to the trash.
+    pub collections: Vec<String>,
+    /// Days a trashed document is kept, 30 by default.
+    pub retention_days: Option<u64>,
+}
+
+pub(super) struct Trash {
+    collections: HashSet<String>,
+    retention: Duration,
+    /// Databases whose `_trash` TTL index is known to be in place.
+    indexed: Mutex<HashSet<String>>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct TrashDeleteArgs {
+    collection: String,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+pub(super) struct TrashFilterArgs {
+    collection: String,
+    filter: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ListTrashArgs {
+    collection: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct RestoreFromTrashArgs {
+    collection: String,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+pub(super) struct EmptyTrashArgs {
+    collection: Option<String>,
+}
+
+fn entry(collection: &str, id: Bson, document: Document, actor: &JsonValue) -> Document {
+    doc! {
+        "collection": collection,
+        "documentId": id,
+        "document": document,
+        "deletedAt": DateTime::now(),
+        "deletedBy": Bson::try_from(actor.clone()).unwrap_or(Bson::Null),
+    }
+}
+
+fn scope(collection: Option<&str>) -> Document {
+    match collection {
+        Some(collection) => doc! { "collection": collection },
+        None => Document::new(),
+    }
+}
+
+impl Trash {
+    pub(super) fn new(config: &TrashConfig) -> Self {
+        let days = config.retention_days.unwrap_or(DEFAULT_RETENTION_DAYS);
+        Self {
+            collections: config.collections.iter().cloned().collect(),
+            retention: Duration::from_secs(days * 24 * 60 * 60),
+            indexed: Mutex::default(),
+        }
+    }
+
+    pub(super) fn trashes(&self, payload: &JsonValue) -> bool {
+        let collection = payload.get("collection").and_then(JsonValue::as_str);
+        collection.is_some_and(|collection| self.collections.contains(collection))
+    }
+
+    /// Creates the TTL index that purges old entries, or updates its expiry
+    /// when `retentionDays` changed since it was created.
+    pub(super) async fn ensure_ttl(&self, db: &Database) -> Result<(), MongoPluginError> {
+        if self.indexed.lock().unwrap().contains(db.name()) {
+            return Ok(());
+        }
+        let options = IndexOptions::builder().name(TTL_INDEX.to_string()).expire_after(self.retention).build();
+        let index = IndexModel::builder().keys(doc! { "deletedAt": 1 }).options(options).build();
+        let created = match db.collection::<Document>(TRASH_COLLECTION).create_index(index, None).await {
+            Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(e) if e.code == INDEX_OPTIONS_CONFLICT) => {
+                let command = doc! {
+                    "collMod": TRASH_COLLECTION,
+                    "index": { "name": TTL_INDEX, "expireAfterSeconds": self.retention.as_secs() as i64 },
+                };
+                db.run_command(command, None).await.map(|_| ())
+            }
+            created => created.map(|_| ()),
+        };
+        match created {
+            Ok(()) => {
+                self.indexed.lock().unwrap().insert(db.name().to_string());
+                Ok(())
+            }
+            Err(e) => Err(errors::failed("Failed to set up the trash", e)),
+        }
+    }
+
+    /// Moves a document to the trash inside a transaction's session.
+    pub(super) async fn delete_with_session(
+        &self,
+        db: &Database,
+        collection: &str,
+        id: Bson,
+        actor: &JsonValue,
+        session: &mut ClientSession,
+    ) -> Result<JsonValue, MongoError> {
+        let coll = db.collection::<Document>(collection);
+        let document = match coll.find_one_with_session(doc! { "_id": id.clone() }, None, session).await? {
+            Some(document) => document,
+            None => return Ok(json!({ "deletedCount": 0 })),
+        };
+        let trash = db.collection::<Document>(TRASH_COLLECTION);
+        trash.insert_one_with_session(entry(collection, id.clone(), document, actor), None, session).await?;
+        let result = coll.delete_one_with_session(doc! { "_id": id }, None, session).await?;
+        Ok(json!({ "deletedCount": result.deleted_count }))
+    }
+}
+
+/// Moves a document to the trash, returning it if it was there to move.
+/// The entry is written before the document is removed, and taken back out
+/// if removing it fails, so a failure neither loses the document nor leaves
+/// a stray copy in the trash.
+async fn move_to_trash(ctx: &CommandContext, collection: &str, id: Bson) -> Result<Option<Document>, MongoPluginError> {
+    let coll = ctx.db.collection::<Document>(collection);
+    let document = match coll.find_one(doc! { "_id": id.clone() }, None).await {
+        Ok(Some(document)) => document,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(errors::failed("Failed to delete document", e)),
+    };
+    let bin = ctx.db.collection::<Document>(TRASH_COLLECTION);
+    let trashed = match bin.insert_one(entry(collection, id.clone(), document.clone(), &ctx.actor), None).await {
+        Ok(result) => result.inserted_id,
+        Err(e) => return Err(errors::failed("Failed to move document to the trash", e)),
+    };
+    match coll.delete_one(doc! { "_id": id }, None).await {
+        Ok(result) if result.deleted_count == 0 => {
+            // Deleted by someone else in the meantime; their delete stands.
+            let _ = bin.delete_one(doc! { "_id": trashed }, None).await;
+            Ok(None)
+        }
+        Ok(_) => Ok(Some(document)),
+        Err(e) => {
+            let _ = bin.delete_one(doc! { "_id": trashed }, None).await;
+            Err(errors::failed("Failed to delete document", e))
+        }
+    }
+}
+
+fn trash_of(ctx: &CommandContext) -> Result<&Trash, MongoPluginError> {
+    match &ctx.trash {
+        Some(trash) => Ok(trash),
+        None => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "The trash is not configured")),
+    }
+}
+
+pub(super) async fn delete_by_id(ctx: CommandContext, args: TrashDeleteArgs) -> Result<JsonValue, MongoPluginError> {
+    trash_of(&ctx)?.ensure_ttl(&ctx.db).await?;
+    let deleted = move_to_trash(&ctx, &args.collection, coerce_id(&args.id)?).await?;
+    Ok(json!({ "deletedCount": deleted.is_some() as u64 }))
+}
+
+/// The ids of the first document matching the filter or, with `many`, of
+/// all of them.
+async fn matching_ids(ctx: &CommandContext, args: &TrashFilterArgs, many: bool) -> Result<Vec<Bson>, MongoPluginError> {
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    let options = FindOptions::builder().projection(doc! { "_id": 1 }).limit((!many).then_some(1)).build();
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    let found: Result<Vec<Document>, MongoError> = match coll.find(filter, options).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match found {
+        Ok(docs) => Ok(docs.into_iter().filter_map(|mut doc| doc.remove("_id")).collect()),
+        Err(e) => Err(errors::failed("Failed to delete documents", e)),
+    }
+}
+
+/// Moves the first match or, with `many`, every match to the trash, one
+/// document at a time.
+pub(super) async fn delete_matching(ctx: CommandContext, args: TrashFilterArgs, many: bool) -> Result<JsonValue, MongoPluginError> {
+    trash_of(&ctx)?.ensure_ttl(&ctx.db).await?;
+    let mut deleted = 0u64;
+    for id in matching_ids(&ctx, &args, many).await? {
+        if move_to_trash(&ctx, &args.collection, id).await?.is_some() {
+            deleted += 1;
+        }
+    }
+    Ok(json!({ "deletedCount": deleted }))
+}
+
+/// Moves the first match to the trash and returns it, or null when nothing
+/// matched.
+pub(super) async fn find_one_and_delete(ctx: CommandContext, args: TrashFilterArgs) -> Result<JsonValue, MongoPluginError> {
+    trash_of(&ctx)?.ensure_ttl(&ctx.db).await?;
+    let deleted = match matching_ids(&ctx, &args, false).await?.pop() {
+        Some(id) => move_to_trash(&ctx, &args.collection, id).await?,
+        None => None,
+    };
+    Ok(serde_json::to_value(deleted).unwrap())
+}
+
+/// Trashed documents, most recently deleted first, from one collection or
+/// from all of them.
+pub(super) async fn list_trash(ctx: CommandContext, args: ListTrashArgs) -> Result<JsonValue, MongoPluginError> {
+    let bin = ctx.db.collection::<Document>(TRASH_COLLECTION);
+    let options = FindOptions::builder().sort(doc! { "deletedAt": -1 }).limit(args.limit).build();
+    let cursor = match bin.find(scope(args.collection.as_deref()), options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to read the trash", e)),
+    };
+    let entries: Vec<Document> = match cursor.try_collect().await {
+        Ok(entries) => entries,
+        Err(e) => return Err(errors::failed("Failed to read the trash", e)),
+    };
+    let mut entries = serde_json::to_value(entries).unwrap();
+    if let Some(encryption) = &ctx.transforms.encryption {
+        encryption.open_result(&mut entries);
+    }
+    Ok(entries)
+}
+
+/// Puts the most recently trashed copy of a document back where it came
+/// from. Fails, keeping the trash entry, if a document with the same `_id`
+/// has been created since.
+pub(super) async fn restore_from_trash(ctx: CommandContext, args: RestoreFromTrashArgs) -> Result<JsonValue, MongoPluginError> {
+    let bin = ctx.db.collection::<Document>(TRASH_COLLECTION);
+    let filter = doc! { "collection": &args.collection, "documentId": coerce_id(&args.id)? };
+    let options = FindOneOptions::builder().sort(doc! { "deletedAt": -1 }).build();
+    let trashed = match bin.find_one(filter, options).await {
+        Ok(Some(trashed)) => trashed,
+        Ok(None) => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, "This document is not in the trash")),
+        Err(e) => return Err(errors::failed("Failed to read the trash", e)),
+    };
+    let document = match trashed.get_document("document") {
+        Ok(document) => document.clone(),
+        Err(_) => return Err("The trash entry has no document".into()),
+    };
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    match coll.insert_one(document, None).await {
+        Ok(_) => {}
+        Err(e) if is_duplicate_key(&e) => {
+            let message = format!("A document with this id exists again in '{}'", args.collection);
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+        Err(e) => return Err(errors::failed("Failed to restore document", e)),
+    }
+    if let Err(e) = bin.delete_one(doc! { "_id": trashed.get("_id").cloned().unwrap_or(Bson::Null) }, None).await {
+        return Err(errors::failed("Restored the document but failed to remove it from the trash", e));
+    }
+    Ok(json!({ "restored": true }))
+}
+
+/// Permanently removes trashed documents, from one collection or all.
+pub(super) async fn empty_trash(ctx: CommandContext, args: EmptyTrashArgs) -> Result<JsonValue, MongoPluginError> {
+    let bin = ctx.db.collection::<Document>(TRASH_COLLECTION);
+    match bin.delete_many(scope(args.collection.as_deref()), None).await {
+        Ok(result) => Ok(json!({ "deletedCount": result.deleted_count })),
+        Err(e) => Err(errors::failed("Failed to empty the trash", e)),
+    }
+}
@@ -0,0 +1,145 @@
+//! Collections of serde types for the app's own Rust code.
+//!
+//! [`MongoPluginExt::collection`] is available on the `App`, `AppHandle`
+//! and every `Window`, and gives a [`TypedCollection`] that takes filters
+//! and updates as BSON documents and reads and writes `T` directly, with
+//! no JSON text in between. Calls run like the frontend's commands on the
+//! connected database, or the one `on_connection` names, so field
+//! encryption, id strategies, timestamps, soft deletes, change tracking,
+//! history and `mongo://write` events all apply; the window policy and the
+//! before and after hooks don't, as they are for invokes.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Note {
+//!     title: String,
+//!     done: bool,
+//! }
+//!
+//! let notes = app.collection::<Note>("notes");
+//! notes.insert_one(&Note { title: "Buy milk".into(), done: false }).await?;
+//! let open: Vec<Note> = notes.find(doc! { "done": false }).await?;
+//! ```
+
+use mongodb::bson::{self, Bson, Document};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value as JsonValue};
+use std::marker::PhantomData;
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, execute, MongoState};
+
+/// Typed collections on anything that holds the app.
+#[allow(dead_code)]
+pub trait MongoPluginExt<R: Runtime> {
+    fn collection<T: Serialize + DeserializeOwned>(&self, name: &str) -> TypedCollection<R, T>;
+}
+
+impl<R: Runtime, M: Manager<R>> MongoPluginExt<R> for M {
+    fn collection<T: Serialize + DeserializeOwned>(&self, name: &str) -> TypedCollection<R, T> {
+        TypedCollection { app: self.app_handle(), connection_id: None, name: name.to_string(), documents: PhantomData }
+    }
+}
+
+/// A collection whose documents are `T`.
+pub struct TypedCollection<R: Runtime, T> {
+    app: AppHandle<R>,
+    connection_id: Option<String>,
+    name: String,
+    documents: PhantomData<fn() -> T>,
+}
+
+fn extjson(value: impl Into<Bson>) -> JsonValue {
+    value.into().into_canonical_extjson()
+}
+
+fn to_bson<V: Serialize>(value: &V) -> Result<Bson, MongoPluginError> {
+    bson::to_bson(value).map_err(|e| MongoPluginError::new(errors::ErrorKind::Bson, format!("Failed to convert document: {}", e)))
+}
+
+fn read<V: DeserializeOwned>(result: JsonValue) -> Result<V, MongoPluginError> {
+    convert::from_extjson_value(result).map_err(|e| MongoPluginError::new(errors::ErrorKind::Bson, format!("Failed to read result: {}", e)))
+}
+
+#[allow(dead_code)]
+impl<R: Runtime, T: Serialize + DeserializeOwned> TypedCollection<R, T> {
+    /// The same collection on connection `id` rather than the default one.
+    pub fn on_connection(mut self, id: &str) -> Self {
+        self.connection_id = Some(id.to_string());
+        self
+    }
+
+    /// Runs `command` with `args` on this collection, returning its result
+    /// in relaxed Extended JSON.
+    async fn run(&self, command: &str, mut args: JsonValue) -> Result<JsonValue, MongoPluginError> {
+        args["collection"] = json!(self.name);
+        let state = self.app.state::<MongoState>();
+        let ctx = state.context(self.connection_id.as_deref(), None)?;
+        let task = match execute(&ctx, command, args) {
+            Some(task) => task,
+            None => return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("Unknown command: {}", command))),
+        };
+        state.runtime.run(task).await.map_err(errors::from_failure)
+    }
+
+    pub async fn find(&self, filter: Document) -> Result<Vec<T>, MongoPluginError> {
+        read(self.run("find", json!({ "query": extjson(filter) })).await?)
+    }
+
+    pub async fn find_one(&self, filter: Document) -> Result<Option<T>, MongoPluginError> {
+        read(self.run("findOne", json!({ "query": extjson(filter) })).await?)
+    }
+
+    pub async fn find_by_id(&self, id: impl Into<Bson>) -> Result<Option<T>, MongoPluginError> {
+        read(self.run("findById", json!({ "id": extjson(id) })).await?)
+    }
+
+    /// The documents with these ids, in the same order, `None` where no
+    /// document has the id.
+    pub async fn find_by_ids(&self, ids: Vec<Bson>) -> Result<Vec<Option<T>>, MongoPluginError> {
+        read(self.run("findByIds", json!({ "ids": extjson(ids) })).await?)
+    }
+
+    pub async fn count_documents(&self, filter: Document) -> Result<u64, MongoPluginError> {
+        read(self.run("countDocuments", json!({ "filter": extjson(filter) })).await?)
+    }
+
+    /// Runs `pipeline`, reading each result as `U`, as its stages may
+    /// reshape the documents.
+    pub async fn aggregate<U: DeserializeOwned>(&self, pipeline: Vec<Document>) -> Result<Vec<U>, MongoPluginError> {
+        let pipeline: Vec<Bson> = pipeline.into_iter().map(Bson::Document).collect();
+        read(self.run("aggregate", json!({ "pipeline": extjson(pipeline) })).await?)
+    }
+
+    /// Returns `"success"`, or `{ insertedId }` when the collection has an id
+    /// strategy.
+    pub async fn insert_one(&self, document: &T) -> Result<JsonValue, MongoPluginError> {
+        self.run("insertOne", json!({ "data": extjson(to_bson(document)?) })).await
+    }
+
+    pub async fn insert_many(&self, documents: &[T]) -> Result<JsonValue, MongoPluginError> {
+        self.run("insertMany", json!({ "data": extjson(to_bson(&documents)?) })).await
+    }
+
+    pub async fn replace_one(&self, filter: Document, replacement: &T) -> Result<JsonValue, MongoPluginError> {
+        self.run("replaceOne", json!({ "filter": extjson(filter), "replacement": extjson(to_bson(replacement)?) })).await
+    }
+
+    pub async fn update_one(&self, filter: Document, update: Document) -> Result<JsonValue, MongoPluginError> {
+        self.run("updateOne", json!({ "filter": extjson(filter), "update": extjson(update) })).await
+    }
+
+    pub async fn update_many(&self, filter: Document, update: Document) -> Result<JsonValue, MongoPluginError> {
+        self.run("updateMany", json!({ "filter": extjson(filter), "update": extjson(update) })).await
+    }
+
+    pub async fn delete_one(&self, filter: Document) -> Result<JsonValue, MongoPluginError> {
+        self.run("deleteOne", json!({ "filter": extjson(filter) })).await
+    }
+
+    pub async fn delete_many(&self, filter: Document) -> Result<JsonValue, MongoPluginError> {
+        self.run("deleteMany", json!({ "filter": extjson(filter) })).await
+    }
+}
@@ -0,0 +1,152 @@
+//! Prior versions of the documents in configured collections.
+//!
+//! Before each write that changes or deletes a document in a versioned
+//! collection, the document as it was is kept in `<collection>_versions`,
+//! numbered per document from 1. Only the latest `maxVersions` are kept.
+//! `revertToVersion` puts one back, itself keeping the version it replaces,
+//! so a revert can be undone the same way.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::Result as MongoResult;
+use mongodb::options::{FindOneOptions, FindOptions, ReplaceOptions};
+use mongodb::{ClientSession, Database};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashSet;
+
+use super::errors::{self, MongoPluginError};
+use super::changes::Change;
+use super::{coerce_id, update_result_json, CommandContext};
+
+const SHADOW_SUFFIX: &str = "_versions";
+const DEFAULT_MAX_VERSIONS: usize = 20;
+
+/// The `versioning` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct VersioningConfig {
+    /// Collections whose documents are versioned.
+    pub collections: Vec<String>,
+    /// Versions kept per document, 20 by default; the oldest go first.
+    pub max_versions: Option<usize>,
+}
+
+pub(super) struct Versioning {
+    collections: HashSet<String>,
+    max_versions: usize,
+}
+
+#[derive(Deserialize)]
+pub(super) struct GetVersionsArgs {
+    collection: String,
+    id: JsonValue,
+}
+
+#[derive(Deserialize)]
+pub(super) struct RevertToVersionArgs {
+    collection: String,
+    id: JsonValue,
+    version: i64,
+}
+
+fn shadow(collection: &str) -> String {
+    format!("{}{}", collection, SHADOW_SUFFIX)
+}
+
+impl Versioning {
+    pub(super) fn new(config: &VersioningConfig) -> Self {
+        Self {
+            collections: config.collections.iter().cloned().collect(),
+            max_versions: config.max_versions.unwrap_or(DEFAULT_MAX_VERSIONS).max(1),
+        }
+    }
+
+    /// Whether a command can replace a version of a document in `collection`.
+    /// Inserts have no prior version to keep.
+    pub(super) fn versions(&self, command: &str, collection: &str) -> bool {
+        !matches!(command, "insertOne" | "insertMany") && self.collections.contains(collection)
+    }
+
+    /// Keeps the prior version of every document a write changed or deleted.
+    pub(super) async fn record(
+        &self,
+        db: &Database,
+        collection: &str,
+        changes: &[Change],
+        mut session: Option<&mut ClientSession>,
+    ) -> MongoResult<()> {
+        let versions = db.collection::<Document>(&shadow(collection));
+        let at = DateTime::now();
+        for change in changes {
+            let before = match &change.before {
+                Some(before) => before,
+                None => continue,
+            };
+            let filter = doc! { "documentId": change.id.clone() };
+            let options = FindOneOptions::builder().sort(doc! { "version": -1 }).build();
+            let latest = match session.as_deref_mut() {
+                Some(session) => versions.find_one_with_session(filter.clone(), options, session).await?,
+                None => versions.find_one(filter.clone(), options).await?,
+            };
+            let version = latest.and_then(|latest| latest.get_i64("version").ok()).unwrap_or(0) + 1;
+            let snapshot = doc! { "documentId": change.id.clone(), "version": version, "at": at, "document": before.clone() };
+            let pruned = doc! { "documentId": change.id.clone(), "version": { "$lte": version - self.max_versions as i64 } };
+            match session.as_deref_mut() {
+                Some(session) => {
+                    versions.insert_one_with_session(snapshot, None, &mut *session).await?;
+                    versions.delete_many_with_session(pruned, None, session).await?;
+                }
+                None => {
+                    versions.insert_one(snapshot, None).await?;
+                    versions.delete_many(pruned, None).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A document's kept versions, newest first: `[{ version, at, document }]`.
+pub(super) async fn get_versions(ctx: CommandContext, args: GetVersionsArgs) -> Result<JsonValue, MongoPluginError> {
+    let versions = ctx.db.collection::<Document>(&shadow(&args.collection));
+    let options = FindOptions::builder()
+        .sort(doc! { "version": -1 })
+        .projection(doc! { "_id": 0, "version": 1, "at": 1, "document": 1 })
+        .build();
+    let cursor = match versions.find(doc! { "documentId": coerce_id(&args.id)? }, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to read versions", e)),
+    };
+    let versions: Vec<Document> = match cursor.try_collect().await {
+        Ok(versions) => versions,
+        Err(e) => return Err(errors::failed("Failed to read versions", e)),
+    };
+    let mut versions = serde_json::to_value(versions).unwrap();
+    if let Some(encryption) = &ctx.transforms.encryption {
+        encryption.open_result(&mut versions);
+    }
+    Ok(versions)
+}
+
+/// Replaces the document with one of its kept versions, re-inserting it if
+/// it has since been deleted.
+pub(super) async fn revert_to_version(ctx: CommandContext, args: RevertToVersionArgs) -> Result<JsonValue, MongoPluginError> {
+    let id = coerce_id(&args.id)?;
+    let versions = ctx.db.collection::<Document>(&shadow(&args.collection));
+    let snapshot = match versions.find_one(doc! { "documentId": id.clone(), "version": args.version }, None).await {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return Err(MongoPluginError::new(errors::ErrorKind::NotFound, format!("No version {} of this document is kept", args.version))),
+        Err(e) => return Err(errors::failed("Failed to read versions", e)),
+    };
+    let document = match snapshot.get("document") {
+        Some(Bson::Document(document)) => document.clone(),
+        _ => return Err(format!("Version {} of this document is malformed", args.version).into()),
+    };
+    let coll = ctx.db.collection::<Document>(&args.collection);
+    let options = ReplaceOptions::builder().upsert(true).build();
+    match coll.replace_one(doc! { "_id": id }, document, options).await {
+        Ok(result) => Ok(update_result_json(&result)),
+        Err(e) => Err(errors::failed("Failed to revert document", e)),
+    }
+}
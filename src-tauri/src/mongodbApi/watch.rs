@@ -0,0 +1,182 @@
+//! Live change events from a collection or a whole database.
+//!
+//! `watch` opens a change stream, on `collection` or, without one, on the
+//! whole database, with an optional `pipeline` filtering or reshaping the
+//! events, and returns a `watchId` at once. Each change is then emitted as
+//! a `mongo://change/<watchId>` event carrying the change document. When the
+//! stream fails, the last event is `{ error }`, and when the collection is
+//! dropped or renamed, MongoDB's own `invalidate` change.
+//!
+//! `maxAwaitTimeMS` and `batchSize` trade event latency against round trips:
+//! the server holds each getMore up to `maxAwaitTimeMS` waiting for changes
+//! and returns at most `batchSize` of them. `startAtOperationTime`, an
+//! Extended JSON `$timestamp` or whole seconds since the epoch, replays the
+//! changes since that point in the oplog. `unwatch` closes a
+//! stream early; streams a window opened are closed when it is destroyed,
+//! and those on a connection when it closes.
+
+use futures::StreamExt;
+use mongodb::bson::{oid::ObjectId, Bson, Document, Timestamp};
+use mongodb::change_stream::event::ChangeStreamEvent;
+use mongodb::change_stream::ChangeStream;
+use mongodb::options::{ChangeStreamOptions, FullDocumentType};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::task::AbortHandle;
+
+use super::errors::{self, MongoPluginError};
+use super::{convert, Connection, MongoState};
+
+const CHANGE_EVENT_PREFIX: &str = "mongo://change/";
+
+struct Watch {
+    /// The window that opened it.
+    window: String,
+    connection: Weak<Connection>,
+    task: AbortHandle,
+}
+
+/// The open change streams, by watch id.
+#[derive(Default)]
+pub(super) struct Watches(Mutex<HashMap<String, Watch>>);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct WatchArgs {
+    collection: Option<String>,
+    pipeline: Option<String>,
+    /// `updateLookup` to have update events carry the whole document.
+    full_document: Option<FullDocumentType>,
+    #[serde(rename = "maxAwaitTimeMS")]
+    max_await_time_ms: Option<u64>,
+    batch_size: Option<u32>,
+    start_at_operation_time: Option<JsonValue>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct UnwatchArgs {
+    watch_id: String,
+}
+
+impl Watches {
+    fn close_where(&self, closes: impl Fn(&Watch) -> bool) {
+        self.0.lock().unwrap().retain(|_, watch| {
+            let closed = closes(watch);
+            if closed {
+                watch.task.abort();
+            }
+            !closed
+        });
+    }
+
+    /// Closes the streams `window` opened.
+    pub(super) fn close_window(&self, window: &str) {
+        self.close_where(|watch| watch.window == window);
+    }
+
+    /// Closes the streams on `connection`, which would otherwise hold up
+    /// shutting its client down.
+    pub(super) fn close_connection(&self, connection: &Arc<Connection>) {
+        self.close_where(|watch| watch.connection.as_ptr() == Arc::as_ptr(connection));
+    }
+
+    pub(super) fn close_all(&self) {
+        self.close_where(|_| true);
+    }
+}
+
+fn operation_time(value: &JsonValue) -> Result<Timestamp, MongoPluginError> {
+    if let Some(seconds) = value.as_u64() {
+        return match u32::try_from(seconds) {
+            Ok(time) => Ok(Timestamp { time, increment: 0 }),
+            Err(_) => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "startAtOperationTime is out of range")),
+        };
+    }
+    match Bson::try_from(value.clone()) {
+        Ok(Bson::Timestamp(timestamp)) => Ok(timestamp),
+        _ => Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "startAtOperationTime must be a $timestamp or a number of seconds")),
+    }
+}
+
+type Changes = ChangeStream<ChangeStreamEvent<Document>>;
+
+async fn forward<R: Runtime>(app: &AppHandle<R>, id: &str, collection: Option<&str>, mut changes: Changes) {
+    let event = format!("{}{}", CHANGE_EVENT_PREFIX, id);
+    let transforms = app.state::<MongoState>().transforms.clone();
+    while let Some(change) = changes.next().await {
+        match change {
+            Ok(change) => {
+                let mut change = serde_json::to_value(change).unwrap();
+                transforms.finish("watch", collection, &mut change);
+                let _ = app.emit_all(&event, change);
+            }
+            Err(e) => {
+                let _ = app.emit_all(&event, json!({ "error": format!("Change stream failed: {}", e) }));
+                return;
+            }
+        }
+    }
+}
+
+/// Opens a change stream for the calling `window`, returning its id.
+pub(super) async fn watch<R: Runtime>(
+    app: AppHandle<R>,
+    window: String,
+    connection: Option<String>,
+    tenant: Option<String>,
+    args: WatchArgs,
+) -> Result<JsonValue, MongoPluginError> {
+    let state = app.state::<MongoState>();
+    let opened = state.connections.get(connection.as_deref())?;
+    let db = state.database(connection.as_deref(), tenant.as_deref())?;
+    let pipeline: Vec<Document> = match &args.pipeline {
+        Some(pipeline) => match convert::from_extjson(pipeline) {
+            Ok(pipeline) => pipeline,
+            Err(e) => return Err(errors::invalid("Failed to parse pipeline", e)),
+        },
+        None => Vec::new(),
+    };
+    let start_at = args.start_at_operation_time.as_ref().map(operation_time).transpose()?;
+    let options = ChangeStreamOptions::builder()
+        .full_document(args.full_document)
+        .max_await_time(args.max_await_time_ms.map(Duration::from_millis))
+        .batch_size(args.batch_size)
+        .start_at_operation_time(start_at)
+        .build();
+    let changes = match &args.collection {
+        Some(collection) => db.collection::<Document>(collection).watch(pipeline, options).await,
+        None => db.watch(pipeline, options).await,
+    };
+    let changes = match changes {
+        Ok(changes) => changes,
+        Err(e) => return Err(errors::failed("Failed to open change stream", e)),
+    };
+
+    let id = ObjectId::new().to_hex();
+    // Held until the stream is registered, so a stream that ends at once
+    // doesn't try to unregister before it is registered.
+    let registry = app.state::<Watches>();
+    let mut watches = registry.0.lock().unwrap();
+    let (task_app, task_id) = (app.clone(), id.clone());
+    let task = tokio::spawn(async move {
+        forward(&task_app, &task_id, args.collection.as_deref(), changes).await;
+        task_app.state::<Watches>().0.lock().unwrap().remove(&task_id);
+    });
+    watches.insert(id.clone(), Watch { window, connection: Arc::downgrade(&opened), task: task.abort_handle() });
+    Ok(json!({ "watchId": id }))
+}
+
+pub(super) async fn unwatch<R: Runtime>(app: AppHandle<R>, args: UnwatchArgs) -> Result<JsonValue, MongoPluginError> {
+    match app.state::<Watches>().0.lock().unwrap().remove(&args.watch_id) {
+        Some(watch) => {
+            watch.task.abort();
+            Ok(json!({ "closed": true }))
+        }
+        None => Ok(json!({ "closed": false })),
+    }
+}
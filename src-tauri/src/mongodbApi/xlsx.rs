@@ -0,0 +1,421 @@
+//! Writing the results of a find to an Excel workbook.
+//!
+//! `exportXlsx` writes one row per matching document and one column per
+//! entry of `fields`, dotted paths headed by the path itself, into a single
+//! worksheet with a bold, frozen and filterable header row. Cells keep their
+//! type: numbers stay numbers, booleans booleans, and dates become Excel
+//! dates shown as `yyyy-mm-dd hh:mm:ss` in UTC. Ids, nested documents and
+//! arrays are written as text, missing fields as empty cells.
+//!
+//! The workbook goes to `path`, which must end in `.xlsx` and lie within one
+//! of the `xlsx.directories` in the plugin config, or to a new file in the
+//! temporary directory that `deleteExportFile` can remove. Like the NDJSON exports it runs as an `export`
+//! [operation](super::operations) reporting `{ count }` every thousand
+//! documents, and returns `{ operationId, path, count, bytes }`. Nothing is
+//! written unless the whole export succeeds.
+
+use crc32fast::Hasher;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use futures::TryStreamExt;
+use mongodb::bson::Document;
+use mongodb::options::FindOptions;
+use mongodb::Cursor;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::errors::{self, MongoPluginError};
+use super::operations::Operation;
+use super::{convert, export, gridfs, CommandContext};
+
+const PROGRESS_EVERY: u64 = 1000;
+/// Rows in a worksheet, the header included.
+const MAX_ROWS: u64 = 1_048_576;
+const MAX_COLUMNS: usize = 16_384;
+const MAX_CELL_CHARS: usize = 32_767;
+const DEFAULT_SHEET_NAME: &str = "Export";
+/// Days from Excel's epoch to the Unix epoch.
+const UNIX_EPOCH_SERIAL: f64 = 25_569.0;
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+// Indexes into `cellXfs` in STYLES.
+const HEADER_STYLE: u8 = 1;
+const DATE_STYLE: u8 = 2;
+
+const CONTENT_TYPES: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">"#,
+    r#"<Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>"#,
+    r#"<Default Extension="xml" ContentType="application/xml"/>"#,
+    r#"<Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>"#,
+    r#"<Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>"#,
+    r#"<Override PartName="/xl/styles.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.styles+xml"/>"#,
+    r#"</Types>"#,
+);
+
+const ROOT_RELS: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+    r#"<Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/>"#,
+    r#"</Relationships>"#,
+);
+
+const WORKBOOK_RELS: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+    r#"<Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/>"#,
+    r#"<Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles" Target="styles.xml"/>"#,
+    r#"</Relationships>"#,
+);
+
+/// Plain cells, the bold shaded header and UTC dates, in that order.
+const STYLES: &str = concat!(
+    r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+    r#"<styleSheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main">"#,
+    r#"<numFmts count="1"><numFmt numFmtId="164" formatCode="yyyy\-mm\-dd\ hh:mm:ss"/></numFmts>"#,
+    r#"<fonts count="2"><font><sz val="11"/><name val="Calibri"/></font><font><b/><sz val="11"/><name val="Calibri"/></font></fonts>"#,
+    r#"<fills count="3"><fill><patternFill patternType="none"/></fill><fill><patternFill patternType="gray125"/></fill>"#,
+    r#"<fill><patternFill patternType="solid"><fgColor rgb="FFD9E1F2"/><bgColor indexed="64"/></patternFill></fill></fills>"#,
+    r#"<borders count="1"><border><left/><right/><top/><bottom/><diagonal/></border></borders>"#,
+    r#"<cellStyleXfs count="1"><xf numFmtId="0" fontId="0" fillId="0" borderId="0"/></cellStyleXfs>"#,
+    r#"<cellXfs count="3"><xf numFmtId="0" fontId="0" fillId="0" borderId="0" xfId="0"/>"#,
+    r#"<xf numFmtId="0" fontId="1" fillId="2" borderId="0" xfId="0" applyFont="1" applyFill="1"/>"#,
+    r#"<xf numFmtId="164" fontId="0" fillId="0" borderId="0" xfId="0" applyNumberFormat="1"/></cellXfs>"#,
+    r#"<cellStyles count="1"><cellStyle name="Normal" xfId="0" builtinId="0"/></cellStyles>"#,
+    r#"</styleSheet>"#,
+);
+
+/// The `xlsx` section of the plugin config.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct XlsxConfig {
+    /// Directories `exportXlsx` may write workbooks to.
+    pub directories: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ExportXlsxArgs {
+    collection: String,
+    filter: String,
+    fields: Vec<String>,
+    path: Option<PathBuf>,
+    sheet_name: Option<String>,
+    #[serde(rename = "maxTimeMS")]
+    max_time_ms: Option<u64>,
+    operation_id: Option<String>,
+}
+
+enum Cell {
+    Empty,
+    Number(String),
+    Boolean(bool),
+    Date(f64),
+    Text(String),
+}
+
+/// The cell a field's value is written as.
+fn cell(value: Option<&JsonValue>) -> Cell {
+    let value = match value {
+        None | Some(JsonValue::Null) => return Cell::Empty,
+        Some(value) => value,
+    };
+    match value {
+        JsonValue::Number(n) => Cell::Number(n.to_string()),
+        JsonValue::Bool(b) => Cell::Boolean(*b),
+        JsonValue::String(s) => Cell::Text(s.clone()),
+        JsonValue::Object(map) if map.len() == 1 => {
+            let millis = match map.get("$date") {
+                Some(JsonValue::Object(date)) => date.get("$numberLong").and_then(JsonValue::as_str).and_then(|n| n.parse::<i64>().ok()),
+                Some(JsonValue::Number(n)) => n.as_i64(),
+                Some(JsonValue::String(s)) => mongodb::bson::DateTime::parse_rfc3339_str(s).ok().map(|date| date.timestamp_millis()),
+                _ => None,
+            };
+            if let Some(millis) = millis {
+                return Cell::Date(UNIX_EPOCH_SERIAL + millis as f64 / MILLIS_PER_DAY);
+            }
+            let number = ["$numberLong", "$numberDecimal", "$numberDouble", "$numberInt"]
+                .iter()
+                .find_map(|key| map.get(*key))
+                .and_then(JsonValue::as_str)
+                .filter(|n| n.parse::<f64>().is_ok_and(f64::is_finite));
+            match (number, map.get("$oid").and_then(JsonValue::as_str)) {
+                (Some(number), _) => Cell::Number(number.to_string()),
+                (None, Some(id)) => Cell::Text(id.to_string()),
+                (None, None) => Cell::Text(value.to_string()),
+            }
+        }
+        _ => Cell::Text(value.to_string()),
+    }
+}
+
+/// The value at a dotted path, where numeric segments index arrays.
+fn field<'a>(value: &'a JsonValue, path: &str) -> Option<&'a JsonValue> {
+    path.split('.').try_fold(value, |value, segment| match value {
+        JsonValue::Object(map) => map.get(segment),
+        JsonValue::Array(items) => items.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// `A` for the first column, `AA` for the 27th.
+fn column_name(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+/// Escapes text for XML, dropping the control characters XML can't hold
+/// and anything past what a cell can.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars().take(MAX_CELL_CHARS) {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if (c as u32) < 0x20 || c == '\u{FFFE}' || c == '\u{FFFF}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_cell(xml: &mut String, reference: &str, cell: &Cell, style: Option<u8>) {
+    let style = style.map_or_else(String::new, |style| format!(r#" s="{}""#, style));
+    match cell {
+        Cell::Empty => {}
+        Cell::Number(n) => xml.push_str(&format!(r#"<c r="{}"{}><v>{}</v></c>"#, reference, style, n)),
+        Cell::Boolean(b) => xml.push_str(&format!(r#"<c r="{}"{} t="b"><v>{}</v></c>"#, reference, style, u8::from(*b))),
+        Cell::Date(serial) => xml.push_str(&format!(r#"<c r="{}" s="{}"><v>{}</v></c>"#, reference, DATE_STYLE, serial)),
+        Cell::Text(text) => xml.push_str(&format!(
+            r#"<c r="{}"{} t="inlineStr"><is><t xml:space="preserve">{}</t></is></c>"#,
+            reference,
+            style,
+            escape(text)
+        )),
+    }
+}
+
+fn check_sheet_name(name: &str) -> Result<(), MongoPluginError> {
+    let length = name.chars().count();
+    if length == 0 || length > 31 || name.contains(['[', ']', ':', '*', '?', '/', '\\']) || name.starts_with('\'') || name.ends_with('\'') {
+        let message = "sheetName must be 1 to 31 characters without []:*?/\\ or surrounding quotes";
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+    }
+    Ok(())
+}
+
+fn check_path(ctx: &CommandContext, path: &Path) -> Result<PathBuf, MongoPluginError> {
+    let xlsx = path.extension().and_then(|extension| extension.to_str()).is_some_and(|extension| extension.eq_ignore_ascii_case("xlsx"));
+    if !xlsx {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, "path must end in .xlsx"));
+    }
+    gridfs::within(&ctx.config.xlsx.directories, path, "an xlsx export directory")
+}
+
+/// One deflated zip entry, compressed as it's written.
+struct Entry {
+    encoder: DeflateEncoder<Vec<u8>>,
+    crc: Hasher,
+    size: u64,
+}
+
+impl Entry {
+    fn new() -> Self {
+        Self { encoder: DeflateEncoder::new(Vec::new(), Compression::default()), crc: Hasher::new(), size: 0 }
+    }
+
+    fn write(&mut self, data: &str) {
+        // Writing into a Vec can't fail.
+        self.encoder.write_all(data.as_bytes()).unwrap();
+        self.crc.update(data.as_bytes());
+        self.size += data.len() as u64;
+    }
+}
+
+/// A zip archive built in memory, the container format of a workbook.
+#[derive(Default)]
+struct Zip {
+    bytes: Vec<u8>,
+    central: Vec<u8>,
+    entries: u16,
+}
+
+impl Zip {
+    fn add_text(&mut self, name: &str, text: &str) -> Result<(), String> {
+        let mut entry = Entry::new();
+        entry.write(text);
+        self.add(name, entry)
+    }
+
+    fn add(&mut self, name: &str, entry: Entry) -> Result<(), String> {
+        let crc = entry.crc.finalize();
+        let data = entry.encoder.finish().unwrap();
+        let (offset, compressed, size) = match (u32::try_from(self.bytes.len()), u32::try_from(data.len()), u32::try_from(entry.size)) {
+            (Ok(offset), Ok(compressed), Ok(size)) => (offset, compressed, size),
+            _ => return Err("The workbook is too large to export".to_string()),
+        };
+        // Version 2.0, deflated, dated 1980-01-01.
+        let fields = |header: &mut Vec<u8>| {
+            for value in [20u16, 0, 8, 0, 0x21] {
+                header.extend_from_slice(&value.to_le_bytes());
+            }
+            for value in [crc, compressed, size] {
+                header.extend_from_slice(&value.to_le_bytes());
+            }
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes());
+        };
+        self.bytes.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        fields(&mut self.bytes);
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.extend_from_slice(&data);
+
+        self.central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        self.central.extend_from_slice(&20u16.to_le_bytes());
+        fields(&mut self.central);
+        // No comment, disk 0, no attributes.
+        self.central.extend_from_slice(&[0; 10]);
+        self.central.extend_from_slice(&offset.to_le_bytes());
+        self.central.extend_from_slice(name.as_bytes());
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        let (offset, size) = match (u32::try_from(self.bytes.len()), u32::try_from(self.central.len())) {
+            (Ok(offset), Ok(size)) => (offset, size),
+            _ => return Err("The workbook is too large to export".to_string()),
+        };
+        self.bytes.append(&mut self.central);
+        self.bytes.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        self.bytes.extend_from_slice(&[0; 4]);
+        self.bytes.extend_from_slice(&self.entries.to_le_bytes());
+        self.bytes.extend_from_slice(&self.entries.to_le_bytes());
+        self.bytes.extend_from_slice(&size.to_le_bytes());
+        self.bytes.extend_from_slice(&offset.to_le_bytes());
+        self.bytes.extend_from_slice(&0u16.to_le_bytes());
+        Ok(self.bytes)
+    }
+}
+
+pub(super) async fn export_xlsx(ctx: CommandContext, args: ExportXlsxArgs) -> Result<JsonValue, MongoPluginError> {
+    let filter: Document = match convert::from_extjson(&args.filter) {
+        Ok(filter) => filter,
+        Err(e) => return Err(errors::invalid("Failed to parse filter", e)),
+    };
+    if args.fields.is_empty() || args.fields.len() > MAX_COLUMNS {
+        return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, format!("fields must name 1 to {} fields", MAX_COLUMNS)));
+    }
+    let sheet_name = args.sheet_name.clone().unwrap_or_else(|| DEFAULT_SHEET_NAME.to_string());
+    check_sheet_name(&sheet_name)?;
+    let path = match &args.path {
+        Some(path) => check_path(&ctx, path)?,
+        None => export::export_path("xlsx"),
+    };
+    let options = FindOptions::builder().max_time(args.max_time_ms.map(Duration::from_millis)).build();
+    let cursor = match ctx.db.collection::<Document>(&args.collection).find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return Err(errors::failed("Failed to execute query", e)),
+    };
+    let mut operation = ctx.operations.start(&ctx.events, ctx.owner(), "export", args.operation_id.clone())?;
+    let outcome = write_workbook(&ctx, &args, &sheet_name, path, cursor, &mut operation).await;
+    operation.finish(&outcome);
+    outcome
+}
+
+async fn write_workbook(
+    ctx: &CommandContext,
+    args: &ExportXlsxArgs,
+    sheet_name: &str,
+    path: PathBuf,
+    mut cursor: Cursor<Document>,
+    operation: &mut Operation,
+) -> Result<JsonValue, MongoPluginError> {
+    let columns: Vec<String> = (0..args.fields.len()).map(column_name).collect();
+    let mut sheet = Entry::new();
+    let mut xml = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+        r#"<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main">"#,
+        r#"<sheetViews><sheetView workbookViewId="0"><pane ySplit="1" topLeftCell="A2" activePane="bottomLeft" state="frozen"/></sheetView></sheetViews>"#,
+        "<cols>",
+    ));
+    for (index, field) in args.fields.iter().enumerate() {
+        let width = (field.chars().count() + 4).clamp(12, 60);
+        xml.push_str(&format!(r#"<col min="{0}" max="{0}" width="{1}" customWidth="1"/>"#, index + 1, width));
+    }
+    xml.push_str(r#"</cols><sheetData><row r="1">"#);
+    for (column, field) in columns.iter().zip(&args.fields) {
+        write_cell(&mut xml, &format!("{}1", column), &Cell::Text(field.clone()), Some(HEADER_STYLE));
+    }
+    xml.push_str("</row>");
+    sheet.write(&xml);
+
+    let mut count: u64 = 0;
+    while let Some(doc) = cursor.try_next().await.map_err(|e| errors::failed("Failed to read results", e))? {
+        if !operation.proceed().await {
+            return Err(MongoPluginError::new(errors::ErrorKind::Cancelled, "The export was cancelled"));
+        }
+        if count + 1 >= MAX_ROWS {
+            let message = format!("The results don't fit in a worksheet's {} rows", MAX_ROWS);
+            return Err(MongoPluginError::new(errors::ErrorKind::InvalidArgument, message));
+        }
+        let mut value = serde_json::to_value(doc).unwrap();
+        ctx.transforms.finish("findOne", Some(&args.collection), &mut value);
+        count += 1;
+        let row = count + 1;
+        xml.clear();
+        xml.push_str(&format!(r#"<row r="{}">"#, row));
+        for (column, path) in columns.iter().zip(&args.fields) {
+            write_cell(&mut xml, &format!("{}{}", column, row), &cell(field(&value, path)), None);
+        }
+        xml.push_str("</row>");
+        sheet.write(&xml);
+        if count.is_multiple_of(PROGRESS_EVERY) {
+            operation.progress(json!({ "count": count }));
+        }
+    }
+    let (last_column, last_row) = (&columns[columns.len() - 1], count + 1);
+    sheet.write(&format!(r#"</sheetData><autoFilter ref="A1:{}{}"/></worksheet>"#, last_column, last_row));
+
+    let workbook = format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+            r#"<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" "#,
+            r#"xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">"#,
+            r#"<sheets><sheet name="{0}" sheetId="1" r:id="rId1"/></sheets>"#,
+            r#"<definedNames><definedName name="_xlnm._FilterDatabase" localSheetId="0" hidden="1">'{1}'!$A$1:${2}${3}</definedName></definedNames>"#,
+            r#"</workbook>"#,
+        ),
+        escape(sheet_name),
+        escape(&sheet_name.replace('\'', "''")),
+        last_column,
+        last_row
+    );
+    let mut zip = Zip::default();
+    zip.add_text("[Content_Types].xml", CONTENT_TYPES)?;
+    zip.add_text("_rels/.rels", ROOT_RELS)?;
+    zip.add_text("xl/workbook.xml", &workbook)?;
+    zip.add_text("xl/_rels/workbook.xml.rels", WORKBOOK_RELS)?;
+    zip.add_text("xl/styles.xml", STYLES)?;
+    zip.add("xl/worksheets/sheet1.xml", sheet)?;
+    let bytes = zip.finish()?;
+    if let Err(e) = tokio::fs::write(&path, &bytes).await {
+        return Err(errors::failed("Failed to write workbook", e));
+    }
+    operation.progress(json!({ "count": count }));
+    Ok(json!({ "operationId": operation.id(), "path": path, "count": count, "bytes": bytes.len() }))
+}